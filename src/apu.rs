@@ -0,0 +1,832 @@
+use crate::{
+    state::{StateError, StateReader, StateWriter},
+    utils::{Address, Byte, Word},
+};
+
+/// Output sample rate in Hz
+pub const SAMPLE_RATE: u32 = 44100;
+/// Machine cycles per second
+const MCYCLE_RATE: u32 = 1 << 20;
+/// Samples kept while nobody takes them, newer ones are dropped
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize;
+
+const NR10_ADDRESS: Address = 0xFF10;
+const NR14_ADDRESS: Address = 0xFF14;
+/// NR20 does not exist, so channel 2 lines up with channel 1 without the sweep
+const NR20_ADDRESS: Address = 0xFF15;
+const NR24_ADDRESS: Address = 0xFF19;
+const NR30_ADDRESS: Address = 0xFF1A;
+const NR34_ADDRESS: Address = 0xFF1E;
+/// 32 4 bit samples, high nibble first
+const WAVE_RAM_START: Address = 0xFF30;
+const WAVE_RAM_END: Address = 0xFF3F;
+const WAVE_RAM_SIZE: usize = 16;
+/// NR40 does not exist either
+const NR40_ADDRESS: Address = 0xFF1F;
+const NR44_ADDRESS: Address = 0xFF23;
+/// Master volume for the left (bits 4-6) and right (bits 0-2) output
+pub const NR50_ADDRESS: Address = 0xFF24;
+/// Panning, bit n + 4 routes channel n + 1 to the left and bit n to the right
+pub const NR51_ADDRESS: Address = 0xFF25;
+/// Power in bit 7, reads back which channels are playing in bits 0-3
+pub const NR52_ADDRESS: Address = 0xFF26;
+const POWER_FLAG: Byte = 0b1000_0000;
+
+const TRIGGER_FLAG: Byte = 0b1000_0000;
+const LENGTH_ENABLE_FLAG: Byte = 0b0100_0000;
+
+/// Waveforms for duty 12.5%, 25%, 50% and 75%
+const DUTY_PATTERNS: [[Byte; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+/// Noise timer periods in machine cycles for the NR43 divisor codes, before the shift
+const NOISE_DIVISORS: [u32; 8] = [2, 4, 8, 12, 16, 20, 24, 28];
+
+/// Volume envelope of NRx2
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Envelope {
+    initial: Byte,
+    increase: bool,
+    period: Byte,
+    volume: Byte,
+    timer: Byte,
+}
+
+impl Envelope {
+    fn write(&mut self, byte: Byte) {
+        self.initial = byte >> 4;
+        self.increase = byte & 0b1000 != 0;
+        self.period = byte & 0b111;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.byte(self.initial);
+        state.bool(self.increase);
+        state.byte(self.period);
+        state.byte(self.volume);
+        state.byte(self.timer);
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let envelope = Self {
+            initial: state.byte()?,
+            increase: state.bool()?,
+            period: state.byte()?,
+            volume: state.byte()?,
+            timer: state.byte()?,
+        };
+        if envelope.initial > 15
+            || envelope.period > 7
+            || envelope.volume > 15
+            || envelope.timer > 7
+        {
+            return Err(StateError::InvalidValue("volume envelope"));
+        }
+        Ok(envelope)
+    }
+
+    /// Clocked at 64 Hz, a period of 0 stops the envelope
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Frequency sweep of NR10, only on channel 1
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Sweep {
+    period: Byte,
+    negate: bool,
+    shift: Byte,
+    timer: Byte,
+    /// Copy of the frequency the sweep works on
+    shadow: Word,
+    enabled: bool,
+}
+
+impl Sweep {
+    fn write(&mut self, byte: Byte) {
+        self.period = (byte >> 4) & 0b111;
+        self.negate = byte & 0b1000 != 0;
+        self.shift = byte & 0b111;
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.byte(self.period);
+        state.bool(self.negate);
+        state.byte(self.shift);
+        state.byte(self.timer);
+        state.word(self.shadow);
+        state.bool(self.enabled);
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let sweep = Self {
+            period: state.byte()?,
+            negate: state.bool()?,
+            shift: state.byte()?,
+            timer: state.byte()?,
+            shadow: state.word()?,
+            enabled: state.bool()?,
+        };
+        if sweep.period > 7 || sweep.shift > 7 || sweep.timer > 8 || sweep.shadow > 0x7FF {
+            return Err(StateError::InvalidValue("frequency sweep"));
+        }
+        Ok(sweep)
+    }
+
+    /// A period of 0 is treated as 8
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    /// Next frequency, above 2047 means the channel overflows
+    fn next_frequency(&self) -> Word {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+/// Square wave channel, channel 1 has a sweep
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Square {
+    enabled: bool,
+    dac_enabled: bool,
+    duty: usize,
+    duty_step: usize,
+    /// 11 bit period value of NRx3/NRx4
+    frequency: Word,
+    /// Machine cycles until the next duty step
+    timer: Word,
+    length: Word,
+    length_enabled: bool,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+}
+
+impl Square {
+    fn new(has_sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 2048,
+            length: 0,
+            length_enabled: false,
+            envelope: Envelope::default(),
+            sweep: has_sweep.then(Sweep::default),
+        }
+    }
+
+    /// Write to NRx0-NRx4
+    fn write(&mut self, register: Address, byte: Byte) {
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.write(byte);
+                }
+            }
+            1 => {
+                self.duty = (byte >> 6) as usize;
+                self.length = 64 - (byte & 0x3F) as Word;
+            }
+            2 => {
+                self.envelope.write(byte);
+                // the dac is off when the upper 5 bits are clear, which also stops the channel
+                self.dac_enabled = byte & 0xF8 != 0;
+                self.enabled &= self.dac_enabled;
+            }
+            3 => self.frequency = (self.frequency & 0x700) | byte as Word,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((byte as Word & 0b111) << 8);
+                self.length_enabled = byte & LENGTH_ENABLE_FLAG != 0;
+                if byte & TRIGGER_FLAG != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("square register {}", register),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.timer = 2048 - self.frequency;
+        self.envelope.trigger();
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 && sweep.next_frequency() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.byte(self.duty as Byte);
+        state.byte(self.duty_step as Byte);
+        state.word(self.frequency);
+        state.word(self.timer);
+        state.word(self.length);
+        state.bool(self.length_enabled);
+        self.envelope.write_state(state);
+        if let Some(sweep) = &self.sweep {
+            sweep.write_state(state);
+        }
+    }
+
+    fn read_state(state: &mut StateReader, has_sweep: bool) -> Result<Self, StateError> {
+        let square = Self {
+            enabled: state.bool()?,
+            dac_enabled: state.bool()?,
+            duty: state.byte()? as usize,
+            duty_step: state.byte()? as usize,
+            frequency: state.word()?,
+            timer: state.word()?,
+            length: state.word()?,
+            length_enabled: state.bool()?,
+            envelope: Envelope::read_state(state)?,
+            sweep: match has_sweep {
+                true => Some(Sweep::read_state(state)?),
+                false => None,
+            },
+        };
+        if square.duty >= DUTY_PATTERNS.len()
+            || square.duty_step >= 8
+            || square.frequency > 0x7FF
+            || square.timer == 0
+            || square.timer > 2048
+        {
+            return Err(StateError::InvalidValue("square channel"));
+        }
+        Ok(square)
+    }
+
+    /// Step the duty cycle once every 2048 - frequency machine cycles
+    fn tick(&mut self, mcycles: u8) {
+        let mut cycles = mcycles as Word;
+        while cycles > 0 {
+            let step = cycles.min(self.timer);
+            self.timer -= step;
+            cycles -= step;
+            if self.timer == 0 {
+                self.timer = 2048 - self.frequency;
+                self.duty_step = (self.duty_step + 1) % 8;
+            }
+        }
+    }
+
+    /// Clocked at 256 Hz, the channel stops when the length runs out
+    fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Clocked at 128 Hz, an overflowing frequency stops the channel
+    fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let frequency = sweep.next_frequency();
+        if frequency > 0x7FF {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // the new frequency is checked for overflow again right away
+            if sweep.next_frequency() > 0x7FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Digital output, 0-15
+    fn output(&self) -> Byte {
+        if !self.enabled || !self.dac_enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty][self.duty_step] * self.envelope.volume
+    }
+}
+
+/// Wave channel, plays the 4 bit samples of wave ram
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    /// Right shift applied to the samples, 4 mutes the channel
+    volume_shift: Byte,
+    /// Index of the sample being played, 0-31
+    position: usize,
+    frequency: Word,
+    /// Half machine cycles until the next sample, the wave channel runs twice
+    /// as fast as the square channels
+    timer: Word,
+    length: Word,
+    length_enabled: bool,
+    ram: [Byte; WAVE_RAM_SIZE],
+}
+
+impl Wave {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            volume_shift: 4,
+            position: 0,
+            frequency: 0,
+            timer: 2048,
+            length: 0,
+            length_enabled: false,
+            ram: [0; WAVE_RAM_SIZE],
+        }
+    }
+
+    /// Write to NR30-NR34
+    fn write(&mut self, register: Address, byte: Byte) {
+        match register {
+            0 => {
+                self.dac_enabled = byte & 0b1000_0000 != 0;
+                self.enabled &= self.dac_enabled;
+            }
+            1 => self.length = 256 - byte as Word,
+            // 0 mutes, 1-3 play at 100%, 50% and 25%
+            2 => self.volume_shift = [4, 0, 1, 2][((byte >> 5) & 0b11) as usize],
+            3 => self.frequency = (self.frequency & 0x700) | byte as Word,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((byte as Word & 0b111) << 8);
+                self.length_enabled = byte & LENGTH_ENABLE_FLAG != 0;
+                if byte & TRIGGER_FLAG != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("wave register {}", register),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 256;
+        }
+        self.position = 0;
+        self.timer = 2048 - self.frequency;
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.byte(self.volume_shift);
+        state.byte(self.position as Byte);
+        state.word(self.frequency);
+        state.word(self.timer);
+        state.word(self.length);
+        state.bool(self.length_enabled);
+        state.bytes(&self.ram);
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let wave = Self {
+            enabled: state.bool()?,
+            dac_enabled: state.bool()?,
+            volume_shift: state.byte()?,
+            position: state.byte()? as usize,
+            frequency: state.word()?,
+            timer: state.word()?,
+            length: state.word()?,
+            length_enabled: state.bool()?,
+            ram: state.bytes(WAVE_RAM_SIZE)?.try_into().unwrap(),
+        };
+        if wave.volume_shift > 4
+            || wave.position >= 2 * WAVE_RAM_SIZE
+            || wave.frequency > 0x7FF
+            || wave.timer == 0
+            || wave.timer > 2048
+        {
+            return Err(StateError::InvalidValue("wave channel"));
+        }
+        Ok(wave)
+    }
+
+    /// Step to the next sample once every (2048 - frequency) / 2 machine cycles
+    fn tick(&mut self, mcycles: u8) {
+        let mut cycles = 2 * mcycles as Word;
+        while cycles > 0 {
+            let step = cycles.min(self.timer);
+            self.timer -= step;
+            cycles -= step;
+            if self.timer == 0 {
+                self.timer = 2048 - self.frequency;
+                self.position = (self.position + 1) % (2 * WAVE_RAM_SIZE);
+            }
+        }
+    }
+
+    /// Clocked at 256 Hz, the channel stops when the length runs out
+    fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Digital output, 0-15
+    fn output(&self) -> Byte {
+        if !self.enabled || !self.dac_enabled {
+            return 0;
+        }
+        let byte = self.ram[self.position / 2];
+        // high nibble first
+        let sample = (byte >> (4 - 4 * (self.position & 1))) & 0xF;
+        sample >> self.volume_shift
+    }
+}
+
+/// Noise channel, plays the low bit of a linear feedback shift register
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Noise {
+    enabled: bool,
+    dac_enabled: bool,
+    /// 15 bit shift register
+    lfsr: Word,
+    /// Also feed bit 6 back, making a 7 bit register with a more tonal sound
+    short_mode: bool,
+    shift: Byte,
+    divisor: usize,
+    /// Machine cycles until the next shift
+    timer: u32,
+    length: Word,
+    length_enabled: bool,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            lfsr: 0x7FFF,
+            short_mode: false,
+            shift: 0,
+            divisor: 0,
+            timer: NOISE_DIVISORS[0],
+            length: 0,
+            length_enabled: false,
+            envelope: Envelope::default(),
+        }
+    }
+
+    /// Write to NR41-NR44, register 0 does not exist
+    fn write(&mut self, register: Address, byte: Byte) {
+        match register {
+            0 => (),
+            1 => self.length = 64 - (byte & 0x3F) as Word,
+            2 => {
+                self.envelope.write(byte);
+                self.dac_enabled = byte & 0xF8 != 0;
+                self.enabled &= self.dac_enabled;
+            }
+            3 => {
+                self.shift = byte >> 4;
+                self.short_mode = byte & 0b1000 != 0;
+                self.divisor = (byte & 0b111) as usize;
+            }
+            4 => {
+                self.length_enabled = byte & LENGTH_ENABLE_FLAG != 0;
+                if byte & TRIGGER_FLAG != 0 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("noise register {}", register),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor] << self.shift
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        if self.length == 0 {
+            self.length = 64;
+        }
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac_enabled);
+        state.word(self.lfsr);
+        state.bool(self.short_mode);
+        state.byte(self.shift);
+        state.byte(self.divisor as Byte);
+        state.u32(self.timer);
+        state.word(self.length);
+        state.bool(self.length_enabled);
+        self.envelope.write_state(state);
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let noise = Self {
+            enabled: state.bool()?,
+            dac_enabled: state.bool()?,
+            lfsr: state.word()?,
+            short_mode: state.bool()?,
+            shift: state.byte()?,
+            divisor: state.byte()? as usize,
+            timer: state.u32()?,
+            length: state.word()?,
+            length_enabled: state.bool()?,
+            envelope: Envelope::read_state(state)?,
+        };
+        if noise.divisor >= NOISE_DIVISORS.len() || noise.shift > 15 || noise.timer == 0 {
+            return Err(StateError::InvalidValue("noise channel"));
+        }
+        Ok(noise)
+    }
+
+    /// Shift the register once every period, the xor of the two low bits comes in at the top
+    fn tick(&mut self, mcycles: u8) {
+        let mut cycles = mcycles as u32;
+        while cycles > 0 {
+            let step = cycles.min(self.timer);
+            self.timer -= step;
+            cycles -= step;
+            if self.timer == 0 {
+                self.timer = self.period();
+                let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+                self.lfsr = (self.lfsr >> 1) | (bit << 14);
+                if self.short_mode {
+                    self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+                }
+            }
+        }
+    }
+
+    /// Clocked at 256 Hz, the channel stops when the length runs out
+    fn clock_length(&mut self) {
+        if self.length_enabled && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Digital output, 0-15, a clear low bit plays the volume
+    fn output(&self) -> Byte {
+        if !self.enabled || !self.dac_enabled {
+            return 0;
+        }
+        (!self.lfsr & 1) as Byte * self.envelope.volume
+    }
+}
+
+/// Shrink samples to at most len by picking evenly spaced ones, e.g. to play
+/// audio produced faster than real time at the right pitch
+pub fn resample<T: Copy>(samples: &[T], len: usize) -> Vec<T> {
+    if len >= samples.len() {
+        return samples.to_vec();
+    }
+    (0..len).map(|i| samples[i * samples.len() / len]).collect()
+}
+
+/// Audio processing unit, follows the sound register writes and produces samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct Apu {
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    /// NR52 bit 7, while off the channels are silent and the registers read as 0
+    powered: bool,
+    master_volume: Byte,
+    panning: Byte,
+    /// Machine cycles times the sample rate since the last sample
+    sample_clock: u32,
+    /// Left and right samples
+    samples: Vec<(f32, f32)>,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    /// Powered off, as before the boot rom turns the sound on
+    pub fn new() -> Self {
+        Self {
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            powered: false,
+            master_volume: 0,
+            panning: 0,
+            sample_clock: 0,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Turn the sound on or off, turning it off resets every channel but keeps wave ram
+    fn set_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            let ram = self.wave.ram;
+            *self = Self {
+                sample_clock: self.sample_clock,
+                samples: std::mem::take(&mut self.samples),
+                ..Self::new()
+            };
+            self.wave.ram = ram;
+        }
+        self.powered = powered;
+    }
+
+    pub fn powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Channel and mixer state, buffered samples are not part of it
+    pub fn write_state(&self, state: &mut StateWriter) {
+        self.square1.write_state(state);
+        self.square2.write_state(state);
+        self.wave.write_state(state);
+        self.noise.write_state(state);
+        state.bool(self.powered);
+        state.byte(self.master_volume);
+        state.byte(self.panning);
+        state.u32(self.sample_clock);
+    }
+
+    pub fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        Ok(Self {
+            square1: Square::read_state(state, true)?,
+            square2: Square::read_state(state, false)?,
+            wave: Wave::read_state(state)?,
+            noise: Noise::read_state(state)?,
+            powered: state.bool()?,
+            master_volume: state.byte()?,
+            panning: state.byte()?,
+            sample_clock: state.u32()?,
+            samples: Vec::new(),
+        })
+    }
+
+    /// Follow a write to a sound register, only NR52 and wave ram are writable while off
+    pub fn write(&mut self, address: Address, byte: Byte) {
+        if !self.powered && address < NR52_ADDRESS {
+            return;
+        }
+        match address {
+            NR10_ADDRESS..=NR14_ADDRESS => self.square1.write(address - NR10_ADDRESS, byte),
+            NR20_ADDRESS..=NR24_ADDRESS => self.square2.write(address - NR20_ADDRESS, byte),
+            NR30_ADDRESS..=NR34_ADDRESS => self.wave.write(address - NR30_ADDRESS, byte),
+            NR40_ADDRESS..=NR44_ADDRESS => self.noise.write(address - NR40_ADDRESS, byte),
+            NR50_ADDRESS => self.master_volume = byte,
+            NR51_ADDRESS => self.panning = byte,
+            NR52_ADDRESS => self.set_power(byte & POWER_FLAG != 0),
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                self.wave.ram[(address - WAVE_RAM_START) as usize] = byte;
+            }
+            _ => (),
+        }
+    }
+
+    /// One of the 8 steps of the 512 Hz frame sequencer: lengths on even steps,
+    /// the sweep on steps 2 and 6 and envelopes on step 7
+    pub fn clock_frame_sequencer(&mut self, step: Byte) {
+        if !self.powered {
+            return;
+        }
+        if matches!(step, 0 | 2 | 4 | 6) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.square1.clock_sweep();
+        }
+        if step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+    }
+
+    /// Advance the channels, taking a sample every 1/SAMPLE_RATE seconds
+    pub fn tick(&mut self, mcycles: u8) {
+        self.square1.tick(mcycles);
+        self.square2.tick(mcycles);
+        self.wave.tick(mcycles);
+        self.noise.tick(mcycles);
+        self.sample_clock += mcycles as u32 * SAMPLE_RATE;
+        while self.sample_clock >= MCYCLE_RATE {
+            self.sample_clock -= MCYCLE_RATE;
+            if self.samples.len() < MAX_BUFFERED_SAMPLES {
+                self.samples.push(self.sample());
+            }
+        }
+    }
+
+    /// Current left and right mix of the channels after panning and master volume,
+    /// scaled so four channels at full volume reach 1.0
+    pub fn sample(&self) -> (f32, f32) {
+        let outputs = [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(),
+            self.noise.output(),
+        ];
+        let (mut left, mut right) = (0, 0);
+        for (channel, output) in outputs.into_iter().enumerate() {
+            if self.panning & (0x10 << channel) != 0 {
+                left += output as u32;
+            }
+            if self.panning & (0x01 << channel) != 0 {
+                right += output as u32;
+            }
+        }
+        // volumes 0-7 scale by 1/8 to 8/8
+        let left_volume = ((self.master_volume >> 4) & 0b111) as u32 + 1;
+        let right_volume = (self.master_volume & 0b111) as u32 + 1;
+        let scale = |output: u32, volume: u32| (output * volume) as f32 / (15.0 * 4.0 * 8.0);
+        (scale(left, left_volume), scale(right, right_volume))
+    }
+
+    /// Samples since the last call
+    pub fn take_samples(&mut self) -> Vec<(f32, f32)> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Whether channel 1, 2, 3 or 4 is playing
+    pub fn channel_enabled(&self, channel: usize) -> bool {
+        match channel {
+            1 => self.square1.enabled,
+            2 => self.square2.enabled,
+            3 => self.wave.enabled,
+            4 => self.noise.enabled,
+            _ => false,
+        }
+    }
+
+    /// NR52 as read: the power flag and a bit for each playing channel
+    pub fn status(&self) -> Byte {
+        let channels = (1..=4)
+            .filter(|&channel| self.channel_enabled(channel))
+            .fold(0, |status, channel| status | 1 << (channel - 1));
+        if self.powered {
+            POWER_FLAG | channels
+        } else {
+            channels
+        }
+    }
+}
@@ -1,14 +1,27 @@
 use crate::{
     cpu::{INTERRUPT_FLAG_ADDRESS, TIMER_FLAG},
-    memory::Memory,
+    memory::Bus,
+    state::{StateError, StateReader, StateWriter},
+    utils::{bytes2word, Address, Byte, Word},
     utils::{get_flag, set_flag},
-    utils::{Address, Byte},
 };
 
+/// TIMA reload after an overflow, which lags the overflow by one machine cycle
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum TimaReload {
+    #[default]
+    Idle,
+    /// TIMA overflowed and reads 0, a cpu write in this cycle cancels the reload
+    Pending,
+    /// TIMA was just loaded from TMA, cpu writes to TIMA in this cycle are overwritten by TMA
+    Reloading,
+}
+
 #[derive(Default)]
 pub struct Clock {
-    div_counter: Byte,
-    timer_counter: u32,
+    div_counter: Byte, // low byte of the internal divider in t-cycles, DIV is the high byte
+    timer_signal: bool, // TAC enable AND the selected divider bit, TIMA counts its falling edges
+    tima_reload: TimaReload,
     timestamp: u128,
 }
 
@@ -19,58 +32,129 @@ impl Clock {
     pub const TAC_ADDRESS: Address = 0xFF07;
     pub const TAC_ENABLE_FLAG: Byte = 0b100;
     pub const TAC_CLOCK_SELECT: Byte = 0b11;
+    /// DIV bit whose falling edge clocks the frame sequencer
+    pub const DIV_APU_BIT: Byte = 1 << 4;
 
     pub fn new() -> Self {
         Clock {
             div_counter: 0,
-            timer_counter: 0,
+            timer_signal: false,
+            tima_reload: TimaReload::Idle,
             timestamp: 0,
         }
     }
 
-    pub fn tick(&mut self, mcycles: u8, memory: &mut Memory) {
-        // handle divider register
-        let (new_div, overflow) = self.div_counter.overflowing_add(mcycles);
-        self.div_counter = new_div;
-        if overflow {
-            memory.wrapping_add(Self::DIV_ADDRESS, 1);
+    pub fn tick<B: Bus>(&mut self, mcycles: u8, memory: &mut B) {
+        // handle divider register, a write to DIV reset the whole counter
+        if memory.take_div_reset() {
+            self.div_counter = 0;
+        }
+        let mut counter = bytes2word(
+            self.div_counter,
+            memory.internal_read_byte(Self::DIV_ADDRESS),
+        );
+        let tac = memory.internal_read_byte(Self::TAC_ADDRESS);
+        let tima_written = memory.take_tima_write();
+        match self.tima_reload {
+            TimaReload::Pending if tima_written => self.tima_reload = TimaReload::Idle,
+            TimaReload::Reloading => {
+                // TIMA keeps following TMA, including a TMA write in this cycle
+                let tma = memory.internal_read_byte(Self::TMA_ADDRESS);
+                memory.set_tima(tma);
+            }
+            _ => (),
+        }
+        // a DIV reset or TAC write since the last tick can drop the timer signal
+        self.update_timer(counter, tac, memory);
+
+        let apu_bit = (Self::DIV_APU_BIT as Word) << 8;
+        for _ in 0..mcycles {
+            self.tima_reload = match self.tima_reload {
+                TimaReload::Pending => {
+                    self.reload_tima(memory);
+                    TimaReload::Reloading
+                }
+                _ => TimaReload::Idle,
+            };
+            let new_counter = counter.wrapping_add(4);
+            if counter & !new_counter & apu_bit != 0 {
+                memory.clock_frame_sequencer();
+            }
+            counter = new_counter;
+            self.update_timer(counter, tac, memory);
         }
+        self.div_counter = counter as Byte;
+        memory.set_div((counter >> 8) as Byte);
 
         // total counter
         self.timestamp += mcycles as u128;
+        memory.tick_peripherals(mcycles);
+    }
 
-        // handle tima
-        let tac = memory.read_byte(Self::TAC_ADDRESS);
-        if get_flag(tac, Self::TAC_ENABLE_FLAG) {
-            self.timer_counter += 4 * (mcycles as u32);
-
-            let frequency = match tac & Self::TAC_CLOCK_SELECT {
-                0 => 4096,
-                1 => 262144,
-                2 => 65536,
-                3 => 16384,
-                _ => panic!("Logically cannot happen"),
-            };
+    /// Feed the timer falling edge detector, incrementing TIMA on a falling edge
+    fn update_timer<B: Bus>(&mut self, counter: Word, tac: Byte, memory: &mut B) {
+        let bit = match tac & Self::TAC_CLOCK_SELECT {
+            0 => 9, // 4096Hz
+            1 => 3, // 262144Hz
+            2 => 5, // 65536Hz
+            _ => 7, // 16384Hz
+        };
+        let signal = get_flag(tac, Self::TAC_ENABLE_FLAG) && counter & (1 << bit) != 0;
+        if self.timer_signal && !signal {
+            self.increment_tima(memory);
+        }
+        self.timer_signal = signal;
+    }
 
-            while self.timer_counter >= 4194304 / frequency {
-                memory.wrapping_add(Self::TIMA_ADDRESS, 1);
+    fn increment_tima<B: Bus>(&mut self, memory: &mut B) {
+        let tima = memory
+            .internal_read_byte(Self::TIMA_ADDRESS)
+            .wrapping_add(1);
+        memory.set_tima(tima);
 
-                if memory.read_byte(Self::TIMA_ADDRESS) == 0 {
-                    // set timer interrupt and reset timer
-                    let mut interrupt_flags = memory.read_byte(INTERRUPT_FLAG_ADDRESS);
-                    set_flag(&mut interrupt_flags, TIMER_FLAG);
-                    memory.write_byte(INTERRUPT_FLAG_ADDRESS, interrupt_flags);
+        if tima == 0 {
+            // TIMA reads 0 for a machine cycle before the reload
+            self.tima_reload = TimaReload::Pending;
+        }
+    }
 
-                    let tma = memory.read_byte(Self::TMA_ADDRESS);
-                    memory.write_byte(Self::TIMA_ADDRESS, tma);
-                }
+    /// Reload TIMA from TMA and request the timer interrupt
+    fn reload_tima<B: Bus>(&mut self, memory: &mut B) {
+        let mut interrupt_flags = memory.internal_read_byte(INTERRUPT_FLAG_ADDRESS);
+        set_flag(&mut interrupt_flags, TIMER_FLAG);
+        memory.internal_write_byte(INTERRUPT_FLAG_ADDRESS, interrupt_flags);
 
-                self.timer_counter -= 4194304 / frequency;
-            }
-        }
+        let tma = memory.internal_read_byte(Self::TMA_ADDRESS);
+        memory.set_tima(tma);
     }
 
     pub fn get_timestamp(&self) -> u128 {
         self.timestamp
     }
+
+    pub fn write_state(&self, state: &mut StateWriter) {
+        state.byte(self.div_counter);
+        state.bool(self.timer_signal);
+        state.byte(match self.tima_reload {
+            TimaReload::Idle => 0,
+            TimaReload::Pending => 1,
+            TimaReload::Reloading => 2,
+        });
+        state.u128(self.timestamp);
+    }
+
+    /// Counters from write_state
+    pub fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        Ok(Self {
+            div_counter: state.byte()?,
+            timer_signal: state.bool()?,
+            tima_reload: match state.byte()? {
+                0 => TimaReload::Idle,
+                1 => TimaReload::Pending,
+                2 => TimaReload::Reloading,
+                _ => return Err(StateError::InvalidValue("tima reload")),
+            },
+            timestamp: state.u128()?,
+        })
+    }
 }
@@ -1,15 +1,16 @@
-use log::{debug, info};
+use std::{
+    fmt::{self, Write},
+    ops::Range,
+};
+
+use log::{debug, info, warn};
 
 use crate::{
     clock::Clock,
-    memory::Memory,
-<<<<<<< HEAD
-    utils::{
-        bytes2word, get_flag, reset_flag, Address, Byte, ByteOP, SignedByte, Word, WordOP,
-    },
-=======
+    gb::SymbolTable,
+    memory::{Bus, Memory},
+    state::{StateError, StateReader, StateWriter},
     utils::{bytes2word, get_flag, reset_flag, Address, Byte, ByteOP, SignedByte, Word, WordOP},
->>>>>>> 8e2c31a8bb2a67db705168fbae5e1918ce6c8bf2
 };
 
 // ----- flags -----
@@ -26,6 +27,8 @@ pub const LCD_FLAG: Byte = 0b10;
 pub const TIMER_FLAG: Byte = 0b100;
 pub const SERIAL_FLAG: Byte = 0b1000;
 pub const JOYPAD_FLAG: Byte = 0b10000;
+/// Only the low 5 bits of IE/IF are interrupts
+pub const INTERRUPT_MASK: Byte = 0b11111;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Register {
@@ -86,6 +89,22 @@ impl Register16 {
     }
 }
 
+impl fmt::Display for Register {
+    /// HL stands for the byte it points to
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HL => write!(f, "(HL)"),
+            r => write!(f, "{:?}", r),
+        }
+    }
+}
+
+impl fmt::Display for Register16 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 pub enum Condition {
     NonZero,
@@ -106,6 +125,18 @@ impl Condition {
     }
 }
 
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cc = match self {
+            Self::NonZero => "NZ",
+            Self::Zero => "Z",
+            Self::NotCarry => "NC",
+            Self::Carry => "C",
+        };
+        write!(f, "{}", cc)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash)]
 #[allow(non_camel_case_types)]
 pub enum Instruction {
@@ -317,6 +348,151 @@ pub enum Instruction {
     NOP,
     HALT,
     STOP,
+    /// Undefined opcode, locks up the cpu
+    Illegal(Byte),
+}
+
+/// Signed immediate as +$XX / -$XX
+struct Signed(SignedByte);
+
+impl fmt::Display for Signed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { '-' } else { '+' };
+        write!(f, "{}${:02X}", sign, self.0.unsigned_abs())
+    }
+}
+
+/// Relative jump operand, offset from the start of the (2 byte) jump instruction
+struct Relative(SignedByte);
+
+impl fmt::Display for Relative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "${:+}", self.0 as i16 + 2)
+    }
+}
+
+impl Instruction {
+    /// Absolute target of a relative jump located at address
+    pub fn relative_target(&self, address: Address) -> Option<Address> {
+        match self {
+            Self::JR(e) | Self::JR_CC(_, e) => {
+                Some(address.wrapping_add(2).wrapping_add(*e as Address))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Canonical assembly syntax, e.g. `LD B, C`, `JR NZ, $+5`, `RST $18`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LD_R_R(r1, r2) => write!(f, "LD {}, {}", r1, r2),
+            Self::LD_R_N(r, n) => write!(f, "LD {}, ${:02X}", r, n),
+            Self::LD_R_HL(r) => write!(f, "LD {}, (HL)", r),
+            Self::LD_HL_R(r) => write!(f, "LD (HL), {}", r),
+            Self::LD_HL_N(n) => write!(f, "LD (HL), ${:02X}", n),
+            Self::LD_A_BC => write!(f, "LD A, (BC)"),
+            Self::LD_A_DE => write!(f, "LD A, (DE)"),
+            Self::LD_BC_A => write!(f, "LD (BC), A"),
+            Self::LD_DE_A => write!(f, "LD (DE), A"),
+            Self::LD_A_NN(nn) => write!(f, "LD A, (${:04X})", nn),
+            Self::LD_NN_A(nn) => write!(f, "LD (${:04X}), A", nn),
+            Self::LDH_A_C => write!(f, "LDH A, (C)"),
+            Self::LDH_C_A => write!(f, "LDH (C), A"),
+            Self::LDH_A_N(n) => write!(f, "LDH A, ($FF{:02X})", n),
+            Self::LDH_N_A(n) => write!(f, "LDH ($FF{:02X}), A", n),
+            Self::LD_A_HL_D => write!(f, "LD A, (HL-)"),
+            Self::LD_A_HL_I => write!(f, "LD A, (HL+)"),
+            Self::LD_HL_A_D => write!(f, "LD (HL-), A"),
+            Self::LD_HL_A_I => write!(f, "LD (HL+), A"),
+            Self::LD_RR_NN(rr, nn) => write!(f, "LD {}, ${:04X}", rr, nn),
+            Self::LD_NN_SP(nn) => write!(f, "LD (${:04X}), SP", nn),
+            Self::LD_SP_HL => write!(f, "LD SP, HL"),
+            Self::LD_HL_SP(e) => write!(f, "LD HL, SP{}", Signed(*e)),
+            Self::PUSH(rr) => write!(f, "PUSH {}", rr),
+            Self::POP(rr) => write!(f, "POP {}", rr),
+            Self::ADD_R(r) => write!(f, "ADD A, {}", r),
+            Self::ADD_HL => write!(f, "ADD A, (HL)"),
+            Self::ADD_N(n) => write!(f, "ADD A, ${:02X}", n),
+            Self::SUB_R(r) => write!(f, "SUB {}", r),
+            Self::SUB_HL => write!(f, "SUB (HL)"),
+            Self::SUB_N(n) => write!(f, "SUB ${:02X}", n),
+            Self::AND_R(r) => write!(f, "AND {}", r),
+            Self::AND_HL => write!(f, "AND (HL)"),
+            Self::AND_N(n) => write!(f, "AND ${:02X}", n),
+            Self::OR_R(r) => write!(f, "OR {}", r),
+            Self::OR_HL => write!(f, "OR (HL)"),
+            Self::OR_N(n) => write!(f, "OR ${:02X}", n),
+            Self::ADC_R(r) => write!(f, "ADC A, {}", r),
+            Self::ADC_HL => write!(f, "ADC A, (HL)"),
+            Self::ADC_N(n) => write!(f, "ADC A, ${:02X}", n),
+            Self::SBC_R(r) => write!(f, "SBC A, {}", r),
+            Self::SBC_HL => write!(f, "SBC A, (HL)"),
+            Self::SBC_N(n) => write!(f, "SBC A, ${:02X}", n),
+            Self::XOR_R(r) => write!(f, "XOR {}", r),
+            Self::XOR_HL => write!(f, "XOR (HL)"),
+            Self::XOR_N(n) => write!(f, "XOR ${:02X}", n),
+            Self::CP_R(r) => write!(f, "CP {}", r),
+            Self::CP_HL => write!(f, "CP (HL)"),
+            Self::CP_N(n) => write!(f, "CP ${:02X}", n),
+            Self::INC_R(r) => write!(f, "INC {}", r),
+            Self::INC_RR(rr) => write!(f, "INC {}", rr),
+            Self::INC_HL => write!(f, "INC (HL)"),
+            Self::DEC_R(r) => write!(f, "DEC {}", r),
+            Self::DEC_RR(rr) => write!(f, "DEC {}", rr),
+            Self::DEC_HL => write!(f, "DEC (HL)"),
+            Self::ADD_HL_RR(rr) => write!(f, "ADD HL, {}", rr),
+            Self::ADD_SP_E(e) => write!(f, "ADD SP, {}", Signed(*e)),
+            Self::RLCA => write!(f, "RLCA"),
+            Self::RRCA => write!(f, "RRCA"),
+            Self::RLA => write!(f, "RLA"),
+            Self::RRA => write!(f, "RRA"),
+            Self::RLC(r) => write!(f, "RLC {}", r),
+            Self::RLC_HL => write!(f, "RLC (HL)"),
+            Self::RRC(r) => write!(f, "RRC {}", r),
+            Self::RRC_HL => write!(f, "RRC (HL)"),
+            Self::RL(r) => write!(f, "RL {}", r),
+            Self::RL_HL => write!(f, "RL (HL)"),
+            Self::RR(r) => write!(f, "RR {}", r),
+            Self::RR_HL => write!(f, "RR (HL)"),
+            Self::SLA(r) => write!(f, "SLA {}", r),
+            Self::SLA_HL => write!(f, "SLA (HL)"),
+            Self::SRA(r) => write!(f, "SRA {}", r),
+            Self::SRA_HL => write!(f, "SRA (HL)"),
+            Self::SWAP(r) => write!(f, "SWAP {}", r),
+            Self::SWAP_HL => write!(f, "SWAP (HL)"),
+            Self::SRL(r) => write!(f, "SRL {}", r),
+            Self::SRL_HL => write!(f, "SRL (HL)"),
+            Self::BIT(b, r) => write!(f, "BIT {}, {}", b, r),
+            Self::BIT_HL(b) => write!(f, "BIT {}, (HL)", b),
+            Self::RES(b, r) => write!(f, "RES {}, {}", b, r),
+            Self::RES_HL(b) => write!(f, "RES {}, (HL)", b),
+            Self::SET(b, r) => write!(f, "SET {}, {}", b, r),
+            Self::SET_HL(b) => write!(f, "SET {}, (HL)", b),
+            Self::JP_NN(nn) => write!(f, "JP ${:04X}", nn),
+            Self::JP_HL => write!(f, "JP HL"),
+            Self::JP_CC_NN(cc, nn) => write!(f, "JP {}, ${:04X}", cc, nn),
+            Self::JR(e) => write!(f, "JR {}", Relative(*e)),
+            Self::JR_CC(cc, e) => write!(f, "JR {}, {}", cc, Relative(*e)),
+            Self::CALL(nn) => write!(f, "CALL ${:04X}", nn),
+            Self::CALL_CC(cc, nn) => write!(f, "CALL {}, ${:04X}", cc, nn),
+            Self::RET => write!(f, "RET"),
+            Self::RET_CC(cc) => write!(f, "RET {}", cc),
+            Self::RETI => write!(f, "RETI"),
+            Self::RST(n) => write!(f, "RST ${:02X}", n),
+            Self::CCF => write!(f, "CCF"),
+            Self::SCF => write!(f, "SCF"),
+            Self::DAA => write!(f, "DAA"),
+            Self::CPL => write!(f, "CPL"),
+            Self::EI => write!(f, "EI"),
+            Self::DI => write!(f, "DI"),
+            Self::NOP => write!(f, "NOP"),
+            Self::HALT => write!(f, "HALT"),
+            Self::STOP => write!(f, "STOP"),
+            Self::Illegal(opcode) => write!(f, "db ${:02X}", opcode),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -330,8 +506,12 @@ pub struct SizedInstruction {
 pub struct OpCode(Byte, Byte);
 
 impl OpCode {
+    pub const fn new(pattern: Byte, mask: Byte) -> Self {
+        Self(pattern, mask)
+    }
+
     /// Check if the give opcode `code` matches self, considering mask
-    fn matches(&self, code: Byte) -> bool {
+    pub fn matches(&self, code: Byte) -> bool {
         code.mask(self.1) == self.0
     }
 }
@@ -409,13 +589,27 @@ impl SizedInstruction {
     const CB1: OpCode = OpCode(0b0000_0000, 0b1100_0000);
     /// Interrupt Opcodes
     const IR: OpCode = OpCode(0b1111_0011, 0b1111_0111);
+    /// Stop
+    const STOP: OpCode = OpCode(0x10, 0b1111_1111);
+    /// Opcodes with no instruction, executing one locks up the cpu
+    const ILLEGAL_OPCODES: [Byte; 11] = [
+        0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+    ];
 
     /// Decode the opcode at address into a SizedInstruction
-    pub fn decode(memory: &Memory, address: Address) -> Option<Self> {
-        let opcode = memory.read_byte(address);
+    pub fn decode<B: Bus>(memory: &B, address: Address) -> Option<Self> {
+        Self::decode_opcode(memory, address, memory.read_byte(address))
+    }
+
+    /// Decode opcode as if it was fetched from address, its operands follow address
+    fn decode_opcode<B: Bus>(memory: &B, address: Address, opcode: Byte) -> Option<Self> {
         debug!("Address: {:#04X?}, Opcode: {:#04X?}", address, opcode);
-        let (instruction, size) = if Self::NOP.matches(opcode) {
+        let (instruction, size) = if Self::ILLEGAL_OPCODES.contains(&opcode) {
+            (Instruction::Illegal(opcode), 1)
+        } else if Self::NOP.matches(opcode) {
             (Instruction::NOP, 1)
+        } else if Self::STOP.matches(opcode) {
+            (Instruction::STOP, 2)
         } else if Self::LD1.matches(opcode) {
             let (lr, rr) = Register::get_rr(opcode);
             let instruction = match (lr, rr) {
@@ -427,14 +621,14 @@ impl SizedInstruction {
             (instruction, 1)
         } else if Self::LD2.matches(opcode) {
             let r = Register::get_r(opcode >> 3);
-            let n = memory.read_byte(address + 1);
+            let n = memory.read_byte(address.wrapping_add(1));
             let instruction = match r {
                 Register::HL => Instruction::LD_HL_N(n),
                 reg => Instruction::LD_R_N(reg, n),
             };
             (instruction, 2)
         } else if Self::LD3.matches(opcode) {
-            let nn = memory.read_word(address + 1);
+            let nn = memory.read_word(address.wrapping_add(1));
             let instruction = if opcode & 1 << 4 != 0 {
                 Instruction::LD_A_NN(nn)
             } else {
@@ -449,7 +643,7 @@ impl SizedInstruction {
             };
             (instruction, 1)
         } else if Self::LD5.matches(opcode) {
-            let n = memory.read_byte(address + 1);
+            let n = memory.read_byte(address.wrapping_add(1));
             let instruction = if opcode & 1 << 4 != 0 {
                 Instruction::LDH_A_N(n)
             } else {
@@ -479,18 +673,18 @@ impl SizedInstruction {
             (instruction, 1)
         } else if Self::LD7.matches(opcode) {
             let rr = Register16::get_rr(opcode >> 4, true);
-            let nn = memory.read_word(address + 1);
+            let nn = memory.read_word(address.wrapping_add(1));
             let instruction = Instruction::LD_RR_NN(rr, nn);
             (instruction, 3)
         } else if Self::LD8.matches(opcode) {
-            let nn = memory.read_word(address + 1);
+            let nn = memory.read_word(address.wrapping_add(1));
             let instruction = Instruction::LD_NN_SP(nn);
             (instruction, 3)
         } else if Self::LD9.matches(opcode) {
             if opcode & 1 == 1 {
                 (Instruction::LD_SP_HL, 1)
             } else {
-                let e = memory.read_byte(address + 1) as SignedByte;
+                let e = memory.read_byte(address.wrapping_add(1)) as SignedByte;
                 (Instruction::LD_HL_SP(e), 2)
             }
         } else if Self::PUSH_POP.matches(opcode) {
@@ -529,7 +723,7 @@ impl SizedInstruction {
             };
             (instruction, 1)
         } else if Self::ARITH_OP_N.matches(opcode) {
-            let n = memory.read_byte(address + 1);
+            let n = memory.read_byte(address.wrapping_add(1));
             let instruction = match opcode.get_high_nibble() {
                 0xc => Instruction::ADD_N(n),
                 0xd => Instruction::SUB_N(n),
@@ -539,7 +733,7 @@ impl SizedInstruction {
             };
             (instruction, 2)
         } else if Self::ARITH_OP_C_N.matches(opcode) {
-            let n = memory.read_byte(address + 1);
+            let n = memory.read_byte(address.wrapping_add(1));
             let instruction = match opcode.get_high_nibble() {
                 0xc => Instruction::ADC_N(n),
                 0xd => Instruction::SBC_N(n),
@@ -581,7 +775,7 @@ impl SizedInstruction {
 
             (instruction, 1)
         } else if Self::CALL.matches(opcode) {
-            let nn = memory.read_word(address + 1);
+            let nn = memory.read_word(address.wrapping_add(1));
             let instruction = if opcode & 1 != 0 {
                 // ret
                 Instruction::CALL(nn)
@@ -601,20 +795,20 @@ impl SizedInstruction {
             let n = (opcode >> 3) & 0b111;
             (Instruction::RST(n * 8), 1)
         } else if Self::JP.matches(opcode) {
-            let nn = memory.read_word(address + 1);
+            let nn = memory.read_word(address.wrapping_add(1));
             (Instruction::JP_NN(nn), 3)
         } else if Self::JP_HL.matches(opcode) {
             (Instruction::JP_HL, 1)
         } else if Self::JP_CC.matches(opcode) {
             let cc = Condition::get_cond(opcode >> 3);
-            let nn = memory.read_word(address + 1);
+            let nn = memory.read_word(address.wrapping_add(1));
             (Instruction::JP_CC_NN(cc, nn), 3)
         } else if Self::JR.matches(opcode) {
-            let n = memory.read_byte(address + 1);
+            let n = memory.read_byte(address.wrapping_add(1));
             (Instruction::JR(n as SignedByte), 2)
         } else if Self::JR_CC.matches(opcode) {
             let cc = Condition::get_cond(opcode >> 3);
-            let n = memory.read_byte(address + 1);
+            let n = memory.read_byte(address.wrapping_add(1));
             (Instruction::JR_CC(cc, n as SignedByte), 2)
         } else if Self::DAA.matches(opcode) {
             (Instruction::DAA, 1)
@@ -622,7 +816,7 @@ impl SizedInstruction {
             let rr = Register16::get_rr(opcode >> 4, true);
             (Instruction::ADD_HL_RR(rr), 1)
         } else if Self::ADD_SP_E.matches(opcode) {
-            let e = memory.read_byte(address + 1) as SignedByte;
+            let e = memory.read_byte(address.wrapping_add(1)) as SignedByte;
             (Instruction::ADD_SP_E(e), 2)
         } else if Self::COMP_OP.matches(opcode) {
             let instruction = if opcode & (1 << 4) > 0 {
@@ -644,7 +838,7 @@ impl SizedInstruction {
             };
             (instruction, 1)
         } else if Self::CB.matches(opcode) {
-            let sized_instruction = Self::decode_cb(memory, address + 1);
+            let sized_instruction = Self::decode_cb(memory, address.wrapping_add(1));
             return match sized_instruction {
                 Some(mut instruction) => {
                     instruction.size += 1;
@@ -666,7 +860,7 @@ impl SizedInstruction {
     }
 
     /// Decode CB-Prefixed instructions
-    fn decode_cb(memory: &Memory, address: Address) -> Option<Self> {
+    fn decode_cb<B: Bus>(memory: &B, address: Address) -> Option<Self> {
         let opcode = memory.read_byte(address);
         debug!("CB-Prefixed OpCode: {:#04X?}", opcode);
         let r = Register::get_r(opcode);
@@ -772,6 +966,84 @@ impl SizedInstruction {
             size: 1,
         })
     }
+
+    /// Format the instruction located at address, relative jumps are followed by their target
+    pub fn disassemble(&self, address: Address) -> String {
+        match self.instruction.relative_target(address) {
+            Some(target) => format!("{} ; ${:04X}", self.instruction, target),
+            None => self.instruction.to_string(),
+        }
+    }
+
+    /// Decode the range linearly, bytes that do not decode or would run past the end
+    /// of the range become single byte lines
+    pub fn disassemble_range<B: Bus>(memory: &B, range: Range<Address>) -> Vec<DisassemblyLine> {
+        let mut lines = Vec::new();
+        let mut address = range.start;
+        while address < range.end {
+            let decoded = Self::decode(memory, address)
+                .filter(|ins| address as usize + ins.size as usize <= range.end as usize);
+            let (size, instruction) = match decoded {
+                Some(SizedInstruction {
+                    instruction: Instruction::Illegal(_),
+                    ..
+                })
+                | None => (1, None),
+                Some(ins) => (ins.size, Some(ins.instruction)),
+            };
+            let bytes = (0..size).map(|i| memory.read_byte(address + i)).collect();
+            lines.push(DisassemblyLine {
+                address,
+                bytes,
+                instruction,
+            });
+            address += size;
+        }
+        lines
+    }
+
+    /// Listing of every rom bank, banks are decoded separately so no instruction
+    /// crosses a bank boundary. Labels go on their own line before their address
+    pub fn disassemble_rom(rom: &[Byte], symbols: &SymbolTable) -> String {
+        let mut listing = String::new();
+        for (bank, data) in rom.chunks(0x4000).enumerate() {
+            let start: Address = if bank == 0 { 0x0000 } else { 0x4000 };
+            let mut memory = Memory::new();
+            memory.load_raw(data, start);
+
+            writeln!(listing, "; bank {:02X}", bank).unwrap();
+            for line in Self::disassemble_range(&memory, start..start + data.len() as Address) {
+                if let Some(label) = symbols.label(bank, line.address) {
+                    writeln!(listing, "{}:", label).unwrap();
+                }
+                writeln!(listing, "{:02X}:{}", bank, line).unwrap();
+            }
+        }
+        listing
+    }
+}
+
+/// A line of a disassembly listing, instruction is None for undecodable bytes
+#[derive(Debug, PartialEq, Eq)]
+pub struct DisassemblyLine {
+    pub address: Address,
+    pub bytes: Vec<Byte>,
+    pub instruction: Option<Instruction>,
+}
+
+impl fmt::Display for DisassemblyLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        match &self.instruction {
+            Some(instruction) => write!(f, "{:04X}  {:<8}  {}", self.address, bytes, instruction),
+            None => write!(f, "{:04X}  {:<8}  db ${}", self.address, bytes, bytes),
+        }
+    }
 }
 
 pub struct CPU {
@@ -787,6 +1059,14 @@ pub struct CPU {
     pub pc: Word,                   // program counter
     pub ime: (Option<usize>, bool), // Interrupt Master Enable Flag, left is countdown (if exists), right is the flag
     pub halt: bool,                 // Halt flag
+    pub halt_bug: bool,             // Halt bug flag, the next byte is read twice
+    pub locked: bool,               // Locked up by an illegal opcode, only a reset recovers
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CPU {
@@ -804,6 +1084,8 @@ impl CPU {
             pc: 0x00, // currently start at 0x00,
             ime: (None, false),
             halt: false,
+            halt_bug: false,
+            locked: false,
         }
     }
 
@@ -822,20 +1104,86 @@ impl CPU {
             pc: 0x100, // currently start at 0x100,
             ime: (None, false),
             halt: false,
+            halt_bug: false,
+            locked: false,
         }
     }
 
-    /// Execute the instruction, and return the clock cycles used
-    pub fn execute(&mut self, memory: &mut Memory, clock: &mut Clock) {
-        let instruction = match SizedInstruction::decode(memory, self.pc) {
+    pub fn write_state(&self, state: &mut StateWriter) {
+        for register in [
+            self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l,
+        ] {
+            state.byte(register);
+        }
+        state.word(self.sp);
+        state.word(self.pc);
+        state.bool(self.ime.0.is_some());
+        if let Some(countdown) = self.ime.0 {
+            state.usize(countdown);
+        }
+        state.bool(self.ime.1);
+        state.bool(self.halt);
+        state.bool(self.halt_bug);
+        state.bool(self.locked);
+    }
+
+    /// Registers and flags from write_state
+    pub fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let mut registers = [0; 8];
+        for register in &mut registers {
+            *register = state.byte()?;
+        }
+        let [a, f, b, c, d, e, h, l] = registers;
+        let sp = state.word()?;
+        let pc = state.word()?;
+        let countdown = match state.bool()? {
+            true => Some(state.usize()?),
+            false => None,
+        };
+        Ok(Self {
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            sp,
+            pc,
+            ime: (countdown, state.bool()?),
+            halt: state.bool()?,
+            halt_bug: state.bool()?,
+            locked: state.bool()?,
+        })
+    }
+
+    /// Execute a single instruction and return the machine cycles it took
+    pub fn step<B: Bus>(&mut self, memory: &mut B, clock: &mut Clock) -> u8 {
+        let start = clock.get_timestamp();
+        self.execute(memory, clock);
+        (clock.get_timestamp() - start) as u8
+    }
+
+    /// Execute the instruction, ticking the clock for the cycles used
+    pub fn execute<B: Bus>(&mut self, memory: &mut B, clock: &mut Clock) {
+        let opcode = memory.read_byte(self.pc);
+        if self.halt_bug {
+            // pc fails to increment after the fetch, so the opcode byte is read again
+            // as the first operand, or as the next opcode for 1-byte instructions
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
+        let instruction = match SizedInstruction::decode_opcode(memory, self.pc, opcode) {
             Some(ins) => ins,
-            None => panic!("Could not decode {:#04X?}", memory.read_byte(self.pc)),
+            None => panic!("Could not decode {:#04X?}", opcode),
         };
 
         debug!(
             "Decoded Instruction: {:?} {:#04X?}",
             instruction, instruction
         );
+
         match instruction.instruction {
             Instruction::NOP => {
                 self.pc += instruction.size;
@@ -1294,8 +1642,8 @@ impl CPU {
             Instruction::LD_NN_SP(nn) => {
                 self.pc += 3;
                 memory.write_byte(nn, self.sp.get_low());
-                let nn = nn + 1;
-                memory.write_byte(nn, self.sp.get_high());
+                // the address bus is 16 bits, 0xFFFF wraps to 0x0000
+                memory.write_byte(nn.wrapping_add(1), self.sp.get_high());
                 clock.tick(5, memory);
             }
             Instruction::LD_SP_HL => {
@@ -1459,22 +1807,20 @@ impl CPU {
                 let carry = self.get_flag(CARRY_FLAG);
                 let sub_flag = self.get_flag(SUBTRACT_FLAG);
 
-                if !sub_flag {
-                    if carry || self.a > 0x99 {
-                        self.a = self.a.wrapping_add(0x60);
-                        self.set_flag(CARRY_FLAG);
-                    }
-                    if half_carry || (self.a & 0xF) > 0x9 {
-                        self.a = self.a.wrapping_add(0x6);
-                    }
-                } else {
-                    if carry {
-                        self.a = self.a.wrapping_sub(0x60);
-                    }
-                    if half_carry {
-                        self.a = self.a.wrapping_sub(0x6);
-                    }
+                // both adjustments are decided from the original a and flags
+                let mut adjust = 0;
+                if half_carry || (!sub_flag && (self.a & 0xF) > 0x9) {
+                    adjust |= 0x06;
+                }
+                if carry || (!sub_flag && self.a > 0x99) {
+                    adjust |= 0x60;
+                    self.set_flag(CARRY_FLAG);
                 }
+                self.a = if sub_flag {
+                    self.a.wrapping_sub(adjust)
+                } else {
+                    self.a.wrapping_add(adjust)
+                };
                 self.reset_flag(HALF_CARRY_FLAG);
                 self.zero_flag(self.a);
                 self.pc += instruction.size;
@@ -1865,29 +2211,40 @@ impl CPU {
                 clock.tick(1, memory);
             }
             Instruction::HALT => {
-                // halt bug
-                // unimplemented!();
-                self.halt = true;
-                self.pc += 1;
+                let interrupt_enable = memory.internal_read_byte(INTERRUPT_ENABLE_ADDRESS);
+                let interrupt_flag = memory.internal_read_byte(INTERRUPT_FLAG_ADDRESS);
+                if !self.get_ime() && interrupt_enable & interrupt_flag & INTERRUPT_MASK != 0 {
+                    // halt bug, the cpu does not halt and the next pc increment is skipped
+                    self.halt_bug = true;
+                } else {
+                    self.halt = true;
+                }
+                self.pc += instruction.size;
                 clock.tick(1, memory);
             }
-            _ => {
-                panic!(
-                    "Could not execute {:#04X?} with opcode {:#04X?} at address {:#04X?}",
-                    instruction,
-                    memory.read_byte(self.pc),
-                    self.pc
+            Instruction::STOP => {
+                // low power mode is not emulated, only the divider reset
+                memory.write_byte(Clock::DIV_ADDRESS, 0);
+                self.pc += instruction.size;
+                clock.tick(1, memory);
+            }
+            Instruction::Illegal(opcode) => {
+                warn!(
+                    "Illegal opcode {:#04X?} at address {:#04X?}, cpu locked",
+                    opcode, self.pc
                 );
+                self.locked = true;
+                clock.tick(1, memory);
             }
         };
 
-        self.display_registers(true);
+        self.display_registers(true, memory, None);
     }
 
-    pub fn handle_interrupts(&mut self, memory: &mut Memory) {
-        let interrupt_enable = memory.read_byte(INTERRUPT_ENABLE_ADDRESS);
-        let interrupt_flag = memory.read_byte(INTERRUPT_FLAG_ADDRESS);
-        let mut flag_bytes = interrupt_enable & interrupt_flag;
+    pub fn handle_interrupts<B: Bus>(&mut self, memory: &mut B) {
+        let interrupt_enable = memory.internal_read_byte(INTERRUPT_ENABLE_ADDRESS);
+        let interrupt_flag = memory.internal_read_byte(INTERRUPT_FLAG_ADDRESS);
+        let mut flag_bytes = interrupt_enable & interrupt_flag & INTERRUPT_MASK;
 
         // handle halt
         if flag_bytes != 0 || self.get_ime() {
@@ -1922,7 +2279,7 @@ impl CPU {
                 self.pc = 0x60;
             }
         }
-        memory.write_byte(INTERRUPT_FLAG_ADDRESS, flag_bytes);
+        memory.internal_write_byte(INTERRUPT_FLAG_ADDRESS, flag_bytes);
     }
 
     pub fn get_hl(&self) -> Word {
@@ -1996,7 +2353,7 @@ impl CPU {
         }
     }
 
-    fn get_register(&self, reg: Register) -> Byte {
+    pub fn get_register(&self, reg: Register) -> Byte {
         match reg {
             Register::A => self.a,
             Register::B => self.b,
@@ -2009,7 +2366,7 @@ impl CPU {
         }
     }
 
-    fn set_register(&mut self, reg: Register, byte: Byte) {
+    pub fn set_register(&mut self, reg: Register, byte: Byte) {
         match reg {
             Register::A => self.a = byte,
             Register::B => self.b = byte,
@@ -2022,7 +2379,7 @@ impl CPU {
         }
     }
 
-    fn get_register16(&self, reg: Register16) -> Word {
+    pub fn get_register16(&self, reg: Register16) -> Word {
         match reg {
             Register16::SP => self.sp,
             Register16::BC => bytes2word(self.c, self.b),
@@ -2032,7 +2389,7 @@ impl CPU {
         }
     }
 
-    fn set_register16(&mut self, reg: Register16, word: Word) {
+    pub fn set_register16(&mut self, reg: Register16, word: Word) {
         match reg {
             Register16::SP => self.sp = word,
             Register16::BC => {
@@ -2065,7 +2422,7 @@ impl CPU {
     }
 
     /// Push pc register values to [sp-1],[sp-2]
-    fn push_pc_stack(&mut self, memory: &mut Memory) {
+    fn push_pc_stack<B: Bus>(&mut self, memory: &mut B) {
         self.sp -= 1;
         memory.write_byte(self.sp, self.pc.get_high());
         self.sp -= 1;
@@ -2073,7 +2430,7 @@ impl CPU {
     }
 
     /// Pop pc register values from [sp+1],[sp+2]
-    fn pop_pc_stack(&mut self, memory: &mut Memory) {
+    fn pop_pc_stack<B: Bus>(&mut self, memory: &mut B) {
         let lsb = memory.read_byte(self.sp);
         self.sp += 1;
         let msb = memory.read_byte(self.sp);
@@ -2088,8 +2445,9 @@ impl CPU {
 
     /// Enable the ime flag
     fn ime_enable(&mut self) {
+        // ime_step runs right after EI, so interrupts are checked after the next instruction
         if self.ime.0.is_none() {
-            self.ime.0 = Some(2);
+            self.ime.0 = Some(1);
         }
     }
 
@@ -2115,7 +2473,20 @@ impl CPU {
         }
     }
 
-    pub fn display_registers(&self, to_debug: bool) {
+    /// The program counter, followed by its label if one is known
+    pub fn display_pc<B: Bus>(&self, memory: &B, symbols: Option<&SymbolTable>) -> String {
+        match symbols.and_then(|symbols| symbols.label_at(memory, self.pc)) {
+            Some(label) => format!("{:#06X} <{}>", self.pc, label),
+            None => format!("{:#06X}", self.pc),
+        }
+    }
+
+    pub fn display_registers<B: Bus>(
+        &self,
+        to_debug: bool,
+        memory: &B,
+        symbols: Option<&SymbolTable>,
+    ) {
         if to_debug {
             debug!("Registers:");
             debug!(
@@ -2126,7 +2497,11 @@ impl CPU {
                 "\tD: {:#04X?}\tE: {:#04X?}\tH: {:#04X?}\tL: {:#04X?}",
                 self.d, self.e, self.h, self.l
             );
-            debug!("\tSP: {:#06X?}\tPC: {:#06X}", self.sp, self.pc);
+            debug!(
+                "\tSP: {:#06X?}\tPC: {}",
+                self.sp,
+                self.display_pc(memory, symbols)
+            );
             debug!(
                 "\tIME: {}\t Flags: {}",
                 if self.ime.1 { "ENABLED" } else { "DISABLED" },
@@ -2142,7 +2517,11 @@ impl CPU {
                 "\tD: {:#04X?}\tE: {:#04X?}\tH: {:#04X?}\tL: {:#04X?}",
                 self.d, self.e, self.h, self.l
             );
-            info!("\tSP: {:#06X?}\tPC: {:#06X}", self.sp, self.pc);
+            info!(
+                "\tSP: {:#06X?}\tPC: {}",
+                self.sp,
+                self.display_pc(memory, symbols)
+            );
             info!(
                 "\tIME: {}\t Flags: {}",
                 if self.ime.1 { "ENABLED" } else { "DISABLED" },
@@ -1,40 +1,408 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt, fs,
+    io::{self, Write},
+    ops::{Range, RangeInclusive},
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
-use log::info;
+use log::{info, warn};
 use sdl2::{
+    audio::{AudioQueue, AudioSpecDesired},
     event::{Event, EventType},
     keyboard::Keycode,
 };
 
 use crate::{
+    apu::{self, SAMPLE_RATE},
     clock::Clock,
-    cpu::{Instruction, SizedInstruction, CPU},
-    graphics::Graphics,
+    cpu::{
+        Instruction, OpCode, SizedInstruction, CARRY_FLAG, CPU, HALF_CARRY_FLAG, SUBTRACT_FLAG,
+        ZERO_FLAG,
+    },
+    graphics::{Graphics, Layer, PPU},
     joypad::Joypad,
-    memory::Memory,
-    utils::Address,
+    memory::{
+        io_register_name, unpack_rom, Bus, CartridgeError, CartridgeHeader, HardwareModel, Memory,
+        MemoryAccess, BOOTROM_SIZE,
+    },
+    state::{StateError, StateReader, StateWriter},
+    utils::{Address, Byte, Word},
 };
 
+const WRAM_RANGE: Range<Address> = 0xC000..0xE000;
+const HRAM_RANGE: Range<Address> = 0xFF80..0xFFFF;
+/// I/O registers traced by set_io_trace
+const IO_TRACE_RANGE: RangeInclusive<Address> = 0xFF00..=0xFF7F;
+/// Audio queued ahead of playback, about 0.1s
+const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE as usize / 10;
+/// Machine cycles run_boot waits for the boot rom, the dmg boot rom needs about 2.5M
+const BOOT_CYCLE_LIMIT: u128 = 10_000_000;
+/// Machine cycles run_mooneye_test waits for the result, about 20 seconds
+const MOONEYE_CYCLE_LIMIT: u128 = 20_000_000;
+/// Mooneye test roms stop at LD B,B once the result is in the registers
+const MOONEYE_BREAKPOINT: Byte = 0x40;
+/// B, C, D, E, H and L of a passing mooneye test
+const MOONEYE_PASS: [Byte; 6] = [3, 5, 8, 13, 21, 34];
+
 pub struct GameBoy {
     cpu: CPU,
     memory: Memory,
     graphics: Option<Graphics>,
+    /// Renders when there is no window, so headless runs and save states keep the ppu timing
+    headless_ppu: PPU,
     clock: Clock,
     joypad: Joypad,
     dbg: Debugger,
+    save_path: Option<PathBuf>,
+    control_map: HashMap<Keycode, ControlAction>,
+    /// Sink for the I/O register write trace
+    io_trace: Option<Box<dyn Write>>,
+    audio: Option<Box<dyn AudioSink>>,
+    /// Last time audio was queued, turbo resamples a frame into the time it took
+    last_audio: Instant,
+    /// Run without the frame limiter
+    turbo: bool,
+    /// Frames presented, every nth one normally and during turbo
+    frame_skip: u32,
+    turbo_frame_skip: u32,
+    /// Queue silence during turbo instead of resampling the sped up audio
+    turbo_mute: bool,
+    /// Started in the post boot state, a reset does not map the boot rom again
+    skip_boot: bool,
+}
+
+/// Plays the stereo samples of the apu, interleaved left first
+pub trait AudioSink {
+    /// Left and right sample pairs waiting to be played
+    fn queued(&self) -> usize;
+    fn queue(&mut self, samples: &[f32]) -> Result<(), String>;
+}
+
+impl AudioSink for AudioQueue<f32> {
+    fn queued(&self) -> usize {
+        self.size() as usize / (2 * std::mem::size_of::<f32>())
+    }
+
+    fn queue(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.queue_audio(samples)
+    }
+}
+
+/// Emulator control actions, bound to keys outside of the joypad
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ControlAction {
+    Quit,
+    Pause,
+    Step,
+    Finish,
+    /// Write WRAM and HRAM to a file while paused
+    Dump,
+    Screenshot,
+    /// Show or hide a layer to debug rendering
+    ToggleLayer(Layer),
+    SaveState,
+    LoadState,
+    /// Run uncapped while the key is held
+    Turbo,
+    /// Power cycle, the cartridge and its ram are kept
+    Reset,
+}
+
+impl ControlAction {
+    /// Default bindings: Escape/Q quit, P pause, ] step, F finish, M dump, F12 screenshot,
+    /// 1/2/3 toggle the background, window and objects, F5/F9 save and load the state,
+    /// holding Space runs in turbo, R resets
+    pub fn default_map() -> HashMap<Keycode, ControlAction> {
+        HashMap::from([
+            (Keycode::Escape, ControlAction::Quit),
+            (Keycode::Q, ControlAction::Quit),
+            (Keycode::P, ControlAction::Pause),
+            (Keycode::RightBracket, ControlAction::Step),
+            (Keycode::F, ControlAction::Finish),
+            (Keycode::M, ControlAction::Dump),
+            (Keycode::F12, ControlAction::Screenshot),
+            (Keycode::F5, ControlAction::SaveState),
+            (Keycode::F9, ControlAction::LoadState),
+            (Keycode::Space, ControlAction::Turbo),
+            (Keycode::R, ControlAction::Reset),
+            (Keycode::Num1, ControlAction::ToggleLayer(Layer::Background)),
+            (Keycode::Num2, ControlAction::ToggleLayer(Layer::Window)),
+            (Keycode::Num3, ControlAction::ToggleLayer(Layer::Objects)),
+        ])
+    }
+}
+
+/// One line of the I/O trace: pc, register name and address, old and new value
+pub fn format_io_write(pc: Address, address: Address, old: Byte, new: Byte) -> String {
+    let name = io_register_name(address).unwrap_or("?");
+    format!(
+        "{:04X}: {:<5} {:04X} {:02X} -> {:02X}",
+        pc, name, address, old, new
+    )
+}
+
+/// Errors when running the boot rom on its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BootError {
+    /// No boot rom is mapped
+    NotMapped,
+    /// The boot rom did not unmap itself within the cycle limit
+    Timeout { mcycles: u128, pc: Address },
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotMapped => write!(f, "no boot rom is mapped"),
+            Self::Timeout { mcycles, pc } => write!(
+                f,
+                "boot rom still mapped after {} machine cycles, pc at {:#06X}",
+                mcycles, pc
+            ),
+        }
+    }
+}
+
+impl Error for BootError {}
+
+/// Errors when loading a rom or boot rom file, each names the file
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file could not be read
+    Io { path: PathBuf, error: io::Error },
+    /// The file has no data
+    Empty { path: PathBuf },
+    /// A boot rom file that is not BOOTROM_SIZE bytes
+    BootSize { path: PathBuf, len: usize },
+    /// The file is not a cartridge that can be loaded
+    Cartridge {
+        path: PathBuf,
+        error: CartridgeError,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, error } => {
+                write!(f, "unable to read {}: {}", path.display(), error)
+            }
+            Self::Empty { path } => write!(f, "{} is empty", path.display()),
+            Self::BootSize { path, len } => write!(
+                f,
+                "{} is {:#X} bytes, a boot rom is {:#X} bytes",
+                path.display(),
+                len,
+                BOOTROM_SIZE
+            ),
+            Self::Cartridge { path, error } => {
+                write!(f, "unable to load {}: {}", path.display(), error)
+            }
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io { error, .. } => Some(error),
+            Self::Cartridge { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Read a non empty file, for roms and boot roms
+fn read_file(path: &Path) -> Result<Vec<u8>, LoadError> {
+    let data = fs::read(path).map_err(|error| LoadError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    if data.is_empty() {
+        return Err(LoadError::Empty {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(data)
+}
+
+/// Rom bytes from a plain, .zip or .gz file, see unpack_rom
+pub fn read_rom_file(path: impl AsRef<Path>) -> Result<Vec<u8>, LoadError> {
+    let path = path.as_ref();
+    unpack_rom(read_file(path)?).map_err(|error| LoadError::Cartridge {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+/// Outcome of a Mooneye acceptance test rom
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TestResult {
+    Pass,
+    /// Hit the breakpoint without the pass pattern, failures load 0x42 into every register
+    Fail {
+        registers: [Byte; 6],
+    },
+    /// Never reached the breakpoint within the cycle limit
+    Timeout {
+        mcycles: u128,
+        pc: Address,
+    },
+}
+
+/// Run a Mooneye test rom headless from the cartridge entry point until its LD B,B
+/// breakpoint, then check B, C, D, E, H and L for the Fibonacci pass pattern
+pub fn run_mooneye_test(rom: Vec<u8>) -> Result<TestResult, CartridgeError> {
+    let mut gameboy = GameBoy::new_skip_boot(false);
+    gameboy.load_rom(rom)?;
+    let start = gameboy.clock.get_timestamp();
+    loop {
+        let cpu = &gameboy.cpu;
+        if !cpu.halt && gameboy.memory.read_byte(cpu.pc) == MOONEYE_BREAKPOINT {
+            let registers = [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l];
+            return Ok(if registers == MOONEYE_PASS {
+                TestResult::Pass
+            } else {
+                TestResult::Fail { registers }
+            });
+        }
+        let mcycles = gameboy.clock.get_timestamp() - start;
+        if mcycles >= MOONEYE_CYCLE_LIMIT {
+            return Ok(TestResult::Timeout {
+                mcycles,
+                pc: cpu.pc,
+            });
+        }
+        gameboy.step_and_render();
+    }
+}
+
+/// A single difference between two machine states, left is self and right is other
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateDiff {
+    Register {
+        name: &'static str,
+        left: Word,
+        right: Word,
+    },
+    Flag {
+        name: &'static str,
+        left: bool,
+        right: bool,
+    },
+    Memory {
+        address: Address,
+        left: Byte,
+        right: Byte,
+    },
+    /// Byte of a work ram or external ram bank, by offset into the bank
+    Bank {
+        area: &'static str,
+        bank: usize,
+        offset: usize,
+        left: Byte,
+        right: Byte,
+    },
+}
+
+/// Labels loaded from a .sym file, lines of `<bank>:<addr> <name>`
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    labels: HashMap<(usize, Address), String>,
+    addresses: HashMap<String, (usize, Address)>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse a .sym file, skipping comments and malformed lines
+    pub fn parse(contents: &str) -> Self {
+        let mut table = Self::new();
+        for line in contents.lines() {
+            let line = line.split(';').next().unwrap().trim();
+            let Some((location, name)) = line.split_once(char::is_whitespace) else {
+                continue;
+            };
+            let (bank, address) = match location.split_once(':') {
+                Some((bank, address)) => (bank, address),
+                None => ("0", location),
+            };
+            if let (Ok(bank), Ok(address)) = (
+                usize::from_str_radix(bank, 16),
+                Address::from_str_radix(address, 16),
+            ) {
+                table.insert(bank, address, name.trim());
+            }
+        }
+        table
+    }
+
+    pub fn insert(&mut self, bank: usize, address: Address, name: &str) {
+        self.labels.insert((bank, address), name.to_string());
+        self.addresses.insert(name.to_string(), (bank, address));
+    }
+
+    /// Label at address in the given rom bank
+    pub fn label(&self, bank: usize, address: Address) -> Option<&str> {
+        self.labels.get(&(bank, address)).map(String::as_str)
+    }
+
+    /// Label at address, in the rom bank currently mapped there
+    pub fn label_at<B: Bus>(&self, memory: &B, address: Address) -> Option<&str> {
+        self.label(memory.rom_bank(address), address)
+    }
+
+    /// Bank and address of a label
+    pub fn address(&self, name: &str) -> Option<(usize, Address)> {
+        self.addresses.get(name).copied()
+    }
 }
 
 /// Struct to hold all debugger constructs
 struct Debugger {
     pause: bool,
     step: bool,
+    /// Stack pointer when `finish` was issued, pause once a return pops above it
+    finish: Option<Word>,
     breakpoints: HashSet<Breakpoint>,
+    symbols: SymbolTable,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
-enum Breakpoint {
+pub enum Breakpoint {
     Inst(Instruction),
     Addr(Address),
+    /// Address in the switchable rom area, only while the bank is mapped
+    BankedAddr {
+        bank: usize,
+        address: Address,
+    },
+    /// Opcode byte at pc, regardless of operands
+    Opcode(Byte),
+    /// Opcode byte at pc matching pattern under mask, e.g. a family of instructions
+    OpcodeMasked {
+        pattern: Byte,
+        mask: Byte,
+    },
+    /// Pause after an instruction reads the address
+    Read(Address),
+    /// Pause after an instruction writes the address
+    Write(Address),
+}
+
+impl Breakpoint {
+    /// Breakpoint at address, tied to the bank in the switchable rom area
+    fn at(bank: usize, address: Address) -> Self {
+        match address {
+            0x4000..=0x7FFF => Self::BankedAddr { bank, address },
+            _ => Self::Addr(address),
+        }
+    }
 }
 
 impl Debugger {
@@ -42,10 +410,25 @@ impl Debugger {
         Self {
             pause: false,
             step: false,
+            finish: None,
             breakpoints: HashSet::new(),
+            symbols: SymbolTable::new(),
         }
     }
 
+    /// Parse a breakpoint given as a label, a hex address or a `<bank>:<addr>` pair
+    fn parse_breakpoint(&self, spec: &str) -> Option<Breakpoint> {
+        if let Some((bank, address)) = self.symbols.address(spec) {
+            return Some(Breakpoint::at(bank, address));
+        }
+        let (bank, address) = match spec.split_once(':') {
+            Some((bank, address)) => (usize::from_str_radix(bank, 16).ok()?, address),
+            None => (0, spec),
+        };
+        let address = Address::from_str_radix(address.trim_start_matches("0x"), 16).ok()?;
+        Some(Breakpoint::at(bank, address))
+    }
+
     fn toggle_pause(&mut self) {
         self.pause = !self.pause;
     }
@@ -55,17 +438,70 @@ impl Debugger {
         self.pause = false;
     }
 
-    #[allow(dead_code)]
+    /// Run until the current subroutine returns to its caller
+    fn finish(&mut self, sp: Word) {
+        self.finish = Some(sp);
+        self.pause = false;
+    }
+
     fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
         self.breakpoints.insert(breakpoint);
     }
 
     fn check_breakpoints(&self, cpu: &CPU, memory: &Memory) -> bool {
-        let instruction = SizedInstruction::decode(memory, cpu.pc)
-            .unwrap()
-            .instruction;
-        self.breakpoints.contains(&Breakpoint::Inst(instruction))
+        let opcode = memory.read_byte(cpu.pc);
+        let masked = |breakpoint: &Breakpoint| match breakpoint {
+            Breakpoint::OpcodeMasked { pattern, mask } => {
+                OpCode::new(*pattern, *mask).matches(opcode)
+            }
+            _ => false,
+        };
+        let instruction = match SizedInstruction::decode(memory, cpu.pc) {
+            Some(ins) => self
+                .breakpoints
+                .contains(&Breakpoint::Inst(ins.instruction)),
+            None => false,
+        };
+        instruction
             || self.breakpoints.contains(&Breakpoint::Addr(cpu.pc))
+            || self.breakpoints.contains(&Breakpoint::BankedAddr {
+                bank: memory.rom_bank(cpu.pc),
+                address: cpu.pc,
+            })
+            || self.breakpoints.contains(&Breakpoint::Opcode(opcode))
+            || self.breakpoints.iter().any(masked)
+    }
+
+    fn has_watchpoints(&self) -> bool {
+        self.breakpoints
+            .iter()
+            .any(|breakpoint| matches!(breakpoint, Breakpoint::Read(_) | Breakpoint::Write(_)))
+    }
+
+    /// Pause if the last instruction touched a watched address
+    fn check_watchpoints(&mut self, pc: Address, accesses: &[MemoryAccess]) {
+        for access in accesses {
+            match *access {
+                MemoryAccess::Read { address, value }
+                    if self.breakpoints.contains(&Breakpoint::Read(address)) =>
+                {
+                    info!(
+                        "Watchpoint: {:#04X?} read {:#04X?} = {:#04X?}",
+                        pc, address, value
+                    );
+                }
+                MemoryAccess::Write { address, old, new }
+                    if self.breakpoints.contains(&Breakpoint::Write(address)) =>
+                {
+                    info!(
+                        "Watchpoint: {:#04X?} wrote {:#04X?}: {:#04X?} -> {:#04X?}",
+                        pc, address, old, new
+                    );
+                }
+                _ => continue,
+            }
+            self.pause = true;
+        }
     }
 
     /// Check if pause, with effect
@@ -77,10 +513,24 @@ impl Debugger {
             self.pause = true;
             self.step = false;
             false
+        } else if let Some(sp) = self.finish {
+            // nested calls and pushes stay below the stack pointer, only a return goes above,
+            // breakpoints are ignored until then
+            if cpu.sp <= sp {
+                return false;
+            }
+            self.pause = true;
+            self.finish = None;
+            info!("Finished: {}", cpu.display_pc(memory, Some(&self.symbols)));
+            cpu.display_registers(false, memory, Some(&self.symbols));
+            true
         } else if self.check_breakpoints(cpu, memory) {
             self.pause = true;
-            info!("Breakpoint: {:#04X?}", cpu.pc);
-            cpu.display_registers(false);
+            info!(
+                "Breakpoint: {}",
+                cpu.display_pc(memory, Some(&self.symbols))
+            );
+            cpu.display_registers(false, memory, Some(&self.symbols));
             true
         } else {
             false
@@ -90,31 +540,692 @@ impl Debugger {
 
 impl GameBoy {
     pub fn new(graphics_enabled: bool) -> Self {
-        // Initialize SDL
-        let context = sdl2::init().unwrap();
-
         GameBoy {
             cpu: CPU::new(),
             memory: Memory::new(),
             graphics: if graphics_enabled {
-                Some(Graphics::new(&context))
+                // Initialize SDL
+                let context = sdl2::init().unwrap();
+                Some(Graphics::new(&context, Graphics::DEFAULT_SCALE))
             } else {
                 None
             },
+            headless_ppu: PPU::new(),
             joypad: Joypad::new(),
             clock: Clock::new(),
             dbg: Debugger::new(),
+            save_path: None,
+            control_map: ControlAction::default_map(),
+            io_trace: None,
+            audio: None,
+            last_audio: Instant::now(),
+            turbo: false,
+            frame_skip: 1,
+            turbo_frame_skip: 1,
+            turbo_mute: true,
+            skip_boot: false,
+        }
+    }
+
+    /// Start in the state left by the boot rom, without needing a boot rom
+    pub fn new_skip_boot(graphics_enabled: bool) -> Self {
+        let mut gameboy = Self::new(graphics_enabled);
+        gameboy.cpu = CPU::new_skip_boot();
+        gameboy.memory.init_post_boot();
+        gameboy.skip_boot = true;
+        gameboy
+    }
+
+    /// Power cycle: reload the cartridge and start over from the boot rom, or from the
+    /// post boot state when started without one. External ram and settings are kept
+    pub fn reset(&mut self) {
+        self.memory.reset(!self.skip_boot);
+        if self.skip_boot {
+            self.cpu = CPU::new_skip_boot();
+            self.memory.init_post_boot();
+        } else {
+            self.cpu = CPU::new();
+        }
+        self.clock = Clock::new();
+        match &mut self.graphics {
+            Some(graphics) => graphics.restore_ppu(PPU::new()),
+            None => self.headless_ppu = PPU::new(),
+        }
+    }
+
+    pub fn load_rom(&mut self, rom_data: Vec<u8>) -> Result<(), CartridgeError> {
+        self.memory.load_cartidge(rom_data)?;
+        if let (Some(graphics), Some(header)) = (&mut self.graphics, self.memory.header()) {
+            graphics.set_title(&format!("GB-rs - {}", header.title));
+        }
+        Ok(())
+    }
+
+    /// Model being emulated, picked from the header of the loaded cartridge
+    pub fn model(&self) -> HardwareModel {
+        self.memory.get_model()
+    }
+
+    /// Run cartridges as model instead of the one their header asks for
+    pub fn force_model(&mut self, model: Option<HardwareModel>) {
+        self.memory.force_model(model);
+    }
+
+    /// Read, unpack and load a rom file, returning its header
+    pub fn load_rom_from_path(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<CartridgeHeader, LoadError> {
+        let path = path.as_ref();
+        let rom = read_rom_file(path)?;
+        self.load_rom(rom).map_err(|error| LoadError::Cartridge {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Ok(self
+            .memory
+            .header()
+            .cloned()
+            .expect("loaded rom has a header"))
+    }
+
+    /// Read and map a BOOTROM_SIZE byte boot rom file
+    pub fn load_boot_from_path(&mut self, path: impl AsRef<Path>) -> Result<(), LoadError> {
+        let path = path.as_ref();
+        let boot = read_file(path)?;
+        if boot.len() != BOOTROM_SIZE {
+            return Err(LoadError::BootSize {
+                path: path.to_path_buf(),
+                len: boot.len(),
+            });
+        }
+        self.load_boot(boot);
+        Ok(())
+    }
+
+    /// Load a program without a cartridge header at entry, skipping the boot sequence.
+    /// The stack pointer defaults to 0xFFFE like after the boot rom
+    pub fn load_raw_program(&mut self, program: Vec<u8>, entry: Address, sp: Option<Word>) {
+        self.memory.load_raw(&program, entry);
+        self.cpu = CPU::new_skip_boot();
+        self.cpu.pc = entry;
+        self.cpu.sp = sp.unwrap_or(0xFFFE);
+    }
+
+    /// Load battery backed ram from path, and save it back there on exit
+    pub fn load_save(&mut self, path: PathBuf) -> io::Result<()> {
+        self.memory.load_save(&path)?;
+        self.save_path = Some(path);
+        Ok(())
+    }
+
+    /// Flush battery backed ram to the save file, if it changed
+    pub fn save_ram(&mut self) -> io::Result<()> {
+        match &self.save_path {
+            Some(path) => self.memory.save_ram(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Snapshot of the cpu, clock, ppu and memory, see load_state_data
+    pub fn save_state_data(&self) -> Vec<Byte> {
+        let mut state = StateWriter::new();
+        self.cpu.write_state(&mut state);
+        self.clock.write_state(&mut state);
+        self.ppu().write_state(&mut state);
+        self.memory.write_state(&mut state);
+        state.finish()
+    }
+
+    /// Restore a snapshot of save_state_data, nothing changes until every field was read
+    pub fn load_state_data(&mut self, data: &[Byte]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data)?;
+        let cpu = CPU::read_state(&mut state)?;
+        let clock = Clock::read_state(&mut state)?;
+        let ppu = PPU::read_state(&mut state)?;
+        let memory = self.memory.parse_state(&mut state)?;
+        state.finish()?;
+
+        self.memory.restore_state(memory);
+        self.cpu = cpu;
+        self.clock = clock;
+        match &mut self.graphics {
+            Some(graphics) => graphics.restore_ppu(ppu),
+            None => self.headless_ppu = ppu,
+        }
+        Ok(())
+    }
+
+    pub fn save_state(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.save_state_data())
+    }
+
+    pub fn load_state(&mut self, path: &Path) -> io::Result<()> {
+        let data = fs::read(path)?;
+        self.load_state_data(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Next to the battery save, or in the working directory without one
+    fn state_path(&self) -> PathBuf {
+        match &self.save_path {
+            Some(path) => path.with_extension("state"),
+            None => PathBuf::from("quicksave.state"),
+        }
+    }
+
+    /// Load debugger labels from the contents of a .sym file
+    pub fn load_symbols(&mut self, contents: &str) {
+        self.dbg.symbols = SymbolTable::parse(contents);
+    }
+
+    /// Add a breakpoint at a label or hex address, false if it could not be parsed
+    pub fn add_breakpoint(&mut self, spec: &str) -> bool {
+        match self.dbg.parse_breakpoint(spec) {
+            Some(breakpoint) => {
+                self.dbg.add_breakpoint(breakpoint);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.dbg.add_breakpoint(breakpoint);
+        self.update_access_logging();
+    }
+
+    /// Play the apu output through an SDL audio queue, needs graphics for the SDL context
+    pub fn enable_audio(&mut self) -> Result<(), String> {
+        let Some(graphics) = &self.graphics else {
+            return Err(String::from("audio needs graphics"));
+        };
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: None,
+        };
+        let queue = graphics
+            .context
+            .audio()?
+            .open_queue::<f32, _>(None, &spec)?;
+        queue.resume();
+        self.set_audio_sink(Some(Box::new(queue)));
+        Ok(())
+    }
+
+    /// Play the apu output through sink, None drops it
+    pub fn set_audio_sink(&mut self, sink: Option<Box<dyn AudioSink>>) {
+        self.audio = sink;
+        self.last_audio = Instant::now();
+    }
+
+    /// Run as fast as possible instead of at 60 frames per second
+    pub fn set_turbo(&mut self, turbo: bool) {
+        if turbo == self.turbo {
+            return;
+        }
+        self.turbo = turbo;
+        info!("Turbo {}", if turbo { "on" } else { "off" });
+        self.apply_frame_skip();
+    }
+
+    pub fn turbo(&self) -> bool {
+        self.turbo
+    }
+
+    /// Report opposing D-pad directions held together instead of releasing both
+    pub fn set_allow_opposing_dpad(&mut self, allow: bool) {
+        self.joypad.set_allow_opposing(allow);
+    }
+
+    /// Silence audio during turbo (the default), or resample it to play in real time.
+    /// The apu keeps running either way
+    pub fn set_turbo_mute(&mut self, mute: bool) {
+        self.turbo_mute = mute;
+    }
+
+    /// Queue the samples of the last frame, dropping them when playback fell behind.
+    /// Called once per frame by run
+    pub fn queue_audio(&mut self) {
+        let samples = self.memory.take_audio_samples();
+        let elapsed = self.last_audio.elapsed();
+        self.last_audio = Instant::now();
+        let Some(audio) = &mut self.audio else {
+            return;
+        };
+        if audio.queued() > MAX_QUEUED_SAMPLES {
+            return;
+        }
+        let samples = match (self.turbo, self.turbo_mute) {
+            (false, _) => samples,
+            (true, true) => vec![(0.0, 0.0); samples.len()],
+            (true, false) => {
+                let len = (elapsed.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+                apu::resample(&samples, len)
+            }
+        };
+        let samples: Vec<f32> = samples.into_iter().flat_map(|(l, r)| [l, r]).collect();
+        if let Err(e) = audio.queue(&samples) {
+            warn!("Unable to queue audio due to {}", e);
+        }
+    }
+
+    /// Log every cpu write to 0xFF00-0xFF7F to sink, None stops tracing
+    pub fn set_io_trace(&mut self, sink: Option<Box<dyn Write>>) {
+        self.flush_io_trace();
+        self.io_trace = sink;
+        self.update_access_logging();
+    }
+
+    fn flush_io_trace(&mut self) {
+        if let Some(sink) = &mut self.io_trace {
+            if let Err(e) = sink.flush() {
+                warn!("Unable to write io trace due to {}", e);
+            }
+        }
+    }
+
+    /// Accesses are only logged while something consumes them
+    fn update_access_logging(&mut self) {
+        self.memory
+            .set_access_logging(self.dbg.has_watchpoints() || self.io_trace.is_some());
+    }
+
+    /// Whether a breakpoint matches the current pc
+    pub fn at_breakpoint(&self) -> bool {
+        self.dbg.check_breakpoints(&self.cpu, &self.memory)
+    }
+
+    /// Resize the window to scale times the screen, between 1 and 8
+    pub fn set_scale(&mut self, scale: u32) {
+        if let Some(graphics) = &mut self.graphics {
+            graphics.set_scale(scale);
+        }
+    }
+
+    /// Present only every nth frame
+    pub fn set_frame_skip(&mut self, every: u32) {
+        self.frame_skip = every;
+        self.apply_frame_skip();
+    }
+
+    /// Present only every nth frame during turbo
+    pub fn set_turbo_frame_skip(&mut self, every: u32) {
+        self.turbo_frame_skip = every;
+        self.apply_frame_skip();
+    }
+
+    fn apply_frame_skip(&mut self) {
+        let every = if self.turbo {
+            self.turbo_frame_skip
+        } else {
+            self.frame_skip
+        };
+        if let Some(graphics) = &mut self.graphics {
+            graphics.set_frame_skip(every);
+        }
+    }
+
+    /// Pause the debugger before the next instruction
+    pub fn pause(&mut self) {
+        self.dbg.pause = true;
+    }
+
+    /// Continue until the current subroutine returns, then pause after the call
+    pub fn finish(&mut self) {
+        self.dbg.finish(self.cpu.sp);
+    }
+
+    /// Step unless the debugger pauses here, false if paused
+    pub fn debug_step(&mut self) -> bool {
+        if self.dbg.check_pause(&self.cpu, &self.memory) {
+            return false;
+        }
+        self.watched_step();
+        true
+    }
+
+    /// Step, pause if a watchpoint was hit and trace I/O writes
+    fn watched_step(&mut self) {
+        let pc = self.cpu.pc;
+        if !self.dbg.has_watchpoints() && self.io_trace.is_none() {
+            self.step();
+            return self.check_rom_write(pc);
         }
+        // drop the accesses made outside of the cpu, e.g. by the ppu
+        self.memory.take_accesses();
+        self.step();
+        self.check_rom_write(pc);
+        let accesses = self.memory.take_accesses();
+        self.dbg.check_watchpoints(pc, &accesses);
+        if let Some(sink) = &mut self.io_trace {
+            for access in accesses {
+                let MemoryAccess::Write { address, old, new } = access else {
+                    continue;
+                };
+                if !IO_TRACE_RANGE.contains(&address) {
+                    continue;
+                }
+                if let Err(e) = writeln!(sink, "{}", format_io_write(pc, address, old, new)) {
+                    warn!("Unable to write io trace due to {}", e);
+                    self.io_trace = None;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Pause after an instruction wrote to rom, with RomWriteCheck::Break
+    fn check_rom_write(&mut self, pc: Address) {
+        if let Some((address, value)) = self.memory.take_rom_write() {
+            info!(
+                "Rom write: {:#04X?} wrote {:#04X?} = {:#04X?}",
+                pc, address, value
+            );
+            self.dbg.pause = true;
+        }
+    }
+
+    /// Write WRAM followed by HRAM to path
+    pub fn dump_memory(&self, path: &Path) -> io::Result<()> {
+        let mut dump = self.memory.dump(WRAM_RANGE);
+        dump.extend(self.memory.dump(HRAM_RANGE));
+        fs::write(path, dump)
     }
 
-    pub fn load_rom(&mut self, rom_data: Vec<u8>) {
-        self.memory.load_cartidge(rom_data);
+    /// Bind a key to a control action, replacing the keys previously bound to it
+    pub fn bind_control(&mut self, keycode: Keycode, action: ControlAction) {
+        self.control_map.retain(|_, bound| *bound != action);
+        self.control_map.insert(keycode, action);
+    }
+
+    /// Whether the debugger is paused
+    pub fn paused(&self) -> bool {
+        self.dbg.pause
+    }
+
+    /// Handle an sdl event, control keys take precedence over the joypad.
+    /// Returns false when the emulator should quit
+    pub fn handle_event(&mut self, event: Event) -> bool {
+        match event {
+            Event::Quit { .. } => return false,
+            Event::KeyDown {
+                keycode: Some(k), ..
+            } => match self.control_map.get(&k) {
+                Some(ControlAction::Quit) => return false,
+                Some(ControlAction::Pause) => self.dbg.toggle_pause(),
+                Some(ControlAction::Step) => self.dbg.toggle_step(),
+                Some(ControlAction::Finish) => self.dbg.finish(self.cpu.sp),
+                Some(ControlAction::Dump) if self.dbg.pause => {
+                    let secs = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |time| time.as_secs());
+                    let path = PathBuf::from(format!("dump-{}.bin", secs));
+                    match self.dump_memory(&path) {
+                        Ok(()) => info!("Dumped WRAM and HRAM to {}", path.display()),
+                        Err(e) => warn!("Unable to write dump due to {}", e),
+                    }
+                }
+                Some(ControlAction::Dump) => {}
+                Some(&ControlAction::ToggleLayer(layer)) => {
+                    if let Some(ref mut graphics) = self.graphics {
+                        let shown = graphics.toggle_layer(layer);
+                        info!("{:?} {}", layer, if shown { "shown" } else { "hidden" });
+                    }
+                }
+                Some(ControlAction::Screenshot) => {
+                    if let Some(ref graphics) = self.graphics {
+                        let millis = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |time| time.as_millis());
+                        let path = PathBuf::from(format!("screenshot-{}.png", millis));
+                        match graphics.screenshot(&path) {
+                            Ok(()) => info!("Saved screenshot to {}", path.display()),
+                            Err(e) => warn!("Unable to save screenshot due to {}", e),
+                        }
+                    }
+                }
+                Some(ControlAction::SaveState) => {
+                    let path = self.state_path();
+                    match self.save_state(&path) {
+                        Ok(()) => info!("Saved state to {}", path.display()),
+                        Err(e) => warn!("Unable to save state due to {}", e),
+                    }
+                }
+                Some(ControlAction::LoadState) => {
+                    let path = self.state_path();
+                    match self.load_state(&path) {
+                        Ok(()) => info!("Loaded state from {}", path.display()),
+                        Err(e) => warn!("Unable to load state due to {}", e),
+                    }
+                }
+                Some(ControlAction::Turbo) => self.set_turbo(true),
+                Some(ControlAction::Reset) => {
+                    self.reset();
+                    info!("Reset");
+                }
+                None => self.joypad.handle_button(k, true, &mut self.memory),
+            },
+            Event::KeyUp {
+                keycode: Some(k), ..
+            } => match self.control_map.get(&k) {
+                Some(ControlAction::Turbo) => self.set_turbo(false),
+                _ => self.joypad.handle_button(k, false, &mut self.memory),
+            },
+            Event::ControllerButtonDown { button, .. } => {
+                self.joypad
+                    .handle_controller_button(button, true, &mut self.memory)
+            }
+            Event::ControllerButtonUp { button, .. } => {
+                self.joypad
+                    .handle_controller_button(button, false, &mut self.memory)
+            }
+            Event::ControllerAxisMotion { axis, value, .. } => {
+                self.joypad
+                    .handle_controller_axis(axis, value, &mut self.memory)
+            }
+            Event::ControllerDeviceAdded { which, .. } => {
+                if let Some(ref mut graphics) = self.graphics {
+                    graphics.open_controller(which);
+                }
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(ref mut graphics) = self.graphics {
+                    graphics.close_controller(which);
+                }
+            }
+            _ => {}
+        }
+        true
     }
 
     pub fn load_boot(&mut self, boot_data: Vec<u8>) {
         self.memory.load_boot(boot_data);
     }
 
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// Execute a single instruction (or a halted cycle) and handle interrupts
+    pub fn step(&mut self) {
+        if self.cpu.locked {
+            // only the clock keeps running
+            self.clock.tick(1, &mut self.memory);
+            return;
+        }
+        if self.cpu.halt {
+            self.clock.tick(1, &mut self.memory);
+        } else {
+            self.cpu.execute(&mut self.memory, &mut self.clock);
+        }
+
+        self.cpu.handle_interrupts(&mut self.memory);
+
+        self.cpu.ime_step();
+    }
+
+    /// Run until the boot rom unmaps itself, leaving the cpu at the cartridge entry point.
+    /// Without graphics the headless ppu runs so the boot rom sees vblank
+    pub fn run_boot(&mut self) -> Result<(), BootError> {
+        if !self.memory.boot_mapped() {
+            return Err(BootError::NotMapped);
+        }
+        let start = self.clock.get_timestamp();
+        while self.memory.boot_mapped() {
+            let mcycles = self.clock.get_timestamp() - start;
+            if mcycles >= BOOT_CYCLE_LIMIT {
+                return Err(BootError::Timeout {
+                    mcycles,
+                    pc: self.cpu.pc,
+                });
+            }
+            self.step_and_render();
+        }
+        Ok(())
+    }
+
+    /// Step and render, through the headless ppu when there is no window
+    pub fn step_and_render(&mut self) {
+        self.step();
+        self.render();
+    }
+
+    fn render(&mut self) {
+        let timestamp = self.clock.get_timestamp();
+        match self.graphics {
+            Some(ref mut graphics) => graphics.render(&mut self.memory, timestamp),
+            None => {
+                self.headless_ppu.render(&mut self.memory, timestamp);
+            }
+        }
+    }
+
+    /// The ppu in use, the window's or the headless one
+    pub fn ppu(&self) -> &PPU {
+        match &self.graphics {
+            Some(graphics) => graphics.ppu(),
+            None => &self.headless_ppu,
+        }
+    }
+
+    /// Compare registers, flags, the memory map and banked ram against another gameboy.
+    /// Memory is read as the ppu does, so the comparison has no side effects and does not
+    /// depend on the ppu mode or the I/O read masks
+    pub fn state_diff(&self, other: &GameBoy) -> Vec<StateDiff> {
+        let mut diffs = Vec::new();
+
+        let (l, r) = (&self.cpu, &other.cpu);
+        let registers = [
+            ("A", l.a as Word, r.a as Word),
+            ("F", l.f as Word, r.f as Word),
+            ("B", l.b as Word, r.b as Word),
+            ("C", l.c as Word, r.c as Word),
+            ("D", l.d as Word, r.d as Word),
+            ("E", l.e as Word, r.e as Word),
+            ("H", l.h as Word, r.h as Word),
+            ("L", l.l as Word, r.l as Word),
+            ("SP", l.sp, r.sp),
+            ("PC", l.pc, r.pc),
+        ];
+        for (name, left, right) in registers {
+            if left != right {
+                diffs.push(StateDiff::Register { name, left, right });
+            }
+        }
+
+        let flags = [
+            ("Z", l.get_flag(ZERO_FLAG), r.get_flag(ZERO_FLAG)),
+            ("N", l.get_flag(SUBTRACT_FLAG), r.get_flag(SUBTRACT_FLAG)),
+            (
+                "H",
+                l.get_flag(HALF_CARRY_FLAG),
+                r.get_flag(HALF_CARRY_FLAG),
+            ),
+            ("C", l.get_flag(CARRY_FLAG), r.get_flag(CARRY_FLAG)),
+            ("IME", l.ime.1, r.ime.1),
+            ("HALT", l.halt, r.halt),
+        ];
+        for (name, left, right) in flags {
+            if left != right {
+                diffs.push(StateDiff::Flag { name, left, right });
+            }
+        }
+
+        // echo ram is skipped, it only mirrors work ram. External ram is compared
+        // bank by bank below when both sides have it
+        let banked_ram =
+            self.memory.external_ram_size() > 0 && other.memory.external_ram_size() > 0;
+        for address in (0..0xE000).chain(0xFE00..=Address::MAX) {
+            if banked_ram && (0xA000..0xC000).contains(&address) {
+                continue;
+            }
+            let left = self.memory.ppu_read_byte(address);
+            let right = other.memory.ppu_read_byte(address);
+            if left != right {
+                diffs.push(StateDiff::Memory {
+                    address,
+                    left,
+                    right,
+                });
+            }
+        }
+
+        // a work ram bank mapped on both sides was compared above
+        let mapped_wram = self
+            .memory
+            .mapped_wram_bank()
+            .filter(|&bank| other.memory.mapped_wram_bank() == Some(bank));
+        let banked = [
+            (
+                "work ram",
+                self.memory.wram_banks(),
+                other.memory.wram_banks(),
+                mapped_wram,
+            ),
+            (
+                "external ram",
+                self.memory.external_ram_banks(),
+                other.memory.external_ram_banks(),
+                None,
+            ),
+        ];
+        for (area, left_banks, right_banks, skip) in banked {
+            for (bank, (left_bank, right_bank)) in left_banks.iter().zip(&right_banks).enumerate() {
+                if skip == Some(bank) {
+                    continue;
+                }
+                for (offset, (&left, &right)) in left_bank.iter().zip(right_bank.iter()).enumerate()
+                {
+                    if left != right {
+                        diffs.push(StateDiff::Bank {
+                            area,
+                            bank,
+                            offset,
+                            left,
+                            right,
+                        });
+                    }
+                }
+            }
+        }
+
+        diffs
+    }
+
     pub fn run(mut self) {
         // self.dbg.add_breakpoint(Breakpoint::Addr(0x039e));
         // self.dbg.add_breakpoint(Breakpoint::Inst(Instruction::EI));
@@ -134,45 +1245,33 @@ impl GameBoy {
                     }
                 }
             }
-            graphics.event_pump.enable_event(EventType::Quit);
-            graphics.event_pump.enable_event(EventType::KeyDown);
-            graphics.event_pump.enable_event(EventType::KeyUp);
+            for evt in [
+                EventType::Quit,
+                EventType::KeyDown,
+                EventType::KeyUp,
+                EventType::ControllerButtonDown,
+                EventType::ControllerButtonUp,
+                EventType::ControllerAxisMotion,
+                EventType::ControllerDeviceAdded,
+                EventType::ControllerDeviceRemoved,
+            ] {
+                graphics.event_pump.enable_event(evt);
+            }
         }
 
-        loop {
+        'run: loop {
             // poll every 0.1s
-            if let Some(ref mut graphics) = self.graphics {
-                if last_poll_time.elapsed().as_millis() > 50 {
-                    for event in graphics.event_pump.poll_iter() {
-                        match event {
-                            Event::Quit { .. }
-                            | Event::KeyDown {
-                                keycode: Some(Keycode::Escape),
-                                ..
-                            }
-                            | Event::KeyDown {
-                                keycode: Some(Keycode::Q),
-                                ..
-                            } => return,
-                            Event::KeyDown {
-                                keycode: Some(Keycode::P),
-                                ..
-                            } => self.dbg.toggle_pause(),
-                            Event::KeyDown {
-                                keycode: Some(Keycode::RightBracket),
-                                ..
-                            } => self.dbg.toggle_step(),
-                            Event::KeyDown {
-                                keycode: Some(k), ..
-                            } => self.joypad.handle_button(k, true, &mut self.memory),
-                            Event::KeyUp {
-                                keycode: Some(k), ..
-                            } => self.joypad.handle_button(k, false, &mut self.memory),
-                            _ => {}
-                        }
+            if last_poll_time.elapsed().as_millis() > 50 {
+                let events: Vec<_> = match self.graphics {
+                    Some(ref mut graphics) => graphics.event_pump.poll_iter().collect(),
+                    None => Vec::new(),
+                };
+                for event in events {
+                    if !self.handle_event(event) {
+                        break 'run;
                     }
-                    last_poll_time = std::time::Instant::now();
                 }
+                last_poll_time = std::time::Instant::now();
             }
             if self.dbg.check_pause(&self.cpu, &self.memory) {
                 continue;
@@ -182,37 +1281,51 @@ impl GameBoy {
             self.joypad.update(&mut self.memory);
 
             // start executing gb
-            if self.cpu.halt {
-                self.clock.tick(1, &mut self.memory);
-            } else {
-                self.cpu.execute(&mut self.memory, &mut self.clock);
-            }
-
-            self.cpu.handle_interrupts(&mut self.memory);
+            self.watched_step();
 
-            self.cpu.ime_step();
-
-            // serial output debug
-            if self.memory.read_byte(0xff02) != 0 {
-                let c = self.memory.read_byte(0xff01) as char;
-                print!("{}", c);
-                self.memory.write_byte(0xff02, 0);
+            // print the link port output, e.g. blargg test results
+            for byte in self.memory.take_serial_output() {
+                print!("{}", byte as char);
             }
 
             // render graphics
+            let mut frame_done = false;
             if let Some(ref mut graphics) = self.graphics {
                 // non gb related keydowns
                 graphics.render(&mut self.memory, self.clock.get_timestamp());
                 if self.clock.get_timestamp() - last_timestamp > 17476 {
-                    while last_time.elapsed().as_millis() < 16 {
+                    while !self.turbo && last_time.elapsed().as_millis() < 16 {
                         graphics.timer.delay(1);
                     }
                     last_timestamp = self.clock.get_timestamp();
                     last_time = std::time::Instant::now();
+                    frame_done = true;
+                }
+            } else {
+                self.headless_ppu
+                    .render(&mut self.memory, self.clock.get_timestamp());
+            }
+
+            // run audio, once per frame
+            if frame_done {
+                self.queue_audio();
+            }
+
+            // rumble goes to the open controllers, and is logged without one
+            if let Some(rumble) = self.memory.take_rumble_state() {
+                let forwarded = match self.graphics {
+                    Some(ref mut graphics) => graphics.set_rumble(rumble),
+                    None => false,
+                };
+                if !forwarded {
+                    info!("Rumble {}", if rumble { "on" } else { "off" });
                 }
             }
+        }
 
-            // run audio
+        self.flush_io_trace();
+        if let Err(e) = self.save_ram() {
+            warn!("Unable to write save file due to {}", e);
         }
     }
 }
@@ -1,19 +1,25 @@
 use std::{
     collections::{hash_map::Entry, HashMap, VecDeque},
+    fs::File,
+    io::{self, BufWriter, Write},
     ops::Range,
+    path::Path,
 };
 
+use log::{debug, info, warn};
 use sdl2::{
+    controller::GameController,
     pixels::{Color, PixelFormatEnum},
     render::{Canvas, TextureCreator},
     video::{Window, WindowContext},
-    EventPump, Sdl, TimerSubsystem,
+    EventPump, GameControllerSubsystem, Sdl, TimerSubsystem,
 };
 use std::fmt;
 
 use crate::{
     cpu::{INTERRUPT_FLAG_ADDRESS, LCD_FLAG, VBLANK_FLAG},
-    memory::Memory,
+    memory::{Bus, HardwareModel, Memory},
+    state::{StateError, StateReader, StateWriter},
     utils::{get_flag, set_flag, set_flag_ref, Address, Byte, Word},
 };
 
@@ -37,7 +43,6 @@ const WINDOW_TILE_MAP_FLAG: Byte = 0b0100_0000;
 const WINDOW_ENABLE_FLAG: Byte = 0b0010_0000;
 const BGW_TILES_DATA_FLAG: Byte = 0b0001_0000;
 const BG_TILE_MAP_FLAG: Byte = 0b0000_1000;
-#[allow(dead_code)]
 const OBJ_SIZE_FLAG: Byte = 0b0000_0100;
 const OBJ_ENABLE_FLAG: Byte = 0b0000_0010;
 const BGW_ENABLE_FLAG: Byte = 0b0000_0001;
@@ -49,6 +54,7 @@ const OBP1_ADDRESS: Address = 0xFF49;
 // Object Attribute/Flags
 const OBJ_TILE_ADDRESS: Address = 0x8000;
 const OBJ_COUNT: usize = 40;
+const OBJ_PER_LINE: usize = 10;
 const OBJ_PRIORITY_FLAG: Byte = 0b1000_0000;
 const OBJ_YFLIP_FLAG: Byte = 0b0100_0000;
 const OBJ_XFLIP_FLAG: Byte = 0b0010_0000;
@@ -68,6 +74,14 @@ const DARK_GREY: Color = Color::RGB(48, 48, 48);
 const LIGHT_GREY: Color = Color::RGB(139, 139, 139);
 const WHITE: Color = Color::RGB(255, 255, 255);
 
+/// Layers that can be hidden for debugging
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Background,
+    Window,
+    Objects,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 enum PixelSource {
     /// When background is disabled
@@ -94,6 +108,33 @@ impl Pixel {
     }
 }
 
+impl PixelSource {
+    fn write_state(&self, state: &mut StateWriter) {
+        match *self {
+            Self::Background { enabled } => {
+                state.byte(0);
+                state.bool(enabled);
+            }
+            Self::Object { number } => {
+                state.byte(1);
+                state.usize(number);
+            }
+        }
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        Ok(match state.byte()? {
+            0 => Self::Background {
+                enabled: state.bool()?,
+            },
+            1 => Self::Object {
+                number: state.usize()?,
+            },
+            _ => return Err(StateError::InvalidValue("pixel source")),
+        })
+    }
+}
+
 impl fmt::Debug for Pixel {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.color_ref)?;
@@ -152,24 +193,24 @@ impl fmt::Debug for Tile {
 }
 
 impl Tile {
-    pub fn fetch_tile(memory: &Memory, pixel_source: PixelSource, address: Address) -> Self {
+    pub fn fetch_tile<B: Bus>(memory: &B, pixel_source: PixelSource, address: Address) -> Self {
         let default_tile = Pixel {
             color_ref: 0,
             pixel_source,
         };
         let mut tile = [[default_tile; 8]; 8];
 
-        for x in 0..8 {
+        for (x, row) in tile.iter_mut().enumerate() {
             let lsb_address = address + 2 * (x as Address);
             let msb_address = address + 2 * (x as Address) + 1;
 
-            let lsb = memory.read_byte(lsb_address);
-            let msb = memory.read_byte(msb_address);
+            let lsb = memory.ppu_read_byte(lsb_address);
+            let msb = memory.ppu_read_byte(msb_address);
 
-            for y in 0..8 {
+            for (y, pixel) in row.iter_mut().enumerate() {
                 let b = 7 - y;
                 let color_ref = ((msb >> b) & 1) * 2 + ((lsb >> b) & 1);
-                tile[x][y] = Pixel {
+                *pixel = Pixel {
                     color_ref,
                     pixel_source,
                 };
@@ -179,6 +220,25 @@ impl Tile {
         Self { tile }
     }
 
+    fn write_state(&self, state: &mut StateWriter) {
+        for pixel in self.tile.iter().flatten() {
+            state.byte(pixel.color_ref);
+            pixel.pixel_source.write_state(state);
+        }
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let mut tile = [[Pixel::new(0, PixelSource::Background { enabled: false }); 8]; 8];
+        for pixel in tile.iter_mut().flatten() {
+            let color_ref = state.byte()?;
+            if color_ref > 3 {
+                return Err(StateError::InvalidValue("tile pixel"));
+            }
+            *pixel = Pixel::new(color_ref, PixelSource::read_state(state)?);
+        }
+        Ok(Self { tile })
+    }
+
     pub fn get_range(&self, x: Range<usize>, y: usize) -> &[Pixel] {
         &self.tile[y][x]
     }
@@ -188,15 +248,11 @@ impl Tile {
             row.reverse();
         }
     }
-
-    pub fn flip_y(&mut self) {
-        self.tile.reverse();
-    }
 }
 
 pub trait FIFO {
-    fn next_line(&mut self, memory: &Memory);
-    fn pop(&mut self, memory: &Memory) -> Pixel;
+    fn next_line<B: Bus>(&mut self, memory: &B);
+    fn pop<B: Bus>(&mut self, memory: &B) -> Pixel;
 }
 
 struct BgFIFO {
@@ -206,7 +262,11 @@ struct BgFIFO {
 
     screen_pos: PixelPos,
     in_window: bool,
-    tile_cache: HashMap<TilePos, Tile>,
+    /// Window row to draw, only advances on lines where the window was drawn
+    window_line: usize,
+    /// Tiles fetched for the current row, keyed by tile map so background and window
+    /// tiles at the same position don't alias
+    tile_cache: HashMap<(Address, TilePos), Tile>,
 }
 
 impl BgFIFO {
@@ -218,27 +278,70 @@ impl BgFIFO {
             lcdc: 0,
             initialized: false,
             in_window: false,
+            window_line: 0,
             tile_cache: HashMap::new(),
         }
     }
-    fn get_scroll(memory: &Memory) -> (usize, usize) {
+    /// Position and cached tiles carried between lines, the fifo itself is refilled per line
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bool(self.initialized);
+        state.usize(self.screen_pos.x);
+        state.usize(self.screen_pos.y);
+        state.bool(self.in_window);
+        state.usize(self.window_line);
+        let mut keys: Vec<_> = self.tile_cache.keys().copied().collect();
+        keys.sort_by_key(|&(address, pos)| (address, pos.i, pos.j));
+        state.usize(keys.len());
+        for key in keys {
+            let (address, pos) = key;
+            state.word(address);
+            state.usize(pos.i);
+            state.usize(pos.j);
+            self.tile_cache[&key].write_state(state);
+        }
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let mut fifo = Self::new();
+        fifo.initialized = state.bool()?;
+        fifo.screen_pos = PixelPos {
+            x: state.usize()?,
+            y: state.usize()?,
+        };
+        fifo.in_window = state.bool()?;
+        fifo.window_line = state.usize()?;
+        for _ in 0..state.usize()? {
+            let address = state.word()?;
+            let pos = TilePos {
+                i: state.usize()?,
+                j: state.usize()?,
+            };
+            fifo.tile_cache
+                .insert((address, pos), Tile::read_state(state)?);
+        }
+        Ok(fifo)
+    }
+
+    fn get_scroll<B: Bus>(memory: &B) -> (usize, usize) {
         let scy = memory.read_byte(SCY_ADDRESS) as usize;
         let scx = memory.read_byte(SCX_ADDRESS) as usize;
         (scx, scy)
     }
-    fn get_viewport(memory: &Memory) -> (usize, usize) {
+    fn get_viewport<B: Bus>(memory: &B) -> (usize, usize) {
         let wy = memory.read_byte(WY_ADDRESS) as usize;
         let wx = memory.read_byte(WX_ADDRESS) as usize;
         (wx, wy)
     }
-    fn in_window(p: PixelPos, memory: &Memory) -> bool {
+    fn in_window<B: Bus>(p: PixelPos, memory: &B) -> bool {
         let (wx, wy) = Self::get_viewport(memory);
         let lcdc = memory.read_byte(LCDC_ADDRESS);
-        let window_enable = get_flag(lcdc, WINDOW_ENABLE_FLAG);
+        // on DMG the bgw flag disables the window as well, on CGB it only affects priority
+        let window_enable = get_flag(lcdc, WINDOW_ENABLE_FLAG)
+            && (get_flag(lcdc, BGW_ENABLE_FLAG) || memory.get_model() == HardwareModel::Cgb);
         window_enable && p.x + 7 >= wx && p.y >= wy
     }
 
-    fn fetch(&mut self, memory: &Memory) {
+    fn fetch<B: Bus>(&mut self, memory: &B) {
         let lcdc = memory.read_byte(LCDC_ADDRESS);
         let window_enabled = get_flag(lcdc, BGW_ENABLE_FLAG);
 
@@ -261,22 +364,22 @@ impl BgFIFO {
                 } else {
                     0x9800
                 };
-                let (wx, wy) = Self::get_viewport(memory);
+                let (wx, _) = Self::get_viewport(memory);
                 (
                     (self.screen_pos.x + self.fifo.len() + 7 - wx) % 255,
-                    (self.screen_pos.y - wy) % 255,
+                    self.window_line % 255,
                     window_map_address,
                 )
             };
             let fp = PixelPos { x: fx, y: fy };
             let tile_pos = fp.to_tile();
 
-            let tile = match self.tile_cache.entry(tile_pos) {
+            let tile = match self.tile_cache.entry((map_address, tile_pos)) {
                 Entry::Occupied(occ) => occ.into_mut(),
                 Entry::Vacant(vacant) => {
                     let tile_idx = tile_pos.i + tile_pos.j * 32;
                     let tile_num_address = map_address + (tile_idx as Address);
-                    let tile_num = memory.read_byte(tile_num_address);
+                    let tile_num = memory.ppu_read_byte(tile_num_address);
                     let tile_start_address = if get_flag(lcdc, BGW_TILES_DATA_FLAG) {
                         0x8000 + BYTES_PER_TILE * (tile_num as Address)
                     } else {
@@ -301,7 +404,7 @@ impl BgFIFO {
 
             // if last line, clear cache
             if ty == 7 {
-                self.tile_cache.remove(&tile_pos);
+                self.tile_cache.remove(&(map_address, tile_pos));
             }
         }
     }
@@ -309,8 +412,11 @@ impl BgFIFO {
 
 impl FIFO for BgFIFO {
     // must call before using
-    fn next_line(&mut self, memory: &Memory) {
+    fn next_line<B: Bus>(&mut self, memory: &B) {
         self.screen_pos = if self.initialized {
+            if self.in_window {
+                self.window_line += 1;
+            }
             self.screen_pos.next_line()
         } else {
             self.initialized = true;
@@ -318,11 +424,11 @@ impl FIFO for BgFIFO {
         };
         self.in_window = Self::in_window(self.screen_pos, memory);
         self.fifo.clear();
-        self.lcdc = Graphics::get_lcdc(memory);
+        self.lcdc = PPU::get_lcdc(memory);
 
         self.fetch(memory);
     }
-    fn pop(&mut self, memory: &Memory) -> Pixel {
+    fn pop<B: Bus>(&mut self, memory: &B) -> Pixel {
         if !self.in_window && Self::in_window(self.screen_pos, memory) {
             self.in_window = true;
             self.fifo.clear();
@@ -356,6 +462,56 @@ impl Object {
     }
 }
 
+/// Decoded object attributes, with its position on screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpriteInfo {
+    pub index: usize,
+    pub screen_x: isize,
+    pub screen_y: isize,
+    pub tile_num: Byte,
+    pub priority: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    pub palette: usize,
+}
+
+impl From<Object> for SpriteInfo {
+    fn from(obj: Object) -> Self {
+        Self {
+            index: obj.index,
+            screen_x: obj.x_pos as isize - 8,
+            screen_y: obj.y_pos as isize - 16,
+            tile_num: obj.tile_num as Byte,
+            priority: get_flag(obj.flag, OBJ_PRIORITY_FLAG),
+            y_flip: get_flag(obj.flag, OBJ_YFLIP_FLAG),
+            x_flip: get_flag(obj.flag, OBJ_XFLIP_FLAG),
+            palette: get_flag(obj.flag, OBJ_PALETTE_FLAG) as usize,
+        }
+    }
+}
+
+/// Decoded palette state, dmg shades go from 0 = white to 3 = black
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PaletteSnapshot {
+    pub bgp: [Byte; 4],
+    pub obp0: [Byte; 4],
+    pub obp1: [Byte; 4],
+    /// CGB background palettes of four 15 bit colors, only in CGB mode
+    pub cgb_bg: Option<[[Word; 4]; 8]>,
+    /// CGB object palettes of four 15 bit colors, only in CGB mode
+    pub cgb_obj: Option<[[Word; 4]; 8]>,
+}
+
+/// Shade of each of the four color indices in a dmg palette register
+fn decode_palette(palette: Byte) -> [Byte; 4] {
+    [
+        palette & 0b11,
+        (palette >> 2) & 0b11,
+        (palette >> 4) & 0b11,
+        (palette >> 6) & 0b11,
+    ]
+}
+
 pub struct ObjFIFO {
     fifo: VecDeque<Pixel>,
     lcdc: Byte,
@@ -374,6 +530,19 @@ impl ObjFIFO {
             obj_attr: HashMap::new(),
         }
     }
+    /// Only the line position outlives a scanline, objects are selected again per line
+    fn write_state(&self, state: &mut StateWriter) {
+        state.bool(self.initialized);
+        state.usize(self.screen_y);
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let mut fifo = Self::new();
+        fifo.initialized = state.bool()?;
+        fifo.screen_y = state.usize()?;
+        Ok(fifo)
+    }
+
     fn merge(p1: Pixel, p2: Pixel) -> Pixel {
         if p1.color_ref == 0 {
             p2
@@ -384,11 +553,44 @@ impl ObjFIFO {
     fn get_obj_attr(&self, obj_index: usize) -> Object {
         *self.obj_attr.get(&obj_index).unwrap()
     }
+
+    /// Object height in pixels, 8x16 objects when the OBJ size flag is set
+    fn obj_height(lcdc: Byte) -> usize {
+        if get_flag(lcdc, OBJ_SIZE_FLAG) {
+            16
+        } else {
+            8
+        }
+    }
+
+    /// Find the objects that intersect the line, in OAM order and at most 10
+    fn select_objects<B: Bus>(memory: &B, screen_y: usize) -> Vec<Object> {
+        let height = Self::obj_height(PPU::get_lcdc(memory));
+        let mut objects = Vec::new();
+        for obj_idx in 0..OBJ_COUNT {
+            let obj_address = OAM_ADDRESS + 4 * (obj_idx as Address);
+
+            let y_pos = memory.ppu_read_byte(obj_address) as usize;
+            let x_pos = memory.ppu_read_byte(obj_address + 1) as usize;
+            let tile_number = memory.ppu_read_byte(obj_address + 2) as Address;
+            let flag = memory.ppu_read_byte(obj_address + 3);
+
+            // the OAM scan only checks y, objects off screen in x still use up a slot
+            if y_pos <= screen_y + 16 && screen_y + 16 < y_pos + height {
+                objects.push(Object::new(obj_idx, x_pos, y_pos, tile_number, flag));
+            }
+
+            if objects.len() >= OBJ_PER_LINE {
+                break;
+            }
+        }
+        objects
+    }
 }
 
 impl FIFO for ObjFIFO {
     // must call before using, finds all objects that intersect
-    fn next_line(&mut self, memory: &Memory) {
+    fn next_line<B: Bus>(&mut self, memory: &B) {
         self.screen_y = if self.initialized {
             self.screen_y + 1
         } else {
@@ -397,72 +599,71 @@ impl FIFO for ObjFIFO {
         };
         self.fifo.clear();
         self.obj_attr.clear();
-        self.lcdc = Graphics::get_lcdc(memory);
+        self.lcdc = PPU::get_lcdc(memory);
 
         let mut line_pixels = [Pixel::new(0, PixelSource::Object { number: 0 }); SCREEN_WIDTH];
 
         if get_flag(self.lcdc, OBJ_ENABLE_FLAG) {
             // find all intersections
-            for obj_idx in 0..OBJ_COUNT {
-                let obj_address = OAM_ADDRESS + 4 * (obj_idx as Address);
-
-                let y_pos = memory.read_byte(obj_address) as usize;
-                let x_pos = memory.read_byte(obj_address + 1) as usize;
-                let tile_number = memory.read_byte(obj_address + 2) as Address;
-                let flag = memory.read_byte(obj_address + 3);
-
-                // TODO: modify for 16x8 objects
-                if y_pos <= self.screen_y + 16
-                    && self.screen_y + 8 < y_pos
-                    && !(x_pos == 0 || x_pos >= 168)
-                {
-                    let tile_start_address = OBJ_TILE_ADDRESS + BYTES_PER_TILE * tile_number;
-                    let mut tile = Tile::fetch_tile(
-                        memory,
-                        PixelSource::Object { number: obj_idx },
-                        tile_start_address,
-                    );
+            let height = Self::obj_height(self.lcdc);
+            let mut objects = Self::select_objects(memory, self.screen_y);
+            // on DMG the object with the smaller x is drawn on top, then the lower OAM index,
+            // CGB only uses the OAM index
+            if memory.get_model() == HardwareModel::Dmg {
+                objects.sort_by_key(|obj| (obj.x_pos, obj.index));
+            }
+            for obj in objects {
+                let (x_pos, y_pos) = (obj.x_pos, obj.y_pos);
+                if x_pos == 0 || x_pos >= 168 {
+                    continue;
+                }
+                // row within the object, y flip mirrors the whole 8 or 16 pixel span
+                let mut y = self.screen_y + 16 - y_pos;
+                if get_flag(obj.flag, OBJ_YFLIP_FLAG) {
+                    y = height - 1 - y;
+                }
+                // 8x16 objects ignore the low bit, the top tile is even and the bottom odd
+                let tile_num = if height == 16 {
+                    (obj.tile_num & 0xFE) | (y / 8) as Address
+                } else {
+                    obj.tile_num
+                };
+                let y = y % 8;
 
-                    if get_flag(flag, OBJ_XFLIP_FLAG) {
-                        tile.flip_x();
-                    }
-                    if get_flag(flag, OBJ_YFLIP_FLAG) {
-                        tile.flip_y();
-                    }
+                let tile_start_address = OBJ_TILE_ADDRESS + BYTES_PER_TILE * tile_num;
+                let mut tile = Tile::fetch_tile(
+                    memory,
+                    PixelSource::Object { number: obj.index },
+                    tile_start_address,
+                );
 
-                    let y = self.screen_y + 16 - y_pos;
-                    let xrange = if x_pos < 8 {
-                        8 - x_pos..8
-                    } else if x_pos > SCREEN_WIDTH {
-                        0..(8 + SCREEN_WIDTH) - x_pos
-                    } else {
-                        0..8
-                    };
+                if get_flag(obj.flag, OBJ_XFLIP_FLAG) {
+                    tile.flip_x();
+                }
 
-                    let tile_line = tile.get_range(0..8, y);
-                    for d in xrange {
-                        line_pixels[x_pos + d - 8] =
-                            Self::merge(line_pixels[x_pos + d - 8], tile_line[d]);
-                    }
+                let xrange = if x_pos < 8 {
+                    8 - x_pos..8
+                } else if x_pos > SCREEN_WIDTH {
+                    0..(8 + SCREEN_WIDTH) - x_pos
+                } else {
+                    0..8
+                };
 
-                    self.obj_attr.insert(
-                        obj_idx,
-                        Object::new(obj_idx, x_pos, y_pos, tile_number, flag),
-                    );
+                let tile_line = tile.get_range(0..8, y);
+                for d in xrange {
+                    line_pixels[x_pos + d - 8] =
+                        Self::merge(line_pixels[x_pos + d - 8], tile_line[d]);
                 }
 
-                if self.obj_attr.len() >= 10 {
-                    break;
-                }
+                self.obj_attr.insert(obj.index, obj);
             }
         }
 
         self.fifo.extend(line_pixels);
     }
 
-    fn pop(&mut self, _memory: &Memory) -> Pixel {
-        let p = self.fifo.pop_front().unwrap();
-        p
+    fn pop<B: Bus>(&mut self, _memory: &B) -> Pixel {
+        self.fifo.pop_front().unwrap()
     }
 }
 
@@ -488,6 +689,15 @@ impl PPUMode {
             Self::Mode3 { .. } => 3,
         }
     }
+
+    fn line(&self) -> usize {
+        match *self {
+            Self::Mode0 { line }
+            | Self::Mode1 { line }
+            | Self::Mode2 { line }
+            | Self::Mode3 { line } => line,
+        }
+    }
 }
 
 pub struct Graphics {
@@ -496,30 +706,119 @@ pub struct Graphics {
     pub event_pump: EventPump,
     pub texture_creator: TextureCreator<WindowContext>,
     pub timer: TimerSubsystem,
+    /// None when SDL has no game controller support
+    controller_subsystem: Option<GameControllerSubsystem>,
+    /// Open controllers, their events stop once they are dropped
+    controllers: Vec<GameController>,
 
     // gb related
+    ppu: PPU,
+    frame_skip: FrameSkip,
+    /// Copy of the last presented frame, the ppu buffer may be mid frame
+    displayed: Vec<Byte>,
+}
+
+/// Counts finished frames, only every nth one is presented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameSkip {
+    every: u32,
+    count: u32,
+}
+
+impl FrameSkip {
+    pub fn new(every: u32) -> Self {
+        Self {
+            every: every.max(1),
+            count: 0,
+        }
+    }
+
+    /// Count a finished frame, true if it should be presented
+    pub fn next_frame(&mut self) -> bool {
+        self.count = (self.count + 1) % self.every;
+        self.count == 0
+    }
+
+    /// Run the ppu, true if it finished a frame that should be presented
+    pub fn render(&mut self, ppu: &mut PPU, memory: &mut Memory, timestamp: u128) -> bool {
+        ppu.render(memory, timestamp) && self.next_frame()
+    }
+}
+
+/// Encode an RGB24 screen buffer as a png
+pub fn encode_png<W: Write>(writer: W, buffer: &[Byte]) -> io::Result<()> {
+    assert_eq!(buffer.len(), PIXEL_COUNT * 3);
+    let mut encoder = png::Encoder::new(writer, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(buffer)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Transitions kept in the PPU transition log
+const TRANSITION_LOG_SIZE: usize = 32;
+
+/// A PPU mode transition, logged for debugging the timing state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PPUTransition {
+    pub from: PPUMode,
+    pub to: PPUMode,
+    pub line: usize,
+    pub clock_diff: u128,
+}
+
+/// Pixel Processing Unit, renders the screen buffer without depending on SDL
+pub struct PPU {
     line_y: usize,
     screen_buffer: [Byte; SCREEN_WIDTH * SCREEN_HEIGHT * 3],
     last_timestamp: u128,
     bg_fifo: BgFIFO,
     obj_fifo: ObjFIFO,
     last_ppu_mode: PPUMode,
+    /// LCDC enable flag seen on the last render
+    lcd_enabled: bool,
+    /// First line after the lcd is enabled, which skips the OAM scan
+    first_line: bool,
+    /// Most recent mode transitions, oldest first
+    transitions: VecDeque<PPUTransition>,
+    /// Debug layer toggles, hidden layers are drawn transparent
+    show_bg: bool,
+    show_window: bool,
+    show_obj: bool,
 }
 
 impl Graphics {
-    pub fn new(context: &Sdl) -> Self {
+    /// Window scale used unless another one is set
+    pub const DEFAULT_SCALE: u32 = 2;
+    pub const MAX_SCALE: u32 = 8;
+    /// Rumble stays on until the cartridge turns it off, SDL overflows on u32::MAX
+    const RUMBLE_DURATION_MS: u32 = 60_000;
+
+    pub fn new(context: &Sdl, scale: u32) -> Self {
+        assert!((1..=Self::MAX_SCALE).contains(&scale));
         // Set hint for vsync
         sdl2::hint::set("SDL_HINT_RENDER_VSYNC", "1");
 
         // Create window and renderer
         let video_subsystem = context.video().unwrap();
         let window = video_subsystem
-            .window("GB-rs", SCREEN_WIDTH as u32 * 2, SCREEN_HEIGHT as u32 * 2)
+            .window(
+                "GB-rs",
+                SCREEN_WIDTH as u32 * scale,
+                SCREEN_HEIGHT as u32 * scale,
+            )
             .position_centered()
+            .resizable()
             .build()
             .unwrap();
 
         let mut canvas = window.into_canvas().build().unwrap();
+        // keep the aspect ratio when the window is resized
+        canvas
+            .set_logical_size(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+            .unwrap();
 
         canvas.set_draw_color(BLACK);
         canvas.clear();
@@ -530,19 +829,242 @@ impl Graphics {
 
         let timer = context.timer().unwrap();
 
-        Self {
+        let controller_subsystem = context
+            .game_controller()
+            .map_err(|e| warn!("Game controllers unavailable due to {}", e))
+            .ok();
+
+        let mut graphics = Self {
             context: context.clone(),
             canvas,
             event_pump,
             texture_creator,
             timer,
+            controller_subsystem,
+            controllers: Vec::new(),
+            ppu: PPU::new(),
+            frame_skip: FrameSkip::new(1),
+            displayed: vec![0; PIXEL_COUNT * 3],
+        };
+        // controllers plugged in later are opened on ControllerDeviceAdded
+        let joysticks = match &graphics.controller_subsystem {
+            Some(subsystem) => subsystem.num_joysticks().unwrap_or(0),
+            None => 0,
+        };
+        for index in 0..joysticks {
+            graphics.open_controller(index);
+        }
+        graphics
+    }
+
+    /// Open the joystick at index if it is a game controller and not open yet
+    pub fn open_controller(&mut self, index: u32) {
+        let Some(subsystem) = &self.controller_subsystem else {
+            return;
+        };
+        if !subsystem.is_game_controller(index) {
+            return;
+        }
+        match subsystem.open(index) {
+            Ok(controller) => {
+                let id = controller.instance_id();
+                if self.controllers.iter().all(|open| open.instance_id() != id) {
+                    info!("Opened controller {}", controller.name());
+                    self.controllers.push(controller);
+                }
+            }
+            Err(e) => warn!("Unable to open controller {} due to {}", index, e),
+        }
+    }
+
+    /// Drop a disconnected controller by its instance id
+    pub fn close_controller(&mut self, id: u32) {
+        self.controllers
+            .retain(|controller| controller.instance_id() != id);
+    }
+
+    /// Turn rumble on or off on every open controller, false if none is open
+    pub fn set_rumble(&mut self, on: bool) -> bool {
+        let strength = if on { u16::MAX } else { 0 };
+        for controller in &mut self.controllers {
+            if let Err(e) = controller.set_rumble(strength, strength, Self::RUMBLE_DURATION_MS) {
+                debug!("Unable to rumble {} due to {}", controller.name(), e);
+            }
+        }
+        !self.controllers.is_empty()
+    }
+
+    /// Resize the window to an integer multiple of the screen
+    pub fn set_scale(&mut self, scale: u32) {
+        assert!((1..=Self::MAX_SCALE).contains(&scale));
+        self.canvas
+            .window_mut()
+            .set_size(SCREEN_WIDTH as u32 * scale, SCREEN_HEIGHT as u32 * scale)
+            .unwrap();
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
+    }
+
+    /// Show or hide a layer for debugging, returns whether it is now shown
+    pub fn toggle_layer(&mut self, layer: Layer) -> bool {
+        self.ppu.toggle_layer(layer)
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    /// Swap in a restored ppu, e.g. from a save state
+    pub fn restore_ppu(&mut self, ppu: PPU) {
+        self.ppu = ppu;
+    }
+
+    /// Present only every nth frame, the ppu still draws all of them
+    pub fn set_frame_skip(&mut self, every: u32) {
+        self.frame_skip = FrameSkip::new(every);
+    }
+
+    /// Run the ppu and present the screen buffer on vblank
+    pub fn render(&mut self, memory: &mut Memory, timestamp: u128) {
+        if self.frame_skip.render(&mut self.ppu, memory, timestamp) {
+            self.present();
+        }
+    }
+
+    fn present(&mut self) {
+        let mut texture = self
+            .texture_creator
+            .create_texture_target(
+                PixelFormatEnum::RGB24,
+                SCREEN_WIDTH as u32,
+                SCREEN_HEIGHT as u32,
+            )
+            .unwrap();
+        texture
+            .update(None, self.ppu.screen_buffer(), SCREEN_WIDTH * 3)
+            .unwrap();
+        self.canvas.copy(&texture, None, None).unwrap();
+        self.canvas.present();
+        self.displayed.copy_from_slice(self.ppu.screen_buffer());
+    }
+
+    /// Save the frame currently on screen as a 160x144 png
+    pub fn screenshot(&self, path: &Path) -> io::Result<()> {
+        encode_png(BufWriter::new(File::create(path)?), &self.displayed)
+    }
+
+    /// Current dmg palettes, and the CGB palettes when running as CGB
+    pub fn palettes(memory: &Memory) -> PaletteSnapshot {
+        let cgb_palettes = |object| {
+            let mut palettes = [[0; 4]; 8];
+            for (i, palette) in palettes.iter_mut().enumerate() {
+                *palette = memory.cgb_palette(object, i);
+            }
+            palettes
+        };
+        let cgb = memory.get_model() == HardwareModel::Cgb;
+        PaletteSnapshot {
+            bgp: decode_palette(memory.read_byte(BG_PALETTE_ADDRESS)),
+            obp0: decode_palette(memory.read_byte(OBP0_ADDRESS)),
+            obp1: decode_palette(memory.read_byte(OBP1_ADDRESS)),
+            cgb_bg: cgb.then(|| cgb_palettes(false)),
+            cgb_obj: cgb.then(|| cgb_palettes(true)),
+        }
+    }
+
+    /// Sprites selected for the given line, in OAM order and capped at 10 per line.
+    /// Sprites off screen in x are included, they count toward the cap
+    pub fn sprites_on_line(memory: &Memory, line: usize) -> Vec<SpriteInfo> {
+        ObjFIFO::select_objects(memory, line)
+            .into_iter()
+            .map(SpriteInfo::from)
+            .collect()
+    }
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PPU {
+    pub fn new() -> Self {
+        Self {
             screen_buffer: [0; PIXEL_COUNT * 3],
             line_y: 0,
             last_timestamp: 0,
             bg_fifo: BgFIFO::new(),
             obj_fifo: ObjFIFO::new(),
             last_ppu_mode: PPUMode::Mode1 { line: 153 },
+            lcd_enabled: false,
+            first_line: false,
+            transitions: VecDeque::with_capacity(TRANSITION_LOG_SIZE),
+            show_bg: true,
+            show_window: true,
+            show_obj: true,
+        }
+    }
+
+    /// Rendering state, the transition log and layer toggles are not part of it
+    pub fn write_state(&self, state: &mut StateWriter) {
+        state.usize(self.line_y);
+        state.bytes(&self.screen_buffer);
+        state.u128(self.last_timestamp);
+        state.byte(self.last_ppu_mode.get_num());
+        state.usize(self.last_ppu_mode.line());
+        state.bool(self.lcd_enabled);
+        state.bool(self.first_line);
+        self.bg_fifo.write_state(state);
+        self.obj_fifo.write_state(state);
+    }
+
+    pub fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let mut ppu = Self::new();
+        ppu.line_y = state.usize()?;
+        ppu.screen_buffer
+            .copy_from_slice(state.bytes(PIXEL_COUNT * 3)?);
+        ppu.last_timestamp = state.u128()?;
+        let mode = state.byte()?;
+        let line = state.usize()?;
+        ppu.last_ppu_mode = match mode {
+            0 => PPUMode::Mode0 { line },
+            1 => PPUMode::Mode1 { line },
+            2 => PPUMode::Mode2 { line },
+            3 => PPUMode::Mode3 { line },
+            _ => return Err(StateError::InvalidValue("ppu mode")),
+        };
+        ppu.lcd_enabled = state.bool()?;
+        ppu.first_line = state.bool()?;
+        ppu.bg_fifo = BgFIFO::read_state(state)?;
+        ppu.obj_fifo = ObjFIFO::read_state(state)?;
+        if ppu.line_y > 153 || line > 153 {
+            return Err(StateError::InvalidValue("ppu line"));
         }
+        Ok(ppu)
+    }
+
+    /// Screen buffer in RGB24
+    pub fn screen_buffer(&self) -> &[Byte] {
+        &self.screen_buffer
+    }
+
+    /// Show or hide a layer, returns whether it is now shown
+    pub fn toggle_layer(&mut self, layer: Layer) -> bool {
+        let shown = match layer {
+            Layer::Background => &mut self.show_bg,
+            Layer::Window => &mut self.show_window,
+            Layer::Objects => &mut self.show_obj,
+        };
+        *shown = !*shown;
+        *shown
+    }
+
+    /// Most recent mode transitions, oldest first
+    pub fn recent_transitions(&self) -> &VecDeque<PPUTransition> {
+        &self.transitions
     }
 
     /// Render according to gb specifications [pandocs](https://gbdev.io/pandocs/Rendering.html)
@@ -550,13 +1072,29 @@ impl Graphics {
     /// First 20 mcycles are OAM scan,
     /// Between 20-72/92 mcycles are pixel rendering
     /// Between 72/92-114 mcycles is HBlank (do nothing)
-    pub fn render(&mut self, memory: &mut Memory, timestamp: u128) {
+    /// Returns true when entering vblank, i.e. the frame is ready to be presented
+    pub fn render(&mut self, memory: &mut Memory, timestamp: u128) -> bool {
+        let mut frame_ready = false;
+
+        let lcd_enabled = get_flag(Self::get_lcdc(memory), LCDC_ENABLE_FLAG);
+        if lcd_enabled && !self.lcd_enabled {
+            self.enable_lcd(memory, timestamp);
+        } else if !lcd_enabled && self.lcd_enabled {
+            self.disable_lcd(memory);
+        }
+        self.lcd_enabled = lcd_enabled;
+        if !lcd_enabled {
+            // the ppu is stopped, LY and the STAT mode stay at 0
+            return false;
+        }
+
         let clock_diff = timestamp - self.last_timestamp;
 
         if clock_diff >= SCANLINE_CYCLES {
             // to next line
             self.last_timestamp += SCANLINE_CYCLES;
             self.line_y += 1;
+            self.first_line = false;
         }
 
         if self.line_y > 153 {
@@ -570,6 +1108,16 @@ impl Graphics {
         let current_ppu_mode = self.get_mode(clock_diff);
 
         if self.last_ppu_mode != current_ppu_mode {
+            if self.transitions.len() == TRANSITION_LOG_SIZE {
+                self.transitions.pop_front();
+            }
+            self.transitions.push_back(PPUTransition {
+                from: self.last_ppu_mode,
+                to: current_ppu_mode,
+                line: self.line_y,
+                clock_diff,
+            });
+
             // PPU Mode transitions
             match (self.last_ppu_mode, current_ppu_mode) {
                 (PPUMode::Mode1 { line: l1 }, PPUMode::Mode2 { line: l2 })
@@ -582,6 +1130,10 @@ impl Graphics {
                     // draw scanline
                     self.draw_scanline(memory);
                 }
+                (PPUMode::Mode0 { line: 0 }, PPUMode::Mode3 { line: 0 }) if self.first_line => {
+                    // draw the first scanline after the lcd is enabled
+                    self.draw_scanline(memory);
+                }
                 (PPUMode::Mode3 { line: l1 }, PPUMode::Mode0 { line: l2 }) if l1 == l2 => {
                     // finish draw pixel to hblank
                 }
@@ -593,38 +1145,52 @@ impl Graphics {
                     // render to screen if vblank
                     self.set_lyc(memory);
                     self.set_vblank_int(memory);
-                    let mut texture = self
-                        .texture_creator
-                        .create_texture_target(
-                            PixelFormatEnum::RGB24,
-                            SCREEN_WIDTH as u32,
-                            SCREEN_HEIGHT as u32,
-                        )
-                        .unwrap();
-                    texture
-                        .update(None, &self.screen_buffer, SCREEN_WIDTH * 3)
-                        .unwrap();
-                    self.canvas.copy(&texture, None, None).unwrap();
-                    self.canvas.present();
+                    frame_ready = true;
                 }
                 (PPUMode::Mode1 { line: l1 }, PPUMode::Mode1 { line: l2 }) if l1 + 1 == l2 => {
                     // newline in vblank mode
                     self.set_lyc(memory);
                 }
                 _ => panic!(
-                    "PPU Transition Error {:?} {:?}, Clock Diff {:?} at line {:?}",
-                    self.last_ppu_mode, current_ppu_mode, clock_diff, self.line_y
+                    "PPU Transition Error {:?} {:?}, Clock Diff {:?} at line {:?}, recent transitions {:#?}",
+                    self.last_ppu_mode, current_ppu_mode, clock_diff, self.line_y, self.transitions
                 ),
             }
             self.last_ppu_mode = current_ppu_mode;
             self.set_ppu(current_ppu_mode, memory);
         }
+        frame_ready
+    }
+
+    /// Restart at line 0 when the lcd is switched on, the first line stays in mode 0
+    /// instead of scanning OAM
+    fn enable_lcd(&mut self, memory: &mut Memory, timestamp: u128) {
+        self.last_timestamp = timestamp;
+        self.line_y = 0;
+        self.first_line = true;
+        self.bg_fifo = BgFIFO::new();
+        self.obj_fifo = ObjFIFO::new();
+        self.last_ppu_mode = PPUMode::Mode0 { line: 0 };
+
+        let stat_flag = memory.read_byte(LCD_STATUS_ADDRESS) & !0b11;
+        memory.write_byte(LCD_STATUS_ADDRESS, stat_flag);
+        self.set_lyc(memory);
+    }
+
+    /// Stop the ppu when the lcd is switched off, LY and the STAT mode read 0
+    fn disable_lcd(&mut self, memory: &mut Memory) {
+        self.line_y = 0;
+        let stat_flag = memory.read_byte(LCD_STATUS_ADDRESS) & !0b11;
+        memory.write_byte(LCD_STATUS_ADDRESS, stat_flag);
+        memory.write_byte(LY_ADDRESS, 0);
     }
 
     fn get_mode(&self, clock_diff: u128) -> PPUMode {
         assert!(clock_diff <= SCANLINE_CYCLES);
         if self.line_y >= 144 {
             PPUMode::Mode1 { line: self.line_y }
+        } else if self.first_line && clock_diff <= 20 {
+            PPUMode::Mode0 { line: self.line_y }
         } else if clock_diff <= 20 {
             PPUMode::Mode2 { line: self.line_y }
         } else if clock_diff < 77 {
@@ -634,13 +1200,26 @@ impl Graphics {
         }
     }
 
-    fn draw_scanline(&mut self, memory: &mut Memory) {
+    /// Draw the current line into the screen buffer
+    pub fn draw_scanline(&mut self, memory: &mut Memory) {
         // draw line to screen_buffer
         self.bg_fifo.next_line(memory);
         self.obj_fifo.next_line(memory);
         for x in 0..SCREEN_WIDTH {
-            let bg_pixel = self.bg_fifo.pop(memory);
-            let obj_pixel = self.obj_fifo.pop(memory);
+            let mut bg_pixel = self.bg_fifo.pop(memory);
+            let mut obj_pixel = self.obj_fifo.pop(memory);
+            let bg_shown = if self.bg_fifo.in_window {
+                self.show_window
+            } else {
+                self.show_bg
+            };
+            if !bg_shown {
+                // drawn like a disabled background, white on DMG and below every object
+                bg_pixel = Pixel::new(0, PixelSource::Background { enabled: false });
+            }
+            if !self.show_obj {
+                obj_pixel.color_ref = 0;
+            }
             let pixel = self.mix(bg_pixel, obj_pixel);
             let color = self.pixel_to_color(pixel, memory);
 
@@ -662,13 +1241,11 @@ impl Graphics {
     fn pixel_to_color(&self, pixel: Pixel, memory: &mut Memory) -> Color {
         let palette = match pixel.pixel_source {
             PixelSource::Background { enabled } => {
-                let palette = memory.read_byte(BG_PALETTE_ADDRESS);
-                if enabled {
-                    palette
-                } else {
-                    // background is diabled, just use black
-                    0xFF
+                if !enabled && memory.get_model() == HardwareModel::Dmg {
+                    // background and window are blank on DMG, whatever the tile data and BGP
+                    return WHITE;
                 }
+                memory.read_byte(BG_PALETTE_ADDRESS)
             }
             PixelSource::Object { number } => {
                 let obj_flag = self.obj_fifo.get_obj_attr(number).flag;
@@ -682,13 +1259,7 @@ impl Graphics {
             }
         };
 
-        let color_idx = match pixel.color_ref {
-            0 => palette & 0b11,
-            1 => (palette >> 2) & 0b11,
-            2 => (palette >> 4) & 0b11,
-            3 => (palette >> 6) & 0b11,
-            _ => panic!(),
-        };
+        let color_idx = decode_palette(palette)[pixel.color_ref as usize];
         match color_idx {
             0 => WHITE,
             1 => LIGHT_GREY,
@@ -746,7 +1317,7 @@ impl Graphics {
         memory.write_byte(INTERRUPT_FLAG_ADDRESS, int_flag);
     }
 
-    fn get_lcdc(memory: &Memory) -> Byte {
+    fn get_lcdc<B: Bus>(memory: &B) -> Byte {
         memory.read_byte(LCDC_ADDRESS)
     }
 
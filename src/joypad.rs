@@ -1,10 +1,16 @@
 use std::collections::{HashMap, HashSet};
 
-use sdl2::keyboard::Keycode;
+use sdl2::{
+    controller::{Axis, Button},
+    keyboard::Keycode,
+};
+
+use log::debug;
 
 use crate::{
     cpu::{INTERRUPT_FLAG_ADDRESS, JOYPAD_FLAG},
     memory::Memory,
+    state::{StateError, StateReader, StateWriter},
     utils::{get_flag, set_flag, Address, Byte},
 };
 
@@ -22,9 +28,144 @@ pub const B_BUTTON: Byte = 0b1101_1101;
 pub const SELECT_BUTTON: Byte = 0b1101_1011;
 pub const START_BUTTON: Byte = 0b1101_0111;
 
+/// Stick deflection past which the left stick presses the D-pad, about 25%
+const STICK_DEAD_ZONE: i16 = 8000;
+
+// ----- sgb packets -----
+const SGB_PACKET_SIZE: usize = 16;
+const SGB_MLT_REQ: Byte = 0x11;
+
+/// SGB command packets sent bit by bit through P14/P15, only MLT_REQ is handled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SgbLink {
+    /// Last P14/P15 bits written
+    select: Byte,
+    packet: [Byte; SGB_PACKET_SIZE],
+    /// Bits of the packet received so far, None outside of a packet
+    bit: Option<usize>,
+    /// Number of joypads requested by MLT_REQ (1, 2 or 4)
+    players: Byte,
+    /// Joypad currently reported while P14 and P15 are high
+    player: Byte,
+}
+
+impl Default for SgbLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SgbLink {
+    pub fn new() -> Self {
+        Self {
+            select: DPAD_FLAG | BUTTONS_FLAG,
+            packet: [0; SGB_PACKET_SIZE],
+            bit: None,
+            players: 1,
+            player: 0,
+        }
+    }
+
+    /// Follow a write to P1, only changes of P14/P15 matter
+    pub fn write(&mut self, byte: Byte) {
+        let select = byte & (DPAD_FLAG | BUTTONS_FLAG);
+        let previous = self.select;
+        if select == previous {
+            return;
+        }
+        self.select = select;
+
+        let idle = DPAD_FLAG | BUTTONS_FLAG;
+        if select == 0 {
+            // reset pulse, starts a packet
+            self.packet = [0; SGB_PACKET_SIZE];
+            self.bit = Some(0);
+        } else if previous == idle && select != idle {
+            // P15 low is a 1, P14 low is a 0
+            match self.bit {
+                Some(bit) if bit < SGB_PACKET_SIZE * 8 => {
+                    if select == DPAD_FLAG {
+                        self.packet[bit / 8] |= 1 << (bit % 8);
+                    }
+                    self.bit = Some(bit + 1);
+                }
+                // stop bit
+                Some(_) => {
+                    self.bit = None;
+                    self.handle_packet();
+                }
+                None => (),
+            }
+        }
+
+        // the next joypad is selected when P15 goes high outside of a packet
+        if self.bit.is_none() && previous & BUTTONS_FLAG == 0 && select & BUTTONS_FLAG != 0 {
+            self.player = (self.player + 1) % self.players;
+        }
+    }
+
+    fn handle_packet(&mut self) {
+        let command = self.packet[0] >> 3;
+        debug!("SGB command {:#04X?}", command);
+        if command == SGB_MLT_REQ {
+            self.players = match self.packet[1] & 0b11 {
+                1 => 2,
+                3 => 4,
+                _ => 1,
+            };
+            self.player = 0;
+        }
+    }
+
+    pub fn write_state(&self, state: &mut StateWriter) {
+        state.byte(self.select);
+        state.bytes(&self.packet);
+        state.bool(self.bit.is_some());
+        if let Some(bit) = self.bit {
+            state.usize(bit);
+        }
+        state.byte(self.players);
+        state.byte(self.player);
+    }
+
+    pub fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let link = Self {
+            select: state.byte()?,
+            packet: state.bytes(SGB_PACKET_SIZE)?.try_into().unwrap(),
+            bit: match state.bool()? {
+                true => Some(state.usize()?),
+                false => None,
+            },
+            players: state.byte()?,
+            player: state.byte()?,
+        };
+        if !matches!(link.players, 1 | 2 | 4) || link.player >= link.players {
+            return Err(StateError::InvalidValue("sgb joypad"));
+        }
+        Ok(link)
+    }
+
+    /// Id of the current joypad as read in the low nibble of P1, 0xF for the first
+    pub fn player_id(&self) -> Byte {
+        0xF - self.player
+    }
+}
+
 pub struct Joypad {
     last_keys: HashSet<Keycode>,
     code_keys: HashMap<Byte, Keycode>,
+    /// Buttons held on a game controller, D-pad and face buttons
+    controller_buttons: HashSet<Byte>,
+    /// D-pad directions held with the left stick
+    stick_buttons: HashSet<Byte>,
+    /// Report left+right and up+down, which a real D-pad can not press
+    allow_opposing: bool,
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Joypad {
@@ -41,63 +182,119 @@ impl Joypad {
                 (SELECT_BUTTON, Keycode::U),
                 (START_BUTTON, Keycode::I),
             ]),
+            controller_buttons: HashSet::new(),
+            stick_buttons: HashSet::new(),
+            allow_opposing: false,
         }
     }
 
-    /// Update button register
+    /// Let opposing D-pad directions through, by default both are released
+    pub fn set_allow_opposing(&mut self, allow: bool) {
+        self.allow_opposing = allow;
+    }
+
+    /// Held on the keyboard, the controller buttons or the stick
+    fn held(&self, button: Byte) -> bool {
+        self.last_keys.contains(&self.code_keys[&button])
+            || self.controller_buttons.contains(&button)
+            || self.stick_buttons.contains(&button)
+    }
+
+    /// Held, unless the opposite direction is held too and that is filtered
+    fn pressed(&self, button: Byte) -> bool {
+        let opposite = match button {
+            LEFT_BUTTON => RIGHT_BUTTON,
+            RIGHT_BUTTON => LEFT_BUTTON,
+            UP_BUTTON => DOWN_BUTTON,
+            DOWN_BUTTON => UP_BUTTON,
+            _ => return self.held(button),
+        };
+        self.held(button) && (self.allow_opposing || !self.held(opposite))
+    }
+
+    /// Joypad interrupt for a newly pressed button, P1 bit 4 or 5 tells the group apart
+    fn request_interrupt(group_flag: Byte, memory: &mut Memory) {
+        if get_flag(memory.read_byte(JOYPAD_REGISTER_ADDRESS), group_flag) {
+            let mut int_flag = memory.read_byte(INTERRUPT_FLAG_ADDRESS);
+            set_flag(&mut int_flag, JOYPAD_FLAG);
+            memory.write_byte(INTERRUPT_FLAG_ADDRESS, int_flag);
+        }
+    }
+
+    /// Group flag of a button code, the one bit of P14/P15 it clears
+    fn group_flag(button: Byte) -> Byte {
+        !button & (DPAD_FLAG | BUTTONS_FLAG)
+    }
+
+    /// Update the pressed lines, P1 reads them through the selected group
     pub fn update(&mut self, memory: &mut Memory) {
-        let joypad_flags = memory.read_byte(JOYPAD_REGISTER_ADDRESS);
-        let new_flags = if !get_flag(joypad_flags, DPAD_FLAG) {
-            let mut flag = joypad_flags | 0xF;
-            for dpad in [UP_BUTTON, DOWN_BUTTON, LEFT_BUTTON, RIGHT_BUTTON] {
-                if self.last_keys.contains(self.code_keys.get(&dpad).unwrap()) {
-                    flag &= dpad;
-                }
-            }
-            flag
-        } else if !get_flag(joypad_flags, BUTTONS_FLAG) {
-            let mut flag = joypad_flags | 0xF;
-            for btn in [A_BUTTON, B_BUTTON, SELECT_BUTTON, START_BUTTON] {
-                if self.last_keys.contains(self.code_keys.get(&btn).unwrap()) {
-                    flag &= btn;
-                }
-            }
-            flag
-        } else {
-            joypad_flags | 0xF
+        let lines = |buttons: [Byte; 4]| {
+            buttons
+                .into_iter()
+                .filter(|&button| self.pressed(button))
+                .fold(0xF, |lines, button| lines & button)
         };
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, new_flags);
+        memory.set_joypad_state(
+            lines([UP_BUTTON, DOWN_BUTTON, LEFT_BUTTON, RIGHT_BUTTON]),
+            lines([A_BUTTON, B_BUTTON, SELECT_BUTTON, START_BUTTON]),
+        );
     }
 
     /// Handle button press
     pub fn handle_button(&mut self, keycode: Keycode, down: bool, memory: &mut Memory) {
-        let joypad_flags = memory.read_byte(JOYPAD_REGISTER_ADDRESS);
-        match keycode {
-            Keycode::A | Keycode::W | Keycode::D | Keycode::S => {
-                if down {
-                    if !self.last_keys.contains(&keycode) && get_flag(joypad_flags, DPAD_FLAG) {
-                        let mut int_flag = memory.read_byte(INTERRUPT_FLAG_ADDRESS);
-                        set_flag(&mut int_flag, JOYPAD_FLAG);
-                        memory.write_byte(INTERRUPT_FLAG_ADDRESS, int_flag);
-                    }
-                    self.last_keys.insert(keycode);
-                } else {
-                    self.last_keys.remove(&keycode);
-                }
+        let group_flag = match keycode {
+            Keycode::A | Keycode::W | Keycode::D | Keycode::S => DPAD_FLAG,
+            Keycode::J | Keycode::K | Keycode::U | Keycode::I => BUTTONS_FLAG,
+            _ => return,
+        };
+        if down {
+            if !self.last_keys.contains(&keycode) {
+                Self::request_interrupt(group_flag, memory);
             }
-            Keycode::J | Keycode::K | Keycode::U | Keycode::I => {
-                if down {
-                    if !self.last_keys.contains(&keycode) && get_flag(joypad_flags, BUTTONS_FLAG) {
-                        let mut int_flag = memory.read_byte(INTERRUPT_FLAG_ADDRESS);
-                        set_flag(&mut int_flag, JOYPAD_FLAG);
-                        memory.write_byte(INTERRUPT_FLAG_ADDRESS, int_flag);
-                    }
-                    self.last_keys.insert(keycode);
-                } else {
-                    self.last_keys.remove(&keycode);
-                }
+            self.last_keys.insert(keycode);
+        } else {
+            self.last_keys.remove(&keycode);
+        }
+    }
+
+    /// Handle a game controller button, the D-pad, A, B, Back for select and Start
+    pub fn handle_controller_button(&mut self, button: Button, down: bool, memory: &mut Memory) {
+        let button = match button {
+            Button::DPadUp => UP_BUTTON,
+            Button::DPadDown => DOWN_BUTTON,
+            Button::DPadLeft => LEFT_BUTTON,
+            Button::DPadRight => RIGHT_BUTTON,
+            Button::A => A_BUTTON,
+            Button::B => B_BUTTON,
+            Button::Back => SELECT_BUTTON,
+            Button::Start => START_BUTTON,
+            _ => return,
+        };
+        if down {
+            if self.controller_buttons.insert(button) {
+                Self::request_interrupt(Self::group_flag(button), memory);
+            }
+        } else {
+            self.controller_buttons.remove(&button);
+        }
+    }
+
+    /// Handle the left stick, pushed past the dead zone it holds a D-pad direction
+    pub fn handle_controller_axis(&mut self, axis: Axis, value: i16, memory: &mut Memory) {
+        let (negative, positive) = match axis {
+            Axis::LeftX => (LEFT_BUTTON, RIGHT_BUTTON),
+            Axis::LeftY => (UP_BUTTON, DOWN_BUTTON),
+            _ => return,
+        };
+        for (button, held) in [
+            (negative, value < -STICK_DEAD_ZONE),
+            (positive, value > STICK_DEAD_ZONE),
+        ] {
+            if !held {
+                self.stick_buttons.remove(&button);
+            } else if self.stick_buttons.insert(button) {
+                Self::request_interrupt(DPAD_FLAG, memory);
             }
-            _ => (),
         }
     }
 }
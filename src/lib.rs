@@ -1,9 +1,12 @@
+pub mod apu;
 pub mod clock;
 pub mod cpu;
 pub mod gb;
 pub mod graphics;
 pub mod joypad;
 pub mod memory;
+pub mod serial;
+pub mod state;
 pub mod utils;
 
 mod test;
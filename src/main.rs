@@ -1,8 +1,17 @@
-use std::{fs, path::Path};
+use std::{
+    fs,
+    io::{self, BufWriter},
+    path::Path,
+};
 
 use clap::{App, Arg};
-use gb_rs::gb::GameBoy;
-use log::{debug, info};
+use gb_rs::{
+    cpu::SizedInstruction,
+    gb::{read_rom_file, GameBoy, SymbolTable},
+    graphics::Graphics,
+    memory::{HardwareModel, RomWriteCheck},
+};
+use log::{info, warn};
 
 fn main() -> Result<(), String> {
     env_logger::init();
@@ -27,6 +36,132 @@ fn main() -> Result<(), String> {
                 .help("Sets the Boot ROM file to read")
                 .default_value(Path::new("assets").join("dmg_boot.bin").to_str().unwrap()),
         )
+        .arg(
+            Arg::with_name("skip_boot")
+                .long("skip-boot")
+                .help("Starts at the cartridge entry point without a boot rom")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("disasm")
+                .long("disasm")
+                .value_name("OUT")
+                .help("Writes a disassembly of the rom to OUT and exits")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Refuses roms with bad header or global checksums, or a size other than the header's")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("model")
+                .long("model")
+                .value_name("MODEL")
+                .help("Runs as a DMG or CGB instead of the model the rom header asks for")
+                .takes_value(true)
+                .possible_values(["dmg", "cgb"])
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("strict_rom_writes")
+                .long("strict-rom-writes")
+                .value_name("MODE")
+                .help("Reports writes to rom on cartridges without an mbc")
+                .takes_value(true)
+                .possible_values(["off", "log", "break"])
+                .default_value("off")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("no_access_restrictions")
+                .long("no-access-restrictions")
+                .help("Lets the cpu access vram and OAM while the ppu is using them")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("instant_dma")
+                .long("instant-dma")
+                .help("Copies OAM DMA transfers at once instead of over 160 machine cycles")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("symbols")
+                .long("symbols")
+                .value_name("SYM")
+                .help("Sets the symbol file used by the debugger and --disasm")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("breakpoint")
+                .long("break")
+                .alias("break-at")
+                .value_name("LABEL|ADDR")
+                .help("Adds a debugger breakpoint at a label or hex address")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("start_paused")
+                .long("start-paused")
+                .help("Pauses before the first instruction")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .value_name("N")
+                .help("Sets the window size to N times the screen, from 1 to 8")
+                .takes_value(true)
+                .default_value("2")
+                .validator(|scale| match scale.parse::<u32>() {
+                    Ok(1..=Graphics::MAX_SCALE) => Ok(()),
+                    _ => Err(format!("expected 1 to {}", Graphics::MAX_SCALE)),
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("frame_skip")
+                .long("frame-skip")
+                .value_name("N")
+                .help("Presents only every Nth frame")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|n| match n.parse::<u32>() {
+                    Ok(1..) => Ok(()),
+                    _ => Err(String::from("expected at least 1")),
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("turbo_frame_skip")
+                .long("turbo-frame-skip")
+                .value_name("N")
+                .help("Presents only every Nth frame while turbo (Space) is held")
+                .takes_value(true)
+                .default_value("1")
+                .validator(|n| match n.parse::<u32>() {
+                    Ok(1..) => Ok(()),
+                    _ => Err(String::from("expected at least 1")),
+                })
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("trace_io")
+                .long("trace-io")
+                .help("Logs every write to the I/O registers to stderr")
+                .takes_value(false)
+                .required(false),
+        )
         .arg(
             Arg::with_name("no_graphics")
                 .long("no-graphics")
@@ -41,35 +176,110 @@ fn main() -> Result<(), String> {
                 .takes_value(false)
                 .required(false), // Set default value to true
         )
+        .arg(
+            Arg::with_name("allow_opposing_dpad")
+                .long("allow-opposing-dpad")
+                .help("Lets left+right and up+down through instead of releasing both")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("no_turbo_mute")
+                .long("no-turbo-mute")
+                .help("Resamples audio during turbo instead of muting it")
+                .takes_value(false)
+                .required(false),
+        )
         .get_matches();
 
-    let boot_bin = matches.value_of("boot_bin").unwrap();
-    info!("Loading boot bin {}", boot_bin);
-    let contents = fs::read(boot_bin);
-    let boot_bin = match contents {
-        Ok(fs) => fs,
-        Err(e) => {
-            debug!("Unable to read file {} due to {}", boot_bin, e.to_string());
-            return Err(String::from("Unable to read file"));
-        }
-    };
-
     let rom_file = matches.value_of("rom_file").unwrap();
     info!("Running rom file {}", rom_file);
-    let contents = fs::read(rom_file);
-    let rom_file = match contents {
-        Ok(fs) => fs,
-        Err(e) => {
-            debug!("Unable to read file {} due to {}", rom_file, e.to_string());
-            return Err(String::from("Unable to read file"));
+
+    let symbols = matches.value_of("symbols").and_then(|path| {
+        fs::read_to_string(path)
+            .map_err(|e| warn!("Unable to read symbol file {} due to {}", path, e))
+            .ok()
+    });
+
+    if let Some(disasm) = matches.value_of("disasm") {
+        let rom = read_rom_file(rom_file).map_err(|e| e.to_string())?;
+        let symbols = SymbolTable::parse(symbols.as_deref().unwrap_or_default());
+        info!("Writing disassembly to {}", disasm);
+        if let Err(e) = fs::write(disasm, SizedInstruction::disassemble_rom(&rom, &symbols)) {
+            return Err(format!("Unable to write {}: {}", disasm, e));
         }
-    };
+        return Ok(());
+    }
 
     let graphics_enabled = !matches.is_present("no_graphics");
 
-    let mut gameboy = GameBoy::new(graphics_enabled);
-    gameboy.load_boot(boot_bin);
-    gameboy.load_rom(rom_file);
+    let mut gameboy = if matches.is_present("skip_boot") {
+        GameBoy::new_skip_boot(graphics_enabled)
+    } else {
+        let boot_bin = matches.value_of("boot_bin").unwrap();
+        info!("Loading boot bin {}", boot_bin);
+        let mut gameboy = GameBoy::new(graphics_enabled);
+        gameboy
+            .load_boot_from_path(boot_bin)
+            .map_err(|e| e.to_string())?;
+        gameboy
+    };
+    gameboy
+        .memory_mut()
+        .set_strict(matches.is_present("strict"));
+    gameboy
+        .memory_mut()
+        .set_rom_write_check(match matches.value_of("strict_rom_writes") {
+            Some("log") => RomWriteCheck::Log,
+            Some("break") => RomWriteCheck::Break,
+            _ => RomWriteCheck::Off,
+        });
+    gameboy
+        .memory_mut()
+        .set_access_restricted(!matches.is_present("no_access_restrictions"));
+    gameboy
+        .memory_mut()
+        .set_instant_dma(matches.is_present("instant_dma"));
+    gameboy.force_model(match matches.value_of("model") {
+        Some("dmg") => Some(HardwareModel::Dmg),
+        Some("cgb") => Some(HardwareModel::Cgb),
+        _ => None,
+    });
+    let header = gameboy
+        .load_rom_from_path(rom_file)
+        .map_err(|e| e.to_string())?;
+    info!("Loaded {} as {:?}", header.title, gameboy.model());
+
+    let save_path = Path::new(rom_file).with_extension("sav");
+    if let Err(e) = gameboy.load_save(save_path) {
+        warn!("Unable to read save file due to {}", e);
+    }
+
+    if let Some(symbols) = &symbols {
+        gameboy.load_symbols(symbols);
+    }
+    for breakpoint in matches.values_of("breakpoint").into_iter().flatten() {
+        if !gameboy.add_breakpoint(breakpoint) {
+            warn!("Unknown breakpoint {}", breakpoint);
+        }
+    }
+    gameboy.set_scale(matches.value_of_t_or_exit("scale"));
+    gameboy.set_frame_skip(matches.value_of_t_or_exit("frame_skip"));
+    gameboy.set_turbo_frame_skip(matches.value_of_t_or_exit("turbo_frame_skip"));
+    if matches.is_present("trace_io") {
+        gameboy.set_io_trace(Some(Box::new(BufWriter::new(io::stderr()))));
+    }
+    if matches.is_present("start_paused") {
+        gameboy.pause();
+    }
+    gameboy.set_allow_opposing_dpad(matches.is_present("allow_opposing_dpad"));
+    gameboy.set_turbo_mute(!matches.is_present("no_turbo_mute"));
+    if graphics_enabled && !matches.is_present("no_audio") {
+        if let Err(e) = gameboy.enable_audio() {
+            warn!("Unable to open audio due to {}", e);
+        }
+    }
+
     gameboy.run();
 
     Ok(())
@@ -1,27 +1,201 @@
-use log::info;
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt, fs,
+    io::{self, Cursor, Read},
+    ops::Range,
+    path::Path,
+};
+
+use flate2::read::GzDecoder;
+use log::{info, warn};
+use zip::ZipArchive;
 
 use crate::{
+    apu::{Apu, NR50_ADDRESS, NR51_ADDRESS, NR52_ADDRESS},
+    clock::Clock,
+    cpu::{INTERRUPT_FLAG_ADDRESS, SERIAL_FLAG},
     graphics::OAM_ADDRESS,
+    joypad::{SgbLink, BUTTONS_FLAG, DPAD_FLAG, JOYPAD_REGISTER_ADDRESS},
+    serial::{Serial, SB_ADDRESS, SC_ADDRESS, TRANSFER_FLAG},
+    state::{self, StateError, StateReader, StateWriter},
     utils::{address2string, bytes2word, Address, Byte, Word},
 };
 
 const MEMORY_SIZE: usize = 0x10000;
-const BOOTROM_SIZE: usize = 0x100;
+pub const BOOTROM_SIZE: usize = 0x100;
 const ROM_SIZE: usize = 0x4000;
+const RAM_SIZE: usize = 0x2000;
+const RTC_REGISTER_COUNT: usize = 5;
+/// Machine cycles per rtc second
+const RTC_CYCLES_PER_SECOND: u32 = 1 << 20;
+
+const EXTERNAL_RAM_START: usize = 0xA000;
+const EXTERNAL_RAM_END: usize = 0xBFFF;
 
 const DMA_ADDRESS: Address = 0xFF46;
+/// Bytes copied by an OAM DMA, one per machine cycle
+const DMA_SIZE: Word = 0xA0;
 const MBC_TYPE_ADDRESS: Address = 0x0147;
 const ROM_SIZE_ADDRESS: Address = 0x0148;
 const RAM_SIZE_ADDRESS: Address = 0x0149;
+const TITLE_START: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const CGB_FLAG_ADDRESS: usize = 0x0143;
+/// CGB flag bit 7 marks CGB support, with bit 6 the cartridge does not run on a DMG
+const CGB_SUPPORT_FLAG: Byte = 0x80;
+const CGB_ONLY_FLAG: Byte = 0xC0;
+const NEW_LICENSEE_ADDRESS: usize = 0x0144;
+const SGB_FLAG_ADDRESS: usize = 0x0146;
+const OLD_LICENSEE_ADDRESS: usize = 0x014B;
+const HEADER_CHECKSUM_ADDRESS: usize = 0x014D;
+const GLOBAL_CHECKSUM_ADDRESS: usize = 0x014E;
+/// First byte after the cartridge header
+const HEADER_END: usize = 0x0150;
+/// Compressed roms are detected by their first bytes, not by extension
+const ZIP_MAGIC: &[Byte] = b"PK\x03\x04";
+const GZIP_MAGIC: &[Byte] = &[0x1F, 0x8B];
+const ROM_EXTENSIONS: [&str; 2] = [".gb", ".gbc"];
 
+/// CGB speed switch, bit 7 is the current speed and bit 0 arms a switch on STOP
+const KEY1_ADDRESS: Address = 0xFF4D;
+const KEY1_PREPARE_FLAG: Byte = 0b0000_0001;
+const KEY1_SPEED_FLAG: Byte = 0b1000_0000;
 const UNLOAD_BOOT_ADDRESS: Address = 0xFF50;
 
+/// Echo ram mirrors work ram at 0xC000-0xDDFF
+const ECHO_RAM_START: usize = 0xE000;
+const ECHO_RAM_END: usize = 0xFDFF;
+const ECHO_RAM_OFFSET: usize = 0x2000;
+
+/// CGB work ram bank select, banks 1-7 are switched into 0xD000-0xDFFF
+const SVBK_ADDRESS: Address = 0xFF70;
+const WRAM_BANK_START: usize = 0xD000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
+
+/// Prohibited area between OAM and the I/O registers
+const UNUSABLE_START: usize = 0xFEA0;
+const UNUSABLE_END: usize = 0xFEFF;
+
+const VRAM_START: usize = 0x8000;
+const VRAM_END: usize = 0x9FFF;
+const OAM_END: usize = 0xFE9F;
+const LCDC_ADDRESS: usize = 0xFF40;
+const STAT_ADDRESS: usize = 0xFF41;
+
+/// DMG I/O register values left by the boot rom
+const POST_BOOT_REGISTERS: [(Address, Byte); 34] = [
+    (0xFF04, 0xAB),
+    (0xFF05, 0x00),
+    (0xFF06, 0x00),
+    (0xFF07, 0xF8),
+    (0xFF0F, 0xE1),
+    (0xFF10, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF14, 0xBF),
+    (0xFF16, 0x3F),
+    (0xFF17, 0x00),
+    (0xFF19, 0xBF),
+    (0xFF1A, 0x7F),
+    (0xFF1B, 0xFF),
+    (0xFF1C, 0x9F),
+    (0xFF1E, 0xBF),
+    (0xFF20, 0xFF),
+    (0xFF21, 0x00),
+    (0xFF22, 0x00),
+    (0xFF23, 0xBF),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (0xFF26, 0xF1),
+    (0xFF40, 0x91),
+    (0xFF41, 0x85),
+    (0xFF42, 0x00),
+    (0xFF43, 0x00),
+    (0xFF45, 0x00),
+    (0xFF47, 0xFC),
+    // not set by the boot rom, but commonly 0xFF
+    (0xFF48, 0xFF),
+    (0xFF49, 0xFF),
+    (0xFF4A, 0x00),
+    (0xFF4B, 0x00),
+    (0xFFFF, 0x00),
+];
+
+const SOUND_START: Address = 0xFF10;
+const SOUND_END: Address = 0xFF3F;
+
+const IO_START: usize = 0xFF00;
+const IO_END: usize = 0xFF7F;
+
+/// Unused bits of the DMG I/O registers, which read as 1, unmapped registers read 0xFF
+#[rustfmt::skip]
+const IO_READ_MASKS: [Byte; IO_END - IO_START + 1] = [
+    // P1    SB    SC          DIV   TIMA  TMA   TAC                                         IF
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0,
+    // NR10-NR14, NR21-NR24, NR30-NR34
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+    // NR41-NR44, NR50-NR52
+    0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    // wave ram
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    // LCDC  STAT  SCY   SCX   LY    LYC   DMA   BGP   OBP0  OBP1  WY    WX
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+/// Read masks of the registers that differ on CGB
+const CGB_IO_READ_MASKS: [(Address, Byte); 10] = [
+    (0xFF02, 0x7C),
+    (0xFF4D, 0x7E),
+    (0xFF4F, 0xFE),
+    (0xFF56, 0x3C),
+    (0xFF68, 0x40),
+    (0xFF69, 0x00),
+    (0xFF6A, 0x40),
+    (0xFF6B, 0x00),
+    (0xFF6C, 0xFE),
+    (0xFF70, 0xF8),
+];
+
+/// CGB palette index/data registers, background and object
+const BCPS_ADDRESS: Address = 0xFF68;
+const BCPD_ADDRESS: Address = 0xFF69;
+const OCPS_ADDRESS: Address = 0xFF6A;
+const OCPD_ADDRESS: Address = 0xFF6B;
+const PALETTE_AUTO_INCREMENT_FLAG: Byte = 0b1000_0000;
+/// 8 palettes of 4 colors, 2 bytes per color
+const CGB_PALETTE_RAM_SIZE: usize = 64;
+
+/// Hardware model being emulated
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HardwareModel {
+    #[default]
+    Dmg,
+    Cgb,
+}
+
+/// What to do about writes to rom on cartridges without an mbc, which are ignored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RomWriteCheck {
+    #[default]
+    Off,
+    /// Warn with the address and value
+    Log,
+    /// Warn and pause the debugger
+    Break,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum CartridgeType {
     None,
     RomOnly,
     MBC1,
     MBC3,
+    MBC5,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -30,6 +204,348 @@ pub enum CartridgeState {
     RomOnly(RomState),
     MBC1(MBC1State),
     MBC3(MBC3State),
+    MBC5(MBC5State),
+}
+
+/// Errors when loading a cartridge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeError {
+    /// The file is too short to hold a cartridge header
+    BadHeader { len: usize },
+    /// The header declares a cartridge type that is not emulated
+    UnsupportedMbc(Byte),
+    /// The header declares an unknown ram size
+    UnsupportedRamSize(Byte),
+    /// The header declares an unknown rom size
+    UnsupportedRomSize(Byte),
+    /// The file length does not match the rom size declared in the header, only in strict mode
+    RomSizeMismatch { expected: usize, actual: usize },
+    /// The rom could not be read or unpacked from its archive
+    Unreadable(String),
+    /// A zip archive without a .gb or .gbc entry
+    NoRomInArchive,
+    /// A CGB only cartridge (CGB flag 0xC0) loaded on a DMG
+    CgbOnly,
+    /// The header checksum does not match, only in strict mode
+    HeaderChecksumMismatch { expected: Byte, actual: Byte },
+    /// The global checksum does not match, only in strict mode
+    GlobalChecksumMismatch { expected: Word, actual: Word },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadHeader { len } => write!(
+                f,
+                "rom is {:#X} bytes, too short for a cartridge header",
+                len
+            ),
+            Self::UnsupportedMbc(rom_type) => {
+                write!(f, "unsupported cartridge type {:#04X}", rom_type)
+            }
+            Self::UnsupportedRamSize(ram_size) => {
+                write!(f, "unsupported ram size {:#04X}", ram_size)
+            }
+            Self::UnsupportedRomSize(rom_size) => {
+                write!(f, "unsupported rom size {:#04X}", rom_size)
+            }
+            Self::RomSizeMismatch { expected, actual } => write!(
+                f,
+                "rom is {:#X} bytes but the header declares {:#X} bytes",
+                actual, expected
+            ),
+            Self::Unreadable(reason) => write!(f, "unable to read rom: {}", reason),
+            Self::NoRomInArchive => write!(f, "archive contains no .gb or .gbc rom"),
+            Self::CgbOnly => write!(f, "cartridge only runs on a CGB, not on a DMG"),
+            Self::HeaderChecksumMismatch { expected, actual } => write!(
+                f,
+                "header checksum is {:#04X} but the header declares {:#04X}",
+                actual, expected
+            ),
+            Self::GlobalChecksumMismatch { expected, actual } => write!(
+                f,
+                "global checksum is {:#06X} but the header declares {:#06X}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+/// Name of the I/O register at address, None for unmapped addresses
+pub fn io_register_name(address: Address) -> Option<&'static str> {
+    const SOUND: [&str; 0x17] = [
+        "NR10", "NR11", "NR12", "NR13", "NR14", "", "NR21", "NR22", "NR23", "NR24", "NR30", "NR31",
+        "NR32", "NR33", "NR34", "", "NR41", "NR42", "NR43", "NR44", "NR50", "NR51", "NR52",
+    ];
+    const LCD: [&str; 0x0C] = [
+        "LCDC", "STAT", "SCY", "SCX", "LY", "LYC", "DMA", "BGP", "OBP0", "OBP1", "WY", "WX",
+    ];
+    let name = match address {
+        0xFF00 => "P1",
+        0xFF01 => "SB",
+        0xFF02 => "SC",
+        0xFF04 => "DIV",
+        0xFF05 => "TIMA",
+        0xFF06 => "TMA",
+        0xFF07 => "TAC",
+        0xFF0F => "IF",
+        0xFF10..=0xFF26 => SOUND[(address - 0xFF10) as usize],
+        0xFF30..=0xFF3F => "WAVE",
+        0xFF40..=0xFF4B => LCD[(address - 0xFF40) as usize],
+        0xFF4D => "KEY1",
+        0xFF4F => "VBK",
+        0xFF50 => "BOOT",
+        0xFF51 => "HDMA1",
+        0xFF52 => "HDMA2",
+        0xFF53 => "HDMA3",
+        0xFF54 => "HDMA4",
+        0xFF55 => "HDMA5",
+        0xFF56 => "RP",
+        0xFF68 => "BCPS",
+        0xFF69 => "BCPD",
+        0xFF6A => "OCPS",
+        0xFF6B => "OCPD",
+        0xFF6C => "OPRI",
+        0xFF70 => "SVBK",
+        0xFFFF => "IE",
+        _ => "",
+    };
+    (!name.is_empty()).then_some(name)
+}
+
+/// Rom bytes from a plain, zip or gzip file, plain files are returned as they are.
+/// Zip archives use their first .gb or .gbc entry
+pub fn unpack_rom(data: Vec<Byte>) -> Result<Vec<Byte>, CartridgeError> {
+    let unreadable = |e: &dyn Error| CartridgeError::Unreadable(e.to_string());
+    if data.starts_with(ZIP_MAGIC) {
+        let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|e| unreadable(&e))?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i).map_err(|e| unreadable(&e))?;
+            let name = entry.name().to_ascii_lowercase();
+            if entry.is_file() && ROM_EXTENSIONS.iter().any(|ext| name.ends_with(ext)) {
+                info!("Unpacking {} from zip archive", entry.name());
+                let mut rom = Vec::new();
+                entry.read_to_end(&mut rom).map_err(|e| unreadable(&e))?;
+                return Ok(rom);
+            }
+        }
+        Err(CartridgeError::NoRomInArchive)
+    } else if data.starts_with(GZIP_MAGIC) {
+        let mut rom = Vec::new();
+        GzDecoder::new(data.as_slice())
+            .read_to_end(&mut rom)
+            .map_err(|e| unreadable(&e))?;
+        Ok(rom)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Number of 8KB ram banks for a header ram size code
+fn ram_bank_count(ram_size: Byte) -> Option<usize> {
+    match ram_size {
+        0x00 | 0x01 => Some(0),
+        0x02 => Some(1),
+        0x03 => Some(4),
+        0x04 => Some(16),
+        0x05 => Some(8),
+        _ => None,
+    }
+}
+
+/// Ram bank numbers past the last bank wrap around, like the address lines a smaller ram lacks
+fn wrap_ram_bank(bank: usize, banks: usize) -> usize {
+    bank % banks.max(1)
+}
+
+/// Metadata from the cartridge header at 0x0134-0x014D
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb_flag: Byte,
+    /// Two ascii characters, used when old_licensee is 0x33
+    pub new_licensee: [Byte; 2],
+    pub old_licensee: Byte,
+    pub sgb_flag: Byte,
+    pub cartridge_type: Byte,
+    pub rom_size: Byte,
+    pub ram_size: Byte,
+    pub header_checksum: Byte,
+    /// Whether header_checksum matches the sum over 0x0134-0x014C
+    pub checksum_valid: bool,
+    /// Big endian sum of every rom byte except the checksum itself
+    pub global_checksum: Word,
+    pub global_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[Byte]) -> Self {
+        let cgb_flag = rom[CGB_FLAG_ADDRESS];
+        // the last title byte is the cgb flag on cgb cartridges
+        let title_end = if cgb_flag & CGB_SUPPORT_FLAG != 0 {
+            CGB_FLAG_ADDRESS
+        } else {
+            TITLE_END + 1
+        };
+        let title = rom[TITLE_START..title_end]
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as char)
+            .collect::<String>();
+        let header_checksum = rom[HEADER_CHECKSUM_ADDRESS];
+        let global_checksum = bytes2word(
+            rom[GLOBAL_CHECKSUM_ADDRESS + 1],
+            rom[GLOBAL_CHECKSUM_ADDRESS],
+        );
+
+        Self {
+            title,
+            cgb_flag,
+            new_licensee: [rom[NEW_LICENSEE_ADDRESS], rom[NEW_LICENSEE_ADDRESS + 1]],
+            old_licensee: rom[OLD_LICENSEE_ADDRESS],
+            sgb_flag: rom[SGB_FLAG_ADDRESS],
+            cartridge_type: rom[MBC_TYPE_ADDRESS as usize],
+            rom_size: rom[ROM_SIZE_ADDRESS as usize],
+            ram_size: rom[RAM_SIZE_ADDRESS as usize],
+            header_checksum,
+            checksum_valid: Self::checksum(rom) == header_checksum,
+            global_checksum,
+            global_checksum_valid: Self::global_checksum(rom) == global_checksum,
+        }
+    }
+
+    /// Number of 8KB ram banks declared by the ram size byte, None for unknown codes
+    pub fn ram_banks(&self) -> Option<usize> {
+        ram_bank_count(self.ram_size)
+    }
+
+    /// Model the cartridge asks for, CGB when it supports it
+    pub fn model(&self) -> HardwareModel {
+        if self.cgb_flag & CGB_SUPPORT_FLAG != 0 {
+            HardwareModel::Cgb
+        } else {
+            HardwareModel::Dmg
+        }
+    }
+
+    /// Whether the cartridge refuses to run on a DMG
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag & CGB_ONLY_FLAG == CGB_ONLY_FLAG
+    }
+
+    /// Whether the cartridge type includes external ram
+    pub fn has_ram(&self) -> bool {
+        matches!(
+            self.cartridge_type,
+            0x02 | 0x03
+                | 0x08
+                | 0x09
+                | 0x0C
+                | 0x0D
+                | 0x10
+                | 0x12
+                | 0x13
+                | 0x1A
+                | 0x1B
+                | 0x1D
+                | 0x1E
+        )
+    }
+
+    /// Global checksum computed over the whole rom, skipping 0x014E-0x014F
+    pub fn global_checksum(rom: &[Byte]) -> Word {
+        let sum = rom
+            .iter()
+            .fold(0, |sum: Word, &b| sum.wrapping_add(b as Word));
+        sum.wrapping_sub(rom[GLOBAL_CHECKSUM_ADDRESS] as Word)
+            .wrapping_sub(rom[GLOBAL_CHECKSUM_ADDRESS + 1] as Word)
+    }
+
+    /// Header checksum computed over 0x0134-0x014C
+    pub fn checksum(rom: &[Byte]) -> Byte {
+        rom[TITLE_START..HEADER_CHECKSUM_ADDRESS]
+            .iter()
+            .fold(0, |x: Byte, &b| x.wrapping_sub(b).wrapping_sub(1))
+    }
+}
+
+impl CartridgeState {
+    fn write_state(&self, state: &mut StateWriter) {
+        match self {
+            Self::None => state.byte(0),
+            Self::RomOnly(_) => state.byte(1),
+            Self::MBC1(mbc) => {
+                state.byte(2);
+                state.bool(mbc.ram_enabled);
+                state.usize(mbc.rom_number);
+                state.usize(mbc.ram_number);
+                state.byte(mbc.mode);
+            }
+            Self::MBC3(mbc) => {
+                state.byte(3);
+                state.bool(mbc.ram_enabled);
+                state.usize(mbc.rom_number);
+                state.usize(mbc.ram_number);
+                state.byte(mbc.latch_write);
+                let rtc = &mbc.rtc;
+                state.u32(rtc.cycles);
+                state.bytes(&[rtc.seconds, rtc.minutes, rtc.hours]);
+                state.word(rtc.days);
+                state.bool(rtc.halt);
+                state.bool(rtc.carry);
+                state.bytes(&rtc.latched);
+            }
+            Self::MBC5(mbc) => {
+                state.byte(4);
+                state.bool(mbc.ram_enabled);
+                state.usize(mbc.rom_number);
+                state.usize(mbc.ram_number);
+                state.bool(mbc.has_rumble);
+                state.bool(mbc.rumble);
+                state.bool(mbc.rumble_changed);
+            }
+        }
+    }
+
+    fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        Ok(match state.byte()? {
+            0 => Self::None,
+            1 => Self::RomOnly(RomState {}),
+            2 => Self::MBC1(MBC1State {
+                ram_enabled: state.bool()?,
+                rom_number: state.usize()?,
+                ram_number: state.usize()?,
+                mode: state.byte()?,
+            }),
+            3 => Self::MBC3(MBC3State {
+                ram_enabled: state.bool()?,
+                rom_number: state.usize()?,
+                ram_number: state.usize()?,
+                latch_write: state.byte()?,
+                rtc: Rtc {
+                    cycles: state.u32()?,
+                    seconds: state.byte()?,
+                    minutes: state.byte()?,
+                    hours: state.byte()?,
+                    days: state.word()?,
+                    halt: state.bool()?,
+                    carry: state.bool()?,
+                    latched: state.bytes(RTC_REGISTER_COUNT)?.try_into().unwrap(),
+                },
+            }),
+            4 => Self::MBC5(MBC5State {
+                ram_enabled: state.bool()?,
+                rom_number: state.usize()?,
+                ram_number: state.usize()?,
+                has_rumble: state.bool()?,
+                rumble: state.bool()?,
+                rumble_changed: state.bool()?,
+            }),
+            _ => return Err(StateError::InvalidValue("cartridge type")),
+        })
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -38,8 +554,12 @@ pub struct RomState {}
 #[derive(Debug, PartialEq, Eq)]
 pub struct MBC1State {
     ram_enabled: bool,
+    /// Lower 5 bits of the rom bank, never 0
     rom_number: usize,
+    /// Secondary 2 bit register, ram bank or upper rom bits depending on mode
     ram_number: usize,
+    /// 0 = rom banking mode, 1 = ram banking mode
+    mode: Byte,
 }
 
 impl MBC1State {
@@ -48,15 +568,140 @@ impl MBC1State {
             rom_number: 1,
             ram_enabled: false,
             ram_number: 0,
+            mode: 0,
+        }
+    }
+
+    /// Rom bank mapped at address, banks 0x20/0x40/0x60 are unreachable at 0x4000
+    /// since rom_number is never 0
+    fn rom_bank(&self, address: usize) -> usize {
+        match address {
+            0x0000..=0x3FFF if self.mode == 1 => self.ram_number << 5,
+            0x0000..=0x3FFF => 0,
+            _ => (self.ram_number << 5) | self.rom_number,
+        }
+    }
+
+    /// Ram bank mapped at 0xA000, only switchable in ram banking mode
+    fn ram_bank(&self) -> usize {
+        if self.mode == 1 {
+            self.ram_number
+        } else {
+            0
         }
     }
 }
 
+/// MBC3 real time clock, driven by machine cycles
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Rtc {
+    /// Machine cycles since the last second
+    cycles: u32,
+    seconds: Byte,
+    minutes: Byte,
+    hours: Byte,
+    /// 9 bit day counter
+    days: Word,
+    halt: bool,
+    /// Set when the day counter overflows, cleared only by writing
+    carry: bool,
+    /// Snapshot taken by the last latch, visible through 0xA000-0xBFFF
+    latched: [Byte; RTC_REGISTER_COUNT],
+}
+
+impl Rtc {
+    pub fn tick(&mut self, mcycles: u8) {
+        if self.halt {
+            return;
+        }
+        self.cycles += mcycles as u32;
+        while self.cycles >= RTC_CYCLES_PER_SECOND {
+            self.cycles -= RTC_CYCLES_PER_SECOND;
+            self.advance_second();
+        }
+    }
+
+    /// Counters wrap at their bit width without carrying when set out of range
+    fn advance_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    /// Live register values: seconds, minutes, hours, day low, day high
+    pub fn registers(&self) -> [Byte; RTC_REGISTER_COUNT] {
+        let mut day_high = (self.days >> 8) as Byte & 1;
+        if self.halt {
+            day_high |= 1 << 6;
+        }
+        if self.carry {
+            day_high |= 1 << 7;
+        }
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as Byte,
+            day_high,
+        ]
+    }
+
+    /// Copy the live registers into the latched snapshot
+    pub fn latch(&mut self) {
+        self.latched = self.registers();
+    }
+
+    pub fn read(&self, register: usize) -> Byte {
+        self.latched[register]
+    }
+
+    /// Write to the live register, the latched value follows so it reads back
+    pub fn write(&mut self, register: usize, byte: Byte) {
+        match register {
+            0 => {
+                self.seconds = byte & 0x3F;
+                self.cycles = 0;
+            }
+            1 => self.minutes = byte & 0x3F,
+            2 => self.hours = byte & 0x1F,
+            3 => self.days = (self.days & 0x100) | byte as Word,
+            4 => {
+                self.days = (self.days & 0xFF) | ((byte as Word & 1) << 8);
+                self.halt = byte & (1 << 6) != 0;
+                self.carry = byte & (1 << 7) != 0;
+            }
+            _ => unreachable!("rtc register {}", register),
+        }
+        self.latched[register] = self.registers()[register];
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct MBC3State {
     ram_enabled: bool,
     rom_number: usize,
+    /// 0x00-0x03 selects a ram bank, 0x08-0x0C selects a rtc register
     ram_number: usize,
+    /// Last byte written to the latch register, 0x00 then 0x01 latches
+    latch_write: Byte,
+    rtc: Rtc,
 }
 
 impl MBC3State {
@@ -65,17 +710,276 @@ impl MBC3State {
             rom_number: 1,
             ram_enabled: false,
             ram_number: 0,
+            latch_write: 0xFF,
+            rtc: Rtc::default(),
         }
     }
+
+    /// Rom bank mapped at address
+    fn rom_bank(&self, address: usize) -> usize {
+        match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_number,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MBC5State {
+    ram_enabled: bool,
+    /// 9 bit rom bank, bank 0 can be mapped at 0x4000
+    rom_number: usize,
+    /// 4 bit ram bank, 3 bit on rumble cartridges
+    ram_number: usize,
+    /// Rumble cartridges use bit 3 of the ram bank register for the motor
+    has_rumble: bool,
+    rumble: bool,
+    /// Rumble state changed since it was last taken
+    rumble_changed: bool,
+}
+
+impl MBC5State {
+    fn new(has_rumble: bool) -> Self {
+        Self {
+            rom_number: 1,
+            ram_enabled: false,
+            ram_number: 0,
+            has_rumble,
+            rumble: false,
+            rumble_changed: false,
+        }
+    }
+
+    /// Rom bank mapped at address
+    fn rom_bank(&self, address: usize) -> usize {
+        match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_number,
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A memory access, recorded for debugger watchpoints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Read {
+        address: Address,
+        value: Byte,
+    },
+    Write {
+        address: Address,
+        old: Byte,
+        new: Byte,
+    },
+}
+
+/// Memory as seen by the cpu, clock and ppu, so they can run against a scripted bus.
+/// The timer and ppu hooks default to a plain bus without side effects
+pub trait Bus {
+    fn read_byte(&self, address: Address) -> Byte;
+    fn write_byte(&mut self, address: Address, byte: Byte);
+
+    fn read_word(&self, address: Address) -> Word {
+        bytes2word(
+            self.read_byte(address),
+            self.read_byte(address.wrapping_add(1)),
+        )
+    }
+
+    /// Read as the ppu does, ignoring the cpu access restrictions
+    fn ppu_read_byte(&self, address: Address) -> Byte {
+        self.read_byte(address)
+    }
+
+    /// Read as the timer and interrupt logic do, not recorded as a cpu access
+    fn internal_read_byte(&self, address: Address) -> Byte {
+        self.read_byte(address)
+    }
+
+    /// Write as the timer and interrupt logic do, not recorded as a cpu access
+    fn internal_write_byte(&mut self, address: Address, byte: Byte) {
+        self.write_byte(address, byte);
+    }
+
+    fn get_model(&self) -> HardwareModel {
+        HardwareModel::Dmg
+    }
+
+    /// Whether DIV was reset since the last call
+    fn take_div_reset(&mut self) -> bool {
+        false
+    }
+
+    /// Set DIV as the divider counts, without resetting it
+    fn set_div(&mut self, div: Byte) {
+        self.write_byte(Clock::DIV_ADDRESS, div);
+    }
+
+    /// Whether TIMA was written since the last call
+    fn take_tima_write(&mut self) -> bool {
+        false
+    }
+
+    /// Set TIMA as the timer counts, without it counting as a cpu write
+    fn set_tima(&mut self, tima: Byte) {
+        self.write_byte(Clock::TIMA_ADDRESS, tima);
+    }
+
+    /// Advance the frame sequencer by one step (DIV-APU event)
+    fn clock_frame_sequencer(&mut self) {}
+
+    /// Advance cartridge and DMA state by mcycles
+    fn tick_peripherals(&mut self, _mcycles: u8) {}
+
+    /// Rom bank mapped at address, 0 outside of rom
+    fn rom_bank(&self, address: Address) -> usize {
+        match address {
+            0x0000..=0x7FFF => address as usize / ROM_SIZE,
+            _ => 0,
+        }
+    }
+}
+
+impl Bus for Memory {
+    fn read_byte(&self, address: Address) -> Byte {
+        Memory::read_byte(self, address)
+    }
+
+    fn write_byte(&mut self, address: Address, byte: Byte) {
+        Memory::write_byte(self, address, byte)
+    }
+
+    fn read_word(&self, address: Address) -> Word {
+        Memory::read_word(self, address)
+    }
+
+    fn ppu_read_byte(&self, address: Address) -> Byte {
+        Memory::ppu_read_byte(self, address)
+    }
+
+    fn internal_read_byte(&self, address: Address) -> Byte {
+        self.read_unlogged(address)
+    }
+
+    fn internal_write_byte(&mut self, address: Address, byte: Byte) {
+        self.write_unlogged(address, byte)
+    }
+
+    fn get_model(&self) -> HardwareModel {
+        Memory::get_model(self)
+    }
+
+    fn take_div_reset(&mut self) -> bool {
+        Memory::take_div_reset(self)
+    }
+
+    fn set_div(&mut self, div: Byte) {
+        Memory::set_div(self, div)
+    }
+
+    fn take_tima_write(&mut self) -> bool {
+        Memory::take_tima_write(self)
+    }
+
+    fn set_tima(&mut self, tima: Byte) {
+        Memory::set_tima(self, tima)
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        Memory::clock_frame_sequencer(self)
+    }
+
+    fn tick_peripherals(&mut self, mcycles: u8) {
+        self.tick_rtc(mcycles);
+        self.tick_dma(mcycles);
+        self.tick_serial(mcycles);
+        self.apu.tick(mcycles);
+    }
+
+    fn rom_bank(&self, address: Address) -> usize {
+        match address {
+            0x0000..=0x7FFF => self.rom_offset(address) / ROM_SIZE,
+            _ => 0,
+        }
+    }
+}
+
+/// Memory state read by Memory::parse_state, not applied yet
+pub struct PendingState<'a> {
+    memory: &'a [Byte],
+    boot_rom: &'a [Byte],
+    boot_mapped: bool,
+    model: HardwareModel,
+    bg_palette_ram: &'a [Byte],
+    obj_palette_ram: &'a [Byte],
+    wram: &'a [Byte],
+    div_reset: bool,
+    tima_written: bool,
+    frame_sequencer: Byte,
+    dma: Option<OamDma>,
+    cartridge: CartridgeState,
+    serial: Serial,
+    sgb: SgbLink,
+    apu: Apu,
+    ram: &'a [Byte],
 }
 
 pub struct Memory {
     memory: [Byte; MEMORY_SIZE],
     boot_rom: [Byte; BOOTROM_SIZE],
+    boot_mapped: bool,
     rom: Vec<Vec<Byte>>,
-    #[allow(dead_code)]
     ram: Vec<Vec<Byte>>,
+    /// External ram changed since the last save
+    ram_dirty: bool,
     cartridge: CartridgeState,
+    header: Option<CartridgeHeader>,
+    /// Reject cartridges with bad checksums instead of warning
+    strict: bool,
+    model: HardwareModel,
+    /// Model to run cartridges as, None picks it from the header CGB flag
+    forced_model: Option<HardwareModel>,
+    bg_palette_ram: [Byte; CGB_PALETTE_RAM_SIZE],
+    obj_palette_ram: [Byte; CGB_PALETTE_RAM_SIZE],
+    /// CGB work ram banks, empty on DMG. The selected bank lives in the flat
+    /// memory at 0xD000-0xDFFF and is only copied back here when switched out
+    wram: Vec<[Byte; WRAM_BANK_SIZE]>,
+    /// DIV was written since the last clock tick, the clock resets its internal counter
+    div_reset: bool,
+    /// TIMA was written by the cpu since the last clock tick, cancels a pending reload
+    tima_written: bool,
+    /// Frame sequencer step (0-7), clocked by falling edges of DIV bit 4
+    frame_sequencer: Byte,
+    /// OAM DMA in flight, the cpu only reaches HRAM and the I/O registers until it ends
+    dma: Option<OamDma>,
+    sgb: SgbLink,
+    serial: Serial,
+    apu: Apu,
+    /// Pressed dpad and button lines, active low in the low nibble as read through P1
+    joypad_dpad: Byte,
+    joypad_buttons: Byte,
+    /// Lock the cpu out of vram in mode 3 and out of OAM in modes 2/3
+    access_restricted: bool,
+    /// Copy OAM DMA transfers at once instead of one byte per machine cycle
+    instant_dma: bool,
+    rom_write_check: RomWriteCheck,
+    /// Address and value of a stray rom write not yet taken by the debugger
+    rom_write: Option<(Address, Byte)>,
+    /// Accesses since the last `take_accesses`, only recorded while watchpoints are set
+    access_log: Option<RefCell<Vec<MemoryAccess>>>,
+}
+
+/// Progress of an OAM DMA transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OamDma {
+    source: Address,
+    copied: Word,
 }
 
 impl Memory {
@@ -83,13 +987,81 @@ impl Memory {
         Memory {
             memory: [0; MEMORY_SIZE],
             boot_rom: [0; BOOTROM_SIZE],
+            boot_mapped: false,
             rom: Vec::new(),
             ram: Vec::new(),
+            ram_dirty: false,
             cartridge: CartridgeState::None,
+            header: None,
+            strict: false,
+            model: HardwareModel::Dmg,
+            forced_model: None,
+            div_reset: false,
+            tima_written: false,
+            frame_sequencer: 0,
+            dma: None,
+            sgb: SgbLink::new(),
+            serial: Serial::new(),
+            apu: Apu::new(),
+            joypad_dpad: 0xF,
+            joypad_buttons: 0xF,
+            access_restricted: true,
+            instant_dma: false,
+            rom_write_check: RomWriteCheck::Off,
+            rom_write: None,
+            access_log: None,
+            bg_palette_ram: [0; CGB_PALETTE_RAM_SIZE],
+            obj_palette_ram: [0; CGB_PALETTE_RAM_SIZE],
+            wram: Vec::new(),
+        }
+    }
+
+    /// Power cycle, keeping the cartridge with its external ram, the boot rom and the
+    /// settings. The boot rom is mapped again over the reloaded cartridge when map_boot is set
+    pub fn reset(&mut self, map_boot: bool) {
+        let rom = self.rom.concat();
+        let ram = std::mem::take(&mut self.ram);
+        let ram_dirty = self.ram_dirty;
+        *self = Memory {
+            boot_rom: self.boot_rom,
+            strict: self.strict,
+            forced_model: self.forced_model,
+            access_restricted: self.access_restricted,
+            instant_dma: self.instant_dma,
+            rom_write_check: self.rom_write_check,
+            access_log: self.access_log.take(),
+            ..Memory::new()
+        };
+        if map_boot {
+            self.memory[..BOOTROM_SIZE].copy_from_slice(&self.boot_rom);
+            self.boot_mapped = true;
+        }
+        if !rom.is_empty() {
+            self.load_cartidge(rom)
+                .expect("a loaded cartridge loads again");
+            self.ram = ram;
+            self.ram_dirty = ram_dirty;
         }
     }
 
-    pub fn load_cartidge(&mut self, rom_data: Vec<u8>) {
+    /// Read a plain, zip or gzip rom and load it, see unpack_rom
+    pub fn load_cartridge_from_reader(
+        &mut self,
+        mut reader: impl Read,
+    ) -> Result<(), CartridgeError> {
+        let mut data = Vec::new();
+        reader
+            .read_to_end(&mut data)
+            .map_err(|e| CartridgeError::Unreadable(e.to_string()))?;
+        self.load_cartidge(unpack_rom(data)?)
+    }
+
+    pub fn load_cartidge(&mut self, mut rom_data: Vec<u8>) -> Result<(), CartridgeError> {
+        if rom_data.len() < HEADER_END {
+            return Err(CartridgeError::BadHeader {
+                len: rom_data.len(),
+            });
+        }
         let ctype = self.get_cartridge_type_rom(&rom_data);
         let rom_size = self.get_rom_size_rom(&rom_data);
         let ram_size = self.get_ram_size_rom(&rom_data);
@@ -98,71 +1070,673 @@ impl Memory {
         info!("Rom Size {:?}", rom_size);
         info!("Ram Size {:?}", ram_size);
 
+        if ctype == CartridgeType::None {
+            return Err(CartridgeError::UnsupportedMbc(
+                rom_data[MBC_TYPE_ADDRESS as usize],
+            ));
+        }
+        let mut ram_bank_num = self
+            .get_ram_bank_count(ram_size)
+            .ok_or(CartridgeError::UnsupportedRamSize(ram_size as Byte))?;
+
+        // 0x00 is 2 banks up to 0x08 with 512 banks (8MB)
+        if rom_size > 0x08 {
+            return Err(CartridgeError::UnsupportedRomSize(rom_size as Byte));
+        }
+        let expected = (1 << (rom_size + 1)) * ROM_SIZE;
+        if rom_size == 0 && rom_data.len() < expected {
+            // homebrew and test roms can be smaller than the 32KB minimum, the rest reads 0xFF
+            rom_data.resize(expected, 0xFF);
+        }
+        if rom_data.len() != expected {
+            if self.strict {
+                return Err(CartridgeError::RomSizeMismatch {
+                    expected,
+                    actual: rom_data.len(),
+                });
+            }
+            // bad dumps, missing banks read 0xFF like an open bus
+            warn!(
+                "Rom is {:#X} bytes but the header declares {:#X} bytes, {} it",
+                rom_data.len(),
+                expected,
+                if rom_data.len() < expected {
+                    "padding"
+                } else {
+                    "truncating"
+                }
+            );
+            rom_data.resize(expected, 0xFF);
+        }
+
+        let header = CartridgeHeader::parse(&rom_data);
+        info!("Title {:?}", header.title);
+        if !header.checksum_valid {
+            let actual = CartridgeHeader::checksum(&rom_data);
+            if self.strict {
+                return Err(CartridgeError::HeaderChecksumMismatch {
+                    expected: header.header_checksum,
+                    actual,
+                });
+            }
+            warn!(
+                "Header checksum mismatch, expected {:#04X?} got {:#04X?}",
+                header.header_checksum, actual
+            );
+        }
+        if !header.global_checksum_valid {
+            let actual = CartridgeHeader::global_checksum(&rom_data);
+            if self.strict {
+                return Err(CartridgeError::GlobalChecksumMismatch {
+                    expected: header.global_checksum,
+                    actual,
+                });
+            }
+            warn!(
+                "Global checksum mismatch, expected {:#06X?} got {:#06X?}",
+                header.global_checksum, actual
+            );
+        }
+        let model = self.forced_model.unwrap_or_else(|| header.model());
+        if model == HardwareModel::Dmg && header.cgb_only() {
+            return Err(CartridgeError::CgbOnly);
+        }
+        info!("Model {:?}", model);
+        if ram_bank_num > 0 && !header.has_ram() {
+            warn!(
+                "Cartridge type {:#04X?} has no ram but declares {} banks, ignoring them",
+                header.cartridge_type, ram_bank_num
+            );
+            ram_bank_num = 0;
+        }
+        self.header = Some(header);
+        if model != self.model {
+            self.set_model(model);
+        }
+
         self.cartridge = match ctype {
             CartridgeType::RomOnly => CartridgeState::RomOnly(RomState {}),
             CartridgeType::MBC1 => CartridgeState::MBC1(MBC1State::new()),
             CartridgeType::MBC3 => CartridgeState::MBC3(MBC3State::new()),
-            CartridgeType::None => panic!("Unknown cartridge type"),
+            CartridgeType::MBC5 => CartridgeState::MBC5(MBC5State::new(matches!(
+                rom_data[MBC_TYPE_ADDRESS as usize],
+                0x1C..=0x1E
+            ))),
+            CartridgeType::None => unreachable!(),
         };
 
         // copy rom_data to self.rom
         let rom_data = rom_data.as_slice();
 
-        let rom_bank_num = 1 << (rom_size + 1);
-        for i in 0..rom_bank_num {
-            let mut rom_bank = Vec::with_capacity(ROM_SIZE);
-            rom_bank.extend_from_slice(&rom_data[ROM_SIZE * i..ROM_SIZE * (i + 1)]);
-            self.rom.push(rom_bank);
-        }
-        self.memory[BOOTROM_SIZE..ROM_SIZE].copy_from_slice(&self.rom[0][BOOTROM_SIZE..ROM_SIZE]);
+        self.rom = rom_data
+            .chunks(ROM_SIZE)
+            .map(|rom_bank| rom_bank.to_vec())
+            .collect();
+        let start = if self.boot_mapped { BOOTROM_SIZE } else { 0 };
+        self.memory[start..ROM_SIZE].copy_from_slice(&self.rom[0][start..ROM_SIZE]);
         self.memory[ROM_SIZE..ROM_SIZE * 2].copy_from_slice(&self.rom[1]);
+
+        self.ram = vec![vec![0; RAM_SIZE]; ram_bank_num];
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    /// Bytes of external ram allocated for the loaded cartridge
+    pub fn external_ram_size(&self) -> usize {
+        self.ram.len() * RAM_SIZE
+    }
+
+    /// Every external ram bank, whether or not it is mapped or enabled
+    pub fn external_ram_banks(&self) -> Vec<&[Byte]> {
+        self.ram.iter().map(Vec::as_slice).collect()
+    }
+
+    /// Every CGB work ram bank switchable at 0xD000-0xDFFF, the mapped one is read
+    /// from the flat memory. Bank 0 is never switched out and is left empty
+    pub fn wram_banks(&self) -> Vec<&[Byte]> {
+        let mapped = self.wram_bank();
+        (0..self.wram.len())
+            .map(|bank| match bank {
+                0 => &[],
+                _ if bank == mapped => {
+                    &self.memory[WRAM_BANK_START..WRAM_BANK_START + WRAM_BANK_SIZE]
+                }
+                _ => &self.wram[bank][..],
+            })
+            .collect()
+    }
+
+    /// CGB work ram bank mapped at 0xD000-0xDFFF, None on DMG
+    pub fn mapped_wram_bank(&self) -> Option<usize> {
+        (!self.wram.is_empty()).then(|| self.wram_bank())
+    }
+
+    /// Whether the loaded cartridge keeps its ram powered by a battery
+    pub fn has_battery(&self) -> bool {
+        match self.rom.first() {
+            Some(bank) => matches!(
+                bank[MBC_TYPE_ADDRESS as usize],
+                0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+            ),
+            None => false,
+        }
+    }
+
+    /// Load external ram from a save file, the raw concatenation of the ram banks.
+    /// A missing file leaves the ram untouched
+    pub fn load_save(&mut self, path: &Path) -> io::Result<()> {
+        let save = match fs::read(path) {
+            Ok(save) => save,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        info!("Load Save Size {:#04X?}", save.len());
+        for (bank, data) in self.ram.iter_mut().zip(save.chunks(RAM_SIZE)) {
+            bank[..data.len()].copy_from_slice(data);
+        }
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    /// Write external ram to a save file if it is battery backed and changed
+    pub fn save_ram(&mut self, path: &Path) -> io::Result<()> {
+        if !self.has_battery() || !self.ram_dirty {
+            return Ok(());
+        }
+        info!("Saving ram to {}", path.display());
+        fs::write(path, self.ram.concat())?;
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    /// Copy a raw program without a cartridge header into memory at address
+    pub fn load_raw(&mut self, program: &[u8], address: Address) {
+        let start = address as usize;
+        self.memory[start..start + program.len()].copy_from_slice(program);
+    }
+
+    /// Replace the whole flat memory with image, cartridge banks and palette ram are untouched
+    pub fn load_image(&mut self, image: &[Byte; MEMORY_SIZE]) {
+        self.memory.copy_from_slice(image);
+    }
+
+    /// Copy of the whole flat memory
+    pub fn snapshot(&self) -> [Byte; MEMORY_SIZE] {
+        self.memory
+    }
+
+    /// Copy of an address range as currently mapped, without the cpu access restrictions
+    pub fn dump(&self, range: Range<Address>) -> Vec<Byte> {
+        range.map(|address| self.ppu_read_byte(address)).collect()
+    }
+
+    /// Write a dump of an address range to path
+    pub fn dump_to_file(&self, path: &Path, range: Range<Address>) -> io::Result<()> {
+        fs::write(path, self.dump(range))
+    }
+
+    /// Write data back over an address range, as the cpu would write it
+    pub fn restore(&mut self, range: Range<Address>, data: &[Byte]) {
+        assert_eq!(range.len(), data.len());
+        for (address, &byte) in range.zip(data) {
+            self.write_byte(address, byte);
+        }
+    }
+
+    /// Hash of the loaded rom, identifies which rom a save state belongs to
+    pub fn rom_hash(&self) -> u64 {
+        state::rom_hash(self.rom.iter().map(Vec::as_slice))
+    }
+
+    /// Serialize the flat memory, mbc registers and external ram.
+    /// The rom itself is only stored as a hash
+    pub fn save_state(&self) -> Vec<Byte> {
+        let mut state = StateWriter::new();
+        self.write_state(&mut state);
+        state.finish()
+    }
+
+    /// Restore a state from save_state, fails without changes for another rom
+    pub fn load_state(&mut self, data: &[Byte]) -> Result<(), StateError> {
+        let mut state = StateReader::new(data)?;
+        let pending = self.parse_state(&mut state)?;
+        state.finish()?;
+        self.restore_state(pending);
+        Ok(())
+    }
+
+    pub fn write_state(&self, state: &mut StateWriter) {
+        state.u64(self.rom_hash());
+        state.bytes(&self.memory);
+        state.bytes(&self.boot_rom);
+        state.bool(self.boot_mapped);
+        state.bool(self.model == HardwareModel::Cgb);
+        state.bytes(&self.bg_palette_ram);
+        state.bytes(&self.obj_palette_ram);
+        state.usize(self.wram.len());
+        for bank in &self.wram {
+            state.bytes(bank);
+        }
+        state.bool(self.div_reset);
+        state.bool(self.tima_written);
+        state.byte(self.frame_sequencer);
+        state.bool(self.dma.is_some());
+        if let Some(dma) = self.dma {
+            state.word(dma.source);
+            state.word(dma.copied);
+        }
+        self.cartridge.write_state(state);
+        self.serial.write_state(state);
+        self.sgb.write_state(state);
+        self.apu.write_state(state);
+        state.usize(self.ram.len());
+        for bank in &self.ram {
+            state.bytes(bank);
+        }
+    }
+
+    /// Read a state written by write_state, memory is only changed if every field is valid
+    pub fn read_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let pending = self.parse_state(state)?;
+        self.restore_state(pending);
+        Ok(())
+    }
+
+    /// Read and check a state written by write_state without changing memory,
+    /// so the caller can validate the rest of the state before restore_state
+    pub fn parse_state<'a>(
+        &self,
+        state: &mut StateReader<'a>,
+    ) -> Result<PendingState<'a>, StateError> {
+        let expected = state.u64()?;
+        let actual = self.rom_hash();
+        if expected != actual {
+            return Err(StateError::RomMismatch { expected, actual });
+        }
+        let memory = state.bytes(MEMORY_SIZE)?;
+        let boot_rom = state.bytes(BOOTROM_SIZE)?;
+        let boot_mapped = state.bool()?;
+        let model = match state.bool()? {
+            true => HardwareModel::Cgb,
+            false => HardwareModel::Dmg,
+        };
+        let bg_palette_ram = state.bytes(CGB_PALETTE_RAM_SIZE)?;
+        let obj_palette_ram = state.bytes(CGB_PALETTE_RAM_SIZE)?;
+        let wram_banks = state.usize()?;
+        let expected_banks = match model {
+            HardwareModel::Cgb => WRAM_BANKS,
+            HardwareModel::Dmg => 0,
+        };
+        if wram_banks != expected_banks {
+            return Err(StateError::InvalidValue("work ram bank count"));
+        }
+        let wram = state.bytes(wram_banks * WRAM_BANK_SIZE)?;
+        let div_reset = state.bool()?;
+        let tima_written = state.bool()?;
+        let frame_sequencer = state.byte()?;
+        let dma = match state.bool()? {
+            true => Some(OamDma {
+                source: state.word()?,
+                copied: state.word()?,
+            }),
+            false => None,
+        };
+        if let Some(dma) = dma {
+            if dma.copied >= DMA_SIZE || dma.source & 0xFF != 0 {
+                return Err(StateError::InvalidValue("oam dma"));
+            }
+        }
+        let cartridge = CartridgeState::read_state(state)?;
+        let serial = Serial::read_state(state)?;
+        let sgb = SgbLink::read_state(state)?;
+        let apu = Apu::read_state(state)?;
+        if std::mem::discriminant(&cartridge) != std::mem::discriminant(&self.cartridge) {
+            return Err(StateError::InvalidValue("cartridge type"));
+        }
+        if state.usize()? != self.ram.len() {
+            return Err(StateError::InvalidValue("ram bank count"));
+        }
+        let ram = state.bytes(self.ram.len() * RAM_SIZE)?;
+
+        Ok(PendingState {
+            memory,
+            boot_rom,
+            boot_mapped,
+            model,
+            bg_palette_ram,
+            obj_palette_ram,
+            wram,
+            div_reset,
+            tima_written,
+            frame_sequencer,
+            dma,
+            cartridge,
+            serial,
+            sgb,
+            apu,
+            ram,
+        })
+    }
+
+    /// Apply a state checked by parse_state
+    pub fn restore_state(&mut self, pending: PendingState) {
+        let PendingState {
+            memory,
+            boot_rom,
+            boot_mapped,
+            model,
+            bg_palette_ram,
+            obj_palette_ram,
+            wram,
+            div_reset,
+            tima_written,
+            frame_sequencer,
+            dma,
+            cartridge,
+            serial,
+            sgb,
+            apu,
+            ram,
+        } = pending;
+        self.memory.copy_from_slice(memory);
+        self.boot_rom.copy_from_slice(boot_rom);
+        self.boot_mapped = boot_mapped;
+        self.model = model;
+        self.bg_palette_ram.copy_from_slice(bg_palette_ram);
+        self.obj_palette_ram.copy_from_slice(obj_palette_ram);
+        self.wram = wram
+            .chunks(WRAM_BANK_SIZE)
+            .map(|bank| bank.try_into().unwrap())
+            .collect();
+        self.div_reset = div_reset;
+        self.tima_written = tima_written;
+        self.frame_sequencer = frame_sequencer;
+        self.dma = dma;
+        self.cartridge = cartridge;
+        self.serial = serial;
+        self.sgb = sgb;
+        self.apu = apu;
+        for (bank, data) in self.ram.iter_mut().zip(ram.chunks(RAM_SIZE)) {
+            bank.copy_from_slice(data);
+        }
+        self.ram_dirty = true;
+    }
+
+    /// Set the I/O registers to the state the boot rom leaves them in
+    pub fn init_post_boot(&mut self) {
+        for (address, byte) in POST_BOOT_REGISTERS {
+            self.memory[address as usize] = byte;
+        }
+        // turn the sound on without replaying the boot sound on channel 1
+        for address in [NR52_ADDRESS, NR50_ADDRESS, NR51_ADDRESS] {
+            self.apu.write(address, self.memory[address as usize]);
+        }
     }
 
     pub fn load_boot(&mut self, boot_data: Vec<u8>) {
         info!("Boot Size {:#04X?}", boot_data.len());
         self.boot_rom.copy_from_slice(&boot_data);
         self.memory[..BOOTROM_SIZE].copy_from_slice(&self.boot_rom);
+        self.boot_mapped = true;
     }
 
+    /// Read byte as the cpu, vram and OAM reads return 0xFF while the ppu uses them
     pub fn read_byte(&self, address: Address) -> Byte {
-        let address = address as usize;
-        self.memory[address]
+        let value = self.read_unlogged(address);
+        if let Some(log) = &self.access_log {
+            log.borrow_mut().push(MemoryAccess::Read { address, value });
+        }
+        value
+    }
+
+    fn read_unlogged(&self, address: Address) -> Byte {
+        if self.dma.is_some() && (address as usize) < IO_START {
+            return 0xFF;
+        }
+        if (VRAM_START..=VRAM_END).contains(&(address as usize)) && self.vram_blocked() {
+            return 0xFF;
+        }
+        if (OAM_ADDRESS as usize..=OAM_END).contains(&(address as usize)) && self.oam_blocked() {
+            return 0xFF;
+        }
+        if (UNUSABLE_START..=UNUSABLE_END).contains(&(address as usize)) {
+            return self.read_unusable(address);
+        }
+        if (IO_START..=IO_END).contains(&(address as usize)) {
+            return self.ppu_read_byte(address) | self.io_read_mask(address);
+        }
+        self.ppu_read_byte(address)
+    }
+
+    /// Bits of the I/O register at address that always read as 1 on the current model
+    fn io_read_mask(&self, address: Address) -> Byte {
+        if self.model == HardwareModel::Cgb {
+            if let Some((_, mask)) = CGB_IO_READ_MASKS.iter().find(|(a, _)| *a == address) {
+                return *mask;
+            }
+        }
+        IO_READ_MASKS[address as usize - IO_START]
+    }
+
+    /// Read byte as the ppu, which is never blocked from vram
+    pub fn ppu_read_byte(&self, address: Address) -> Byte {
+        match address {
+            BCPD_ADDRESS => return self.bg_palette_ram[self.palette_index(BCPS_ADDRESS)],
+            OCPD_ADDRESS => return self.obj_palette_ram[self.palette_index(OCPS_ADDRESS)],
+            JOYPAD_REGISTER_ADDRESS => return self.read_joypad(),
+            NR52_ADDRESS => return self.apu.status(),
+            _ => (),
+        }
+
+        let address = Self::unmirror(address as usize);
+        match (&self.cartridge, address) {
+            (
+                CartridgeState::MBC1(_) | CartridgeState::MBC3(_) | CartridgeState::MBC5(_),
+                0x0000..=0x7FFF,
+            ) => self.read_rom(address),
+            (CartridgeState::MBC1(state), EXTERNAL_RAM_START..=EXTERNAL_RAM_END) => {
+                self.read_mbc1_ram(state, address)
+            }
+            (CartridgeState::MBC3(state), EXTERNAL_RAM_START..=EXTERNAL_RAM_END) => {
+                self.read_mbc3_ram(state, address)
+            }
+            (CartridgeState::MBC5(state), EXTERNAL_RAM_START..=EXTERNAL_RAM_END) => {
+                self.read_mbc5_ram(state, address)
+            }
+            // no external ram, open bus
+            (CartridgeState::RomOnly(_), EXTERNAL_RAM_START..=EXTERNAL_RAM_END) => 0xFF,
+            _ => self.memory[address],
+        }
     }
 
     pub fn read_word(&self, address: Address) -> Word {
+        bytes2word(
+            self.read_byte(address),
+            self.read_byte(address.wrapping_add(1)),
+        )
+    }
+
+    /// Resolve a rom address (0x0000-0x7FFF) to an offset into the rom file
+    pub fn rom_offset(&self, address: Address) -> usize {
         let address = address as usize;
-        bytes2word(self.memory[address], self.memory[address + 1])
+        let bank = match &self.cartridge {
+            CartridgeState::MBC1(state) => state.rom_bank(address),
+            CartridgeState::MBC3(state) => state.rom_bank(address),
+            CartridgeState::MBC5(state) => state.rom_bank(address),
+            _ => address / ROM_SIZE,
+        };
+        // without a cartridge there is nothing to wrap around
+        (bank % self.rom.len().max(2)) * ROM_SIZE + address % ROM_SIZE
+    }
+
+    /// Read from the banked rom, the boot rom is mapped over the start until unloaded
+    fn read_rom(&self, address: usize) -> Byte {
+        if self.boot_mapped && address < BOOTROM_SIZE {
+            return self.boot_rom[address];
+        }
+        let offset = self.rom_offset(address as Address);
+        self.rom[offset / ROM_SIZE][offset % ROM_SIZE]
+    }
+
+    /// Map echo ram addresses onto the work ram they mirror
+    fn unmirror(address: usize) -> usize {
+        match address {
+            ECHO_RAM_START..=ECHO_RAM_END => address - ECHO_RAM_OFFSET,
+            _ => address,
+        }
+    }
+
+    /// Read from 0xFEA0-0xFEFF, on DMG 0x00 unless OAM is locked by the ppu (modes 2/3),
+    /// on CGB (revision E) the high nibble of the address repeated
+    fn read_unusable(&self, address: Address) -> Byte {
+        match self.model {
+            HardwareModel::Dmg => {
+                let lcd_on = self.memory[LCDC_ADDRESS] & 0x80 != 0;
+                if lcd_on && self.memory[STAT_ADDRESS] & 0b10 != 0 {
+                    0xFF
+                } else {
+                    0x00
+                }
+            }
+            HardwareModel::Cgb => {
+                let high = (address as Byte) >> 4;
+                (high << 4) | high
+            }
+        }
+    }
+
+    /// Whether the ppu is in mode 3 with the lcd on, locking the cpu out of vram
+    fn vram_blocked(&self) -> bool {
+        self.access_restricted
+            && self.memory[LCDC_ADDRESS] & 0x80 != 0
+            && self.memory[STAT_ADDRESS] & 0b11 == 3
+    }
+
+    /// Whether the ppu is in mode 2 or 3 with the lcd on, locking the cpu out of OAM
+    fn oam_blocked(&self) -> bool {
+        self.access_restricted
+            && self.memory[LCDC_ADDRESS] & 0x80 != 0
+            && self.memory[STAT_ADDRESS] & 0b10 != 0
+    }
+
+    /// Let the cpu reach vram and OAM in any ppu mode, for debugging
+    pub fn set_access_restricted(&mut self, restricted: bool) {
+        self.access_restricted = restricted;
+    }
+
+    /// Finish OAM DMA transfers as soon as they start, faster but not cycle accurate
+    pub fn set_instant_dma(&mut self, instant: bool) {
+        self.instant_dma = instant;
+    }
+
+    /// Record reads and writes for watchpoints
+    pub fn set_access_logging(&mut self, enabled: bool) {
+        self.access_log = enabled.then(|| RefCell::new(Vec::new()));
+    }
+
+    /// Accesses since the last call, empty unless logging is enabled
+    pub fn take_accesses(&mut self) -> Vec<MemoryAccess> {
+        match &mut self.access_log {
+            Some(log) => std::mem::take(log.get_mut()),
+            None => Vec::new(),
+        }
     }
 
     /// Write byte to address according to MMU(Memory Management Unit)
     pub fn write_byte(&mut self, address: Address, byte: Byte) {
+        if self.access_log.is_some() {
+            // the register value itself, without the bits that always read as 1
+            let old = self.ppu_read_byte(address);
+            if let Some(log) = &mut self.access_log {
+                log.get_mut().push(MemoryAccess::Write {
+                    address,
+                    old,
+                    new: byte,
+                });
+            }
+        }
+        self.write_unlogged(address, byte);
+    }
+
+    fn write_unlogged(&mut self, address: Address, byte: Byte) {
+        if (VRAM_START..=VRAM_END).contains(&(address as usize)) && self.vram_blocked() {
+            return;
+        }
+        if (OAM_ADDRESS as usize..=OAM_END).contains(&(address as usize)) && self.oam_blocked() {
+            return;
+        }
+        if (UNUSABLE_START..=UNUSABLE_END).contains(&(address as usize)) {
+            return;
+        }
         match address {
             UNLOAD_BOOT_ADDRESS => self.unload_boot(),
             DMA_ADDRESS => self.dma(byte),
+            Clock::DIV_ADDRESS => return self.reset_div(),
+            Clock::TIMA_ADDRESS => self.tima_written = true,
+            KEY1_ADDRESS => {
+                // only the prepare bit is writable
+                let speed = self.memory[KEY1_ADDRESS as usize] & KEY1_SPEED_FLAG;
+                self.memory[KEY1_ADDRESS as usize] = speed | (byte & KEY1_PREPARE_FLAG);
+                return;
+            }
+            SVBK_ADDRESS if self.model == HardwareModel::Cgb => {
+                return self.switch_wram_bank(byte & 0b111)
+            }
+            NR52_ADDRESS => {
+                self.apu.write(address, byte);
+                if !self.apu.powered() {
+                    // powering off clears every sound register, wave ram is kept
+                    self.memory[SOUND_START as usize..NR52_ADDRESS as usize].fill(0);
+                }
+            }
+            // ignored while the sound is off
+            SOUND_START..NR52_ADDRESS if !self.apu.powered() => return,
+            SOUND_START..=SOUND_END => self.apu.write(address, byte),
+            SC_ADDRESS => {
+                let sb = self.memory[SB_ADDRESS as usize];
+                let cgb = self.model == HardwareModel::Cgb;
+                self.serial.write_control(byte, sb, cgb);
+            }
+            JOYPAD_REGISTER_ADDRESS => {
+                // only the select lines are writable, the low nibble follows the buttons
+                self.sgb.write(byte);
+                self.memory[JOYPAD_REGISTER_ADDRESS as usize] = byte & (DPAD_FLAG | BUTTONS_FLAG);
+                return;
+            }
+            BCPD_ADDRESS | OCPD_ADDRESS => return self.write_palette_data(address, byte),
             _ => (),
         }
 
-        let address = address as usize;
+        let address = Self::unmirror(address as usize);
 
         let ctype = self.get_cartridge_type();
         match ctype {
             CartridgeType::RomOnly => {
-                if address >= 0x8000 {
+                if address < 0x8000 {
+                    self.check_rom_write(address as Address, byte);
+                } else if !(EXTERNAL_RAM_START..=EXTERNAL_RAM_END).contains(&address) {
                     self.memory[address] = byte;
                 }
             }
             CartridgeType::MBC1 => {
-                if address >= 0x8000 {
+                if address < 0x8000 || (EXTERNAL_RAM_START..=EXTERNAL_RAM_END).contains(&address) {
+                    self.write_mbc1(address, byte);
+                } else {
                     self.memory[address] = byte;
-                } else if address < 0x8000 {
-                    unimplemented!("{}", address2string(address as Address));
                 }
             }
             CartridgeType::MBC3 => {
-                if address >= 0x8000 {
+                if address < 0x8000 || (EXTERNAL_RAM_START..=EXTERNAL_RAM_END).contains(&address) {
+                    self.write_mbc3(address, byte);
+                } else {
+                    self.memory[address] = byte;
+                }
+            }
+            CartridgeType::MBC5 => {
+                if address < 0x8000 || (EXTERNAL_RAM_START..=EXTERNAL_RAM_END).contains(&address) {
+                    self.write_mbc5(address, byte);
+                } else {
                     self.memory[address] = byte;
-                } else if address < 0x8000 {
-                    unimplemented!("{}", address2string(address as Address));
                 }
             }
             CartridgeType::None => {
@@ -171,6 +1745,252 @@ impl Memory {
         }
     }
 
+    /// Write to the MBC1 registers, or to the selected ram bank
+    fn write_mbc1(&mut self, address: usize, byte: Byte) {
+        let CartridgeState::MBC1(state) = &mut self.cartridge else {
+            panic!("Cartridge is not MBC1");
+        };
+        match address {
+            0x0000..=0x1FFF => state.ram_enabled = byte & 0xF == 0xA,
+            0x2000..=0x3FFF => state.rom_number = ((byte & 0x1F) as usize).max(1),
+            0x4000..=0x5FFF => state.ram_number = (byte & 0b11) as usize,
+            0x6000..=0x7FFF => state.mode = byte & 1,
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END if state.ram_enabled => {
+                let bank = wrap_ram_bank(state.ram_bank(), self.ram.len());
+                if let Some(bank) = self.ram.get_mut(bank) {
+                    bank[address - EXTERNAL_RAM_START] = byte;
+                    self.ram_dirty = true;
+                }
+            }
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => (),
+            _ => unreachable!("{}", address2string(address as Address)),
+        }
+    }
+
+    /// Read from the selected MBC1 ram bank, 0xFF (open bus) if unavailable
+    fn read_mbc1_ram(&self, state: &MBC1State, address: usize) -> Byte {
+        if !state.ram_enabled {
+            return 0xFF;
+        }
+        let bank = wrap_ram_bank(state.ram_bank(), self.ram.len());
+        match self.ram.get(bank) {
+            Some(bank) => bank[address - EXTERNAL_RAM_START],
+            None => 0xFF,
+        }
+    }
+
+    /// Write to the MBC3 registers, or to the selected ram bank / rtc register
+    fn write_mbc3(&mut self, address: usize, byte: Byte) {
+        let CartridgeState::MBC3(state) = &mut self.cartridge else {
+            panic!("Cartridge is not MBC3");
+        };
+        match address {
+            0x0000..=0x1FFF => state.ram_enabled = byte & 0xF == 0xA,
+            0x2000..=0x3FFF => state.rom_number = ((byte & 0x7F) as usize).max(1),
+            0x4000..=0x5FFF => state.ram_number = byte as usize,
+            0x6000..=0x7FFF => {
+                if state.latch_write == 0x00 && byte == 0x01 {
+                    state.rtc.latch();
+                }
+                state.latch_write = byte;
+            }
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END if state.ram_enabled => match state.ram_number {
+                // banks 4-7 only exist on MBC30
+                0x00..=0x07 => {
+                    let bank = wrap_ram_bank(state.ram_number, self.ram.len());
+                    if let Some(bank) = self.ram.get_mut(bank) {
+                        bank[address - EXTERNAL_RAM_START] = byte;
+                        self.ram_dirty = true;
+                    }
+                }
+                0x08..=0x0C => state.rtc.write(state.ram_number - 0x08, byte),
+                _ => (),
+            },
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => (),
+            _ => unreachable!("{}", address2string(address as Address)),
+        }
+    }
+
+    /// Read from the selected MBC3 ram bank / rtc register, 0xFF (open bus) if unavailable
+    fn read_mbc3_ram(&self, state: &MBC3State, address: usize) -> Byte {
+        if !state.ram_enabled {
+            return 0xFF;
+        }
+        match state.ram_number {
+            0x00..=0x07 => {
+                let bank = wrap_ram_bank(state.ram_number, self.ram.len());
+                match self.ram.get(bank) {
+                    Some(bank) => bank[address - EXTERNAL_RAM_START],
+                    None => 0xFF,
+                }
+            }
+            0x08..=0x0C => state.rtc.read(state.ram_number - 0x08),
+            _ => 0xFF,
+        }
+    }
+
+    /// Advance the cartridge real time clock, if any
+    pub fn tick_rtc(&mut self, mcycles: u8) {
+        if let CartridgeState::MBC3(state) = &mut self.cartridge {
+            state.rtc.tick(mcycles);
+        }
+    }
+
+    /// Write to the MBC5 registers, or to the selected ram bank
+    fn write_mbc5(&mut self, address: usize, byte: Byte) {
+        let CartridgeState::MBC5(state) = &mut self.cartridge else {
+            panic!("Cartridge is not MBC5");
+        };
+        match address {
+            0x0000..=0x1FFF => state.ram_enabled = byte == 0x0A,
+            0x2000..=0x2FFF => state.rom_number = (state.rom_number & 0x100) | byte as usize,
+            0x3000..=0x3FFF => {
+                state.rom_number = (state.rom_number & 0xFF) | ((byte as usize & 1) << 8)
+            }
+            0x4000..=0x5FFF if state.has_rumble => {
+                state.ram_number = (byte & 0x07) as usize;
+                let rumble = byte & 0x08 != 0;
+                state.rumble_changed |= rumble != state.rumble;
+                state.rumble = rumble;
+            }
+            0x4000..=0x5FFF => state.ram_number = (byte & 0x0F) as usize,
+            0x6000..=0x7FFF => (),
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END if state.ram_enabled => {
+                let bank = wrap_ram_bank(state.ram_number, self.ram.len());
+                if let Some(bank) = self.ram.get_mut(bank) {
+                    bank[address - EXTERNAL_RAM_START] = byte;
+                    self.ram_dirty = true;
+                }
+            }
+            EXTERNAL_RAM_START..=EXTERNAL_RAM_END => (),
+            _ => unreachable!("{}", address2string(address as Address)),
+        }
+    }
+
+    /// Read from the selected MBC5 ram bank, 0xFF (open bus) if unavailable
+    fn read_mbc5_ram(&self, state: &MBC5State, address: usize) -> Byte {
+        if !state.ram_enabled {
+            return 0xFF;
+        }
+        let bank = wrap_ram_bank(state.ram_number, self.ram.len());
+        match self.ram.get(bank) {
+            Some(bank) => bank[address - EXTERNAL_RAM_START],
+            None => 0xFF,
+        }
+    }
+
+    /// Palette ram index selected by BCPS/OCPS
+    fn palette_index(&self, spec_address: Address) -> usize {
+        (self.memory[spec_address as usize] & 0x3F) as usize
+    }
+
+    /// Write palette ram through BCPD/OCPD, incrementing the index if auto increment is set
+    fn write_palette_data(&mut self, data_address: Address, byte: Byte) {
+        let (spec_address, palette_ram) = match data_address {
+            BCPD_ADDRESS => (BCPS_ADDRESS, &mut self.bg_palette_ram),
+            _ => (OCPS_ADDRESS, &mut self.obj_palette_ram),
+        };
+        let spec = self.memory[spec_address as usize];
+        palette_ram[(spec & 0x3F) as usize] = byte;
+        if spec & PALETTE_AUTO_INCREMENT_FLAG != 0 {
+            self.memory[spec_address as usize] =
+                PALETTE_AUTO_INCREMENT_FLAG | (spec.wrapping_add(1) & 0x3F);
+        }
+    }
+
+    /// The four 15 bit colors of a CGB background or object palette
+    pub fn cgb_palette(&self, object: bool, palette: usize) -> [Word; 4] {
+        let palette_ram = if object {
+            &self.obj_palette_ram
+        } else {
+            &self.bg_palette_ram
+        };
+        let mut colors = [0; 4];
+        for (i, color) in colors.iter_mut().enumerate() {
+            let offset = palette * 8 + i * 2;
+            *color = bytes2word(palette_ram[offset], palette_ram[offset + 1]) & 0x7FFF;
+        }
+        colors
+    }
+
+    /// Rumble motor state if it changed since the last call
+    pub fn take_rumble_state(&mut self) -> Option<bool> {
+        match &mut self.cartridge {
+            CartridgeState::MBC5(state) if state.rumble_changed => {
+                state.rumble_changed = false;
+                Some(state.rumble)
+            }
+            _ => None,
+        }
+    }
+
+    /// Report writes to rom on cartridges without an mbc
+    pub fn set_rom_write_check(&mut self, check: RomWriteCheck) {
+        self.rom_write_check = check;
+    }
+
+    fn check_rom_write(&mut self, address: Address, byte: Byte) {
+        if self.rom_write_check == RomWriteCheck::Off {
+            return;
+        }
+        warn!("Write to rom {:#06X} = {:#04X}", address, byte);
+        if self.rom_write_check == RomWriteCheck::Break {
+            self.rom_write = Some((address, byte));
+        }
+    }
+
+    /// Stray rom write that should pause the debugger, if one happened since the last call
+    pub fn take_rom_write(&mut self) -> Option<(Address, Byte)> {
+        self.rom_write.take()
+    }
+
+    /// Run the next cartridge as model instead of the one its header asks for
+    pub fn force_model(&mut self, model: Option<HardwareModel>) {
+        self.forced_model = model;
+    }
+
+    /// Fail loading cartridges with bad checksums instead of logging a warning
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Header of the loaded cartridge
+    pub fn header(&self) -> Option<&CartridgeHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn get_model(&self) -> HardwareModel {
+        self.model
+    }
+
+    pub fn set_model(&mut self, model: HardwareModel) {
+        self.model = model;
+        self.wram = match model {
+            HardwareModel::Cgb => vec![[0; WRAM_BANK_SIZE]; WRAM_BANKS],
+            HardwareModel::Dmg => Vec::new(),
+        };
+        self.memory[SVBK_ADDRESS as usize] = 0;
+    }
+
+    /// Work ram bank mapped at 0xD000-0xDFFF, selecting bank 0 maps bank 1
+    fn wram_bank(&self) -> usize {
+        (self.memory[SVBK_ADDRESS as usize] as usize & 0b111).max(1)
+    }
+
+    /// Swap the selected work ram bank into the flat memory, so reads, writes
+    /// and echo ram go through it without further routing
+    fn switch_wram_bank(&mut self, svbk: Byte) {
+        let old = self.wram_bank();
+        self.memory[SVBK_ADDRESS as usize] = svbk;
+        let new = self.wram_bank();
+        if old == new {
+            return;
+        }
+        let mapped = &mut self.memory[WRAM_BANK_START..WRAM_BANK_START + WRAM_BANK_SIZE];
+        self.wram[old].copy_from_slice(mapped);
+        mapped.copy_from_slice(&self.wram[new]);
+    }
+
     /// Get cartridge type from memory
     pub fn get_cartridge_type(&self) -> CartridgeType {
         match self.cartridge {
@@ -178,43 +1998,117 @@ impl Memory {
             CartridgeState::RomOnly(_) => CartridgeType::RomOnly,
             CartridgeState::MBC1(_) => CartridgeType::MBC1,
             CartridgeState::MBC3(_) => CartridgeType::MBC3,
+            CartridgeState::MBC5(_) => CartridgeType::MBC5,
         }
     }
 
-    /// Get cartridge type given rom (in vec)
+    /// Get cartridge type given rom (in vec), None when the type is not emulated
     pub fn get_cartridge_type_rom(&self, rom: &[Byte]) -> CartridgeType {
         let rom_type = rom[MBC_TYPE_ADDRESS as usize];
         match rom_type {
             0x00 => CartridgeType::RomOnly,
-            0x01 => CartridgeType::MBC1,
-            0x13 => CartridgeType::MBC3,
-            _ => unimplemented!("Rom type {:#04X?}", rom_type),
+            0x01..=0x03 => CartridgeType::MBC1,
+            0x0F..=0x13 => CartridgeType::MBC3,
+            0x19..=0x1E => CartridgeType::MBC5,
+            _ => CartridgeType::None,
         }
     }
 
     /// Get rom size
     pub fn get_rom_size_rom(&self, rom: &[Byte]) -> usize {
-        let rom_size = rom[ROM_SIZE_ADDRESS as usize].into();
-        rom_size
+        rom[ROM_SIZE_ADDRESS as usize].into()
     }
 
     /// Get ram size
     pub fn get_ram_size_rom(&self, rom: &[Byte]) -> usize {
-        let ram_size = rom[RAM_SIZE_ADDRESS as usize].into();
-        ram_size
+        rom[RAM_SIZE_ADDRESS as usize].into()
+    }
+
+    /// Get number of ram banks given the header ram size
+    pub fn get_ram_bank_count(&self, ram_size: usize) -> Option<usize> {
+        Byte::try_from(ram_size).ok().and_then(ram_bank_count)
+    }
+
+    /// Whether the boot rom is still mapped over the start of the rom
+    pub fn boot_mapped(&self) -> bool {
+        self.boot_mapped
     }
 
     fn unload_boot(&mut self) {
         info!("Unloading boot rom");
+        self.boot_mapped = false;
         self.memory[..BOOTROM_SIZE].copy_from_slice(&self.rom[0][..BOOTROM_SIZE]);
     }
 
+    /// Start (or restart) an OAM DMA from page byte, the copy happens in tick_dma
     fn dma(&mut self, byte: Byte) {
-        let size = 0x100;
-        let src = bytes2word(0x00, byte) as usize;
+        self.dma = Some(OamDma {
+            source: bytes2word(0x00, byte),
+            copied: 0,
+        });
+        if self.instant_dma {
+            self.tick_dma(DMA_SIZE as Byte);
+        }
+    }
 
-        self.memory
-            .copy_within(src..(src + size), OAM_ADDRESS as usize);
+    /// Finish a serial transfer, nothing is connected so 0xFF is shifted in
+    pub fn tick_serial(&mut self, mcycles: u8) {
+        if self.serial.tick(mcycles) {
+            self.memory[SB_ADDRESS as usize] = 0xFF;
+            self.memory[SC_ADDRESS as usize] &= !TRANSFER_FLAG;
+            self.memory[INTERRUPT_FLAG_ADDRESS as usize] |= SERIAL_FLAG;
+        }
+    }
+
+    /// Bytes sent through the link port since the last call
+    pub fn take_serial_output(&mut self) -> Vec<Byte> {
+        self.serial.take_output()
+    }
+
+    /// Copy one byte per machine cycle of the OAM DMA in flight
+    pub fn tick_dma(&mut self, mcycles: u8) {
+        for _ in 0..mcycles {
+            let Some(mut dma) = self.dma else {
+                return;
+            };
+            // read through the memory map, so banked and mirrored sources resolve
+            let byte = self.ppu_read_byte(dma.source + dma.copied);
+            self.memory[(OAM_ADDRESS + dma.copied) as usize] = byte;
+            dma.copied += 1;
+            self.dma = (dma.copied < DMA_SIZE).then_some(dma);
+        }
+    }
+
+    /// Set the pressed dpad and button lines, active low nibbles
+    pub fn set_joypad_state(&mut self, dpad: Byte, buttons: Byte) {
+        self.joypad_dpad = dpad & 0xF;
+        self.joypad_buttons = buttons & 0xF;
+    }
+
+    /// P1 with the low nibble from the selected lines, the sgb joypad id if none is selected
+    fn read_joypad(&self) -> Byte {
+        let select = self.memory[JOYPAD_REGISTER_ADDRESS as usize] & (DPAD_FLAG | BUTTONS_FLAG);
+        let mut lines = 0xF;
+        if select & DPAD_FLAG == 0 {
+            lines &= self.joypad_dpad;
+        }
+        if select & BUTTONS_FLAG == 0 {
+            lines &= self.joypad_buttons;
+        }
+        if select == DPAD_FLAG | BUTTONS_FLAG {
+            lines = self.sgb.player_id();
+        }
+        select | lines
+    }
+
+    /// Joypad id reported in P1 while neither buttons nor dpad are selected
+    pub fn sgb_player_id(&self) -> Byte {
+        self.sgb.player_id()
+    }
+
+    /// Whether an OAM DMA is in flight
+    pub fn dma_active(&self) -> bool {
+        self.dma.is_some()
     }
 
     /// Wrapping add value to address
@@ -225,6 +2119,55 @@ impl Memory {
         self.write_byte(address, mem_val);
     }
 
+    /// Any write to DIV resets the divider, if DIV bit 4 was set this is a falling edge
+    /// that clocks the frame sequencer
+    fn reset_div(&mut self) {
+        if self.memory[Clock::DIV_ADDRESS as usize] & Clock::DIV_APU_BIT != 0 {
+            self.clock_frame_sequencer();
+        }
+        self.memory[Clock::DIV_ADDRESS as usize] = 0;
+        self.div_reset = true;
+    }
+
+    /// Whether DIV was reset since the last call
+    pub fn take_div_reset(&mut self) -> bool {
+        std::mem::take(&mut self.div_reset)
+    }
+
+    /// Set DIV as the divider counts, without resetting it
+    pub fn set_div(&mut self, div: Byte) {
+        self.memory[Clock::DIV_ADDRESS as usize] = div;
+    }
+
+    /// Whether TIMA was written since the last call
+    pub fn take_tima_write(&mut self) -> bool {
+        std::mem::take(&mut self.tima_written)
+    }
+
+    /// Set TIMA as the timer counts, without it counting as a cpu write
+    pub fn set_tima(&mut self, tima: Byte) {
+        self.memory[Clock::TIMA_ADDRESS as usize] = tima;
+    }
+
+    /// Advance the frame sequencer by one step (DIV-APU event)
+    pub fn clock_frame_sequencer(&mut self) {
+        self.apu.clock_frame_sequencer(self.frame_sequencer);
+        self.frame_sequencer = (self.frame_sequencer + 1) % 8;
+    }
+
+    /// Left and right audio samples produced since the last call
+    pub fn take_audio_samples(&mut self) -> Vec<(f32, f32)> {
+        self.apu.take_samples()
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn get_frame_sequencer(&self) -> Byte {
+        self.frame_sequencer
+    }
+
     pub fn write_test(&mut self, rom: Vec<Byte>) {
         self.memory[..rom.len()].copy_from_slice(&rom);
     }
@@ -0,0 +1,88 @@
+use crate::{
+    state::{StateError, StateReader, StateWriter},
+    utils::{Address, Byte},
+};
+
+pub const SB_ADDRESS: Address = 0xFF01;
+pub const SC_ADDRESS: Address = 0xFF02;
+pub const TRANSFER_FLAG: Byte = 0b1000_0000;
+const FAST_CLOCK_FLAG: Byte = 0b0000_0010;
+const INTERNAL_CLOCK_FLAG: Byte = 0b0000_0001;
+
+/// Machine cycles per bit with the 8192 Hz internal clock
+const BIT_CYCLES: u32 = 128;
+/// The CGB fast clock runs at 262144 Hz
+const FAST_BIT_CYCLES: u32 = 4;
+
+/// Link port without a peer, transfers clocked internally shift in 0xFF
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Serial {
+    /// Machine cycles left until the transfer in flight completes
+    remaining: Option<u32>,
+    /// Bytes sent since the last take_output, e.g. blargg test results
+    output: Vec<Byte>,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Follow a write to SC, bit 7 with the internal clock starts sending sb.
+    /// With the external clock the transfer waits for a peer that never clocks it
+    pub fn write_control(&mut self, sc: Byte, sb: Byte, cgb: bool) {
+        if sc & TRANSFER_FLAG == 0 {
+            self.remaining = None;
+            return;
+        }
+        if sc & INTERNAL_CLOCK_FLAG == 0 {
+            return;
+        }
+        let bit_cycles = if cgb && sc & FAST_CLOCK_FLAG != 0 {
+            FAST_BIT_CYCLES
+        } else {
+            BIT_CYCLES
+        };
+        self.remaining = Some(8 * bit_cycles);
+        self.output.push(sb);
+    }
+
+    /// Advance the transfer, true when it completes
+    pub fn tick(&mut self, mcycles: u8) -> bool {
+        match self.remaining {
+            Some(remaining) if remaining <= mcycles as u32 => {
+                self.remaining = None;
+                true
+            }
+            Some(remaining) => {
+                self.remaining = Some(remaining - mcycles as u32);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn write_state(&self, state: &mut StateWriter) {
+        state.bool(self.remaining.is_some());
+        if let Some(remaining) = self.remaining {
+            state.u32(remaining);
+        }
+    }
+
+    /// Transfer in flight from write_state, the output is not part of the state
+    pub fn read_state(state: &mut StateReader) -> Result<Self, StateError> {
+        let remaining = match state.bool()? {
+            true => Some(state.u32()?),
+            false => None,
+        };
+        Ok(Self {
+            remaining,
+            output: Vec::new(),
+        })
+    }
+
+    /// Bytes sent since the last call
+    pub fn take_output(&mut self) -> Vec<Byte> {
+        std::mem::take(&mut self.output)
+    }
+}
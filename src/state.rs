@@ -0,0 +1,177 @@
+use std::{error::Error, fmt};
+
+use crate::utils::{Byte, Word};
+
+/// First bytes of every save state
+const MAGIC: &[Byte; 4] = b"GBST";
+/// Bumped whenever the layout of a save state changes
+const VERSION: Byte = 4;
+
+/// Errors when restoring a save state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The data does not start with the save state magic and version
+    BadFormat,
+    /// The data ends before every field was read
+    Truncated,
+    /// A field holds a value the emulator can not be in
+    InvalidValue(&'static str),
+    /// The state was saved with a different rom
+    RomMismatch { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadFormat => write!(f, "not a save state, or saved by another version"),
+            Self::Truncated => write!(f, "save state is truncated"),
+            Self::InvalidValue(field) => write!(f, "save state has an invalid {}", field),
+            Self::RomMismatch { expected, actual } => write!(
+                f,
+                "save state is for rom {:#018X} but the loaded rom is {:#018X}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// FNV-1a hash identifying a rom, save states store it instead of the rom itself
+pub fn rom_hash<'a>(banks: impl IntoIterator<Item = &'a [Byte]>) -> u64 {
+    banks
+        .into_iter()
+        .flatten()
+        .fold(0xCBF2_9CE4_8422_2325, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x0100_0000_01B3)
+        })
+}
+
+/// Appends little endian fields after the save state header
+pub struct StateWriter {
+    data: Vec<Byte>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        Self { data }
+    }
+
+    pub fn byte(&mut self, byte: Byte) {
+        self.data.push(byte);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.byte(value as Byte);
+    }
+
+    pub fn word(&mut self, word: Word) {
+        self.data.extend_from_slice(&word.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u128(&mut self, value: u128) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Sizes and bank numbers, stored as u32
+    pub fn usize(&mut self, value: usize) {
+        self.u32(u32::try_from(value).expect("state field does not fit in u32"));
+    }
+
+    /// Raw bytes, the reader has to know the length
+    pub fn bytes(&mut self, bytes: &[Byte]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<Byte> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads back the fields of a StateWriter in the same order
+pub struct StateReader<'a> {
+    data: &'a [Byte],
+}
+
+impl<'a> StateReader<'a> {
+    /// Check the header, the fields follow
+    pub fn new(data: &'a [Byte]) -> Result<Self, StateError> {
+        match data.split_at_checked(MAGIC.len() + 1) {
+            Some((header, data)) if header[..MAGIC.len()] == *MAGIC && header[4] == VERSION => {
+                Ok(Self { data })
+            }
+            _ => Err(StateError::BadFormat),
+        }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[Byte; N], StateError> {
+        let bytes = self.bytes(N)?;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn byte(&mut self) -> Result<Byte, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue("flag")),
+        }
+    }
+
+    pub fn word(&mut self) -> Result<Word, StateError> {
+        Ok(Word::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn u128(&mut self) -> Result<u128, StateError> {
+        Ok(u128::from_le_bytes(self.take()?))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, StateError> {
+        Ok(self.u32()? as usize)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [Byte], StateError> {
+        let (bytes, rest) = self
+            .data
+            .split_at_checked(len)
+            .ok_or(StateError::Truncated)?;
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Every field was read, trailing data means the layout did not match
+    pub fn finish(self) -> Result<(), StateError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::InvalidValue("length"))
+        }
+    }
+}
@@ -1,17 +1,303 @@
 #[cfg(test)]
 mod tests {
-    use sdl2::keyboard::Keycode;
-
+    use sdl2::controller::{Axis, Button};
+    use sdl2::event::Event;
+    use sdl2::keyboard::{Keycode, Mod};
+    use serde_json::Value;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use crate::apu::{resample, Apu};
     use crate::clock::Clock;
     use crate::cpu::{
         Condition, Instruction, Register, Register16, SizedInstruction, CARRY_FLAG, CPU,
-        HALF_CARRY_FLAG, SUBTRACT_FLAG, ZERO_FLAG,
+        HALF_CARRY_FLAG, INTERRUPT_ENABLE_ADDRESS, INTERRUPT_FLAG_ADDRESS, SERIAL_FLAG,
+        SUBTRACT_FLAG, ZERO_FLAG,
+    };
+    use crate::gb::{
+        format_io_write, run_mooneye_test, AudioSink, BootError, Breakpoint, ControlAction,
+        GameBoy, LoadError, StateDiff, SymbolTable, TestResult,
+    };
+    use crate::graphics::{
+        encode_png, FrameSkip, Graphics, Layer, PPUMode, PaletteSnapshot, SpriteInfo, OAM_ADDRESS,
+        PPU,
     };
     use crate::joypad::{
         Joypad, A_BUTTON, BUTTONS_FLAG, B_BUTTON, DOWN_BUTTON, DPAD_FLAG, JOYPAD_REGISTER_ADDRESS,
         LEFT_BUTTON, RIGHT_BUTTON, SELECT_BUTTON, START_BUTTON, UP_BUTTON,
     };
-    use crate::memory::Memory;
+    use crate::memory::{
+        io_register_name, unpack_rom, Bus, CartridgeError, CartridgeHeader, HardwareModel, Memory,
+        RomWriteCheck,
+    };
+    use crate::state::{StateError, StateReader, StateWriter};
+    use crate::utils::{Address, Byte};
+
+    /// Flat 64KB bus without side effects that records every write
+    struct RecordingBus {
+        memory: Vec<Byte>,
+        writes: Vec<(Address, Byte)>,
+    }
+
+    impl RecordingBus {
+        fn new(program: &[Byte]) -> Self {
+            let mut memory = vec![0; 0x10000];
+            memory[..program.len()].copy_from_slice(program);
+            Self {
+                memory,
+                writes: Vec::new(),
+            }
+        }
+    }
+
+    impl Bus for RecordingBus {
+        fn read_byte(&self, address: Address) -> Byte {
+            self.memory[address as usize]
+        }
+
+        fn write_byte(&mut self, address: Address, byte: Byte) {
+            self.writes.push((address, byte));
+            self.memory[address as usize] = byte;
+        }
+
+        // the divider counting is not a write
+        fn set_div(&mut self, div: Byte) {
+            self.memory[Clock::DIV_ADDRESS as usize] = div;
+        }
+    }
+
+    #[test]
+    fn echo_ram() {
+        let mut memory = Memory::new();
+
+        memory.write_byte(0xC000, 0x12);
+        assert_eq!(memory.read_byte(0xE000), 0x12);
+        memory.write_byte(0xFDFF, 0x34);
+        assert_eq!(memory.read_byte(0xDDFF), 0x34);
+        assert_eq!(memory.read_word(0xDDFF - 1), 0x3400);
+
+        // past the echo region is oam, not a mirror of 0xDE00
+        memory.write_byte(0xDE00, 0x56);
+        assert_ne!(memory.read_byte(0xFE00), 0x56);
+    }
+
+    #[test]
+    fn wram_banks_cgb() {
+        let mut memory = Memory::new();
+        memory.set_model(HardwareModel::Cgb);
+
+        assert_eq!(memory.read_byte(0xFF70), 0xF8);
+        for bank in 1..8 {
+            memory.write_byte(0xFF70, bank);
+            assert_eq!(memory.read_byte(0xFF70), 0xF8 | bank);
+            memory.write_byte(0xD000, bank * 0x10);
+            memory.write_byte(0xDFFF, bank * 0x10 + 1);
+        }
+        // bank 0 is always at 0xC000
+        memory.write_byte(0xC000, 0xAA);
+
+        for bank in 1..8 {
+            memory.write_byte(0xFF70, bank);
+            assert_eq!(memory.read_byte(0xD000), bank * 0x10);
+            assert_eq!(memory.read_byte(0xDFFF), bank * 0x10 + 1);
+            // echo ram follows the selected bank
+            assert_eq!(memory.read_byte(0xF000), bank * 0x10);
+            assert_eq!(memory.read_byte(0xC000), 0xAA);
+        }
+
+        // selecting bank 0 maps bank 1 but reads back 0
+        memory.write_byte(0xFF70, 0);
+        assert_eq!(memory.read_byte(0xFF70), 0xF8);
+        assert_eq!(memory.read_byte(0xD000), 0x10);
+        memory.write_byte(0xF001, 0x99);
+        memory.write_byte(0xFF70, 2);
+        assert_eq!(memory.read_byte(0xD001), 0x00);
+        memory.write_byte(0xFF70, 1);
+        assert_eq!(memory.read_byte(0xD001), 0x99);
+
+        // switched out banks are part of the save state
+        let state = memory.save_state();
+        memory.set_model(HardwareModel::Cgb);
+        memory.load_state(&state).unwrap();
+        memory.write_byte(0xFF70, 7);
+        assert_eq!(memory.read_byte(0xD000), 0x70);
+    }
+
+    #[test]
+    fn wram_banks_dmg() {
+        let mut memory = Memory::new();
+
+        memory.write_byte(0xD000, 0x12);
+        memory.write_byte(0xFF70, 2);
+        assert_eq!(memory.read_byte(0xFF70), 0xFF);
+        assert_eq!(memory.read_byte(0xD000), 0x12);
+    }
+
+    #[test]
+    fn unusable_region() {
+        let mut memory = Memory::new();
+
+        // writes are dropped, reads are 0x00 on dmg
+        memory.write_byte(0xFEA0, 0x12);
+        memory.write_byte(0xFEFF, 0x34);
+        assert_eq!(memory.read_byte(0xFEA0), 0x00);
+        assert_eq!(memory.read_byte(0xFEFF), 0x00);
+        // oam itself is writable
+        memory.write_byte(0xFE9F, 0x56);
+        assert_eq!(memory.read_byte(0xFE9F), 0x56);
+
+        // 0xFF while the ppu has OAM locked
+        memory.write_byte(0xFF40, 0x80);
+        memory.write_byte(0xFF41, 0x02);
+        assert_eq!(memory.read_byte(0xFEA0), 0xFF);
+        memory.write_byte(0xFF41, 0x03);
+        assert_eq!(memory.read_byte(0xFEC0), 0xFF);
+        memory.write_byte(0xFF41, 0x00);
+        assert_eq!(memory.read_byte(0xFEC0), 0x00);
+
+        // cgb repeats the high nibble of the address
+        memory.set_model(HardwareModel::Cgb);
+        assert_eq!(memory.read_byte(0xFEA5), 0xAA);
+        assert_eq!(memory.read_byte(0xFEF0), 0xFF);
+    }
+
+    #[test]
+    fn dma_from_echo_ram() {
+        let mut memory = Memory::new();
+        for i in 0..0xA0 {
+            memory.write_byte(0xC100 + i, i as u8 + 1);
+        }
+
+        memory.write_byte(0xFF46, 0xE1);
+        memory.tick_dma(0xA0);
+        for i in 0..0xA0 {
+            assert_eq!(memory.read_byte(0xFE00 + i), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn dma_from_banked_rom_and_ram() {
+        let mut memory = Memory::new();
+        let mut rom = cartridge_rom(0x1B, 0x01, 0x03);
+        for i in 0..0xA0 {
+            rom[3 * 0x4000 + i] = 0xA0 - i as u8;
+        }
+        memory.load_cartidge(rom).unwrap();
+
+        // rom bank 3
+        memory.write_byte(0x2000, 0x03);
+        memory.write_byte(0xFF46, 0x40);
+        memory.tick_dma(0xA0);
+        for i in 0..0xA0 {
+            assert_eq!(memory.read_byte(0xFE00 + i), 0xA0 - i as u8);
+        }
+
+        // ram bank 2
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0x4000, 0x02);
+        for i in 0..0xA0 {
+            memory.write_byte(0xA000 + i, i as u8);
+        }
+        memory.write_byte(0x4000, 0x00);
+        memory.write_byte(0xA000, 0x55);
+        memory.write_byte(0x4000, 0x02);
+        memory.write_byte(0xFF46, 0xA0);
+        memory.tick_dma(0xA0);
+        for i in 0..0xA0 {
+            assert_eq!(memory.read_byte(0xFE00 + i), i as u8);
+        }
+
+        // disabled ram reads as 0xFF
+        memory.write_byte(0x0000, 0x00);
+        memory.write_byte(0xFF46, 0xA0);
+        memory.tick_dma(0xA0);
+        assert_eq!(memory.read_byte(0xFE00), 0xFF);
+    }
+
+    #[test]
+    fn instant_dma() {
+        let mut memory = Memory::new();
+        memory.set_instant_dma(true);
+        for i in 0..0xA0 {
+            memory.write_byte(0xC000 + i, i as u8 + 1);
+        }
+
+        memory.write_byte(0xFF46, 0xC0);
+        assert!(!memory.dma_active());
+        assert_eq!(memory.read_byte(0xC000), 0x01);
+        for i in 0..0xA0 {
+            assert_eq!(memory.read_byte(0xFE00 + i), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn dma_takes_160_cycles() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+        for i in 0..0xA0 {
+            memory.write_byte(0xC000 + i, i as u8 + 1);
+        }
+        memory.write_byte(0xC0A0, 0x34);
+        memory.write_byte(0xFF80, 0x12);
+
+        memory.write_byte(0xFF46, 0xC0);
+        assert!(memory.dma_active());
+        clock.tick(1, &mut memory);
+
+        // only hram and the i/o registers are reachable
+        assert_eq!(memory.read_byte(0xC000), 0xFF);
+        assert_eq!(memory.read_byte(0x0000), 0xFF);
+        assert_eq!(memory.read_byte(0xFF80), 0x12);
+        assert_eq!(memory.read_byte(0xFF46), 0xC0);
+
+        clock.tick(158, &mut memory);
+        assert!(memory.dma_active());
+        assert_eq!(memory.ppu_read_byte(0xFE9E), 0x9F);
+        assert_eq!(memory.ppu_read_byte(0xFE9F), 0x00);
+
+        clock.tick(1, &mut memory);
+        assert!(!memory.dma_active());
+        assert_eq!(memory.read_byte(0xC000), 0x01);
+        for i in 0..0xA0 {
+            assert_eq!(memory.read_byte(0xFE00 + i), i as u8 + 1);
+        }
+        // only 0xA0 bytes are copied
+        assert_eq!(memory.read_byte(0xFEA0), 0x00);
+    }
+
+    #[test]
+    fn dump_and_restore() {
+        let mut memory = Memory::new();
+        for (i, address) in (0xC000..0xC100).enumerate() {
+            memory.write_byte(address, i as Byte);
+        }
+        let dump = memory.dump(0xC000..0xC100);
+        assert_eq!(dump.len(), 0x100);
+        assert_eq!(dump[0x42], 0x42);
+
+        for address in 0xC000..0xC100 {
+            memory.write_byte(address, 0xAA);
+        }
+        assert_ne!(memory.dump(0xC000..0xC100), dump);
+        memory.restore(0xC000..0xC100, &dump);
+        assert_eq!(memory.dump(0xC000..0xC100), dump);
+
+        let path = std::env::temp_dir().join("gb_rs_dump_test.bin");
+        memory.dump_to_file(&path, 0xC000..0xC100).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), dump);
+
+        // WRAM then HRAM
+        let mut gb = GameBoy::new(false);
+        gb.memory_mut().write_byte(0xDFFF, 0x12);
+        gb.memory_mut().write_byte(0xFF80, 0x34);
+        gb.dump_memory(&path).unwrap();
+        let dump = std::fs::read(&path).unwrap();
+        assert_eq!(dump.len(), 0x2000 + 0x7F);
+        assert_eq!(dump[0x1FFF], 0x12);
+        assert_eq!(dump[0x2000], 0x34);
+        std::fs::remove_file(path).unwrap();
+    }
 
     #[test]
     fn memory() {
@@ -25,11 +311,9 @@ mod tests {
 
     #[test]
     fn decode_ldrr() {
-        let mut memory = Memory::new();
-
-        memory.write_test(vec![0x41]);
+        let memory = RecordingBus::new(&[0x41]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -41,12 +325,10 @@ mod tests {
 
     #[test]
     fn decode_ldrn() {
-        let mut memory = Memory::new();
-
         let n = 3;
-        memory.write_test(vec![0x06, n]);
+        let memory = RecordingBus::new(&[0x06, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -56,13 +338,72 @@ mod tests {
         )
     }
 
+    #[test]
+    fn execute_on_recording_bus() {
+        let mut cpu = CPU::new();
+        let mut clock = Clock::new();
+        // LD (HL), A; PUSH BC
+        let mut memory = RecordingBus::new(&[0x77, 0xC5]);
+        cpu.a = 0x42;
+        cpu.set_register16(Register16::HL, 0xC000);
+        cpu.set_register16(Register16::BC, 0x1234);
+        cpu.sp = 0xFFFE;
+
+        cpu.execute(&mut memory, &mut clock);
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(
+            memory.writes,
+            vec![(0xC000, 0x42), (0xFFFD, 0x12), (0xFFFC, 0x34)]
+        );
+        assert_eq!(clock.get_timestamp(), 6);
+    }
+
+    #[test]
+    fn decode_at_top_of_memory() {
+        // LD A,(nn) at 0xFFFE, the high byte of nn wraps around to 0x0000
+        let mut memory = RecordingBus::new(&[0x12]);
+        memory.memory[0xFFFE] = 0xFA;
+        memory.memory[0xFFFF] = 0x34;
+        assert_eq!(
+            SizedInstruction::decode(&memory, 0xFFFE).unwrap(),
+            SizedInstruction {
+                instruction: Instruction::LD_A_NN(0x1234),
+                size: 3
+            }
+        );
+
+        // the same through Memory, with the operand in IE and rom
+        let mut memory = Memory::new();
+        memory.write_test(vec![0x12]);
+        memory.write_byte(0xFFFE, 0xFA);
+        memory.write_byte(0xFFFF, 0x34);
+        assert_eq!(memory.read_word(0xFFFF), 0x1234);
+        assert_eq!(
+            SizedInstruction::decode(&memory, 0xFFFE)
+                .unwrap()
+                .instruction,
+            Instruction::LD_A_NN(0x1234)
+        );
+    }
+
+    #[test]
+    fn ld_nn_sp_wraps_at_top_of_memory() {
+        let mut cpu = CPU::new();
+        let mut clock = Clock::new();
+        // LD (0xFFFF),SP
+        let mut memory = RecordingBus::new(&[0x08, 0xFF, 0xFF]);
+        cpu.sp = 0xABCD;
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(memory.writes, vec![(0xFFFF, 0xCD), (0x0000, 0xAB)]);
+    }
+
     #[test]
     fn decode_ldrhl() {
         let mut memory = Memory::new();
 
         memory.write_test(vec![0x46]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -78,7 +419,7 @@ mod tests {
 
         memory.write_test(vec![0x70]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -95,7 +436,7 @@ mod tests {
         let n = 3;
         memory.write_test(vec![0x36, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -111,7 +452,7 @@ mod tests {
 
         memory.write_test(vec![0x0A]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -127,7 +468,7 @@ mod tests {
 
         memory.write_test(vec![0x1A]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -143,7 +484,7 @@ mod tests {
 
         memory.write_test(vec![0x02]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -159,7 +500,7 @@ mod tests {
 
         memory.write_test(vec![0x12]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -175,7 +516,7 @@ mod tests {
 
         memory.write_test(vec![0xFA, 0x20, 0x03]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -191,7 +532,7 @@ mod tests {
 
         memory.write_test(vec![0xEA, 0x20, 0x03]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -207,7 +548,7 @@ mod tests {
 
         memory.write_test(vec![0xf2]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -223,7 +564,7 @@ mod tests {
 
         memory.write_test(vec![0xe2]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -240,7 +581,7 @@ mod tests {
         let n = 10;
         memory.write_test(vec![0xf0, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -257,7 +598,7 @@ mod tests {
         let n = 10;
         memory.write_test(vec![0xe0, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -273,7 +614,7 @@ mod tests {
 
         memory.write_test(vec![0x3a]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -289,7 +630,7 @@ mod tests {
 
         memory.write_test(vec![0x32]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -305,7 +646,7 @@ mod tests {
 
         memory.write_test(vec![0x2a]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -321,7 +662,7 @@ mod tests {
 
         memory.write_test(vec![0x22]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -337,7 +678,7 @@ mod tests {
 
         memory.write_test(vec![0x01, 0x10, 0x20]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -353,7 +694,7 @@ mod tests {
 
         memory.write_test(vec![0x31, 0x10, 0x20]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -369,7 +710,7 @@ mod tests {
 
         memory.write_test(vec![0x08, 0x30, 0x20]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -385,7 +726,7 @@ mod tests {
 
         memory.write_test(vec![0xf9]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -401,7 +742,7 @@ mod tests {
 
         memory.write_test(vec![0xF8, 0xFF]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -417,7 +758,7 @@ mod tests {
 
         memory.write_test(vec![0xC5]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -433,7 +774,7 @@ mod tests {
 
         memory.write_test(vec![0xC1]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -449,7 +790,7 @@ mod tests {
 
         memory.write_test(vec![0x80]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -465,7 +806,7 @@ mod tests {
 
         memory.write_test(vec![0x86]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -483,7 +824,7 @@ mod tests {
 
         memory.write_test(vec![0xC6, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -499,7 +840,7 @@ mod tests {
 
         memory.write_test(vec![0x88]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -515,7 +856,7 @@ mod tests {
 
         memory.write_test(vec![0x8E]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -533,7 +874,7 @@ mod tests {
 
         memory.write_test(vec![0xCE, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -549,7 +890,7 @@ mod tests {
 
         memory.write_test(vec![0x90]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -565,7 +906,7 @@ mod tests {
 
         memory.write_test(vec![0x96]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -582,7 +923,7 @@ mod tests {
         let n = 0x10;
         memory.write_test(vec![0xD6, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -598,7 +939,7 @@ mod tests {
 
         memory.write_test(vec![0x98]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -614,7 +955,7 @@ mod tests {
 
         memory.write_test(vec![0x9E]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -631,7 +972,7 @@ mod tests {
         let n = 0x10;
         memory.write_test(vec![0xDE, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -647,7 +988,7 @@ mod tests {
 
         memory.write_test(vec![0xB8]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -663,7 +1004,7 @@ mod tests {
 
         memory.write_test(vec![0xBE]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -680,7 +1021,7 @@ mod tests {
         let n = 100;
         memory.write_test(vec![0xFE, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -696,7 +1037,7 @@ mod tests {
 
         memory.write_test(vec![0x04]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -712,7 +1053,7 @@ mod tests {
 
         memory.write_test(vec![0x34]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -728,7 +1069,7 @@ mod tests {
 
         memory.write_test(vec![0x05]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -744,7 +1085,7 @@ mod tests {
 
         memory.write_test(vec![0x35]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -760,7 +1101,7 @@ mod tests {
 
         memory.write_test(vec![0xA0]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -776,7 +1117,7 @@ mod tests {
 
         memory.write_test(vec![0xA6]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -793,7 +1134,7 @@ mod tests {
         let n = 100;
         memory.write_test(vec![0xE6, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -809,7 +1150,7 @@ mod tests {
 
         memory.write_test(vec![0xB0]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -825,7 +1166,7 @@ mod tests {
 
         memory.write_test(vec![0xB6]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -842,7 +1183,7 @@ mod tests {
         let n = 100;
         memory.write_test(vec![0xF6, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -858,7 +1199,7 @@ mod tests {
 
         memory.write_test(vec![0xA8]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -874,7 +1215,7 @@ mod tests {
 
         memory.write_test(vec![0xAE]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -891,7 +1232,7 @@ mod tests {
         let n = 100;
         memory.write_test(vec![0xEE, n]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -907,7 +1248,7 @@ mod tests {
 
         memory.write_test(vec![0x3F]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -923,7 +1264,7 @@ mod tests {
 
         memory.write_test(vec![0x37]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -939,7 +1280,7 @@ mod tests {
 
         memory.write_test(vec![0x27]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -955,7 +1296,7 @@ mod tests {
 
         memory.write_test(vec![0x03]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -971,7 +1312,7 @@ mod tests {
 
         memory.write_test(vec![0x0B]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -987,7 +1328,7 @@ mod tests {
 
         memory.write_test(vec![0xC3, 0x20, 0x30]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1003,7 +1344,7 @@ mod tests {
 
         memory.write_test(vec![0xE9]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1019,7 +1360,7 @@ mod tests {
 
         memory.write_test(vec![0xC2, 0x20, 0x30]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1035,7 +1376,7 @@ mod tests {
 
         memory.write_test(vec![0x18, 0xff]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1051,7 +1392,7 @@ mod tests {
 
         memory.write_test(vec![0x28, 0xff]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1067,7 +1408,7 @@ mod tests {
 
         memory.write_test(vec![0x20, 0xff]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1083,7 +1424,7 @@ mod tests {
 
         memory.write_test(vec![0xCD, 0xff, 0x10]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1099,7 +1440,7 @@ mod tests {
 
         memory.write_test(vec![0xC4, 0xff, 0x10]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1115,7 +1456,7 @@ mod tests {
 
         memory.write_test(vec![0xC9]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1131,7 +1472,7 @@ mod tests {
 
         memory.write_test(vec![0xC0]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1147,7 +1488,7 @@ mod tests {
 
         memory.write_test(vec![0xD9]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1163,7 +1504,7 @@ mod tests {
 
         memory.write_test(vec![0xDF]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1180,7 +1521,7 @@ mod tests {
         let e = 255;
         memory.write_test(vec![0xE8, e]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1197,7 +1538,7 @@ mod tests {
         let e = 255;
         memory.write_test(vec![0xE8, e]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1213,7 +1554,7 @@ mod tests {
 
         memory.write_test(vec![0x1F]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1229,7 +1570,7 @@ mod tests {
 
         memory.write_test(vec![0x0F]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1245,7 +1586,7 @@ mod tests {
 
         memory.write_test(vec![0x17]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1261,7 +1602,7 @@ mod tests {
 
         memory.write_test(vec![0x07]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1277,7 +1618,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x01]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1293,7 +1634,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x12]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1309,7 +1650,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x24]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1325,7 +1666,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x26]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1341,7 +1682,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x35]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1357,7 +1698,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x08]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1373,7 +1714,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x1b]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1389,7 +1730,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x2c]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1405,7 +1746,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x3f]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1421,7 +1762,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x62]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1437,7 +1778,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0x99]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1453,7 +1794,7 @@ mod tests {
 
         memory.write_test(vec![0xCB, 0xea]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1469,7 +1810,7 @@ mod tests {
 
         memory.write_test(vec![0xFB]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1485,7 +1826,7 @@ mod tests {
 
         memory.write_test(vec![0xF3]);
 
-        let instr = SizedInstruction::decode(&mut memory, 0).unwrap();
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
         assert_eq!(
             instr,
             SizedInstruction {
@@ -1581,8 +1922,8 @@ mod tests {
         cpu.execute(&mut memory, &mut clock);
 
         assert_eq!(cpu.sp, 0xffff);
-        assert_eq!(cpu.get_flag(HALF_CARRY_FLAG), false);
-        assert_eq!(cpu.get_flag(CARRY_FLAG), false);
+        assert!(!cpu.get_flag(HALF_CARRY_FLAG));
+        assert!(!cpu.get_flag(CARRY_FLAG));
     }
 
     #[test]
@@ -1598,8 +1939,8 @@ mod tests {
         cpu.execute(&mut memory, &mut clock);
 
         assert_eq!(cpu.sp, 0xe);
-        assert_eq!(cpu.get_flag(HALF_CARRY_FLAG), true);
-        assert_eq!(cpu.get_flag(CARRY_FLAG), true);
+        assert!(cpu.get_flag(HALF_CARRY_FLAG));
+        assert!(cpu.get_flag(CARRY_FLAG));
     }
 
     #[test]
@@ -1615,10 +1956,10 @@ mod tests {
         cpu.execute(&mut memory, &mut clock);
 
         assert_eq!(cpu.b, 0xfe);
-        assert_eq!(cpu.get_flag(ZERO_FLAG), false);
-        assert_eq!(cpu.get_flag(HALF_CARRY_FLAG), false);
-        assert_eq!(cpu.get_flag(CARRY_FLAG), false);
-        assert_eq!(cpu.get_flag(SUBTRACT_FLAG), false);
+        assert!(!cpu.get_flag(ZERO_FLAG));
+        assert!(!cpu.get_flag(HALF_CARRY_FLAG));
+        assert!(!cpu.get_flag(CARRY_FLAG));
+        assert!(!cpu.get_flag(SUBTRACT_FLAG));
     }
 
     #[test]
@@ -1634,10 +1975,10 @@ mod tests {
         cpu.execute(&mut memory, &mut clock);
 
         assert_eq!(cpu.b, 0);
-        assert_eq!(cpu.get_flag(ZERO_FLAG), true);
-        assert_eq!(cpu.get_flag(HALF_CARRY_FLAG), false);
-        assert_eq!(cpu.get_flag(CARRY_FLAG), false);
-        assert_eq!(cpu.get_flag(SUBTRACT_FLAG), false);
+        assert!(cpu.get_flag(ZERO_FLAG));
+        assert!(!cpu.get_flag(HALF_CARRY_FLAG));
+        assert!(!cpu.get_flag(CARRY_FLAG));
+        assert!(!cpu.get_flag(SUBTRACT_FLAG));
     }
 
     #[test]
@@ -1653,9 +1994,9 @@ mod tests {
         cpu.execute(&mut memory, &mut clock);
 
         assert_eq!(cpu.get_hl(), 0);
-        assert_eq!(cpu.get_flag(HALF_CARRY_FLAG), true);
-        assert_eq!(cpu.get_flag(CARRY_FLAG), true);
-        assert_eq!(cpu.get_flag(ZERO_FLAG), false);
+        assert!(cpu.get_flag(HALF_CARRY_FLAG));
+        assert!(cpu.get_flag(CARRY_FLAG));
+        assert!(!cpu.get_flag(ZERO_FLAG));
     }
 
     #[test]
@@ -1673,6 +2014,197 @@ mod tests {
         assert_eq!(cpu.a, 0x1d);
     }
 
+    #[test]
+    fn execute_daa() {
+        // (a, flags) before and (a, flags) after
+        let cases = [
+            (0x9A, 0, 0x00, ZERO_FLAG | CARRY_FLAG),
+            (0x0A, 0, 0x10, 0),
+            (0x99, 0, 0x99, 0),
+            (0xA0, 0, 0x00, ZERO_FLAG | CARRY_FLAG),
+            (0x00, HALF_CARRY_FLAG, 0x06, 0),
+            (0x12, CARRY_FLAG, 0x72, CARRY_FLAG),
+            (0x3F, HALF_CARRY_FLAG | CARRY_FLAG, 0xA5, CARRY_FLAG),
+            // subtraction only adjusts on the flags
+            (0x0F, SUBTRACT_FLAG, 0x0F, SUBTRACT_FLAG),
+            (0x0F, SUBTRACT_FLAG | HALF_CARRY_FLAG, 0x09, SUBTRACT_FLAG),
+            (
+                0xF0,
+                SUBTRACT_FLAG | CARRY_FLAG,
+                0x90,
+                SUBTRACT_FLAG | CARRY_FLAG,
+            ),
+            (
+                0x66,
+                SUBTRACT_FLAG | HALF_CARRY_FLAG | CARRY_FLAG,
+                0x00,
+                ZERO_FLAG | SUBTRACT_FLAG | CARRY_FLAG,
+            ),
+        ];
+        for (a, flags, expected_a, expected_flags) in cases {
+            let mut cpu = CPU::new();
+            let mut memory = Memory::new();
+            let mut clock = Clock::new();
+
+            memory.write_test(vec![0x27]);
+
+            cpu.a = a;
+            cpu.f = flags;
+
+            cpu.execute(&mut memory, &mut clock);
+
+            assert_eq!(cpu.a, expected_a, "daa {:#04X} {:#010b}", a, flags);
+            assert_eq!(cpu.f, expected_flags, "daa {:#04X} {:#010b}", a, flags);
+        }
+    }
+
+    #[test]
+    fn execute_daa_after_add_and_sub() {
+        let mut cpu = CPU::new();
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        // ADD A,B; DAA; SUB C; DAA
+        memory.write_test(vec![0x80, 0x27, 0x91, 0x27]);
+
+        cpu.a = 0x45;
+        cpu.b = 0x38;
+        cpu.c = 0x29;
+
+        cpu.execute(&mut memory, &mut clock);
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.a, 0x83);
+        assert!(!cpu.get_flag(CARRY_FLAG));
+
+        cpu.execute(&mut memory, &mut clock);
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.a, 0x54);
+        assert!(!cpu.get_flag(CARRY_FLAG));
+        assert!(cpu.get_flag(SUBTRACT_FLAG));
+    }
+
+    #[test]
+    fn daa_all_bcd_sums() {
+        let bcd = |n: u8| ((n / 10) << 4) | (n % 10);
+        for x in 0..100 {
+            for y in 0..100 {
+                let mut cpu = CPU::new();
+                let mut memory = Memory::new();
+                let mut clock = Clock::new();
+
+                // ADD A,B; DAA
+                memory.write_test(vec![0x80, 0x27]);
+                cpu.a = bcd(x);
+                cpu.b = bcd(y);
+                cpu.execute(&mut memory, &mut clock);
+                cpu.execute(&mut memory, &mut clock);
+
+                assert_eq!(cpu.a, bcd((x + y) % 100), "{} + {}", x, y);
+                assert_eq!(cpu.get_flag(CARRY_FLAG), x + y >= 100, "{} + {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn step_returns_machine_cycles() {
+        // (program, flags, machine cycles)
+        let cases: [(&[Byte], Byte, u8); 16] = [
+            (&[0x00], 0, 1),
+            (&[0x41], 0, 1),
+            (&[0x06, 0x12], 0, 2),
+            (&[0x7E], 0, 2),
+            (&[0x01, 0x34, 0x12], 0, 3),
+            (&[0xEA, 0x00, 0xC0], 0, 4),
+            (&[0x08, 0x00, 0xC0], 0, 5),
+            (&[0xC5], 0, 4),
+            (&[0xC1], 0, 3),
+            (&[0xC3, 0x00, 0x01], 0, 4),
+            (&[0x20, 0x05], 0, 3),
+            (&[0x20, 0x05], ZERO_FLAG, 2),
+            (&[0xCD, 0x00, 0x01], 0, 6),
+            (&[0xC9], 0, 4),
+            (&[0xCB, 0x11], 0, 2),
+            (&[0xCB, 0x46], 0, 3),
+        ];
+        for (program, flags, cycles) in cases {
+            let mut cpu = CPU::new();
+            let mut memory = Memory::new();
+            let mut clock = Clock::new();
+
+            memory.write_test(program.to_vec());
+            cpu.f = flags;
+            cpu.sp = 0xD000;
+            cpu.h = 0xC0;
+
+            assert_eq!(
+                cpu.step(&mut memory, &mut clock),
+                cycles,
+                "{:02X?}",
+                program
+            );
+            assert_eq!(clock.get_timestamp(), cycles as u128);
+        }
+    }
+
+    #[test]
+    fn cb_prefix_cycles() {
+        // the prefix fetch is included: 2 for registers, 4 for (HL), 3 for BIT n, (HL)
+        for opcode in 0..=0xFF {
+            let expected = match (opcode & 0x07, opcode >> 6) {
+                (0x06, 1) => 3,
+                (0x06, _) => 4,
+                _ => 2,
+            };
+            let mut cpu = CPU::new();
+            let mut memory = Memory::new();
+            let mut clock = Clock::new();
+            memory.write_test(vec![0xCB, opcode]);
+            cpu.h = 0xC0;
+
+            assert_eq!(
+                cpu.step(&mut memory, &mut clock),
+                expected,
+                "CB {:02X}",
+                opcode
+            );
+            assert_eq!(cpu.pc, 2);
+        }
+    }
+
+    #[test]
+    fn conditional_control_flow_cycles() {
+        // (opcode, operands, taken cycles, not taken cycles), all conditioned on NZ
+        let cases: [(Byte, &[Byte], u128, u128); 4] = [
+            (0xC2, &[0x00, 0x01], 4, 3),
+            (0x20, &[0x05], 3, 2),
+            (0xC4, &[0x00, 0x01], 6, 3),
+            (0xC0, &[], 5, 2),
+        ];
+        for (opcode, operands, taken, not_taken) in cases {
+            for (flags, cycles) in [(0, taken), (ZERO_FLAG, not_taken)] {
+                let mut cpu = CPU::new();
+                let mut memory = Memory::new();
+                let mut clock = Clock::new();
+
+                let mut program = vec![opcode];
+                program.extend_from_slice(operands);
+                memory.write_test(program);
+                cpu.f = flags;
+                cpu.sp = 0xD000;
+
+                let start = clock.get_timestamp();
+                cpu.execute(&mut memory, &mut clock);
+                assert_eq!(
+                    clock.get_timestamp() - start,
+                    cycles,
+                    "{:#04X} {:#010b}",
+                    opcode,
+                    flags
+                );
+            }
+        }
+    }
+
     #[test]
     fn execute_set() {
         let mut cpu = CPU::new();
@@ -1728,155 +2260,1015 @@ mod tests {
     }
 
     #[test]
-    fn joypad_test_up() {
+    fn execute_halt() {
+        let mut cpu = CPU::new();
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
+        let mut clock = Clock::new();
 
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+        // HALT ; INC A
+        memory.write_test(vec![0x76, 0x3C]);
 
-        // Pressing some buttons and updating the joypad
-        joypad.handle_button(Keycode::W, true, &mut memory);
-        joypad.update(&mut memory);
+        cpu.execute(&mut memory, &mut clock);
 
-        assert_eq!(
-            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
-            UP_BUTTON & 0x0F
-        );
+        assert_eq!(cpu.pc, 1);
+        assert!(cpu.halt);
+        assert!(!cpu.halt_bug);
     }
 
     #[test]
-    fn joypad_test_left() {
+    fn execute_halt_bug() {
+        let mut cpu = CPU::new();
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
+        let mut clock = Clock::new();
 
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+        // HALT ; INC A, with ime disabled and an interrupt pending
+        memory.write_test(vec![0x76, 0x3C]);
+        memory.write_byte(INTERRUPT_ENABLE_ADDRESS, 0x01);
+        memory.write_byte(INTERRUPT_FLAG_ADDRESS, 0x01);
 
-        // Pressing some buttons and updating the joypad
-        joypad.handle_button(Keycode::A, true, &mut memory);
-        joypad.update(&mut memory);
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.pc, 1);
+        assert!(!cpu.halt);
 
-        assert_eq!(
-            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
-            LEFT_BUTTON & 0x0F
-        );
+        // INC A is executed twice
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.pc, 1);
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(cpu.a, 2);
     }
 
     #[test]
-    fn joypad_test_right() {
+    fn execute_halt_bug_operand() {
+        let mut cpu = CPU::new();
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
+        let mut clock = Clock::new();
 
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+        // HALT ; LD A,$14, with ime disabled and an interrupt pending
+        memory.write_test(vec![0x76, 0x3E, 0x14]);
+        memory.write_byte(INTERRUPT_ENABLE_ADDRESS, 0x01);
+        memory.write_byte(INTERRUPT_FLAG_ADDRESS, 0x01);
 
-        // Pressing some buttons and updating the joypad
-        joypad.handle_button(Keycode::D, true, &mut memory);
-        joypad.update(&mut memory);
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.pc, 1);
 
-        assert_eq!(
-            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
-            RIGHT_BUTTON & 0x0F
-        );
+        // the opcode byte is read again as the operand, so LD A,$3E then INC D
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(cpu.a, 0x3E);
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.pc, 3);
+        assert_eq!(cpu.d, 0x01);
     }
 
     #[test]
-    fn joypad_test_down() {
+    fn execute_halt_bug_jr() {
+        let mut cpu = CPU::new();
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
+        let mut clock = Clock::new();
 
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+        // HALT ; JR $02, with ime disabled and an interrupt pending
+        memory.write_test(vec![0x76, 0x18, 0x02]);
+        memory.write_byte(INTERRUPT_ENABLE_ADDRESS, 0x01);
+        memory.write_byte(INTERRUPT_FLAG_ADDRESS, 0x01);
 
-        // Pressing some buttons and updating the joypad
-        joypad.handle_button(Keycode::S, true, &mut memory);
-        joypad.update(&mut memory);
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.pc, 1);
 
-        assert_eq!(
-            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
-            DOWN_BUTTON & 0x0F
-        );
+        // the offset is the JR opcode itself, relative to the byte after it
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(cpu.pc, 2 + 0x18);
     }
 
     #[test]
-    fn joypad_test_a() {
+    fn sprites_on_line() {
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
-
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
-
-        // Pressing some buttons and updating the joypad
-        joypad.handle_button(Keycode::K, true, &mut memory);
-        joypad.update(&mut memory);
 
+        // y = 20 + 16 places the top row of the sprite on line 20
+        let sprites = [
+            (100, 20, 0x00), // not on the line
+            (36, 0, 0x00),   // hidden by x, still counts toward the limit
+            (36, 30, 0b0010_0000),
+            (30, 40, 0b1001_0000),
+        ];
+        for (i, (y, x, flag)) in sprites.into_iter().enumerate() {
+            let address = OAM_ADDRESS + 4 * i as u16;
+            memory.write_byte(address, y);
+            memory.write_byte(address + 1, x);
+            memory.write_byte(address + 2, i as u8);
+            memory.write_byte(address + 3, flag);
+        }
+        for i in 4..16 {
+            let address = OAM_ADDRESS + 4 * i;
+            memory.write_byte(address, 36);
+            memory.write_byte(address + 1, 8 + i as u8);
+        }
+
+        let line = Graphics::sprites_on_line(&memory, 20);
+        assert_eq!(line.len(), 10);
         assert_eq!(
-            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
-            A_BUTTON & 0x0F
+            line.iter().map(|s| s.index).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]
+        );
+        assert_eq!(line[0].screen_x, -8);
+        assert_eq!(
+            line[1],
+            SpriteInfo {
+                index: 2,
+                screen_x: 22,
+                screen_y: 20,
+                tile_num: 2,
+                priority: false,
+                y_flip: false,
+                x_flip: true,
+                palette: 0,
+            }
         );
+        assert_eq!(line[2].screen_y, 14);
+        assert!(line[2].priority);
+        assert_eq!(line[2].palette, 1);
+
+        // sprite 3 ends at line 21
+        let line = Graphics::sprites_on_line(&memory, 22);
+        assert_eq!(line[1].index, 2);
+        assert!(line.iter().all(|s| s.index != 3));
+        assert!(Graphics::sprites_on_line(&memory, 28).is_empty());
     }
 
     #[test]
-    fn joypad_test_b() {
+    fn tall_sprites() {
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
-
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
+        memory.write_byte(0xFF48, 0xE4);
+        // tile 2 is color 1, tile 3 is color 2
+        for row in 0..8 {
+            memory.write_byte(0x8020 + 2 * row, 0xFF);
+            memory.write_byte(0x8031 + 2 * row, 0xFF);
+        }
+        // the low bit of the tile number is ignored
+        memory.write_byte(OAM_ADDRESS, 16);
+        memory.write_byte(OAM_ADDRESS + 1, 8);
+        memory.write_byte(OAM_ADDRESS + 2, 3);
+
+        // sprite pixel at x = 0 on each of the first 17 lines
+        let column = |memory: &mut Memory, lcdc: u8, flag: u8| {
+            memory.write_byte(0xFF40, lcdc);
+            memory.write_byte(OAM_ADDRESS + 3, flag);
+            let mut ppu = PPU::new();
+            (0..17)
+                .map(|_| {
+                    ppu.draw_scanline(memory);
+                    screen_pixel(&ppu, 0, 0)
+                })
+                .collect::<Vec<_>>()
+        };
+        let empty = column(&mut memory, 0x80, 0)[0];
+
+        // 8x16: top tile then bottom tile
+        let tall = column(&mut memory, 0x86, 0);
+        let (top, bottom) = (tall[0], tall[8]);
+        assert_ne!(top, bottom);
+        assert_ne!(top, empty);
+        assert_ne!(bottom, empty);
+        assert_eq!(tall[..8], [top; 8]);
+        assert_eq!(tall[8..16], [bottom; 8]);
+        assert_eq!(tall[16], empty);
+        assert_eq!(Graphics::sprites_on_line(&memory, 15).len(), 1);
+        assert!(Graphics::sprites_on_line(&memory, 16).is_empty());
+
+        // y flip swaps the tiles
+        let flipped = column(&mut memory, 0x86, 0b0100_0000);
+        assert_eq!(flipped[..8], [bottom; 8]);
+        assert_eq!(flipped[8..16], [top; 8]);
+
+        // 8x8 uses tile 3 as is
+        let short = column(&mut memory, 0x82, 0);
+        assert_eq!(short[..8], [bottom; 8]);
+        assert_eq!(short[8], empty);
+        memory.write_byte(0xFF40, 0x82);
+        assert!(Graphics::sprites_on_line(&memory, 8).is_empty());
+    }
 
-        // Pressing some buttons and updating the joypad
-        joypad.handle_button(Keycode::J, true, &mut memory);
-        joypad.update(&mut memory);
+    #[test]
+    fn sprite_priority_by_x() {
+        let mut memory = Memory::new();
+        memory.write_byte(0xFF40, 0x82);
+        memory.write_byte(0xFF48, 0xE4);
+        // tile 1 is color 1, tile 2 is color 2
+        for row in 0..8 {
+            memory.write_byte(0x8010 + 2 * row, 0xFF);
+            memory.write_byte(0x8021 + 2 * row, 0xFF);
+        }
+        // OAM 0 at x = 4, OAM 1 at x = 0, overlapping on x 4..8
+        for (i, (x, tile)) in [(12, 1), (8, 2)].into_iter().enumerate() {
+            let address = OAM_ADDRESS + 4 * i as u16;
+            memory.write_byte(address, 16);
+            memory.write_byte(address + 1, x);
+            memory.write_byte(address + 2, tile);
+        }
+
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        let color2 = screen_pixel(&ppu, 0, 0);
+        let color1 = screen_pixel(&ppu, 8, 0);
+        assert_ne!(color1, color2);
+        // the smaller x wins despite the higher OAM index
+        assert_eq!(screen_pixel(&ppu, 4, 0), color2);
+
+        // same x, the lower OAM index wins
+        memory.write_byte(OAM_ADDRESS + 1, 8);
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        assert_eq!(screen_pixel(&ppu, 4, 0), color1);
+    }
 
-        assert_eq!(
-            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
-            B_BUTTON & 0x0F
-        );
+    #[test]
+    fn dma_sprite_renders() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+        memory.write_byte(0xFF40, 0x82);
+        memory.write_byte(0xFF48, 0xE4);
+        // tile 1 is color 1
+        for row in 0..8 {
+            memory.write_byte(0x8010 + 2 * row, 0xFF);
+        }
+        // sprite 0 at the top left corner, the rest off screen
+        for i in 0..0xA0 {
+            memory.write_byte(0xC000 + i, 0);
+        }
+        for (i, byte) in [16, 8, 1, 0].into_iter().enumerate() {
+            memory.write_byte(0xC000 + i as u16, byte);
+        }
+
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        let empty = screen_pixel(&ppu, 0, 0);
+
+        memory.write_byte(0xFF46, 0xC0);
+        clock.tick(160, &mut memory);
+        assert!(!memory.dma_active());
+        for i in 0..0xA0 {
+            assert_eq!(memory.read_byte(0xFE00 + i), memory.read_byte(0xC000 + i));
+        }
+
+        // the next frame draws the sprite from the copied OAM
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        assert_ne!(screen_pixel(&ppu, 0, 0), empty);
+        assert_eq!(screen_pixel(&ppu, 8, 0), empty);
     }
 
     #[test]
-    fn joypad_test_select() {
+    fn sprite_priority_cgb_by_index() {
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
+        memory.set_model(HardwareModel::Cgb);
+        memory.write_byte(0xFF40, 0x82);
+        memory.write_byte(0xFF48, 0xE4);
+        for row in 0..8 {
+            memory.write_byte(0x8010 + 2 * row, 0xFF);
+            memory.write_byte(0x8021 + 2 * row, 0xFF);
+        }
+        // OAM 0 at x = 4 with color 1, OAM 1 at x = 0 with color 2
+        for (i, (x, tile)) in [(12, 1), (8, 2)].into_iter().enumerate() {
+            let address = OAM_ADDRESS + 4 * i as u16;
+            memory.write_byte(address, 16);
+            memory.write_byte(address + 1, x);
+            memory.write_byte(address + 2, tile);
+        }
+
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        let color2 = screen_pixel(&ppu, 0, 0);
+        let color1 = screen_pixel(&ppu, 8, 0);
+        assert_ne!(color1, color2);
+        // the lower OAM index wins despite the larger x
+        assert_eq!(screen_pixel(&ppu, 4, 0), color1);
+
+        // same x, still the lower OAM index
+        memory.write_byte(OAM_ADDRESS + 1, 8);
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        assert_eq!(screen_pixel(&ppu, 4, 0), color1);
+        assert_eq!(screen_pixel(&ppu, 0, 0), color1);
+    }
 
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
+    /// Color of the pixel at (x, y) in the screen buffer
+    fn screen_pixel(ppu: &PPU, x: usize, y: usize) -> (u8, u8, u8) {
+        let offset = (y * 160 + x) * 3;
+        let buffer = ppu.screen_buffer();
+        (buffer[offset], buffer[offset + 1], buffer[offset + 2])
+    }
 
-        // Pressing some buttons and updating the joypad
-        joypad.handle_button(Keycode::U, true, &mut memory);
-        joypad.update(&mut memory);
+    /// Background uses tile 0 (blank) and the window uses tile 1 (color 1 on the first row)
+    fn setup_window(memory: &mut Memory, lcdc: u8) {
+        memory.write_byte(0xFF40, lcdc);
+        memory.write_byte(0xFF47, 0xE4);
+        memory.write_byte(0xFF4A, 0);
+        memory.write_byte(0xFF4B, 7);
+        memory.write_byte(0x8010, 0xFF);
+        for i in 0..32 {
+            memory.write_byte(0x9C00 + i, 1);
+        }
+    }
 
-        assert_eq!(
-            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
-            SELECT_BUTTON & 0x0F
-        );
+    #[test]
+    fn window_line_counter() {
+        let mut memory = Memory::new();
+        // lcd, window map 0x9C00, window, tile data 0x8000, bgw
+        setup_window(&mut memory, 0b1111_0001);
+        // only row 4 of the window tile is color 1
+        memory.write_byte(0x8010, 0x00);
+        memory.write_byte(0x8018, 0xFF);
+
+        let mut ppu = PPU::new();
+        let mut line = |memory: &mut Memory, lcdc: u8| {
+            memory.write_byte(0xFF40, lcdc);
+            ppu.draw_scanline(memory);
+            screen_pixel(&ppu, 0, 0)
+        };
+        let blank = line(&mut memory, 0b1111_0001);
+        for _ in 1..4 {
+            assert_eq!(line(&mut memory, 0b1111_0001), blank);
+        }
+        // window hidden on lines 4 and 5
+        line(&mut memory, 0b1101_0001);
+        line(&mut memory, 0b1101_0001);
+        // line 6 continues with window row 4
+        assert_ne!(line(&mut memory, 0b1111_0001), blank);
+        assert_eq!(line(&mut memory, 0b1111_0001), blank);
     }
 
     #[test]
-    fn joypad_test_start() {
+    fn screenshot_png_matches_screen_buffer() {
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
+        setup_window(&mut memory, 0b1111_0001);
+        memory.write_byte(0x8010, 0xFF);
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+
+        let mut png_data = Vec::new();
+        encode_png(&mut png_data, ppu.screen_buffer()).unwrap();
+
+        let mut reader = png::Decoder::new(png_data.as_slice()).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (160, 144));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(&pixels[..info.buffer_size()], ppu.screen_buffer());
+    }
 
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
+    #[test]
+    fn window_starts_at_wx() {
+        let mut memory = Memory::new();
+        // lcd, window map 0x9C00, window, tile data 0x8000, bg map 0x9800, bgw
+        setup_window(&mut memory, 0b1111_0001);
+        // background uses tile 2, color 2 on the first row
+        memory.write_byte(0x8021, 0xFF);
+        for i in 0..32 {
+            memory.write_byte(0x9800 + i, 2);
+        }
+        memory.write_byte(0xFF4B, 87);
+
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        let background = screen_pixel(&ppu, 0, 0);
+        let window = screen_pixel(&ppu, 159, 0);
+        assert_ne!(background, window);
+        for x in 0..80 {
+            assert_eq!(screen_pixel(&ppu, x, 0), background, "x = {}", x);
+        }
+        for x in 80..160 {
+            assert_eq!(screen_pixel(&ppu, x, 0), window, "x = {}", x);
+        }
+    }
 
-        // Pressing some buttons and updating the joypad
-        joypad.handle_button(Keycode::I, true, &mut memory);
-        joypad.update(&mut memory);
+    #[test]
+    fn hidden_layers_draw_white() {
+        let mut memory = Memory::new();
+        setup_window(&mut memory, 0b1111_0001);
+        memory.write_byte(0x8021, 0xFF);
+        for i in 0..32 {
+            memory.write_byte(0x9800 + i, 2);
+        }
+        memory.write_byte(0xFF4B, 87);
+        let white = (255, 255, 255);
+
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        let (background, window) = (screen_pixel(&ppu, 0, 0), screen_pixel(&ppu, 159, 0));
+        assert_ne!(background, white);
+        assert_ne!(window, white);
+
+        let mut ppu = PPU::new();
+        assert!(!ppu.toggle_layer(Layer::Window));
+        ppu.draw_scanline(&mut memory);
+        assert_eq!(screen_pixel(&ppu, 0, 0), background);
+        assert_eq!(screen_pixel(&ppu, 159, 0), white);
+
+        let mut ppu = PPU::new();
+        assert!(!ppu.toggle_layer(Layer::Background));
+        ppu.draw_scanline(&mut memory);
+        assert_eq!(screen_pixel(&ppu, 0, 0), white);
+        assert_eq!(screen_pixel(&ppu, 159, 0), window);
+        assert!(ppu.toggle_layer(Layer::Background));
+    }
+
+    #[test]
+    fn palettes_dmg() {
+        let mut memory = Memory::new();
+        memory.write_byte(0xFF47, 0b11_10_01_00);
+        memory.write_byte(0xFF48, 0b00_01_10_11);
+        memory.write_byte(0xFF49, 0b10_10_00_01);
 
         assert_eq!(
-            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
-            START_BUTTON & 0x0F
+            Graphics::palettes(&memory),
+            PaletteSnapshot {
+                bgp: [0, 1, 2, 3],
+                obp0: [3, 2, 1, 0],
+                obp1: [1, 0, 2, 2],
+                cgb_bg: None,
+                cgb_obj: None,
+            }
         );
     }
 
     #[test]
-    fn joypad_test_left_down_start() {
+    fn bgp_identity_shades() {
         let mut memory = Memory::new();
-        let mut joypad = Joypad::new();
+        memory.write_byte(0xFF40, 0x91);
+        memory.write_byte(0xFF47, 0xE4);
+        // first row of tile 0 is colors 0, 1, 2 and 3, two pixels each
+        memory.write_byte(0x8000, 0b0011_0011);
+        memory.write_byte(0x8001, 0b0000_1111);
+
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        let shades = [(255, 255, 255), (139, 139, 139), (48, 48, 48), (0, 0, 0)];
+        for (index, shade) in shades.into_iter().enumerate() {
+            assert_eq!(screen_pixel(&ppu, 2 * index, 0), shade);
+            assert_eq!(screen_pixel(&ppu, 2 * index + 1, 0), shade);
+        }
+    }
 
-        // test combination of buttons
-        joypad.handle_button(Keycode::A, true, &mut memory);
-        joypad.handle_button(Keycode::S, true, &mut memory);
-        joypad.handle_button(Keycode::I, true, &mut memory);
+    #[test]
+    fn palettes_cgb() {
+        let mut memory = Memory::new();
+        memory.set_model(HardwareModel::Cgb);
+
+        // bg palette 1 color 0 onwards, auto increment
+        memory.write_byte(0xFF68, 0x80 | 0x08);
+        memory.write_byte(0xFF69, 0x1F);
+        memory.write_byte(0xFF69, 0x00);
+        memory.write_byte(0xFF69, 0xE0);
+        memory.write_byte(0xFF69, 0x83);
+        // bit 6 is unused and reads as 1
+        assert_eq!(memory.read_byte(0xFF68), 0x80 | 0x40 | 0x0C);
+
+        // obj palette 7 color 3 high byte, no auto increment
+        memory.write_byte(0xFF6A, 0x3F);
+        memory.write_byte(0xFF6B, 0x7C);
+        assert_eq!(memory.read_byte(0xFF6A), 0x40 | 0x3F);
+        assert_eq!(memory.read_byte(0xFF6B), 0x7C);
+
+        let palettes = Graphics::palettes(&memory);
+        let cgb_bg = palettes.cgb_bg.unwrap();
+        assert_eq!(cgb_bg[1], [0x001F, 0x03E0, 0x0000, 0x0000]);
+        assert_eq!(cgb_bg[0], [0; 4]);
+        let cgb_obj = palettes.cgb_obj.unwrap();
+        assert_eq!(cgb_obj[7], [0, 0, 0, 0x7C00]);
+    }
 
-        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
-        joypad.update(&mut memory);
+    #[test]
+    fn ppu_first_line_after_lcd_enable() {
+        let mut memory = Memory::new();
+        let mut ppu = PPU::new();
+        let stat_mode = |memory: &Memory| memory.read_byte(0xFF41) & 0b11;
+
+        // lcd off, ppu runs from its reset state
+        for timestamp in 0..1000 {
+            ppu.render(&mut memory, timestamp);
+        }
+
+        memory.write_byte(0xFF40, 0x91);
+        ppu.render(&mut memory, 1000);
+        assert_eq!(memory.read_byte(0xFF44), 0);
+        assert_eq!(stat_mode(&memory), 0);
+
+        // first line skips the OAM scan
+        let mut first_line = Vec::new();
+        for timestamp in 1001..1000 + 114 {
+            ppu.render(&mut memory, timestamp);
+            first_line.push(stat_mode(&memory));
+        }
+        assert_eq!(first_line[..20], [0; 20]);
+        assert_eq!(first_line[20], 3);
+
+        // following lines start in mode 2
+        let mut second_line = Vec::new();
+        for timestamp in 1000 + 114..1000 + 2 * 114 {
+            ppu.render(&mut memory, timestamp);
+            second_line.push(stat_mode(&memory));
+        }
+        assert_eq!(memory.read_byte(0xFF44), 1);
+        assert_eq!(second_line[..21], [2; 21]);
+        assert_eq!(second_line[21], 3);
+    }
 
-        assert_eq!(
+    #[test]
+    fn frame_skip_presents_every_nth_frame() {
+        let mut memory = Memory::new();
+        let mut ppu = PPU::new();
+        let mut frame_skip = FrameSkip::new(3);
+        memory.write_byte(0xFF40, 0x91);
+
+        // a frame ends every 114 * 154 machine cycles, at the start of vblank
+        let presented: Vec<_> = (0..114 * 154 * 7)
+            .filter(|&timestamp| frame_skip.render(&mut ppu, &mut memory, timestamp))
+            .map(|timestamp| timestamp / (114 * 154) + 1)
+            .collect();
+        assert_eq!(presented, vec![3, 6]);
+
+        let mut every_frame = FrameSkip::new(1);
+        assert!((0..5).all(|_| every_frame.next_frame()));
+    }
+
+    #[test]
+    fn ppu_transition_log() {
+        let mut memory = Memory::new();
+        let mut ppu = PPU::new();
+        memory.write_byte(0xFF40, 0x91);
+        for timestamp in 0..114 + 30 {
+            ppu.render(&mut memory, timestamp);
+        }
+
+        let log = ppu
+            .recent_transitions()
+            .iter()
+            .map(|t| (t.from, t.to, t.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            log,
+            vec![
+                (PPUMode::Mode0 { line: 0 }, PPUMode::Mode3 { line: 0 }, 0),
+                (PPUMode::Mode3 { line: 0 }, PPUMode::Mode0 { line: 0 }, 0),
+                (PPUMode::Mode0 { line: 0 }, PPUMode::Mode2 { line: 1 }, 1),
+                (PPUMode::Mode2 { line: 1 }, PPUMode::Mode3 { line: 1 }, 1),
+            ]
+        );
+        assert_eq!(ppu.recent_transitions()[0].clock_diff, 21);
+
+        // only the most recent transitions are kept
+        for timestamp in 114 + 30..114 * 154 {
+            ppu.render(&mut memory, timestamp);
+        }
+        assert_eq!(ppu.recent_transitions().len(), 32);
+        assert_eq!(
+            ppu.recent_transitions().back().unwrap().to,
+            PPUMode::Mode1 { line: 153 }
+        );
+    }
+
+    #[test]
+    fn ly_frozen_while_lcd_off() {
+        let mut memory = Memory::new();
+        let mut ppu = PPU::new();
+        let stat_mode = |memory: &Memory| memory.read_byte(0xFF41) & 0b11;
+
+        memory.write_byte(0xFF40, 0x91);
+        for timestamp in 0..=114 * 10 + 30 {
+            ppu.render(&mut memory, timestamp);
+        }
+        assert_eq!(memory.read_byte(0xFF44), 10);
+        assert_eq!(stat_mode(&memory), 3);
+
+        memory.write_byte(0xFF40, 0x11);
+        for timestamp in 114 * 10 + 31..114 * 200 {
+            ppu.render(&mut memory, timestamp);
+            assert_eq!(memory.read_byte(0xFF44), 0);
+            assert_eq!(stat_mode(&memory), 0);
+        }
+
+        // resumes from line 0
+        memory.write_byte(0xFF40, 0x91);
+        for timestamp in 114 * 200..114 * 201 + 1 {
+            ppu.render(&mut memory, timestamp);
+        }
+        assert_eq!(memory.read_byte(0xFF44), 1);
+        assert_eq!(stat_mode(&memory), 2);
+    }
+
+    #[test]
+    fn vram_blocked_in_mode3() {
+        let mut memory = Memory::new();
+        let mut ppu = PPU::new();
+        // lcd, window map 0x9C00, window, tile data 0x8000, bgw
+        setup_window(&mut memory, 0b1111_0001);
+        ppu.draw_scanline(&mut memory);
+        let expected = screen_pixel(&ppu, 0, 0);
+        assert_ne!(expected, screen_pixel(&PPU::new(), 0, 0));
+
+        memory.write_byte(0xFF41, 0x03);
+        assert_eq!(memory.read_byte(0x8010), 0xFF);
+        assert_eq!(memory.read_byte(0x9C00), 0xFF);
+        assert_eq!(memory.ppu_read_byte(0x8010), 0xFF);
+        // writes are dropped
+        memory.write_byte(0x8010, 0x00);
+        assert_eq!(memory.ppu_read_byte(0x8010), 0xFF);
+        memory.write_byte(0x9C00, 0x00);
+        assert_eq!(memory.ppu_read_byte(0x9C00), 0x01);
+
+        // the ppu still sees the real tile data
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        assert_eq!(screen_pixel(&ppu, 0, 0), expected);
+
+        // unblocked outside of mode 3, or with the lcd off
+        memory.write_byte(0xFF41, 0x02);
+        assert_eq!(memory.read_byte(0x9C00), 0x01);
+        memory.write_byte(0xFF41, 0x03);
+        memory.write_byte(0xFF40, 0x00);
+        assert_eq!(memory.read_byte(0x9C00), 0x01);
+    }
+
+    #[test]
+    fn oam_blocked_in_mode2_and_mode3() {
+        let mut memory = Memory::new();
+        memory.write_byte(0xFE00, 0x12);
+        memory.write_byte(0xFE9F, 0x34);
+        memory.write_byte(0xFF40, 0x80);
+
+        for mode in [0x02, 0x03] {
+            memory.write_byte(0xFF41, mode);
+            assert_eq!(memory.read_byte(0xFE00), 0xFF);
+            assert_eq!(memory.read_byte(0xFE9F), 0xFF);
+            // writes are dropped, the ppu still sees the real data
+            memory.write_byte(0xFE00, 0x56);
+            assert_eq!(memory.ppu_read_byte(0xFE00), 0x12);
+        }
+
+        // reachable in hblank / vblank
+        for mode in [0x00, 0x01] {
+            memory.write_byte(0xFF41, mode);
+            assert_eq!(memory.read_byte(0xFE00), 0x12);
+        }
+
+        // the restriction can be lifted for debugging
+        memory.write_byte(0xFF41, 0x03);
+        memory.set_access_restricted(false);
+        assert_eq!(memory.read_byte(0xFE9F), 0x34);
+        memory.write_byte(0xFE00, 0x56);
+        assert_eq!(memory.read_byte(0xFE00), 0x56);
+        memory.write_byte(0x8000, 0x78);
+        assert_eq!(memory.read_byte(0x8000), 0x78);
+    }
+
+    #[test]
+    fn window_disabled_by_bgw_flag_dmg() {
+        let mut memory = Memory::new();
+        let mut ppu = PPU::new();
+        // lcd, window map 0x9C00, window, tile data 0x8000, bgw disabled
+        setup_window(&mut memory, 0b1111_0000);
+
+        ppu.draw_scanline(&mut memory);
+
+        let first = screen_pixel(&ppu, 0, 0);
+        assert_ne!(first, (139, 139, 139));
+        for x in 0..160 {
+            assert_eq!(screen_pixel(&ppu, x, 0), first);
+        }
+    }
+
+    #[test]
+    fn background_blank_when_bgw_disabled_dmg() {
+        let mut memory = Memory::new();
+        // lcd, tile data 0x8000, bg map 0x9800, objects, bgw disabled
+        memory.write_byte(0xFF40, 0b1001_0010);
+        memory.write_byte(0xFF47, 0xFF);
+        memory.write_byte(0xFF48, 0xFF);
+        // every background tile is color 3
+        memory.write_byte(0x8000, 0xFF);
+        memory.write_byte(0x8001, 0xFF);
+        // object on x 0..8 with the same tile, objects still show
+        memory.write_byte(OAM_ADDRESS, 16);
+        memory.write_byte(OAM_ADDRESS + 1, 8);
+
+        let mut ppu = PPU::new();
+        ppu.draw_scanline(&mut memory);
+        assert_eq!(screen_pixel(&ppu, 0, 0), (0, 0, 0));
+        for x in 8..160 {
+            assert_eq!(screen_pixel(&ppu, x, 0), (255, 255, 255));
+        }
+    }
+
+    #[test]
+    fn window_disabled_by_bgw_flag_cgb() {
+        let mut memory = Memory::new();
+        let mut ppu = PPU::new();
+        memory.set_model(HardwareModel::Cgb);
+        setup_window(&mut memory, 0b1111_0000);
+
+        ppu.draw_scanline(&mut memory);
+
+        for x in 0..160 {
+            assert_eq!(screen_pixel(&ppu, x, 0), (139, 139, 139));
+        }
+    }
+
+    /// Send an SGB packet bit by bit through P14/P15
+    fn sgb_send(memory: &mut Memory, packet: [Byte; 16]) {
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x00);
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x30);
+        for bit in 0..128 {
+            let one = packet[bit / 8] & (1 << (bit % 8)) != 0;
+            memory.write_byte(JOYPAD_REGISTER_ADDRESS, if one { 0x10 } else { 0x20 });
+            memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x30);
+        }
+        // stop bit
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x20);
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x30);
+    }
+
+    #[test]
+    fn sgb_mlt_req_cycles_player_id() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+        let mut read_id = |memory: &mut Memory| {
+            // select the dpad, then deselect both, moving to the next joypad
+            memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x10);
+            memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x30);
+            joypad.update(memory);
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F
+        };
+
+        // a single joypad by default
+        assert_eq!(read_id(&mut memory), 0xF);
+        assert_eq!(read_id(&mut memory), 0xF);
+
+        // MLT_REQ, 2 players
+        let mut packet = [0; 16];
+        packet[0] = (0x11 << 3) | 1;
+        packet[1] = 0x01;
+        sgb_send(&mut memory, packet);
+        assert_eq!(read_id(&mut memory), 0xE);
+        assert_eq!(read_id(&mut memory), 0xF);
+        assert_eq!(read_id(&mut memory), 0xE);
+
+        // other commands are ignored
+        let mut packet = [0; 16];
+        packet[0] = (0x04 << 3) | 1;
+        sgb_send(&mut memory, packet);
+        assert_eq!(read_id(&mut memory), 0xF);
+        assert_eq!(read_id(&mut memory), 0xE);
+
+        // back to one player
+        let mut packet = [0; 16];
+        packet[0] = (0x11 << 3) | 1;
+        sgb_send(&mut memory, packet);
+        assert_eq!(read_id(&mut memory), 0xF);
+        assert_eq!(read_id(&mut memory), 0xF);
+    }
+
+    #[test]
+    fn joypad_test_up() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+
+        // Pressing some buttons and updating the joypad
+        joypad.handle_button(Keycode::W, true, &mut memory);
+        joypad.update(&mut memory);
+
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
+            UP_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_controller_buttons() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+        let lines = |memory: &mut Memory, select: Byte| {
+            memory.write_byte(JOYPAD_REGISTER_ADDRESS, select);
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F
+        };
+
+        joypad.handle_controller_button(Button::DPadLeft, true, &mut memory);
+        joypad.handle_controller_button(Button::A, true, &mut memory);
+        joypad.handle_controller_button(Button::Start, true, &mut memory);
+        // not mapped
+        joypad.handle_controller_button(Button::Guide, true, &mut memory);
+        joypad.update(&mut memory);
+        assert_eq!(lines(&mut memory, !DPAD_FLAG), LEFT_BUTTON & 0x0F);
+        assert_eq!(
+            lines(&mut memory, !BUTTONS_FLAG),
+            A_BUTTON & START_BUTTON & 0x0F
+        );
+
+        // the keyboard and controller hold the same button independently
+        joypad.handle_button(Keycode::A, true, &mut memory);
+        joypad.handle_controller_button(Button::DPadLeft, false, &mut memory);
+        joypad.update(&mut memory);
+        assert_eq!(lines(&mut memory, !DPAD_FLAG), LEFT_BUTTON & 0x0F);
+        joypad.handle_button(Keycode::A, false, &mut memory);
+        joypad.handle_controller_button(Button::A, false, &mut memory);
+        joypad.update(&mut memory);
+        assert_eq!(lines(&mut memory, !DPAD_FLAG), 0x0F);
+        assert_eq!(lines(&mut memory, !BUTTONS_FLAG), START_BUTTON & 0x0F);
+    }
+
+    #[test]
+    fn joypad_opposing_directions() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+        let dpad = |joypad: &mut Joypad, memory: &mut Memory| {
+            joypad.update(memory);
+            memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F
+        };
+
+        // left+right from the keyboard and up+down across keyboard and controller
+        joypad.handle_button(Keycode::A, true, &mut memory);
+        joypad.handle_button(Keycode::D, true, &mut memory);
+        joypad.handle_button(Keycode::W, true, &mut memory);
+        joypad.handle_controller_button(Button::DPadDown, true, &mut memory);
+        assert_eq!(dpad(&mut joypad, &mut memory), 0x0F);
+
+        joypad.handle_button(Keycode::D, false, &mut memory);
+        assert_eq!(dpad(&mut joypad, &mut memory), LEFT_BUTTON & 0x0F);
+
+        joypad.set_allow_opposing(true);
+        joypad.handle_button(Keycode::D, true, &mut memory);
+        assert_eq!(
+            dpad(&mut joypad, &mut memory),
+            LEFT_BUTTON & RIGHT_BUTTON & UP_BUTTON & DOWN_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_controller_stick() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+        let dpad = |joypad: &mut Joypad, memory: &mut Memory| {
+            joypad.update(memory);
+            memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F
+        };
+
+        // inside the dead zone nothing is pressed
+        joypad.handle_controller_axis(Axis::LeftX, 4000, &mut memory);
+        assert_eq!(dpad(&mut joypad, &mut memory), 0x0F);
+
+        joypad.handle_controller_axis(Axis::LeftX, 30000, &mut memory);
+        joypad.handle_controller_axis(Axis::LeftY, -30000, &mut memory);
+        assert_eq!(
+            dpad(&mut joypad, &mut memory),
+            RIGHT_BUTTON & UP_BUTTON & 0x0F
+        );
+
+        // swinging over releases the opposite direction
+        joypad.handle_controller_axis(Axis::LeftX, -30000, &mut memory);
+        joypad.handle_controller_axis(Axis::LeftY, 0, &mut memory);
+        assert_eq!(dpad(&mut joypad, &mut memory), LEFT_BUTTON & 0x0F);
+
+        // the right stick is not mapped
+        joypad.handle_controller_axis(Axis::RightY, 30000, &mut memory);
+        assert_eq!(dpad(&mut joypad, &mut memory), LEFT_BUTTON & 0x0F);
+    }
+
+    #[test]
+    fn joypad_test_left() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+
+        // Pressing some buttons and updating the joypad
+        joypad.handle_button(Keycode::A, true, &mut memory);
+        joypad.update(&mut memory);
+
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
+            LEFT_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_test_right() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+
+        // Pressing some buttons and updating the joypad
+        joypad.handle_button(Keycode::D, true, &mut memory);
+        joypad.update(&mut memory);
+
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
+            RIGHT_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_test_down() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !DPAD_FLAG);
+
+        // Pressing some buttons and updating the joypad
+        joypad.handle_button(Keycode::S, true, &mut memory);
+        joypad.update(&mut memory);
+
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
+            DOWN_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_test_a() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
+
+        // Pressing some buttons and updating the joypad
+        joypad.handle_button(Keycode::K, true, &mut memory);
+        joypad.update(&mut memory);
+
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
+            A_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_test_b() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
+
+        // Pressing some buttons and updating the joypad
+        joypad.handle_button(Keycode::J, true, &mut memory);
+        joypad.update(&mut memory);
+
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
+            B_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_test_select() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
+
+        // Pressing some buttons and updating the joypad
+        joypad.handle_button(Keycode::U, true, &mut memory);
+        joypad.update(&mut memory);
+
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
+            SELECT_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_test_start() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
+
+        // Pressing some buttons and updating the joypad
+        joypad.handle_button(Keycode::I, true, &mut memory);
+        joypad.update(&mut memory);
+
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
+            START_BUTTON & 0x0F
+        );
+    }
+
+    #[test]
+    fn joypad_test_left_down_start() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+
+        // test combination of buttons
+        joypad.handle_button(Keycode::A, true, &mut memory);
+        joypad.handle_button(Keycode::S, true, &mut memory);
+        joypad.handle_button(Keycode::I, true, &mut memory);
+
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, !BUTTONS_FLAG);
+        joypad.update(&mut memory);
+
+        assert_eq!(
             memory.read_byte(JOYPAD_REGISTER_ADDRESS) & 0x0F,
             START_BUTTON & 0x0F
         );
@@ -1889,4 +3281,2996 @@ mod tests {
             LEFT_BUTTON & DOWN_BUTTON & 0x0F
         );
     }
+
+    #[test]
+    fn joypad_register_low_nibble_read_only() {
+        let mut memory = Memory::new();
+        let mut joypad = Joypad::new();
+        joypad.handle_button(Keycode::W, true, &mut memory);
+        joypad.handle_button(Keycode::K, true, &mut memory);
+        joypad.update(&mut memory);
+
+        // the written low nibble is dropped
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x20);
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS),
+            0xE0 | (UP_BUTTON & 0x0F)
+        );
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x2F);
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS),
+            0xE0 | (UP_BUTTON & 0x0F)
+        );
+
+        // switching groups is visible right away
+        memory.write_byte(JOYPAD_REGISTER_ADDRESS, 0x10);
+        assert_eq!(
+            memory.read_byte(JOYPAD_REGISTER_ADDRESS),
+            0xD0 | (A_BUTTON & 0x0F)
+        );
+
+        joypad.handle_button(Keycode::K, false, &mut memory);
+        joypad.update(&mut memory);
+        assert_eq!(memory.read_byte(JOYPAD_REGISTER_ADDRESS), 0xDF);
+    }
+
+    /// Build a blank rom with the given header bytes, each bank starts with its bank number
+    fn cartridge_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let bank_count = 1 << (rom_size + 1);
+        let mut rom = vec![0; 0x4000 * bank_count];
+        for bank in 0..bank_count {
+            rom[0x4000 * bank] = bank as u8;
+            rom[0x4000 * bank + 1] = (bank >> 8) as u8;
+        }
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom[0x149] = ram_size;
+        rom
+    }
+
+    #[test]
+    fn cartridge_rom_size_mismatch() {
+        let mut memory = Memory::new();
+        memory.set_strict(true);
+
+        // header declares 8 banks, file has 2
+        let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+        rom[0x148] = 0x02;
+        assert_eq!(
+            memory.load_cartidge(rom),
+            Err(CartridgeError::RomSizeMismatch {
+                expected: 0x20000,
+                actual: 0x8000
+            })
+        );
+
+        // padded past the declared size
+        let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+        rom.resize(0x9000, 0);
+        assert_eq!(
+            memory.load_cartidge(rom),
+            Err(CartridgeError::RomSizeMismatch {
+                expected: 0x8000,
+                actual: 0x9000
+            })
+        );
+
+        // garbage size byte
+        let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+        rom[0x148] = 0xFF;
+        assert_eq!(
+            memory.load_cartidge(rom),
+            Err(CartridgeError::UnsupportedRomSize(0xFF))
+        );
+        assert_eq!(memory.header(), None);
+
+        let error = CartridgeError::RomSizeMismatch {
+            expected: 0x20000,
+            actual: 0x8000,
+        };
+        assert_eq!(
+            error.to_string(),
+            "rom is 0x8000 bytes but the header declares 0x20000 bytes"
+        );
+    }
+
+    #[test]
+    fn cartridge_rom_size_mismatch_lenient() {
+        // header declares 8 banks, file has 2, the missing banks read 0xFF
+        let mut memory = Memory::new();
+        let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+        rom[0x148] = 0x02;
+        memory.load_cartidge(rom).unwrap();
+        memory.write_byte(0x2000, 0x01);
+        assert_eq!(memory.read_byte(0x4000), 0x01);
+        memory.write_byte(0x2000, 0x05);
+        assert_eq!(memory.read_byte(0x4000), 0xFF);
+        assert_eq!(memory.read_byte(0x7FFF), 0xFF);
+
+        // trailing data past the declared size is dropped
+        let mut memory = Memory::new();
+        let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+        rom.resize(0x9000, 0x42);
+        memory.load_cartidge(rom).unwrap();
+        memory.write_byte(0x2000, 0x01);
+        assert_eq!(memory.read_byte(0x4000), 0x01);
+    }
+
+    /// Zip archive of the given entries, deflated
+    fn zip_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (name, data) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn cartridge_from_archives() {
+        let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+        rom[0x134..0x139].copy_from_slice(b"ZIPPY");
+
+        // the first .gb or .gbc entry, whatever comes before it
+        let zip = zip_archive(&[("readme.txt", b"not a rom"), ("Game.GB", &rom)]);
+        let mut memory = Memory::new();
+        memory.load_cartridge_from_reader(zip.as_slice()).unwrap();
+        assert_eq!(memory.header().unwrap().title, "ZIPPY");
+        assert_eq!(unpack_rom(zip).unwrap(), rom);
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&rom).unwrap();
+        let gz = gz.finish().unwrap();
+        let mut memory = Memory::new();
+        memory.load_cartridge_from_reader(gz.as_slice()).unwrap();
+        assert_eq!(memory.header().unwrap().title, "ZIPPY");
+
+        // plain roms are not touched
+        assert_eq!(unpack_rom(rom.clone()).unwrap(), rom);
+
+        let zip = zip_archive(&[("readme.txt", b"not a rom")]);
+        assert_eq!(unpack_rom(zip), Err(CartridgeError::NoRomInArchive));
+        assert!(matches!(
+            unpack_rom(vec![0x1F, 0x8B, 0x00]),
+            Err(CartridgeError::Unreadable(_))
+        ));
+    }
+
+    #[test]
+    fn cartridge_smaller_than_32kb() {
+        for size in [0x2000, 0x4000] {
+            let mut rom = vec![0x11; size];
+            rom[0x147..0x14A].copy_from_slice(&[0x00, 0x00, 0x00]);
+            let mut memory = Memory::new();
+            memory.load_cartidge(rom).unwrap();
+
+            assert_eq!(memory.read_byte(0x0150), 0x11);
+            assert_eq!(memory.read_byte(size as u16 - 1), 0x11);
+            // padded up to the end of bank 1
+            assert_eq!(memory.read_byte(size as u16), 0xFF);
+            assert_eq!(memory.read_byte(0x4000), 0xFF);
+            assert_eq!(memory.read_byte(0x7FFF), 0xFF);
+        }
+
+        // a truncated bigger rom is only padded outside of strict mode
+        let rom = cartridge_rom(0x01, 0x01, 0x00);
+        let mut memory = Memory::new();
+        memory.set_strict(true);
+        assert!(matches!(
+            memory.load_cartidge(rom[..0x4000].to_vec()),
+            Err(CartridgeError::RomSizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn cartridge_ram_banks() {
+        for (code, banks) in [
+            (0x00, 0),
+            (0x01, 0),
+            (0x02, 1),
+            (0x03, 4),
+            (0x04, 16),
+            (0x05, 8),
+        ] {
+            let mut memory = Memory::new();
+            memory
+                .load_cartidge(cartridge_rom(0x03, 0x00, code))
+                .unwrap();
+            assert_eq!(memory.header().unwrap().ram_banks(), Some(banks));
+            assert_eq!(memory.external_ram_size(), banks * 0x2000);
+        }
+
+        // a cartridge type without ram ignores the ram size byte
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x01, 0x00, 0x03))
+            .unwrap();
+        assert!(!memory.header().unwrap().has_ram());
+        assert_eq!(memory.header().unwrap().ram_banks(), Some(4));
+        assert_eq!(memory.external_ram_size(), 0);
+
+        let header = CartridgeHeader::parse(&cartridge_rom(0x03, 0x00, 0x06));
+        assert_eq!(header.ram_banks(), None);
+    }
+
+    #[test]
+    fn cartridge_malformed_header() {
+        let mut memory = Memory::new();
+
+        assert_eq!(
+            memory.load_cartidge(vec![0; 0x100]),
+            Err(CartridgeError::BadHeader { len: 0x100 })
+        );
+        assert_eq!(
+            memory.load_cartidge(Vec::new()),
+            Err(CartridgeError::BadHeader { len: 0 })
+        );
+
+        // MBC2
+        assert_eq!(
+            memory.load_cartidge(cartridge_rom(0x05, 0x00, 0x00)),
+            Err(CartridgeError::UnsupportedMbc(0x05))
+        );
+        assert_eq!(
+            memory.load_cartidge(cartridge_rom(0x03, 0x00, 0x07)),
+            Err(CartridgeError::UnsupportedRamSize(0x07))
+        );
+        assert_eq!(memory.header(), None);
+        assert_eq!(
+            CartridgeError::UnsupportedMbc(0x05).to_string(),
+            "unsupported cartridge type 0x05"
+        );
+    }
+
+    /// Rom with valid header and global checksums
+    fn checksummed_rom() -> Vec<u8> {
+        let mut rom = cartridge_rom(0x01, 0x01, 0x00);
+        rom[0x134..0x134 + 5].copy_from_slice(b"TETRA");
+        rom[0x14D] = CartridgeHeader::checksum(&rom);
+        let global = CartridgeHeader::global_checksum(&rom);
+        rom[0x14E] = (global >> 8) as u8;
+        rom[0x14F] = global as u8;
+        rom
+    }
+
+    #[test]
+    fn cartridge_checksums() {
+        let rom = checksummed_rom();
+        let mut memory = Memory::new();
+        memory.set_strict(true);
+        memory.load_cartidge(rom.clone()).unwrap();
+        let header = memory.header().unwrap();
+        assert!(header.checksum_valid);
+        assert!(header.global_checksum_valid);
+        assert_eq!(
+            header.global_checksum,
+            CartridgeHeader::global_checksum(&rom)
+        );
+
+        // flipped title byte breaks both checksums
+        let mut corrupted = rom.clone();
+        corrupted[0x134] ^= 0x01;
+        let header = CartridgeHeader::parse(&corrupted);
+        assert!(!header.checksum_valid);
+        assert!(!header.global_checksum_valid);
+        // only a warning outside of strict mode
+        assert!(Memory::new().load_cartidge(corrupted.clone()).is_ok());
+        let mut memory = Memory::new();
+        memory.set_strict(true);
+        assert_eq!(
+            memory.load_cartidge(corrupted),
+            Err(CartridgeError::HeaderChecksumMismatch {
+                expected: rom[0x14D],
+                actual: rom[0x14D].wrapping_sub(1),
+            })
+        );
+
+        // flipped byte outside the header only breaks the global checksum
+        let mut corrupted = rom.clone();
+        corrupted[0x5000] ^= 0x01;
+        assert_eq!(
+            memory.load_cartidge(corrupted),
+            Err(CartridgeError::GlobalChecksumMismatch {
+                expected: CartridgeHeader::global_checksum(&rom),
+                actual: CartridgeHeader::global_checksum(&rom).wrapping_add(1),
+            })
+        );
+
+        // truncated file
+        assert!(matches!(
+            memory.load_cartidge(rom[..0x6000].to_vec()),
+            Err(CartridgeError::RomSizeMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn cartridge_header() {
+        let mut rom = cartridge_rom(0x13, 0x01, 0x03);
+        rom[0x134..0x134 + 6].copy_from_slice(b"POKEMO");
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x146] = 0x03;
+        rom[0x14B] = 0x33;
+        rom[0x14D] = CartridgeHeader::checksum(&rom);
+
+        let mut memory = Memory::new();
+        assert_eq!(memory.header(), None);
+        memory.load_cartidge(rom.clone()).unwrap();
+        assert_eq!(
+            memory.header(),
+            Some(&CartridgeHeader {
+                title: String::from("POKEMO"),
+                cgb_flag: 0x00,
+                new_licensee: *b"01",
+                old_licensee: 0x33,
+                sgb_flag: 0x03,
+                cartridge_type: 0x13,
+                rom_size: 0x01,
+                ram_size: 0x03,
+                header_checksum: rom[0x14D],
+                checksum_valid: true,
+                global_checksum: 0,
+                global_checksum_valid: false,
+            })
+        );
+
+        // the cgb flag is not part of the title
+        rom[0x134..0x144].copy_from_slice(b"ABCDEFGHIJKLMNO\x80");
+        rom[0x14D] = CartridgeHeader::checksum(&rom).wrapping_add(1);
+        let header = CartridgeHeader::parse(&rom);
+        assert_eq!(header.title, "ABCDEFGHIJKLMNO");
+        assert_eq!(header.cgb_flag, 0x80);
+        assert!(!header.checksum_valid);
+    }
+
+    #[test]
+    fn mbc5_rom_banks() {
+        let mut memory = Memory::new();
+        // 512 banks (8MB)
+        memory
+            .load_cartidge(cartridge_rom(0x19, 0x08, 0x00))
+            .unwrap();
+
+        assert_eq!(memory.read_word(0x4000), 1);
+        for bank in 0..512 {
+            memory.write_byte(0x2000, bank as u8);
+            memory.write_byte(0x3000, (bank >> 8) as u8);
+            assert_eq!(memory.read_word(0x4000), bank);
+            assert_eq!(memory.read_word(0x0000), 0);
+        }
+
+        // bank 0 can be mapped at 0x4000
+        memory.write_byte(0x3000, 0x00);
+        memory.write_byte(0x2FFF, 0x00);
+        assert_eq!(memory.read_word(0x4000), 0);
+
+        // only bit 0 of the high register is used
+        memory.write_byte(0x3FFF, 0xFF);
+        assert_eq!(memory.read_word(0x4000), 0x100);
+        memory.write_byte(0x2000, 0x42);
+        assert_eq!(memory.read_word(0x4000), 0x142);
+    }
+
+    #[test]
+    fn mbc5_ram_banks() {
+        let mut memory = Memory::new();
+        // 16 ram banks
+        memory
+            .load_cartidge(cartridge_rom(0x1B, 0x00, 0x04))
+            .unwrap();
+
+        // disabled ram is open bus
+        memory.write_byte(0xA000, 0x12);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+
+        memory.write_byte(0x0000, 0x0A);
+        for bank in 0..16 {
+            memory.write_byte(0x4000, bank);
+            memory.write_byte(0xA000, bank + 0x10);
+            memory.write_byte(0xBFFF, bank + 0x20);
+        }
+        for bank in 0..16 {
+            memory.write_byte(0x4000, bank);
+            assert_eq!(memory.read_byte(0xA000), bank + 0x10);
+            assert_eq!(memory.read_byte(0xBFFF), bank + 0x20);
+        }
+
+        // upper bits of the ram register are ignored
+        memory.write_byte(0x4000, 0xF3);
+        assert_eq!(memory.read_byte(0xA000), 0x13);
+
+        memory.write_byte(0x0000, 0x00);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn rom_only_has_no_external_ram() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x00, 0x00, 0x00))
+            .unwrap();
+
+        for address in [0xA000, 0xB123, 0xBFFF] {
+            assert_eq!(memory.read_byte(address), 0xFF);
+            memory.write_byte(address, 0x12);
+            assert_eq!(memory.read_byte(address), 0xFF);
+        }
+
+        // neighbouring work ram is unaffected
+        memory.write_byte(0xC000, 0x12);
+        assert_eq!(memory.read_byte(0xC000), 0x12);
+    }
+
+    #[test]
+    fn mbc1_disabled_ram_is_open_bus() {
+        let mut memory = Memory::new();
+        // mbc1 + ram, 4 ram banks
+        memory
+            .load_cartidge(cartridge_rom(0x02, 0x00, 0x03))
+            .unwrap();
+
+        memory.write_byte(0xA000, 0x12);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+
+        memory.write_byte(0x0000, 0x0A);
+        assert_eq!(memory.read_byte(0xA000), 0x00);
+        memory.write_byte(0xA000, 0x34);
+        assert_eq!(memory.read_byte(0xA000), 0x34);
+
+        // writes while disabled are dropped
+        memory.write_byte(0x0000, 0x00);
+        memory.write_byte(0xA000, 0x56);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+        memory.write_byte(0x0000, 0x0A);
+        assert_eq!(memory.read_byte(0xA000), 0x34);
+
+        // mbc1 without ram stays open bus even when enabled
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x01, 0x00, 0x00))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0xA000, 0x12);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc5_rumble() {
+        let mut memory = Memory::new();
+        // mbc5 + rumble + ram, 4 ram banks
+        memory
+            .load_cartidge(cartridge_rom(0x1D, 0x00, 0x03))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+        assert_eq!(memory.take_rumble_state(), None);
+
+        memory.write_byte(0x4000, 0x08 | 0x01);
+        assert_eq!(memory.take_rumble_state(), Some(true));
+        assert_eq!(memory.take_rumble_state(), None);
+
+        // the motor bit does not select a ram bank
+        memory.write_byte(0xA000, 0x12);
+        memory.write_byte(0x4000, 0x01);
+        assert_eq!(memory.read_byte(0xA000), 0x12);
+        assert_eq!(memory.take_rumble_state(), Some(false));
+
+        // non rumble cartridges use bit 3 for the ram bank
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x1A, 0x00, 0x04))
+            .unwrap();
+        memory.write_byte(0x4000, 0x08);
+        assert_eq!(memory.take_rumble_state(), None);
+    }
+
+    #[test]
+    fn mbc3_rom_banks() {
+        let mut memory = Memory::new();
+        // 128 banks (2MB)
+        memory
+            .load_cartidge(cartridge_rom(0x11, 0x06, 0x00))
+            .unwrap();
+
+        assert_eq!(memory.read_byte(0x4000), 1);
+        for bank in 1..128 {
+            memory.write_byte(0x2000, bank);
+            assert_eq!(memory.read_byte(0x4000), bank);
+            assert_eq!(memory.read_byte(0x0000), 0);
+        }
+
+        // bank 0 maps to bank 1, and the top bit is ignored
+        memory.write_byte(0x3FFF, 0x00);
+        assert_eq!(memory.read_byte(0x4000), 1);
+        memory.write_byte(0x3FFF, 0x85);
+        assert_eq!(memory.read_byte(0x4000), 5);
+        assert_eq!(memory.read_word(0x4000), 0x0005);
+    }
+
+    #[test]
+    fn mbc3_ram_banks() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x13, 0x00, 0x03))
+            .unwrap();
+
+        // enable ram, and write a different value to each bank
+        memory.write_byte(0x0000, 0x0A);
+        for bank in 0..4 {
+            memory.write_byte(0x4000, bank);
+            memory.write_byte(0xA000, 0x10 + bank);
+        }
+        for bank in 0..4 {
+            memory.write_byte(0x4000, bank);
+            assert_eq!(memory.read_byte(0xA000), 0x10 + bank);
+        }
+
+        // disabled ram is open bus
+        memory.write_byte(0x0000, 0x00);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_ram_rtc_isolation() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x13, 0x00, 0x03))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+
+        memory.write_byte(0x4000, 0x00);
+        memory.write_byte(0xA000, 0x12);
+
+        // rtc registers do not alias ram
+        let values = [0x12, 0x34, 0x15, 0xAB, 0xC1];
+        for (rtc, value) in (0x08..=0x0C).zip(values) {
+            memory.write_byte(0x4000, rtc);
+            memory.write_byte(0xA000, value);
+        }
+        for (rtc, value) in (0x08..=0x0C).zip(values) {
+            memory.write_byte(0x4000, rtc);
+            assert_eq!(memory.read_byte(0xA000), value);
+        }
+
+        memory.write_byte(0x4000, 0x00);
+        assert_eq!(memory.read_byte(0xA000), 0x12);
+
+        // bank 4 wraps around to bank 0, unmapped selections are open bus
+        memory.write_byte(0x4000, 0x04);
+        assert_eq!(memory.read_byte(0xA000), 0x12);
+        memory.write_byte(0x4000, 0x0D);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc3_rtc_latch() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x10, 0x00, 0x03))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0x4000, 0x08);
+
+        // 90 seconds, 0x100000 mcycles each
+        for _ in 0..90 * 0x2000 {
+            memory.tick_rtc(0x80);
+        }
+        // not latched yet
+        assert_eq!(memory.read_byte(0xA000), 0x00);
+
+        // only 0x00 followed by 0x01 latches
+        memory.write_byte(0x6000, 0x01);
+        assert_eq!(memory.read_byte(0xA000), 0x00);
+        memory.write_byte(0x6000, 0x00);
+        memory.write_byte(0x6000, 0x01);
+        assert_eq!(memory.read_byte(0xA000), 30);
+        memory.write_byte(0x4000, 0x09);
+        assert_eq!(memory.read_byte(0xA000), 1);
+
+        // latched value is stable while the clock runs
+        memory.write_byte(0x4000, 0x08);
+        for _ in 0..0x2000 {
+            memory.tick_rtc(0x80);
+        }
+        assert_eq!(memory.read_byte(0xA000), 30);
+        memory.write_byte(0x6000, 0x00);
+        memory.write_byte(0x6000, 0x01);
+        assert_eq!(memory.read_byte(0xA000), 31);
+    }
+
+    #[test]
+    fn battery_save_round_trip() {
+        let path = std::env::temp_dir().join("gb-rs-battery_save_round_trip.sav");
+        let _ = std::fs::remove_file(&path);
+
+        // mbc1 + ram + battery, 4 ram banks
+        let rom = cartridge_rom(0x03, 0x00, 0x03);
+        let mut memory = Memory::new();
+        memory.load_cartidge(rom.clone()).unwrap();
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0x6000, 0x01);
+
+        // nothing written yet, no save file
+        memory.save_ram(&path).unwrap();
+        assert!(!path.exists());
+
+        memory.write_byte(0xA000, 0x12);
+        memory.write_byte(0x4000, 0x03);
+        memory.write_byte(0xBFFF, 0x34);
+        memory.save_ram(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap().len(), 4 * 0x2000);
+
+        let mut memory = Memory::new();
+        memory.load_cartidge(rom).unwrap();
+        memory.load_save(&path).unwrap();
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0x6000, 0x01);
+        assert_eq!(memory.read_byte(0xA000), 0x12);
+        memory.write_byte(0x4000, 0x03);
+        assert_eq!(memory.read_byte(0xBFFF), 0x34);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn battery_save_exact_round_trip() {
+        let path = std::env::temp_dir().join("gb-rs-battery_save_exact_round_trip.sav");
+        let _ = std::fs::remove_file(&path);
+
+        // mbc5 + ram + battery, 16 ram banks
+        let rom = cartridge_rom(0x1B, 0x00, 0x04);
+        let mut memory = Memory::new();
+        memory.load_cartidge(rom.clone()).unwrap();
+        assert!(memory.has_battery());
+        memory.write_byte(0x0000, 0x0A);
+        let mut expected = Vec::new();
+        for bank in 0..16 {
+            memory.write_byte(0x4000, bank);
+            for offset in 0..0x2000 {
+                let byte = (offset as u8).wrapping_mul(7) ^ bank;
+                memory.write_byte(0xA000 + offset, byte);
+                expected.push(byte);
+            }
+        }
+        memory.save_ram(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        let mut memory = Memory::new();
+        memory.load_cartidge(rom).unwrap();
+        memory.load_save(&path).unwrap();
+        memory.write_byte(0x0000, 0x0A);
+        for bank in 0..16 {
+            memory.write_byte(0x4000, bank);
+            for offset in 0..0x2000 {
+                assert_eq!(
+                    memory.read_byte(0xA000 + offset),
+                    expected[bank as usize * 0x2000 + offset as usize]
+                );
+            }
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn battery_save_requires_battery() {
+        let path = std::env::temp_dir().join("gb-rs-battery_save_requires_battery.sav");
+        let _ = std::fs::remove_file(&path);
+
+        // mbc1 + ram, no battery
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x02, 0x00, 0x02))
+            .unwrap();
+        assert!(!memory.has_battery());
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0xA000, 0x12);
+        memory.save_ram(&path).unwrap();
+        assert!(!path.exists());
+
+        // a missing save file is not an error
+        memory.load_save(&path).unwrap();
+        assert_eq!(memory.read_byte(0xA000), 0x12);
+    }
+
+    /// Select rtc register and write/latch it
+    fn rtc_write(memory: &mut Memory, register: Byte, byte: Byte) {
+        memory.write_byte(0x4000, register);
+        memory.write_byte(0xA000, byte);
+    }
+
+    fn rtc_latch_read(memory: &mut Memory) -> [Byte; 5] {
+        memory.write_byte(0x6000, 0x00);
+        memory.write_byte(0x6000, 0x01);
+        let mut registers = [0; 5];
+        for (register, value) in (0x08..=0x0C).zip(registers.iter_mut()) {
+            memory.write_byte(0x4000, register);
+            *value = memory.read_byte(0xA000);
+        }
+        registers
+    }
+
+    /// Advance the rtc by seconds
+    fn rtc_advance(memory: &mut Memory, seconds: usize) {
+        for _ in 0..seconds * 0x2000 {
+            memory.tick_rtc(0x80);
+        }
+    }
+
+    #[test]
+    fn mbc3_rtc_day_carry() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x10, 0x00, 0x03))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+
+        // 23:59:58 on day 0x1FF
+        rtc_write(&mut memory, 0x08, 58);
+        rtc_write(&mut memory, 0x09, 59);
+        rtc_write(&mut memory, 0x0A, 23);
+        rtc_write(&mut memory, 0x0B, 0xFF);
+        rtc_write(&mut memory, 0x0C, 0x01);
+        assert_eq!(rtc_latch_read(&mut memory), [58, 59, 23, 0xFF, 0x01]);
+
+        rtc_advance(&mut memory, 1);
+        assert_eq!(rtc_latch_read(&mut memory), [59, 59, 23, 0xFF, 0x01]);
+
+        // day counter overflows and sets the carry bit
+        rtc_advance(&mut memory, 1);
+        assert_eq!(rtc_latch_read(&mut memory), [0, 0, 0, 0x00, 0x80]);
+
+        // carry stays set past the next day
+        rtc_write(&mut memory, 0x08, 59);
+        rtc_write(&mut memory, 0x09, 59);
+        rtc_write(&mut memory, 0x0A, 23);
+        rtc_advance(&mut memory, 1);
+        assert_eq!(rtc_latch_read(&mut memory), [0, 0, 0, 0x01, 0x80]);
+
+        // and is only cleared by writing
+        rtc_write(&mut memory, 0x0C, 0x00);
+        assert_eq!(rtc_latch_read(&mut memory), [0, 0, 0, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn mbc3_rtc_halt() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x10, 0x00, 0x03))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+
+        rtc_write(&mut memory, 0x0C, 0x40);
+        rtc_advance(&mut memory, 5);
+        assert_eq!(rtc_latch_read(&mut memory), [0, 0, 0, 0, 0x40]);
+
+        // resuming counts from where the clock stopped
+        rtc_write(&mut memory, 0x0C, 0x00);
+        rtc_advance(&mut memory, 61);
+        assert_eq!(rtc_latch_read(&mut memory), [1, 1, 0, 0, 0x00]);
+
+        // writing seconds resets the sub-second counter
+        memory.tick_rtc(0x80);
+        rtc_write(&mut memory, 0x08, 0);
+        for _ in 0..0x1FFF {
+            memory.tick_rtc(0x80);
+        }
+        assert_eq!(rtc_latch_read(&mut memory)[0], 0);
+        memory.tick_rtc(0x80);
+        assert_eq!(rtc_latch_read(&mut memory)[0], 1);
+    }
+
+    #[test]
+    fn mbc3_ram_bank_out_of_range() {
+        let mut memory = Memory::new();
+        // only a single 8KB bank
+        memory
+            .load_cartidge(cartridge_rom(0x13, 0x00, 0x02))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+
+        memory.write_byte(0x4000, 0x00);
+        memory.write_byte(0xA123, 0x34);
+
+        // bank 2 wraps around to the only bank
+        memory.write_byte(0x4000, 0x02);
+        assert_eq!(memory.read_byte(0xA123), 0x34);
+        memory.write_byte(0xA123, 0x56);
+
+        memory.write_byte(0x4000, 0x00);
+        assert_eq!(memory.read_byte(0xA123), 0x56);
+
+        // neither a ram bank nor an rtc register
+        memory.write_byte(0x4000, 0x0D);
+        assert_eq!(memory.read_byte(0xA123), 0xFF);
+    }
+
+    #[test]
+    fn ram_size_8_banks() {
+        // MBC30 and MBC5 with 64KB of ram
+        for cartridge_type in [0x13, 0x1B] {
+            let mut memory = Memory::new();
+            memory
+                .load_cartidge(cartridge_rom(cartridge_type, 0x00, 0x05))
+                .unwrap();
+            assert_eq!(memory.external_ram_size(), 0x10000);
+            memory.write_byte(0x0000, 0x0A);
+            for bank in 0..8 {
+                memory.write_byte(0x4000, bank);
+                memory.write_byte(0xA000, 0x10 + bank);
+                memory.write_byte(0xBFFF, 0x20 + bank);
+            }
+            memory.write_byte(0x4000, 0x07);
+            assert_eq!(memory.read_byte(0xA000), 0x17);
+            assert_eq!(memory.read_byte(0xBFFF), 0x27);
+            memory.write_byte(0x4000, 0x00);
+            assert_eq!(memory.read_byte(0xA000), 0x10);
+        }
+
+        // MBC5 banks 8-15 wrap around to 0-7
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x1B, 0x00, 0x05))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0x4000, 0x07);
+        memory.write_byte(0xA000, 0x77);
+        memory.write_byte(0x4000, 0x0F);
+        assert_eq!(memory.read_byte(0xA000), 0x77);
+        memory.write_byte(0x4000, 0x08);
+        memory.write_byte(0xA000, 0x88);
+        memory.write_byte(0x4000, 0x00);
+        assert_eq!(memory.read_byte(0xA000), 0x88);
+    }
+
+    #[test]
+    fn load_raw_program_stack() {
+        let mut gb = GameBoy::new(false);
+        // LD BC,0x1234; PUSH BC; POP DE
+        gb.load_raw_program(vec![0x01, 0x34, 0x12, 0xC5, 0xD1], 0x0200, Some(0xD000));
+        assert_eq!(gb.cpu().pc, 0x0200);
+        assert_eq!(gb.cpu().sp, 0xD000);
+
+        gb.step();
+        gb.step();
+        assert_eq!(gb.cpu().sp, 0xCFFE);
+        assert_eq!(gb.memory().read_byte(0xCFFF), 0x12);
+        assert_eq!(gb.memory().read_byte(0xCFFE), 0x34);
+
+        gb.step();
+        assert_eq!(gb.cpu().sp, 0xD000);
+        assert_eq!(gb.cpu().d, 0x12);
+        assert_eq!(gb.cpu().e, 0x34);
+        assert_eq!(gb.cpu().pc, 0x0205);
+
+        let mut gb = GameBoy::new(false);
+        gb.load_raw_program(vec![0x00], 0x0100, None);
+        assert_eq!(gb.cpu().sp, 0xFFFE);
+    }
+
+    #[test]
+    fn register16_endianness() {
+        let mut gb = GameBoy::new(false);
+        gb.load_raw_program(
+            vec![
+                0x01, 0x34, 0x12, // LD BC,0x1234
+                0x11, 0x78, 0x56, // LD DE,0x5678
+                0x21, 0xBC, 0x9A, // LD HL,0x9ABC
+                0xC5, 0xD5, 0xE5, // PUSH BC; PUSH DE; PUSH HL
+                0xF1, 0xF5, // POP AF; PUSH AF
+                0xC1, 0xD1, 0xE1, // POP BC; POP DE; POP HL
+                0x08, 0x00, 0xC0, // LD (0xC000),SP
+                0x31, 0xCD, 0xAB, // LD SP,0xABCD
+                0x08, 0x02, 0xC0, // LD (0xC002),SP
+            ],
+            0x0200,
+            Some(0xD000),
+        );
+
+        for _ in 0..3 {
+            gb.step();
+        }
+        let cpu = gb.cpu();
+        assert_eq!((cpu.b, cpu.c), (0x12, 0x34));
+        assert_eq!((cpu.d, cpu.e), (0x56, 0x78));
+        assert_eq!((cpu.h, cpu.l), (0x9A, 0xBC));
+
+        // pushes store the high byte at the higher address
+        for _ in 0..3 {
+            gb.step();
+        }
+        assert_eq!(gb.cpu().sp, 0xCFFA);
+        let stack = (0xCFFA..0xD000)
+            .map(|address| gb.memory().read_byte(address))
+            .collect::<Vec<_>>();
+        assert_eq!(stack, [0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(gb.memory().read_word(0xCFFA), 0x9ABC);
+
+        // AF keeps only the upper nibble of F
+        gb.step();
+        assert_eq!((gb.cpu().a, gb.cpu().f), (0x9A, 0xB0));
+        gb.step();
+        assert_eq!(gb.memory().read_word(0xCFFA), 0x9AB0);
+
+        for _ in 0..3 {
+            gb.step();
+        }
+        let cpu = gb.cpu();
+        assert_eq!((cpu.b, cpu.c), (0x9A, 0xB0));
+        assert_eq!((cpu.d, cpu.e), (0x56, 0x78));
+        assert_eq!((cpu.h, cpu.l), (0x12, 0x34));
+        assert_eq!(cpu.sp, 0xD000);
+
+        // LD (nn),SP stores little endian
+        for _ in 0..3 {
+            gb.step();
+        }
+        assert_eq!(gb.memory().read_byte(0xC000), 0x00);
+        assert_eq!(gb.memory().read_byte(0xC001), 0xD0);
+        assert_eq!(gb.memory().read_byte(0xC002), 0xCD);
+        assert_eq!(gb.memory().read_byte(0xC003), 0xAB);
+        assert_eq!(gb.memory().read_word(0xC002), 0xABCD);
+    }
+
+    /// Gameboy running program at 0x0200 with a vblank interrupt pending
+    fn interrupt_pending_gameboy(program: Vec<u8>) -> GameBoy {
+        let mut gb = GameBoy::new(false);
+        gb.load_raw_program(program, 0x0200, None);
+        gb.memory_mut().write_byte(INTERRUPT_ENABLE_ADDRESS, 0x01);
+        gb.memory_mut().write_byte(INTERRUPT_FLAG_ADDRESS, 0x01);
+        gb
+    }
+
+    #[test]
+    fn ei_di_cancels_enable() {
+        // EI; DI; NOP; NOP
+        let mut gb = interrupt_pending_gameboy(vec![0xFB, 0xF3, 0x00, 0x00]);
+        for _ in 0..4 {
+            gb.step();
+        }
+        assert_eq!(gb.cpu().pc, 0x0204);
+        assert!(!gb.cpu().ime.1);
+        assert_eq!(gb.cpu().ime, (None, false));
+    }
+
+    #[test]
+    fn di_ei_enables_after_next_instruction() {
+        // DI; EI; NOP; NOP
+        let mut gb = interrupt_pending_gameboy(vec![0xF3, 0xFB, 0x00, 0x00]);
+        gb.step();
+        gb.step();
+        // no interrupt directly after EI
+        assert_eq!(gb.cpu().pc, 0x0202);
+
+        gb.step();
+        assert_eq!(gb.cpu().pc, 0x0040);
+        assert_eq!(gb.memory().read_word(gb.cpu().sp), 0x0203);
+        assert!(!gb.cpu().ime.1);
+    }
+
+    #[test]
+    fn ei_ei_no_double_delay() {
+        // EI; EI; NOP
+        let mut gb = interrupt_pending_gameboy(vec![0xFB, 0xFB, 0x00]);
+        gb.step();
+        assert_eq!(gb.cpu().pc, 0x0201);
+
+        // the second EI is the instruction following the first
+        gb.step();
+        assert_eq!(gb.cpu().pc, 0x0040);
+        assert_eq!(gb.memory().read_word(gb.cpu().sp), 0x0202);
+    }
+
+    #[test]
+    fn symbol_table() {
+        let symbols = SymbolTable::parse(
+            "; rgblink symbol file\n\
+             00:0150 Start\n\
+             00:0200 Main.loop ; comment\n\
+             \n\
+             03:4000 BankedRoutine\n\
+             C000 wBuffer\n\
+             garbage\n",
+        );
+
+        assert_eq!(symbols.label(0, 0x0150), Some("Start"));
+        assert_eq!(symbols.label(0, 0x0200), Some("Main.loop"));
+        assert_eq!(symbols.label(3, 0x4000), Some("BankedRoutine"));
+        assert_eq!(symbols.label(1, 0x4000), None);
+        assert_eq!(symbols.label(0, 0xC000), Some("wBuffer"));
+
+        assert_eq!(symbols.address("Start"), Some((0, 0x0150)));
+        assert_eq!(symbols.address("BankedRoutine"), Some((3, 0x4000)));
+        assert_eq!(symbols.address("garbage"), None);
+
+        let mut gb = GameBoy::new(false);
+        gb.load_symbols("00:0150 Start\n");
+        assert!(gb.add_breakpoint("Start"));
+        assert!(gb.add_breakpoint("0x0200"));
+        assert!(gb.add_breakpoint("02:4000"));
+        assert!(!gb.add_breakpoint("Missing"));
+
+        // the pc is shown with its label
+        let mut cpu = CPU::new();
+        let memory = Memory::new();
+        cpu.pc = 0x0150;
+        assert_eq!(cpu.display_pc(&memory, Some(&symbols)), "0x0150 <Start>");
+        assert_eq!(cpu.display_pc(&memory, None), "0x0150");
+        cpu.pc = 0x0151;
+        assert_eq!(cpu.display_pc(&memory, Some(&symbols)), "0x0151");
+
+        // labels only go before their own bank in the disassembly
+        let listing = SizedInstruction::disassemble_rom(&cartridge_rom(0x01, 0x01, 0x00), &symbols);
+        let lines = listing.lines().collect::<Vec<_>>();
+        let start = lines.iter().position(|line| *line == "Start:").unwrap();
+        assert_eq!(lines[start + 1], "00:0150  00        NOP");
+        let banked = lines
+            .iter()
+            .position(|line| *line == "BankedRoutine:")
+            .unwrap();
+        assert_eq!(lines[banked - 1], "; bank 03");
+        assert_eq!(lines.iter().filter(|line| line.ends_with(':')).count(), 3);
+    }
+
+    #[test]
+    fn banked_label_breakpoint() {
+        // mbc1, 4 banks
+        let mut rom = cartridge_rom(0x01, 0x01, 0x00);
+        // JP $4000
+        rom[0x0100..0x0103].copy_from_slice(&[0xC3, 0x00, 0x40]);
+        // bank 1: LD A,2; LD ($2000),A; then bank 2 continues with JP $4000
+        rom[0x4000..0x4005].copy_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20]);
+        rom[0x8000] = 0x00;
+        rom[0x8005..0x8008].copy_from_slice(&[0xC3, 0x00, 0x40]);
+
+        let mut gb = GameBoy::new_skip_boot(false);
+        gb.load_rom(rom).unwrap();
+        gb.load_symbols("02:4000 BankedRoutine\n");
+        assert!(gb.add_breakpoint("BankedRoutine"));
+
+        // passing 0x4000 in bank 1 does not pause
+        let mut steps = 0;
+        while gb.debug_step() {
+            steps += 1;
+            assert!(steps < 100);
+        }
+        assert_eq!(steps, 4);
+        assert_eq!(gb.cpu().pc, 0x4000);
+        assert_eq!(gb.memory().rom_offset(0x4000), 0x8000);
+    }
+
+    #[test]
+    fn start_paused_and_break_at() {
+        // NOP; NOP; NOP; NOP
+        let mut gb = GameBoy::new(false);
+        gb.load_raw_program(vec![0x00; 4], 0x0200, None);
+        assert!(gb.add_breakpoint("0x0202"));
+
+        gb.pause();
+        assert!(!gb.debug_step());
+        assert_eq!(gb.cpu().pc, 0x0200);
+
+        // P resumes, then the breakpoint pauses before 0x0202 runs
+        gb.handle_event(key_down(Keycode::P));
+        assert!(gb.debug_step());
+        assert!(gb.debug_step());
+        assert!(!gb.debug_step());
+        assert!(gb.paused());
+        assert_eq!(gb.cpu().pc, 0x0202);
+    }
+
+    #[test]
+    fn write_watchpoint() {
+        // LD A, $42; LD ($C000), A; LD A, ($C001); NOP
+        let mut gb = GameBoy::new(false);
+        gb.load_raw_program(
+            vec![0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xFA, 0x01, 0xC0, 0x00],
+            0x0200,
+            None,
+        );
+        gb.set_breakpoint(Breakpoint::Write(0xC000));
+        gb.set_breakpoint(Breakpoint::Write(0xC001));
+        gb.set_breakpoint(Breakpoint::Read(0xC002));
+
+        assert!(gb.debug_step());
+        assert!(!gb.paused());
+        assert!(gb.debug_step());
+        // paused after the write
+        assert!(gb.paused());
+        assert_eq!(gb.cpu().pc, 0x0205);
+        assert_eq!(gb.memory().read_byte(0xC000), 0x42);
+        assert!(!gb.debug_step());
+
+        // reading a write watched address does not pause
+        gb.handle_event(key_down(Keycode::P));
+        assert!(gb.debug_step());
+        assert!(!gb.paused());
+
+        gb.set_breakpoint(Breakpoint::Read(0xC001));
+        gb.cpu_mut().pc = 0x0205;
+        assert!(gb.debug_step());
+        assert!(gb.paused());
+    }
+
+    #[test]
+    fn watchpoint_ignores_timer_and_interrupt_reads() {
+        // EI; NOP; NOP; LDH A,(DIV)
+        let mut gb = GameBoy::new(false);
+        gb.load_raw_program(vec![0xFB, 0x00, 0x00, 0xF0, 0x04], 0x0200, None);
+        gb.set_breakpoint(Breakpoint::Read(0xFF04));
+        gb.set_breakpoint(Breakpoint::Read(INTERRUPT_ENABLE_ADDRESS));
+        gb.set_breakpoint(Breakpoint::Read(INTERRUPT_FLAG_ADDRESS));
+
+        // the clock and interrupt checks are not cpu reads
+        for _ in 0..3 {
+            assert!(gb.debug_step());
+            assert!(!gb.paused());
+        }
+        assert!(gb.debug_step());
+        assert!(gb.paused());
+        assert_eq!(gb.cpu().pc, 0x0205);
+    }
+
+    #[test]
+    fn skip_boot() {
+        let mut rom = cartridge_rom(0x00, 0x00, 0x00);
+        // JP 0x0150 at the entry point
+        rom[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x0000] = 0x12;
+
+        let mut gb = GameBoy::new_skip_boot(false);
+        gb.load_rom(rom).unwrap();
+        assert_eq!(gb.cpu().pc, 0x0100);
+        assert_eq!(gb.cpu().sp, 0xFFFE);
+        assert_eq!(gb.cpu().a, 0x01);
+        assert_eq!(gb.memory().read_byte(0xFF40), 0x91);
+        assert_eq!(gb.memory().read_byte(0xFF47), 0xFC);
+        assert_eq!(gb.memory().read_byte(0xFF48), 0xFF);
+        assert_eq!(gb.memory().read_byte(0xFF49), 0xFF);
+        // cartridge is mapped over the boot rom area
+        assert_eq!(gb.memory().read_byte(0x0000), 0x12);
+
+        gb.step();
+        gb.step();
+        assert_eq!(gb.cpu().pc, 0x0150);
+    }
+
+    #[test]
+    fn skip_boot_first_instruction() {
+        let mut rom = cartridge_rom(0x00, 0x00, 0x00);
+        // PUSH AF at the entry point
+        rom[0x0100] = 0xF5;
+
+        let mut gb = GameBoy::new_skip_boot(false);
+        gb.load_rom(rom).unwrap();
+        gb.step();
+
+        // the first cartridge instruction sees A=0x01, F=0xB0 and SP=0xFFFE
+        assert_eq!(gb.cpu().pc, 0x0101);
+        assert_eq!(gb.cpu().sp, 0xFFFC);
+        assert_eq!(gb.memory().read_byte(0xFFFD), 0x01);
+        assert_eq!(gb.memory().read_byte(0xFFFC), 0xB0);
+    }
+
+    #[test]
+    fn display_instruction() {
+        let cases: [(&[Byte], &str); 18] = [
+            (&[0x41], "LD B, C"),
+            (&[0x7E], "LD A, (HL)"),
+            (&[0x36, 0x12], "LD (HL), $12"),
+            (&[0x2A], "LD A, (HL+)"),
+            (&[0xF0, 0x44], "LDH A, ($FF44)"),
+            (&[0xE2], "LDH (C), A"),
+            (&[0xFA, 0x34, 0x12], "LD A, ($1234)"),
+            (&[0x08, 0x00, 0xC0], "LD ($C000), SP"),
+            (&[0xF8, 0xFE], "LD HL, SP-$02"),
+            (&[0xE8, 0x05], "ADD SP, +$05"),
+            (&[0x88], "ADC A, B"),
+            (&[0xD6, 0x01], "SUB $01"),
+            (&[0x20, 0x03], "JR NZ, $+5"),
+            (&[0x18, 0xFE], "JR $+0"),
+            (&[0xDC, 0x00, 0x40], "CALL C, $4000"),
+            (&[0xDF], "RST $18"),
+            (&[0xCB, 0x7E], "BIT 7, (HL)"),
+            (&[0xCB, 0x11], "RL C"),
+        ];
+        for (bytes, text) in cases {
+            let mut memory = Memory::new();
+            memory.write_test(bytes.to_vec());
+            let instr = SizedInstruction::decode(&memory, 0).unwrap();
+            assert_eq!(instr.instruction.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn disassemble_relative_jump() {
+        let mut memory = Memory::new();
+        memory.write_test(vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x38, 0xFB]);
+
+        let instr = SizedInstruction::decode(&memory, 6).unwrap();
+        assert_eq!(instr.disassemble(6), "JR C, $-3 ; $0003");
+        let instr = SizedInstruction::decode(&memory, 0).unwrap();
+        assert_eq!(instr.disassemble(0), "NOP");
+    }
+
+    #[test]
+    fn disassemble_range() {
+        let mut memory = Memory::new();
+        // NOP; JP 0x0150; LD A,0x12; undefined; SWAP B; LD BC,0x1234 cut off by the range
+        memory.load_raw(
+            &[
+                0x00, 0xC3, 0x50, 0x01, 0x3E, 0x12, 0xD3, 0xCB, 0x30, 0x01, 0x34, 0x12,
+            ],
+            0x0100,
+        );
+
+        let listing = SizedInstruction::disassemble_range(&memory, 0x0100..0x010B)
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listing,
+            [
+                "0100  00        NOP",
+                "0101  C3 50 01  JP $0150",
+                "0104  3E 12     LD A, $12",
+                "0106  D3        db $D3",
+                "0107  CB 30     SWAP B",
+                "0109  01        db $01",
+                "010A  34        INC (HL)",
+            ]
+        );
+    }
+
+    #[test]
+    fn disassemble_rom_bank_boundary() {
+        let mut rom = cartridge_rom(0x00, 0x00, 0x00);
+        // JP at the end of bank 0, and a truncated LD A,n at the end of bank 1
+        rom[0x3FFD..0x4000].copy_from_slice(&[0xC3, 0x00, 0x40]);
+        rom[0x7FFF] = 0x3E;
+
+        let listing = SizedInstruction::disassemble_rom(&rom, &SymbolTable::new());
+        let lines = listing.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "; bank 00");
+        assert!(lines.contains(&"00:3FFD  C3 00 40  JP $4000"));
+        assert!(lines.contains(&"; bank 01"));
+        assert!(lines.contains(&"01:4000  01 00 00  LD BC, $0000"));
+        assert_eq!(*lines.last().unwrap(), "01:7FFF  3E        db $3E");
+    }
+
+    #[test]
+    fn breakpoint_opcode_masked() {
+        let mut gb = GameBoy::new(false);
+        // all PUSH instructions
+        gb.set_breakpoint(Breakpoint::OpcodeMasked {
+            pattern: 0b1100_0101,
+            mask: 0b1100_1111,
+        });
+
+        for opcode in 0..=0xFF {
+            gb.memory_mut().write_byte(0x0000, opcode);
+            assert_eq!(
+                gb.at_breakpoint(),
+                matches!(opcode, 0xC5 | 0xD5 | 0xE5 | 0xF5),
+                "{:#04X}",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn breakpoint_opcode() {
+        let mut gb = GameBoy::new(false);
+        // LD (HL),A
+        gb.set_breakpoint(Breakpoint::Opcode(0x77));
+        gb.memory_mut().write_byte(0x0000, 0x77);
+        assert!(gb.at_breakpoint());
+        gb.memory_mut().write_byte(0x0000, 0x76);
+        assert!(!gb.at_breakpoint());
+    }
+
+    #[test]
+    fn finish_runs_until_return() {
+        let mut program = vec![0x00; 0x30];
+        // CALL $0210; NOP
+        program[0x00..0x04].copy_from_slice(&[0xCD, 0x10, 0x02, 0x00]);
+        // PUSH BC; CALL $0220; POP BC; RET
+        program[0x10..0x16].copy_from_slice(&[0xC5, 0xCD, 0x20, 0x02, 0xC1, 0xC9]);
+        // NOP; RET
+        program[0x20..0x22].copy_from_slice(&[0x00, 0xC9]);
+
+        let mut gb = GameBoy::new(false);
+        gb.load_raw_program(program, 0x0200, Some(0xD000));
+        gb.set_breakpoint(Breakpoint::Addr(0x0210));
+
+        let mut steps = 0;
+        while gb.debug_step() {
+            steps += 1;
+            assert!(steps < 100);
+        }
+        assert_eq!(gb.cpu().pc, 0x0210);
+
+        // the push and nested call do not end it
+        gb.finish();
+        while gb.debug_step() {
+            steps += 1;
+            assert!(steps < 100);
+        }
+        assert_eq!(gb.cpu().pc, 0x0203);
+        assert_eq!(gb.cpu().sp, 0xD000);
+    }
+
+    fn key_down(keycode: Keycode) -> Event {
+        Event::KeyDown {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(keycode),
+            scancode: None,
+            keymod: Mod::NOMOD,
+            repeat: false,
+        }
+    }
+
+    fn key_up(keycode: Keycode) -> Event {
+        Event::KeyUp {
+            timestamp: 0,
+            window_id: 0,
+            keycode: Some(keycode),
+            scancode: None,
+            keymod: Mod::NOMOD,
+            repeat: false,
+        }
+    }
+
+    #[test]
+    fn turbo_while_held() {
+        let mut gb = GameBoy::new(false);
+        assert!(!gb.turbo());
+
+        assert!(gb.handle_event(key_down(Keycode::Space)));
+        assert!(gb.turbo());
+        // key repeat keeps it on, releasing another key does not stop it
+        assert!(gb.handle_event(key_down(Keycode::Space)));
+        assert!(gb.handle_event(key_up(Keycode::W)));
+        assert!(gb.turbo());
+        assert!(gb.handle_event(key_up(Keycode::Space)));
+        assert!(!gb.turbo());
+
+        gb.bind_control(Keycode::Tab, ControlAction::Turbo);
+        gb.handle_event(key_down(Keycode::Space));
+        assert!(!gb.turbo());
+        gb.handle_event(key_down(Keycode::Tab));
+        assert!(gb.turbo());
+        gb.handle_event(key_up(Keycode::Tab));
+        assert!(!gb.turbo());
+    }
+
+    #[test]
+    fn control_keys_remappable() {
+        let mut gb = GameBoy::new(false);
+
+        assert!(gb.handle_event(key_down(Keycode::P)));
+        assert!(gb.paused());
+        assert!(gb.handle_event(key_down(Keycode::P)));
+        assert!(!gb.paused());
+
+        gb.bind_control(Keycode::Space, ControlAction::Pause);
+        assert!(gb.handle_event(key_down(Keycode::Space)));
+        assert!(gb.paused());
+        // the old binding is gone
+        assert!(gb.handle_event(key_down(Keycode::P)));
+        assert!(gb.paused());
+
+        assert!(!gb.handle_event(key_down(Keycode::Escape)));
+        assert!(!gb.handle_event(key_down(Keycode::Q)));
+    }
+
+    #[test]
+    fn illegal_opcode_locks_cpu() {
+        for opcode in [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ] {
+            let mut memory = Memory::new();
+            memory.write_byte(0x0000, opcode);
+            let instr = SizedInstruction::decode(&memory, 0).unwrap();
+            assert_eq!(instr.instruction, Instruction::Illegal(opcode));
+            assert_eq!(instr.size, 1);
+        }
+
+        // NOP; illegal; INC A
+        let mut gb = interrupt_pending_gameboy(vec![0x00, 0xDD, 0x3C]);
+        gb.cpu_mut().ime = (None, true);
+        gb.memory_mut().write_byte(INTERRUPT_FLAG_ADDRESS, 0x00);
+        gb.step();
+        gb.step();
+        assert!(gb.cpu().locked);
+        assert_eq!(gb.cpu().pc, 0x0201);
+
+        // stays locked, even with an interrupt pending
+        gb.memory_mut().write_byte(INTERRUPT_FLAG_ADDRESS, 0x01);
+        for _ in 0..10 {
+            gb.step();
+        }
+        assert_eq!(gb.cpu().pc, 0x0201);
+        assert_eq!(gb.cpu().a, 0x01);
+    }
+
+    #[test]
+    fn state_diff() {
+        let mut left = GameBoy::new(false);
+        let mut right = GameBoy::new(false);
+        assert!(left.state_diff(&right).is_empty());
+
+        // LD A,n ; LD (0xC000),A
+        left.memory_mut()
+            .write_test(vec![0x3E, 0x12, 0xEA, 0x00, 0xC0]);
+        right
+            .memory_mut()
+            .write_test(vec![0x3E, 0x34, 0xEA, 0x00, 0xC0]);
+        for _ in 0..2 {
+            left.step();
+            right.step();
+        }
+
+        assert_eq!(
+            left.state_diff(&right),
+            vec![
+                StateDiff::Register {
+                    name: "A",
+                    left: 0x12,
+                    right: 0x34
+                },
+                StateDiff::Memory {
+                    address: 0x0001,
+                    left: 0x12,
+                    right: 0x34
+                },
+                StateDiff::Memory {
+                    address: 0xC000,
+                    left: 0x12,
+                    right: 0x34
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn state_diff_banked_ram() {
+        // mbc5 + ram + battery, 4 ram banks
+        let mut left = GameBoy::new(false);
+        let mut right = GameBoy::new(false);
+        left.load_rom(cartridge_rom(0x1B, 0x02, 0x03)).unwrap();
+        right.load_rom(cartridge_rom(0x1B, 0x02, 0x03)).unwrap();
+
+        // bank 2 differs, ram is disabled again with bank 0 mapped
+        let memory = left.memory_mut();
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0x4000, 0x02);
+        memory.write_byte(0xA010, 0x55);
+        memory.write_byte(0x4000, 0x00);
+        memory.write_byte(0x0000, 0x00);
+        memory.set_access_logging(true);
+
+        assert_eq!(
+            left.state_diff(&right),
+            vec![StateDiff::Bank {
+                area: "external ram",
+                bank: 2,
+                offset: 0x10,
+                left: 0x55,
+                right: 0x00
+            }]
+        );
+        // comparing does not read through the cpu
+        assert!(left.memory_mut().take_accesses().is_empty());
+
+        // the mapped and enabled bank is reported once, not also through 0xA000-0xBFFF
+        let memory = right.memory_mut();
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0xA020, 0x66);
+        assert_eq!(
+            left.state_diff(&right),
+            vec![
+                StateDiff::Bank {
+                    area: "external ram",
+                    bank: 0,
+                    offset: 0x20,
+                    left: 0x00,
+                    right: 0x66
+                },
+                StateDiff::Bank {
+                    area: "external ram",
+                    bank: 2,
+                    offset: 0x10,
+                    left: 0x55,
+                    right: 0x00
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn mbc1_ram_enable() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x02, 0x00, 0x02))
+            .unwrap();
+
+        // writes are ignored while ram is disabled
+        memory.write_byte(0xA000, 0x42);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0xA000, 0x42);
+        assert_eq!(memory.read_byte(0xA000), 0x42);
+
+        // any value without 0xA in the low nibble disables
+        memory.write_byte(0x1FFF, 0x1B);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+        memory.write_byte(0x1FFF, 0xFA);
+        assert_eq!(memory.read_byte(0xA000), 0x42);
+    }
+
+    #[test]
+    fn mbc1_ram_banks() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x03, 0x00, 0x03))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+
+        // ram banking mode
+        memory.write_byte(0x6000, 0x01);
+        for bank in 0..4 {
+            memory.write_byte(0x4000, bank);
+            memory.write_byte(0xBFFF, 0x20 + bank);
+        }
+        for bank in 0..4 {
+            memory.write_byte(0x5FFF, bank);
+            assert_eq!(memory.read_byte(0xBFFF), 0x20 + bank);
+        }
+
+        // rom banking mode always maps ram bank 0
+        memory.write_byte(0x6000, 0x00);
+        assert_eq!(memory.read_byte(0xBFFF), 0x20);
+    }
+
+    #[test]
+    fn mbc1_banking_mode() {
+        let mut memory = Memory::new();
+        // 64 banks (1MB)
+        memory
+            .load_cartidge(cartridge_rom(0x01, 0x05, 0x00))
+            .unwrap();
+
+        assert_eq!(memory.rom_offset(0x0123), 0x0123);
+        assert_eq!(memory.rom_offset(0x4123), 0x4123);
+
+        memory.write_byte(0x2000, 0x05);
+        assert_eq!(memory.rom_offset(0x4123), 0x05 * 0x4000 + 0x123);
+
+        // upper bits apply to 0x4000-0x7FFF in both modes
+        memory.write_byte(0x4000, 0x01);
+        assert_eq!(memory.rom_offset(0x4123), 0x25 * 0x4000 + 0x123);
+        assert_eq!(memory.rom_offset(0x0123), 0x0123);
+
+        // and to 0x0000-0x3FFF only in mode 1
+        memory.write_byte(0x6000, 0x01);
+        assert_eq!(memory.rom_offset(0x0123), 0x20 * 0x4000 + 0x123);
+        assert_eq!(memory.read_byte(0x0000), 0x20);
+        assert_eq!(memory.read_byte(0x4000), 0x25);
+    }
+
+    #[test]
+    fn mbc1_large_rom_mapping() {
+        let mut memory = Memory::new();
+        // 64 banks (1MB)
+        memory
+            .load_cartidge(cartridge_rom(0x01, 0x05, 0x00))
+            .unwrap();
+
+        for mode in 0..2 {
+            memory.write_byte(0x6000, mode);
+            for bank in 0..64u8 {
+                memory.write_byte(0x2000, bank & 0x1F);
+                memory.write_byte(0x4000, bank >> 5);
+
+                let expected = if bank & 0x1F == 0 { bank | 1 } else { bank };
+                assert_eq!(memory.read_byte(0x4000), expected);
+                assert_eq!(memory.read_byte(0x7FFF), 0);
+
+                let expected = if mode == 1 { bank & 0x20 } else { 0 };
+                assert_eq!(memory.read_byte(0x0000), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn mbc1_bank_zero_quirk() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x01, 0x06, 0x00))
+            .unwrap();
+
+        // bank 0 selects bank 1, and so banks 0x20/0x40/0x60 become 0x21/0x41/0x61
+        for upper in 0..4 {
+            memory.write_byte(0x4000, upper);
+            memory.write_byte(0x2000, 0x00);
+            assert_eq!(memory.read_byte(0x4000), (upper << 5) | 1);
+        }
+
+        // only the low 5 bits are used
+        memory.write_byte(0x4000, 0x00);
+        memory.write_byte(0x2000, 0xE2);
+        assert_eq!(memory.read_byte(0x4000), 0x02);
+    }
+
+    #[test]
+    fn mbc1_ram_bank_out_of_range() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x03, 0x00, 0x02))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0x6000, 0x01);
+
+        memory.write_byte(0x4000, 0x00);
+        memory.write_byte(0xA010, 0x77);
+
+        // bank 3 wraps around to the only bank of a single bank cartridge
+        memory.write_byte(0x4000, 0x03);
+        assert_eq!(memory.read_byte(0xA010), 0x77);
+        memory.write_byte(0xA010, 0x88);
+
+        memory.write_byte(0x4000, 0x00);
+        assert_eq!(memory.read_byte(0xA010), 0x88);
+    }
+
+    #[test]
+    fn mbc1_no_ram() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x01, 0x00, 0x00))
+            .unwrap();
+        memory.write_byte(0x0000, 0x0A);
+
+        memory.write_byte(0xA000, 0x12);
+        assert_eq!(memory.read_byte(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn io_register_read_masks() {
+        let cases = [
+            // both groups selected, nothing pressed
+            (0xFF00, 0xCF),
+            (0xFF02, 0x7E),
+            (0xFF03, 0xFF),
+            (0xFF07, 0xF8),
+            (0xFF0F, 0xE0),
+            (0xFF10, 0x80),
+            (0xFF26, 0x70),
+            (0xFF41, 0x80),
+            (0xFF4D, 0xFF),
+            (0xFF4F, 0xFF),
+            (0xFF68, 0xFF),
+            (0xFF70, 0xFF),
+            (0xFF7F, 0xFF),
+            // fully used registers
+            (0xFF05, 0x00),
+            (0xFF30, 0x00),
+            (0xFF47, 0x00),
+        ];
+        let mut memory = Memory::new();
+        for (address, mask) in cases {
+            memory.write_byte(address, 0x00);
+            assert_eq!(memory.read_byte(address), mask, "{:#06X}", address);
+        }
+
+        // used bits still read back
+        memory.write_byte(0xFF0F, 0x05);
+        assert_eq!(memory.read_byte(0xFF0F), 0xE5);
+
+        // cgb only registers are mapped on cgb
+        memory.set_model(HardwareModel::Cgb);
+        for (address, mask) in [
+            (0xFF02, 0x7C),
+            (0xFF4D, 0x7E),
+            (0xFF4F, 0xFE),
+            (0xFF70, 0xF8),
+        ] {
+            memory.write_byte(address, 0x00);
+            assert_eq!(memory.read_byte(address), mask, "{:#06X}", address);
+        }
+    }
+
+    #[test]
+    fn model_from_cgb_flag() {
+        let rom = |cgb_flag: u8| {
+            let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+            rom[0x143] = cgb_flag;
+            rom
+        };
+        for (cgb_flag, model) in [
+            (0x00, HardwareModel::Dmg),
+            (0x80, HardwareModel::Cgb),
+            (0xC0, HardwareModel::Cgb),
+        ] {
+            let mut gb = GameBoy::new_skip_boot(false);
+            gb.load_rom(rom(cgb_flag)).unwrap();
+            assert_eq!(gb.model(), model, "{:#04X}", cgb_flag);
+        }
+
+        // overridden, a cgb only rom is refused on dmg
+        let mut gb = GameBoy::new_skip_boot(false);
+        gb.force_model(Some(HardwareModel::Dmg));
+        gb.load_rom(rom(0x80)).unwrap();
+        assert_eq!(gb.model(), HardwareModel::Dmg);
+        assert_eq!(gb.load_rom(rom(0xC0)), Err(CartridgeError::CgbOnly));
+        gb.force_model(Some(HardwareModel::Cgb));
+        gb.load_rom(rom(0x00)).unwrap();
+        assert_eq!(gb.model(), HardwareModel::Cgb);
+    }
+
+    #[test]
+    fn cgb_registers_gated_by_model() {
+        for (cgb_flag, reads) in [(0x00, [0xFF; 3]), (0x80, [0x7E, 0xFE, 0xF8])] {
+            let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+            rom[0x143] = cgb_flag;
+            let mut memory = Memory::new();
+            memory.load_cartidge(rom).unwrap();
+            // KEY1, VBK and SVBK
+            for (address, read) in [0xFF4D, 0xFF4F, 0xFF70].into_iter().zip(reads) {
+                memory.write_byte(address, 0x00);
+                assert_eq!(memory.read_byte(address), read, "{:#06X}", address);
+            }
+        }
+    }
+
+    #[test]
+    fn key1_only_prepare_bit_writable() {
+        let mut memory = Memory::new();
+        memory.set_model(HardwareModel::Cgb);
+
+        memory.write_byte(0xFF4D, 0xFF);
+        // normal speed, armed, unused bits read 1
+        assert_eq!(memory.read_byte(0xFF4D), 0x7F);
+        memory.write_byte(0xFF4D, 0x80);
+        assert_eq!(memory.read_byte(0xFF4D), 0x7E);
+
+        // not mapped on DMG
+        memory.set_model(HardwareModel::Dmg);
+        memory.write_byte(0xFF4D, 0x01);
+        assert_eq!(memory.read_byte(0xFF4D), 0xFF);
+    }
+
+    #[test]
+    fn unused_interrupt_bits_do_not_interrupt() {
+        // IE and IF upper bits set, but no real interrupt pending
+        let mut gb = interrupt_pending_gameboy(vec![0x00, 0x00]);
+        gb.memory_mut().write_byte(INTERRUPT_ENABLE_ADDRESS, 0xE0);
+        gb.memory_mut().write_byte(INTERRUPT_FLAG_ADDRESS, 0x00);
+        gb.cpu_mut().ime.1 = true;
+
+        gb.step();
+        gb.step();
+        assert_eq!(gb.cpu().pc, 0x0202);
+    }
+
+    #[test]
+    fn memory_image_round_trip() {
+        let mut image = [0; 0x10000];
+        // LD (HL),A
+        image[0x0000] = 0x77;
+        image[0xC123] = 0x11;
+        image[0xFFFE] = 0x22;
+
+        let mut memory = Memory::new();
+        memory.load_image(&image);
+        assert_eq!(memory.snapshot(), image);
+
+        let mut cpu = CPU::new();
+        let mut clock = Clock::new();
+        cpu.a = 0x99;
+        cpu.h = 0xC1;
+        cpu.l = 0x23;
+        cpu.execute(&mut memory, &mut clock);
+
+        let snapshot = memory.snapshot();
+        assert_eq!(snapshot[0xC123], 0x99);
+        let changed = (0..0x10000)
+            .filter(|&address| snapshot[address] != image[address])
+            .collect::<Vec<_>>();
+        assert_eq!(changed, vec![0xC123]);
+    }
+
+    #[test]
+    fn div_increments_at_16384hz() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        for _ in 0..63 {
+            clock.tick(1, &mut memory);
+        }
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 1);
+
+        // any write resets it
+        memory.write_byte(Clock::DIV_ADDRESS, 0x55);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0);
+        for _ in 0..63 {
+            clock.tick(1, &mut memory);
+        }
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0);
+    }
+
+    #[test]
+    fn div_counts_up_from_zero_after_write() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        for _ in 0..0x42 {
+            clock.tick(64, &mut memory);
+        }
+        clock.tick(30, &mut memory);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0x42);
+
+        memory.write_byte(Clock::DIV_ADDRESS, 0x55);
+        for expected in 1..=3 {
+            clock.tick(64, &mut memory);
+            assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), expected);
+        }
+    }
+
+    #[test]
+    fn div_write_resets_internal_counter() {
+        let mut cpu = CPU::new();
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        // LDH ($04),A
+        memory.write_test(vec![0xE0, 0x04]);
+        cpu.a = 0x55;
+        clock.tick(100, &mut memory);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 1);
+
+        // the write lands 2 machine cycles before the end of the instruction
+        cpu.execute(&mut memory, &mut clock);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0);
+
+        // the low bits are cleared too, so the next increment is a full period away
+        clock.tick(61, &mut memory);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 1);
+    }
+
+    #[test]
+    fn frame_sequencer_follows_div_bit_4() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        // DIV bit 4 falls every 2048 machine cycles (512Hz)
+        for _ in 0..2047 {
+            clock.tick(1, &mut memory);
+        }
+        assert_eq!(memory.get_frame_sequencer(), 0);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.get_frame_sequencer(), 1);
+
+        // resetting DIV with bit 4 set is an extra falling edge
+        for _ in 0..1024 {
+            clock.tick(1, &mut memory);
+        }
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0x30);
+        memory.write_byte(Clock::DIV_ADDRESS, 0);
+        assert_eq!(memory.get_frame_sequencer(), 2);
+
+        // resetting DIV with bit 4 clear is not, and delays the next step
+        for _ in 0..1023 {
+            clock.tick(1, &mut memory);
+        }
+        memory.write_byte(Clock::DIV_ADDRESS, 0);
+        assert_eq!(memory.get_frame_sequencer(), 2);
+        for _ in 0..2047 {
+            clock.tick(1, &mut memory);
+        }
+        assert_eq!(memory.get_frame_sequencer(), 2);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.get_frame_sequencer(), 3);
+
+        // the step wraps after 8 events
+        for _ in 0..5 * 2048 {
+            clock.tick(1, &mut memory);
+        }
+        assert_eq!(memory.get_frame_sequencer(), 0);
+    }
+
+    #[test]
+    fn timer_counts_falling_edges() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        // 262144Hz, bit 3 of the counter, every 4 machine cycles
+        memory.write_byte(Clock::TAC_ADDRESS, 0b101);
+        for _ in 0..3 {
+            clock.tick(1, &mut memory);
+        }
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 0);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 1);
+
+        // 4096Hz, bit 9 of the counter falls at 1024 t-cycles
+        memory.write_byte(Clock::TAC_ADDRESS, 0b100);
+        clock.tick(251, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 1);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 2);
+
+        // overflow reloads TMA and requests an interrupt
+        memory.write_byte(Clock::TAC_ADDRESS, 0b101);
+        memory.write_byte(Clock::TIMA_ADDRESS, 0xFF);
+        memory.write_byte(Clock::TMA_ADDRESS, 0x80);
+        memory.write_byte(INTERRUPT_FLAG_ADDRESS, 0x00);
+        clock.tick(4, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 0x00);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 0x80);
+        assert_eq!(memory.read_byte(INTERRUPT_FLAG_ADDRESS) & 0x1F, 0b100);
+    }
+
+    #[test]
+    fn tima_reload_delay() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+        let overflow = |memory: &mut Memory, clock: &mut Clock| {
+            memory.write_byte(Clock::TIMA_ADDRESS, 0xFF);
+            memory.write_byte(INTERRUPT_FLAG_ADDRESS, 0x00);
+            clock.tick(4, memory);
+            assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 0x00);
+        };
+        let timer_requested =
+            |memory: &Memory| memory.read_byte(INTERRUPT_FLAG_ADDRESS) & 0b100 != 0;
+
+        // 262144Hz, every 4 machine cycles
+        memory.write_byte(Clock::TAC_ADDRESS, 0b101);
+        memory.write_byte(Clock::TMA_ADDRESS, 0x80);
+
+        // reads 0 for one cycle, then reloads
+        overflow(&mut memory, &mut clock);
+        assert!(!timer_requested(&memory));
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 0x80);
+        assert!(timer_requested(&memory));
+        clock.tick(3, &mut memory);
+
+        // a write while it reads 0 cancels the reload and the interrupt
+        overflow(&mut memory, &mut clock);
+        memory.write_byte(Clock::TIMA_ADDRESS, 0x42);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 0x42);
+        assert!(!timer_requested(&memory));
+        clock.tick(3, &mut memory);
+
+        // a write in the reload cycle is overwritten by TMA, which still follows TMA writes
+        overflow(&mut memory, &mut clock);
+        clock.tick(1, &mut memory);
+        memory.write_byte(Clock::TIMA_ADDRESS, 0x42);
+        memory.write_byte(Clock::TMA_ADDRESS, 0x90);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 0x90);
+        assert!(timer_requested(&memory));
+    }
+
+    #[test]
+    fn timer_registers_read_live_values() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        memory.write_byte(Clock::TMA_ADDRESS, 0xF0);
+        memory.write_byte(Clock::TAC_ADDRESS, 0b110);
+        // 65536Hz, every 16 machine cycles
+        clock.tick(200, &mut memory);
+        clock.tick(100, &mut memory);
+
+        // 300 machine cycles = 1200 t-cycles
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), (1200 >> 8) as Byte);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), (300 / 16) as Byte);
+        assert_eq!(memory.read_byte(Clock::TMA_ADDRESS), 0xF0);
+        assert_eq!(memory.read_byte(Clock::TAC_ADDRESS), 0xF8 | 0b110);
+
+        // TIMA reloads from TMA after an overflow
+        memory.write_byte(Clock::TIMA_ADDRESS, 0xFF);
+        clock.tick(16, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 0xF0);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), (1264 >> 8) as Byte);
+    }
+
+    #[test]
+    fn timer_enable_glitch() {
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        // selected bit high: disabling the timer is a falling edge
+        memory.write_byte(Clock::TAC_ADDRESS, 0b101);
+        clock.tick(2, &mut memory);
+        memory.write_byte(Clock::TAC_ADDRESS, 0b001);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 1);
+
+        // stopped while disabled
+        clock.tick(16, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 1);
+
+        // selected bit low: toggling the enable does nothing
+        memory.write_byte(Clock::DIV_ADDRESS, 0);
+        memory.write_byte(Clock::TAC_ADDRESS, 0b101);
+        clock.tick(1, &mut memory);
+        memory.write_byte(Clock::TAC_ADDRESS, 0b001);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 1);
+
+        // resetting DIV with the selected bit high is a falling edge too
+        memory.write_byte(Clock::TAC_ADDRESS, 0b101);
+        clock.tick(1, &mut memory);
+        memory.write_byte(Clock::DIV_ADDRESS, 0);
+        clock.tick(1, &mut memory);
+        assert_eq!(memory.read_byte(Clock::TIMA_ADDRESS), 2);
+    }
+
+    #[test]
+    fn stop_resets_div() {
+        let mut cpu = CPU::new();
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+
+        memory.write_test(vec![0x10, 0x00, 0x00]);
+        for _ in 0..1024 {
+            clock.tick(1, &mut memory);
+        }
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0x10);
+
+        cpu.execute(&mut memory, &mut clock);
+
+        assert_eq!(cpu.pc, 2);
+        assert_eq!(memory.read_byte(Clock::DIV_ADDRESS), 0);
+        assert_eq!(memory.get_frame_sequencer(), 1);
+    }
+
+    /// Run one SingleStepTests (sm83) case: set up the `initial` state, execute one
+    /// instruction and compare registers and ram against `final`
+    fn run_single_step(case: &Value) {
+        let name = case["name"].as_str().unwrap_or_default();
+        let number = |value: &Value| value.as_u64().unwrap() as usize;
+        let registers = [
+            Register16::SP,
+            Register16::AF,
+            Register16::BC,
+            Register16::DE,
+            Register16::HL,
+        ];
+        let word = |state: &Value, rr: Register16| match rr {
+            Register16::SP => number(&state["sp"]),
+            Register16::AF => number(&state["a"]) << 8 | number(&state["f"]),
+            Register16::BC => number(&state["b"]) << 8 | number(&state["c"]),
+            Register16::DE => number(&state["d"]) << 8 | number(&state["e"]),
+            Register16::HL => number(&state["h"]) << 8 | number(&state["l"]),
+        };
+
+        let initial = &case["initial"];
+        let mut cpu = CPU::new();
+        let mut memory = Memory::new();
+        let mut clock = Clock::new();
+        cpu.pc = number(&initial["pc"]) as u16;
+        for rr in registers {
+            cpu.set_register16(rr, word(initial, rr) as u16);
+        }
+        cpu.ime.1 = number(&initial["ime"]) != 0;
+        let mut image = [0; 0x10000];
+        for entry in initial["ram"].as_array().unwrap() {
+            image[number(&entry[0])] = number(&entry[1]) as Byte;
+        }
+        memory.load_image(&image);
+
+        cpu.execute(&mut memory, &mut clock);
+
+        let expected = &case["final"];
+        assert_eq!(cpu.pc as usize, number(&expected["pc"]), "{} pc", name);
+        for rr in registers {
+            assert_eq!(
+                cpu.get_register16(rr) as usize,
+                word(expected, rr),
+                "{} {:?}",
+                name,
+                rr
+            );
+        }
+        let snapshot = memory.snapshot();
+        for entry in expected["ram"].as_array().unwrap() {
+            let address = number(&entry[0]);
+            assert_eq!(
+                snapshot[address] as usize,
+                number(&entry[1]),
+                "{} {:#06X}",
+                name,
+                address
+            );
+        }
+        if let Some(cycles) = case["cycles"].as_array() {
+            assert_eq!(
+                clock.get_timestamp() as usize,
+                cycles.len(),
+                "{} cycles",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn sm83_single_step() {
+        let cases: Value = serde_json::from_str(
+            r#"[
+                {
+                    "name": "80 ADD A,B",
+                    "initial": {"pc": 256, "sp": 0, "a": 58, "b": 198, "c": 0, "d": 0, "e": 0,
+                        "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [[256, 128]]},
+                    "final": {"pc": 257, "sp": 0, "a": 0, "b": 198, "c": 0, "d": 0, "e": 0,
+                        "f": 176, "h": 0, "l": 0, "ime": 0, "ram": [[256, 128]]},
+                    "cycles": [[256, 128, "r-m"]]
+                },
+                {
+                    "name": "22 LD (HL+),A",
+                    "initial": {"pc": 256, "sp": 0, "a": 86, "b": 0, "c": 0, "d": 0, "e": 0,
+                        "f": 0, "h": 192, "l": 255, "ime": 0, "ram": [[256, 34], [49407, 0]]},
+                    "final": {"pc": 257, "sp": 0, "a": 86, "b": 0, "c": 0, "d": 0, "e": 0,
+                        "f": 0, "h": 193, "l": 0, "ime": 0, "ram": [[256, 34], [49407, 86]]},
+                    "cycles": [[256, 34, "r-m"], [49407, 86, "-wm"]]
+                },
+                {
+                    "name": "27 DAA",
+                    "initial": {"pc": 256, "sp": 0, "a": 154, "b": 0, "c": 0, "d": 0, "e": 0,
+                        "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [[256, 39]]},
+                    "final": {"pc": 257, "sp": 0, "a": 0, "b": 0, "c": 0, "d": 0, "e": 0,
+                        "f": 144, "h": 0, "l": 0, "ime": 0, "ram": [[256, 39]]},
+                    "cycles": [[256, 39, "r-m"]]
+                },
+                {
+                    "name": "C5 PUSH BC",
+                    "initial": {"pc": 256, "sp": 53248, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0,
+                        "f": 0, "h": 0, "l": 0, "ime": 0, "ram": [[256, 197]]},
+                    "final": {"pc": 257, "sp": 53246, "a": 0, "b": 18, "c": 52, "d": 0, "e": 0,
+                        "f": 0, "h": 0, "l": 0, "ime": 0,
+                        "ram": [[256, 197], [53247, 18], [53246, 52]]},
+                    "cycles": [[256, 197, "r-m"], null, [53247, 18, "-wm"], [53246, 52, "-wm"]]
+                }
+            ]"#,
+        )
+        .unwrap();
+        for case in cases.as_array().unwrap() {
+            run_single_step(case);
+        }
+    }
+
+    /// Runs every SingleStepTests json file in $SM83_TESTS_DIR, skipped when unset
+    #[test]
+    fn sm83_single_step_files() {
+        let Ok(dir) = std::env::var("SM83_TESTS_DIR") else {
+            return;
+        };
+        let mut paths = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            let cases: Value =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            for case in cases.as_array().unwrap() {
+                run_single_step(case);
+            }
+        }
+    }
+
+    #[test]
+    fn memory_save_state_round_trip() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x1B, 0x02, 0x03))
+            .unwrap();
+        // enable ram, rom bank 5, ram bank 2
+        memory.write_byte(0x0000, 0x0A);
+        memory.write_byte(0x2000, 0x05);
+        memory.write_byte(0x4000, 0x02);
+        memory.write_byte(0xA000, 0x42);
+        memory.write_byte(0xC000, 0x99);
+        let state = memory.save_state();
+
+        memory.write_byte(0xA000, 0x00);
+        memory.write_byte(0x2000, 0x01);
+        memory.write_byte(0x4000, 0x00);
+        memory.write_byte(0xC000, 0x00);
+        memory.load_state(&state).unwrap();
+
+        assert_eq!(memory.read_byte(0x4000), 0x05);
+        assert_eq!(memory.read_byte(0xA000), 0x42);
+        assert_eq!(memory.read_byte(0xC000), 0x99);
+        assert_eq!(memory.save_state(), state);
+        // the rom is not part of the state
+        assert!(state.len() < 0x20000);
+    }
+
+    #[test]
+    fn memory_load_state_errors() {
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x1B, 0x02, 0x03))
+            .unwrap();
+        memory.write_byte(0xC000, 0x99);
+        let state = memory.save_state();
+
+        let mut rom = cartridge_rom(0x1B, 0x02, 0x03);
+        rom[0x150] = 0x01;
+        let mut other = Memory::new();
+        other.load_cartidge(rom).unwrap();
+        let before = other.save_state();
+        assert_eq!(
+            other.load_state(&state),
+            Err(StateError::RomMismatch {
+                expected: memory.rom_hash(),
+                actual: other.rom_hash(),
+            })
+        );
+        assert_eq!(other.save_state(), before);
+
+        assert_eq!(
+            memory.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(memory.load_state(b"save"), Err(StateError::BadFormat));
+
+        // the work ram bank count has to match the model, or SVBK writes would panic
+        let model = 5 + 8 + 0x10000 + 0x100 + 1;
+        let mut cgb = state.clone();
+        assert_eq!(cgb[model], 0);
+        cgb[model] = 1;
+        assert_eq!(
+            memory.load_state(&cgb),
+            Err(StateError::InvalidValue("work ram bank count"))
+        );
+        memory.set_model(HardwareModel::Cgb);
+        let mut dmg = memory.save_state();
+        assert_eq!(dmg[model], 1);
+        dmg[model] = 0;
+        assert_eq!(
+            memory.load_state(&dmg),
+            Err(StateError::InvalidValue("work ram bank count"))
+        );
+
+        // an oam dma past its last byte would index out of OAM on the next tick
+        let mut memory = Memory::new();
+        memory
+            .load_cartidge(cartridge_rom(0x1B, 0x02, 0x03))
+            .unwrap();
+        memory.write_byte(0xFF46, 0xC1);
+        let mut state = memory.save_state();
+        let copied = model + 1 + 2 * 64 + 4 + 3 + 1 + 2;
+        assert_eq!(state[copied - 2..copied + 2], [0x00, 0xC1, 0x00, 0x00]);
+        memory.load_state(&state).unwrap();
+        state[copied] = 0xA0;
+        assert_eq!(
+            memory.load_state(&state),
+            Err(StateError::InvalidValue("oam dma"))
+        );
+    }
+
+    #[test]
+    fn apu_load_state_errors() {
+        let save = |apu: &Apu| {
+            let mut state = StateWriter::new();
+            apu.write_state(&mut state);
+            state.finish()
+        };
+        let load = |data: &[u8]| {
+            let mut state = StateReader::new(data).unwrap();
+            Apu::read_state(&mut state)
+        };
+        let state = save(&Apu::new());
+        assert!(load(&state).is_ok());
+
+        // channel 1 starts after the 5 byte header: flags, duty, frequency at 4,
+        // timer, length, envelope at 11 and the sweep shift at 18
+        let mut frequency = state.clone();
+        frequency[5 + 4..5 + 6].copy_from_slice(&0x0800u16.to_le_bytes());
+        assert_eq!(
+            load(&frequency),
+            Err(StateError::InvalidValue("square channel"))
+        );
+        let mut volume = state.clone();
+        volume[5 + 14] = 16;
+        assert_eq!(
+            load(&volume),
+            Err(StateError::InvalidValue("volume envelope"))
+        );
+        let mut shift = state.clone();
+        shift[5 + 18] = 16;
+        assert_eq!(
+            load(&shift),
+            Err(StateError::InvalidValue("frequency sweep"))
+        );
+    }
+
+    #[test]
+    fn run_boot_stops_at_entry_point() {
+        let mut boot = vec![0x00; 0x100];
+        // JP 0x00FC
+        boot[..3].copy_from_slice(&[0xC3, 0xFC, 0x00]);
+        // LD A,1; LDH (0x50),A
+        boot[0xFC..].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+
+        let mut gameboy = GameBoy::new(false);
+        assert_eq!(gameboy.run_boot(), Err(BootError::NotMapped));
+        gameboy.load_boot(boot);
+        gameboy.load_rom(cartridge_rom(0x00, 0x00, 0x00)).unwrap();
+        gameboy.run_boot().unwrap();
+
+        assert_eq!(gameboy.cpu().pc, 0x100);
+        assert!(!gameboy.memory().boot_mapped());
+    }
+
+    #[test]
+    fn run_boot_times_out() {
+        let mut boot = vec![0x00; 0x100];
+        // JR -2
+        boot[..2].copy_from_slice(&[0x18, 0xFE]);
+
+        let mut gameboy = GameBoy::new(false);
+        gameboy.load_boot(boot);
+        gameboy.load_rom(cartridge_rom(0x00, 0x00, 0x00)).unwrap();
+        assert!(matches!(
+            gameboy.run_boot(),
+            Err(BootError::Timeout { pc: 0x0000, .. })
+        ));
+    }
+
+    #[test]
+    fn serial_transfer_completes_after_8_bits() {
+        let mut memory = Memory::new();
+        memory.write_byte(0xFF01, b'A');
+        memory.write_byte(0xFF02, 0x81);
+        assert_eq!(memory.take_serial_output(), vec![b'A']);
+
+        // 8 bits at 8192 Hz, 128 machine cycles each
+        for _ in 0..1023 {
+            memory.tick_peripherals(1);
+        }
+        assert_eq!(memory.read_byte(0xFF02) & 0x80, 0x80);
+        assert_eq!(memory.read_byte(0xFF0F) & SERIAL_FLAG, 0);
+
+        memory.tick_peripherals(1);
+        assert_eq!(memory.read_byte(0xFF02) & 0x80, 0);
+        assert_eq!(memory.read_byte(0xFF01), 0xFF);
+        assert_ne!(memory.read_byte(0xFF0F) & SERIAL_FLAG, 0);
+    }
+
+    #[test]
+    fn serial_external_clock_waits() {
+        let mut memory = Memory::new();
+        memory.write_byte(0xFF01, 0x12);
+        memory.write_byte(0xFF02, 0x80);
+        for _ in 0..4 {
+            memory.tick_peripherals(255);
+        }
+        assert_eq!(memory.read_byte(0xFF02) & 0x80, 0x80);
+        assert_eq!(memory.read_byte(0xFF01), 0x12);
+        assert!(memory.take_serial_output().is_empty());
+    }
+
+    /// Write sink that can be read back after it was handed over
+    #[derive(Clone, Default)]
+    struct SharedSink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn io_trace_logs_register_writes() {
+        let mut gameboy = GameBoy::new(false);
+        // LD A,0x91; LDH (LCDC),A; LD A,0xE4; LDH (BGP),A; LD (0xC000),A
+        let program = vec![
+            0x3E, 0x91, 0xE0, 0x40, 0x3E, 0xE4, 0xE0, 0x47, 0xEA, 0x00, 0xC0,
+        ];
+        gameboy.load_raw_program(program, 0x0100, None);
+        let sink = SharedSink::default();
+        gameboy.set_io_trace(Some(Box::new(sink.clone())));
+        for _ in 0..5 {
+            assert!(gameboy.debug_step());
+        }
+
+        let trace = String::from_utf8(sink.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace.lines().collect::<Vec<_>>(),
+            vec!["0102: LCDC  FF40 00 -> 91", "0106: BGP   FF47 00 -> E4"]
+        );
+        assert_eq!(io_register_name(0xFF26), Some("NR52"));
+        assert_eq!(io_register_name(0xFF15), None);
+        assert_eq!(
+            format_io_write(0x0150, 0xFF15, 0xFF, 0x00),
+            "0150: ?     FF15 FF -> 00"
+        );
+    }
+
+    #[test]
+    fn io_trace_skips_internal_writes() {
+        let mut gameboy = GameBoy::new(false);
+        // EI; NOP; NOP; NOP; LD A,0x04; LDH (IF),A
+        let program = vec![0xFB, 0x00, 0x00, 0x00, 0x3E, 0x04, 0xE0, 0x0F];
+        gameboy.load_raw_program(program, 0x0100, None);
+        let sink = SharedSink::default();
+        gameboy.set_io_trace(Some(Box::new(sink.clone())));
+        for _ in 0..6 {
+            assert!(gameboy.debug_step());
+        }
+
+        // the interrupt check rewrites IF every step with IME on, only the cpu write is traced
+        let trace = String::from_utf8(sink.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace.lines().collect::<Vec<_>>(),
+            vec!["0106: IF    FF0F 00 -> 04"]
+        );
+    }
+
+    /// Sound on at full volume, every channel to both sides
+    fn power_on_apu(memory: &mut Memory) {
+        memory.write_byte(0xFF26, 0x80);
+        memory.write_byte(0xFF24, 0x77);
+        memory.write_byte(0xFF25, 0xFF);
+    }
+
+    #[test]
+    fn apu_square_duty_output() {
+        let mut memory = Memory::new();
+        power_on_apu(&mut memory);
+        // 50% duty, volume 15, frequency 0x700 (256 mcycles per duty step)
+        memory.write_byte(0xFF16, 0x80);
+        memory.write_byte(0xFF17, 0xF0);
+        memory.write_byte(0xFF18, 0x00);
+        memory.write_byte(0xFF19, 0x87);
+        assert!(memory.apu().channel_enabled(2));
+        assert!(!memory.apu().channel_enabled(1));
+
+        for _ in 0..16 {
+            memory.tick_peripherals(255);
+        }
+        let samples = memory.take_audio_samples();
+        // 4080 mcycles at 44100 Hz
+        assert_eq!(samples.len(), 4080 * 44100 / (1 << 20));
+        assert!(samples.contains(&(0.0, 0.0)));
+        assert!(samples.contains(&(0.25, 0.25)));
+        assert!(samples
+            .iter()
+            .all(|&s| s == (0.0, 0.0) || s == (0.25, 0.25)));
+    }
+
+    #[test]
+    fn apu_length_and_dac() {
+        let mut memory = Memory::new();
+        power_on_apu(&mut memory);
+        // length 1 with the length counter enabled
+        memory.write_byte(0xFF11, 0x3F);
+        memory.write_byte(0xFF12, 0xF0);
+        memory.write_byte(0xFF14, 0xC0);
+        assert!(memory.apu().channel_enabled(1));
+        // step 0 clocks the length
+        memory.clock_frame_sequencer();
+        assert!(!memory.apu().channel_enabled(1));
+
+        // turning the dac off stops the channel
+        memory.write_byte(0xFF14, 0x80);
+        assert!(memory.apu().channel_enabled(1));
+        memory.write_byte(0xFF12, 0x00);
+        assert!(!memory.apu().channel_enabled(1));
+        memory.write_byte(0xFF14, 0x80);
+        assert!(!memory.apu().channel_enabled(1));
+    }
+
+    #[test]
+    fn apu_sweep_overflow() {
+        let mut memory = Memory::new();
+        power_on_apu(&mut memory);
+        memory.write_byte(0xFF12, 0xF0);
+        // period 1, increase, shift 1
+        memory.write_byte(0xFF10, 0x11);
+
+        // 0x600 + 0x300 overflows as soon as it triggers
+        memory.write_byte(0xFF13, 0x00);
+        memory.write_byte(0xFF14, 0x86);
+        assert!(!memory.apu().channel_enabled(1));
+
+        // 0x400 sweeps to 0x600 on step 2, whose next step overflows
+        memory.write_byte(0xFF14, 0x84);
+        assert!(memory.apu().channel_enabled(1));
+        memory.clock_frame_sequencer();
+        memory.clock_frame_sequencer();
+        assert!(memory.apu().channel_enabled(1));
+        memory.clock_frame_sequencer();
+        assert!(!memory.apu().channel_enabled(1));
+    }
+
+    #[test]
+    fn apu_wave_channel() {
+        let mut memory = Memory::new();
+        power_on_apu(&mut memory);
+        // 16 samples at 15 followed by 16 at 0
+        for i in 0..16 {
+            memory.write_byte(0xFF30 + i, if i < 8 { 0xFF } else { 0x00 });
+        }
+        // dac on, 100% volume, frequency 0x700 (128 mcycles per sample)
+        memory.write_byte(0xFF1A, 0x80);
+        memory.write_byte(0xFF1C, 0x20);
+        memory.write_byte(0xFF1D, 0x00);
+        memory.write_byte(0xFF1E, 0x87);
+        assert!(memory.apu().channel_enabled(3));
+        assert_eq!(memory.apu().sample(), (0.25, 0.25));
+
+        for _ in 0..8 {
+            memory.tick_peripherals(255);
+        }
+        assert_eq!(memory.apu().sample(), (0.25, 0.25));
+        for _ in 0..8 {
+            memory.tick_peripherals(1);
+        }
+        assert_eq!(memory.apu().sample(), (0.0, 0.0));
+
+        // 25% volume shifts the samples right by 2
+        memory.write_byte(0xFF1C, 0x60);
+        memory.write_byte(0xFF1E, 0x87);
+        assert_eq!(memory.apu().sample(), (3.0 / 60.0, 3.0 / 60.0));
+
+        // length 1 runs out on the next length clock
+        memory.write_byte(0xFF1B, 0xFF);
+        memory.write_byte(0xFF1E, 0xC7);
+        memory.clock_frame_sequencer();
+        assert!(!memory.apu().channel_enabled(3));
+        assert_eq!(memory.apu().sample(), (0.0, 0.0));
+
+        // turning the dac off stops the channel
+        memory.write_byte(0xFF1E, 0x87);
+        assert!(memory.apu().channel_enabled(3));
+        memory.write_byte(0xFF1A, 0x00);
+        assert!(!memory.apu().channel_enabled(3));
+    }
+
+    /// Audio sink keeping everything queued
+    struct RecordingAudio(Rc<RefCell<Vec<f32>>>);
+
+    impl AudioSink for RecordingAudio {
+        fn queued(&self) -> usize {
+            0
+        }
+
+        fn queue(&mut self, samples: &[f32]) -> Result<(), String> {
+            self.0.borrow_mut().extend_from_slice(samples);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn turbo_mutes_audio() {
+        let queued = Rc::new(RefCell::new(Vec::new()));
+        let mut gameboy = GameBoy::new(false);
+        gameboy.set_audio_sink(Some(Box::new(RecordingAudio(queued.clone()))));
+        // channel 2 at 50% duty, volume 15, 256 mcycles per duty step
+        let memory = gameboy.memory_mut();
+        power_on_apu(memory);
+        memory.write_byte(0xFF16, 0x80);
+        memory.write_byte(0xFF17, 0xF0);
+        memory.write_byte(0xFF18, 0x00);
+        memory.write_byte(0xFF19, 0x87);
+
+        gameboy.set_turbo(true);
+        for _ in 0..16 {
+            gameboy.memory_mut().tick_peripherals(255);
+        }
+        gameboy.queue_audio();
+        let muted = queued.take();
+        // left and right interleaved
+        assert_eq!(muted.len(), 2 * (4080 * 44100 / (1 << 20)));
+        assert!(muted.iter().all(|&s| s == 0.0));
+        // the apu kept running
+        assert!(gameboy.memory().apu().channel_enabled(2));
+
+        gameboy.set_turbo(false);
+        for _ in 0..16 {
+            gameboy.memory_mut().tick_peripherals(255);
+        }
+        gameboy.queue_audio();
+        assert!(queued.take().contains(&0.25));
+    }
+
+    #[test]
+    fn resample_picks_evenly_spaced_samples() {
+        let samples: Vec<f32> = (0..8).map(|i| i as f32).collect();
+        assert_eq!(resample(&samples, 4), vec![0.0, 2.0, 4.0, 6.0]);
+        assert_eq!(resample(&samples, 8), samples);
+        assert_eq!(resample(&samples, 16), samples);
+        assert!(resample(&samples, 0).is_empty());
+    }
+
+    #[test]
+    fn strict_rom_writes() {
+        // LD A,0x12; LD (0x2000),A; NOP
+        let mut rom = cartridge_rom(0x00, 0x00, 0x00);
+        rom[0x100..0x106].copy_from_slice(&[0x3E, 0x12, 0xEA, 0x00, 0x20, 0x00]);
+
+        for check in [RomWriteCheck::Off, RomWriteCheck::Log, RomWriteCheck::Break] {
+            let mut gb = GameBoy::new_skip_boot(false);
+            gb.load_rom(rom.clone()).unwrap();
+            gb.memory_mut().set_rom_write_check(check);
+            assert!(gb.debug_step());
+            assert!(gb.debug_step());
+            // the write is ignored in every mode
+            assert_eq!(gb.memory().read_byte(0x2000), 0x00);
+            assert_eq!(gb.paused(), check == RomWriteCheck::Break);
+            assert_eq!(gb.debug_step(), check != RomWriteCheck::Break);
+        }
+
+        // writes to ram are fine
+        let mut memory = Memory::new();
+        memory.load_cartidge(rom).unwrap();
+        memory.set_rom_write_check(RomWriteCheck::Break);
+        memory.write_byte(0xC000, 0x12);
+        assert_eq!(memory.take_rom_write(), None);
+        memory.write_byte(0x7FFF, 0x34);
+        assert_eq!(memory.take_rom_write(), Some((0x7FFF, 0x34)));
+        assert_eq!(memory.take_rom_write(), None);
+    }
+
+    #[test]
+    fn apu_noise_channel() {
+        let mut memory = Memory::new();
+        power_on_apu(&mut memory);
+        // volume 15, shift 0 and divisor 0 (2 mcycles per shift)
+        memory.write_byte(0xFF21, 0xF0);
+        memory.write_byte(0xFF22, 0x00);
+        memory.write_byte(0xFF23, 0x80);
+        assert!(memory.apu().channel_enabled(4));
+        // the register starts all ones, which is silent
+        assert_eq!(memory.apu().sample(), (0.0, 0.0));
+
+        // 15 bit mode repeats every 32767 shifts, with zeros in between
+        let outputs = |memory: &mut Memory, shifts: usize| {
+            (0..shifts)
+                .map(|_| {
+                    memory.tick_peripherals(2);
+                    memory.apu().sample().0
+                })
+                .collect::<Vec<_>>()
+        };
+        let long = outputs(&mut memory, 2 * 32767);
+        assert!(long.contains(&0.25));
+        assert_eq!(long[..32767], long[32767..]);
+        assert_ne!(long[..127], long[127..254]);
+
+        // 7 bit mode repeats every 127 shifts
+        memory.write_byte(0xFF22, 0x08);
+        memory.write_byte(0xFF23, 0x80);
+        let short = outputs(&mut memory, 2 * 127);
+        assert!(short.contains(&0.25));
+        assert_eq!(short[..127], short[127..]);
+
+        // length 1 runs out on the next length clock
+        memory.write_byte(0xFF20, 0x3F);
+        memory.write_byte(0xFF23, 0xC0);
+        memory.clock_frame_sequencer();
+        assert!(!memory.apu().channel_enabled(4));
+    }
+
+    #[test]
+    fn apu_panning_and_master_volume() {
+        let mut memory = Memory::new();
+        power_on_apu(&mut memory);
+        // channel 2 at 50% duty and volume 15, starting on a high step
+        memory.write_byte(0xFF16, 0x80);
+        memory.write_byte(0xFF17, 0xF0);
+        memory.write_byte(0xFF19, 0x80);
+        assert_eq!(memory.apu().sample(), (0.25, 0.25));
+
+        // channel 2 only on the left
+        memory.write_byte(0xFF25, 0x20);
+        assert_eq!(memory.apu().sample(), (0.25, 0.0));
+        // only the right, at volume 3 of 7
+        memory.write_byte(0xFF25, 0x02);
+        memory.write_byte(0xFF24, 0x73);
+        assert_eq!(memory.apu().sample(), (0.0, 0.125));
+    }
+
+    #[test]
+    fn apu_power_and_status() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.read_byte(0xFF26), 0x70);
+        // ignored while off
+        memory.write_byte(0xFF24, 0x77);
+        assert_eq!(memory.read_byte(0xFF24), 0x00);
+
+        power_on_apu(&mut memory);
+        assert_eq!(memory.read_byte(0xFF26), 0xF0);
+        memory.write_byte(0xFF17, 0xF0);
+        memory.write_byte(0xFF19, 0x80);
+        memory.write_byte(0xFF21, 0xF0);
+        memory.write_byte(0xFF23, 0x80);
+        assert_eq!(memory.read_byte(0xFF26), 0xFA);
+        // the power bit is the only writable one
+        memory.write_byte(0xFF26, 0x8F);
+        assert_eq!(memory.read_byte(0xFF26), 0xFA);
+
+        // powering off stops the channels and clears the registers but not wave ram
+        memory.write_byte(0xFF30, 0x12);
+        memory.write_byte(0xFF26, 0x00);
+        assert_eq!(memory.read_byte(0xFF26), 0x70);
+        assert!(!memory.apu().channel_enabled(2));
+        assert_eq!(memory.read_byte(0xFF25), 0x00);
+        assert_eq!(memory.read_byte(0xFF17), 0x00);
+        assert_eq!(memory.read_byte(0xFF30), 0x12);
+        assert_eq!(memory.apu().sample(), (0.0, 0.0));
+
+        // the boot rom leaves the sound on
+        let mut memory = Memory::new();
+        memory.init_post_boot();
+        assert!(memory.apu().powered());
+        assert_eq!(memory.read_byte(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn mooneye_harness() {
+        // LD B,3; LD C,5; LD D,8; LD E,13; LD H,21; LD L,34; LD B,B
+        let pass = [
+            0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34, 0x40,
+        ];
+        let mut rom = cartridge_rom(0x00, 0x00, 0x00);
+        rom[0x100..0x100 + pass.len()].copy_from_slice(&pass);
+        assert_eq!(run_mooneye_test(rom), Ok(TestResult::Pass));
+
+        // failures load 0x42 into every register
+        // LD A,0x42; LD B,A; LD C,A; LD D,A; LD E,A; LD H,A; LD L,A; LD B,B
+        let fail = [0x3E, 0x42, 0x47, 0x4F, 0x57, 0x5F, 0x67, 0x6F, 0x40];
+        let mut rom = cartridge_rom(0x00, 0x00, 0x00);
+        rom[0x100..0x100 + fail.len()].copy_from_slice(&fail);
+        assert_eq!(
+            run_mooneye_test(rom),
+            Ok(TestResult::Fail {
+                registers: [0x42; 6]
+            })
+        );
+    }
+
+    /// MBC1 rom that powers the apu, starts the timer and increments WRAM forever
+    fn state_test_rom() -> Vec<u8> {
+        let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+        // JP 0x0150
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        // LD A,0x80 ; LDH (0x26),A ; LD A,0x05 ; LDH (0x07),A ; LD HL,0xC000
+        // loop: INC (HL) ; INC HL ; JR loop
+        rom[0x150..0x15F].copy_from_slice(&[
+            0x3E, 0x80, 0xE0, 0x26, 0x3E, 0x05, 0xE0, 0x07, 0x21, 0x00, 0xC0, 0x34, 0x23, 0x18,
+            0xFC,
+        ]);
+        rom
+    }
+
+    #[test]
+    fn gameboy_save_state_round_trip() {
+        let mut gb = GameBoy::new_skip_boot(false);
+        gb.load_rom(state_test_rom()).unwrap();
+        for _ in 0..1000 {
+            gb.step_and_render();
+        }
+        let path = std::env::temp_dir().join("gb-rs-gameboy_save_state_round_trip.state");
+        gb.save_state(&path).unwrap();
+        for _ in 0..1000 {
+            gb.step_and_render();
+        }
+
+        let mut restored = GameBoy::new_skip_boot(false);
+        restored.load_rom(state_test_rom()).unwrap();
+        restored.load_state(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for _ in 0..1000 {
+            restored.step_and_render();
+        }
+        assert!(gb.state_diff(&restored).is_empty());
+        // the headless ppu is part of the state, mid frame
+        assert_ne!(gb.memory().read_byte(0xFF44), 0);
+        assert_eq!(gb.ppu().screen_buffer(), restored.ppu().screen_buffer());
+        assert_eq!(gb.save_state_data(), restored.save_state_data());
+    }
+
+    #[test]
+    fn reset_key_restarts_the_cartridge() {
+        let mut gb = GameBoy::new_skip_boot(false);
+        gb.load_rom(state_test_rom()).unwrap();
+        for _ in 0..1000 {
+            gb.step_and_render();
+        }
+        assert_ne!(gb.memory().read_byte(0xC000), 0);
+
+        assert!(gb.handle_event(key_down(Keycode::R)));
+        assert_eq!(gb.cpu().pc, 0x0100);
+        assert_eq!(gb.memory().read_byte(0xC000), 0);
+        assert_eq!(gb.memory().read_byte(0x0100), 0xC3);
+        let mut fresh = GameBoy::new_skip_boot(false);
+        fresh.load_rom(state_test_rom()).unwrap();
+        assert_eq!(gb.save_state_data(), fresh.save_state_data());
+    }
+
+    #[test]
+    fn reset_maps_the_boot_rom_again() {
+        let mut gb = GameBoy::new(false);
+        gb.load_boot(vec![0x31; 0x100]);
+        gb.load_rom(state_test_rom()).unwrap();
+        // unmap the boot rom as it does at its end
+        gb.memory_mut().write_byte(0xFF50, 0x01);
+        assert_eq!(gb.memory().read_byte(0x0002), 0x00);
+
+        gb.reset();
+        assert_eq!(gb.cpu().pc, 0x0000);
+        assert_eq!(gb.memory().read_byte(0x0002), 0x31);
+        assert_eq!(gb.memory().read_byte(0x0100), 0xC3);
+    }
+
+    #[test]
+    fn gameboy_load_state_errors() {
+        let mut gb = GameBoy::new_skip_boot(false);
+        gb.load_rom(state_test_rom()).unwrap();
+        gb.step();
+        let state = gb.save_state_data();
+        let before = gb.cpu().pc;
+
+        let mut other = GameBoy::new_skip_boot(false);
+        other.load_rom(cartridge_rom(0x01, 0x00, 0x00)).unwrap();
+        assert!(matches!(
+            other.load_state_data(&state),
+            Err(StateError::RomMismatch { .. })
+        ));
+        assert_eq!(other.cpu().pc, 0x100);
+        assert_eq!(
+            gb.load_state_data(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(gb.cpu().pc, before);
+
+        // trailing bytes are only noticed after memory was read, it must not change either
+        gb.memory_mut().write_byte(0xC000, 0x42);
+        let mut trailing = state.clone();
+        trailing.push(0x00);
+        assert_eq!(
+            gb.load_state_data(&trailing),
+            Err(StateError::InvalidValue("length"))
+        );
+        assert_eq!(gb.cpu().pc, before);
+        assert_eq!(gb.memory().read_byte(0xC000), 0x42);
+
+        let path = std::env::temp_dir().join("gb-rs-gameboy_load_state_errors.state");
+        std::fs::write(&path, b"not a state").unwrap();
+        let error = gb.load_state(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn ppu_save_state_renders_identical_frames() {
+        let setup = |memory: &mut Memory| {
+            memory.write_byte(0xFF40, 0x91);
+            memory.write_byte(0xFF47, 0xE4);
+            // tiles 0-3 with a different pattern per row, a different tile per map entry
+            for address in 0x8000..0x8040 {
+                memory.write_byte(address, (address as u8).wrapping_mul(37));
+            }
+            for i in 0..0x400 {
+                memory.write_byte(0x9800 + i, (i % 4) as u8);
+            }
+        };
+        let mut memory = Memory::new();
+        setup(&mut memory);
+        let mut ppu = PPU::new();
+        // stop mid frame, and mid way through a row of tiles
+        let saved_at = 114 * 75 + 50;
+        for timestamp in 0..saved_at {
+            ppu.render(&mut memory, timestamp);
+        }
+        let mut state = StateWriter::new();
+        ppu.write_state(&mut state);
+        memory.write_state(&mut state);
+        let state = state.finish();
+
+        let end = 114 * 154 * 2;
+        for timestamp in saved_at..end {
+            if timestamp == 114 * 154 + 10 {
+                memory.write_byte(0xFF43, 3);
+            }
+            ppu.render(&mut memory, timestamp);
+        }
+
+        let mut restored_memory = Memory::new();
+        let mut reader = StateReader::new(&state).unwrap();
+        let mut restored = PPU::read_state(&mut reader).unwrap();
+        restored_memory.read_state(&mut reader).unwrap();
+        reader.finish().unwrap();
+        for timestamp in saved_at..end {
+            if timestamp == 114 * 154 + 10 {
+                restored_memory.write_byte(0xFF43, 3);
+            }
+            restored.render(&mut restored_memory, timestamp);
+        }
+        assert_eq!(restored.screen_buffer(), ppu.screen_buffer());
+    }
+
+    #[test]
+    fn load_rom_from_path_errors() {
+        let dir = std::env::temp_dir();
+        let mut gb = GameBoy::new_skip_boot(false);
+
+        let missing = dir.join("gb-rs-load_rom_from_path_missing.gb");
+        let error = gb.load_rom_from_path(&missing).unwrap_err();
+        assert!(matches!(error, LoadError::Io { .. }));
+        assert!(error
+            .to_string()
+            .starts_with(&format!("unable to read {}: ", missing.display())));
+
+        let empty = dir.join("gb-rs-load_rom_from_path_empty.gb");
+        std::fs::write(&empty, b"").unwrap();
+        let error = gb.load_rom_from_path(&empty).unwrap_err();
+        assert!(matches!(error, LoadError::Empty { .. }));
+        assert_eq!(error.to_string(), format!("{} is empty", empty.display()));
+        assert!(matches!(
+            gb.load_boot_from_path(&empty),
+            Err(LoadError::Empty { .. })
+        ));
+        std::fs::remove_file(&empty).unwrap();
+
+        let short = dir.join("gb-rs-load_rom_from_path_short.gb");
+        std::fs::write(&short, [0; 0x40]).unwrap();
+        let error = gb.load_rom_from_path(&short).unwrap_err();
+        assert!(matches!(
+            error,
+            LoadError::Cartridge {
+                error: CartridgeError::BadHeader { len: 0x40 },
+                ..
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "unable to load {}: rom is 0x40 bytes, too short for a cartridge header",
+                short.display()
+            )
+        );
+        assert!(matches!(
+            gb.load_boot_from_path(&short),
+            Err(LoadError::BootSize { len: 0x40, .. })
+        ));
+        std::fs::remove_file(&short).unwrap();
+    }
+
+    #[test]
+    fn load_rom_from_path_valid() {
+        let path = std::env::temp_dir().join("gb-rs-load_rom_from_path_valid.gb");
+        let mut rom = cartridge_rom(0x01, 0x00, 0x00);
+        rom[0x134..0x138].copy_from_slice(b"PATH");
+        std::fs::write(&path, &rom).unwrap();
+
+        let mut gb = GameBoy::new_skip_boot(false);
+        let header = gb.load_rom_from_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(header.title, "PATH");
+        assert_eq!(gb.memory().header(), Some(&header));
+    }
 }